@@ -5,22 +5,25 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use rand::{rng, Rng};
 use tempfile::tempdir;
 use blockstore::block::make_random_block;
-use blockstore::blockstore::{FSStore, Blockstore};
+use blockstore::blockstore::{FSStore, Blockstore, SyncPolicy};
 
 const BLOCK_SIZE: usize = 65536;
 const N_OPS: usize = 10000;
 const RW_RATIO: f64 = 0.5;
 const N_THREADS: usize = 80;
 
-async fn random_rw_bench() {
+async fn random_rw_bench(sync_policy: SyncPolicy) {
     let threshold = i32::MAX / ((1.0 / RW_RATIO) as i32);
     let mut existing: HashSet<Cid> = HashSet::new();
     let root = tempdir().unwrap();
-    let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+    let store = FSStore::create(PathBuf::from(root.path()))
+        .await
+        .unwrap()
+        .with_sync_policy(sync_policy);
 
     for val in rng().random_iter::<i32>().take(N_OPS) {
-        if val < threshold && existing.len() > 0 {
-            let anyblock = existing.iter().next().unwrap().clone();
+        if val < threshold && !existing.is_empty() {
+            let anyblock = *existing.iter().next().unwrap();
             store.del_block(&anyblock).await.unwrap();
             existing.remove(&anyblock);
         } else {
@@ -39,9 +42,22 @@ fn random_rw_bench_wrapper(c: &mut Criterion) {
         .unwrap();
 
     c.bench_function("Random RW Bench", |b| {
-       b.to_async(&rt).iter(|| random_rw_bench())
+       b.to_async(&rt).iter(|| random_rw_bench(SyncPolicy::None))
     });
+
+    // Same workload with durability turned on, to show what each sync policy costs.
+    let mut group = c.benchmark_group("Random RW Bench (synced)");
+    group.sample_size(10);
+    for (name, policy) in [
+        ("DataOnly", SyncPolicy::DataOnly),
+        ("DataAndDir", SyncPolicy::DataAndDir),
+    ] {
+        group.bench_function(name, |b| {
+            b.to_async(&rt).iter(|| random_rw_bench(policy))
+        });
+    }
+    group.finish();
 }
 
 criterion_group!(benches, random_rw_bench_wrapper);
-criterion_main!(benches);
\ No newline at end of file
+criterion_main!(benches);
@@ -0,0 +1,399 @@
+//! Tracking when blocks were last read or written, so that eviction and tiering can tell the
+//! hot ones from the cold.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cid::Cid;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::JoinHandle;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, FSStore, Put, StoreStats};
+
+/// Name of the access journal inside an [`FSStore`]'s root.
+pub const ACCESS_FILE: &str = ".access";
+
+/// What an [`AccessTracker`] knows about a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessInfo {
+    pub last_access: SystemTime,
+    /// How many times the block has been accessed since it started being tracked.
+    pub count: u64,
+}
+
+/// Keeps the last access time and access count of blocks. Recording an access only touches
+/// memory; accesses get written to the journal, if there is one, in batches by
+/// [`AccessTracker::flush`], which [`AccessTracker::start_flusher`] runs periodically. Whatever
+/// hasn't been flushed is lost if the process goes away, which only makes those blocks look a
+/// little colder than they are.
+///
+/// A tracker can be shared, through an [`Arc`], by the layers that feed and use it: see
+/// [`AccessStore`], and the `with_access_tracker` methods of
+/// [`QuotaStore`](crate::quota::QuotaStore) and [`TieredStore`](crate::tiered::TieredStore).
+#[derive(Default)]
+pub struct AccessTracker {
+    accesses: Mutex<Accesses>,
+    journal: AsyncMutex<Option<File>>,
+}
+
+#[derive(Default)]
+struct Accesses {
+    by_cid: HashMap<Cid, AccessInfo>,
+    // Blocks whose entries changed since the last flush.
+    dirty: HashSet<Cid>,
+}
+
+impl AccessTracker {
+    /// Makes a tracker that keeps accesses in memory only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes a tracker that persists accesses to the journal at `path`.
+    pub async fn open(path: PathBuf) -> Result<Self, io::Error> {
+        let by_cid = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => replay_journal(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        // Compact: rewrite the journal with just the live entries, then append from there on.
+        let contents: String = by_cid
+            .iter()
+            .map(|(cid, info)| entry(cid, Some(info)))
+            .collect();
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, contents).await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+
+        let journal = OpenOptions::new().append(true).open(&path).await?;
+        Ok(AccessTracker {
+            accesses: Mutex::new(Accesses {
+                by_cid,
+                dirty: HashSet::new(),
+            }),
+            journal: AsyncMutex::new(Some(journal)),
+        })
+    }
+
+    /// Notes that `cid` was accessed just now.
+    pub fn record(&self, cid: &Cid) {
+        let now = SystemTime::now();
+        let mut accesses = self.accesses.lock().unwrap();
+        let info = accesses.by_cid.entry(*cid).or_insert(AccessInfo {
+            last_access: now,
+            count: 0,
+        });
+        info.last_access = now;
+        info.count += 1;
+        accesses.dirty.insert(*cid);
+    }
+
+    /// Stops tracking `cid`, such as because it was deleted.
+    pub fn forget(&self, cid: &Cid) {
+        let mut accesses = self.accesses.lock().unwrap();
+        if accesses.by_cid.remove(cid).is_some() {
+            accesses.dirty.insert(*cid);
+        }
+    }
+
+    /// Returns what's known about accesses to `cid`, or `None` if none were tracked.
+    pub fn access_info(&self, cid: &Cid) -> Option<AccessInfo> {
+        self.accesses.lock().unwrap().by_cid.get(cid).copied()
+    }
+
+    /// Returns every tracked block, least recently accessed first.
+    pub fn by_last_access(&self) -> Vec<(Cid, AccessInfo)> {
+        let accesses = self.accesses.lock().unwrap();
+        let mut tracked: Vec<(Cid, AccessInfo)> = accesses
+            .by_cid
+            .iter()
+            .map(|(cid, info)| (*cid, *info))
+            .collect();
+        tracked.sort_by_key(|(cid, info)| (info.last_access, *cid));
+        tracked
+    }
+
+    /// Writes out every entry that changed since the last flush, in one go, returning how many
+    /// were written. Does nothing for trackers that keep accesses in memory only.
+    pub async fn flush(&self) -> Result<usize, io::Error> {
+        let mut journal = self.journal.lock().await;
+        let Some(journal) = journal.as_mut() else {
+            self.accesses.lock().unwrap().dirty.clear();
+            return Ok(0);
+        };
+
+        let (dirty, contents) = {
+            let mut accesses = self.accesses.lock().unwrap();
+            let dirty = std::mem::take(&mut accesses.dirty);
+            let contents: String = dirty
+                .iter()
+                .map(|cid| entry(cid, accesses.by_cid.get(cid)))
+                .collect();
+            (dirty, contents)
+        };
+        // Tokio files finish writes in the background, so it takes a flush to see them through.
+        let written = async {
+            journal.write_all(contents.as_bytes()).await?;
+            journal.flush().await
+        };
+        if let Err(e) = written.await {
+            // So that the next flush tries these again.
+            self.accesses.lock().unwrap().dirty.extend(dirty);
+            return Err(e);
+        }
+        Ok(dirty.len())
+    }
+
+    /// Spawns a task that calls [`AccessTracker::flush`] every `interval`. The task stops when
+    /// the returned handle is dropped, or once the tracker itself is.
+    pub fn start_flusher(self: &Arc<Self>, interval: Duration) -> Flusher {
+        let tracker: Weak<Self> = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let Some(tracker) = tracker.upgrade() else {
+                    return;
+                };
+                // A failed flush will just be retried on the next tick.
+                let _ = tracker.flush().await;
+            }
+        });
+
+        Flusher { task }
+    }
+}
+
+/// Handle to the task started by [`AccessTracker::start_flusher`].
+pub struct Flusher {
+    task: JoinHandle<()>,
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Wraps a [`Blockstore`], recording every block put or read through it in an
+/// [`AccessTracker`]. Checking for a block or asking for its size doesn't count as accessing it.
+pub struct AccessStore<S> {
+    store: S,
+    tracker: Arc<AccessTracker>,
+}
+
+impl<S: Blockstore> AccessStore<S> {
+    pub fn new(store: S, tracker: Arc<AccessTracker>) -> Self {
+        AccessStore { store, tracker }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn tracker(&self) -> &Arc<AccessTracker> {
+        &self.tracker
+    }
+
+    /// Returns what's known about accesses to `cid`, as with [`AccessTracker::access_info`].
+    pub fn access_info(&self, cid: &Cid) -> Option<AccessInfo> {
+        self.tracker.access_info(cid)
+    }
+}
+
+impl AccessStore<FSStore> {
+    /// Wraps an [`FSStore`], persisting accesses in a journal inside its root.
+    pub async fn for_fs_store(store: FSStore) -> Result<Self, io::Error> {
+        let tracker = AccessTracker::open(store.root().join(ACCESS_FILE)).await?;
+        Ok(Self::new(store, Arc::new(tracker)))
+    }
+}
+
+impl<S: Blockstore> Blockstore for AccessStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let put = self.store.put_block(block).await?;
+        self.tracker.record(&block.cid);
+        Ok(put)
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        self.store.put_many(blocks).await?;
+        for block in blocks {
+            self.tracker.record(&block.cid);
+        }
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        self.store.has_block(cid).await
+    }
+
+    async fn has_many(&self, cids: &[Cid]) -> Vec<bool> {
+        self.store.has_many(cids).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        let block = self.store.get_block(cid).await?;
+        if block.is_some() {
+            self.tracker.record(cid);
+        }
+        Ok(block)
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, BlockstoreError> {
+        let blocks = self.store.get_many(cids).await?;
+        for block in blocks.iter().flatten() {
+            self.tracker.record(&block.cid);
+        }
+        Ok(blocks)
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.store.block_size(cid).await
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.store.del_block(cid).await?;
+        self.tracker.forget(cid);
+        Ok(())
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.store.stats().await
+    }
+}
+
+// Journal lines are `<nanos> <count> <cid>`, with a zero count for blocks no longer tracked.
+fn entry(cid: &Cid, info: Option<&AccessInfo>) -> String {
+    match info {
+        Some(info) => {
+            let nanos = info
+                .last_access
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            format!("{} {} {}\n", nanos.as_nanos(), info.count, cid)
+        }
+        None => format!("0 0 {}\n", cid),
+    }
+}
+
+fn replay_journal(contents: &str) -> Result<HashMap<Cid, AccessInfo>, io::Error> {
+    // A crash can leave the last line half-written; losing that access does no harm.
+    let complete = &contents[..contents.rfind('\n').map_or(0, |end| end + 1)];
+
+    let mut by_cid = HashMap::new();
+    for line in complete.lines().filter(|line| !line.is_empty()) {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed access entry {:?}", line),
+            )
+        };
+
+        let mut fields = line.splitn(3, ' ');
+        let (Some(nanos), Some(count), Some(cid)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let nanos: u64 = nanos.parse().map_err(|_| invalid())?;
+        let count: u64 = count.parse().map_err(|_| invalid())?;
+        let cid = Cid::try_from(cid).map_err(|_| invalid())?;
+        if count == 0 {
+            by_cid.remove(&cid);
+        } else {
+            let last_access = UNIX_EPOCH + Duration::from_nanos(nanos);
+            by_cid.insert(cid, AccessInfo { last_access, count });
+        }
+    }
+    Ok(by_cid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use tempfile::tempdir;
+
+    async fn make_access_store() -> (AccessStore<MemStore>, ()) {
+        let store = AccessStore::new(MemStore::new(), Arc::new(AccessTracker::new()));
+        (store, ())
+    }
+
+    crate::conformance::conformance_tests!(make_access_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_track_accesses() {
+        let (store, _) = make_access_store().await;
+        let block = make_random_block(100);
+        let missing = make_random_block(100);
+
+        let before = SystemTime::now();
+        store.put_block(&block).await.unwrap();
+        store.get_block(&block.cid).await.unwrap();
+        store.get_many(&[block.cid, missing.cid]).await.unwrap();
+        assert!(store.has_block(&block.cid).await);
+
+        let info = store.access_info(&block.cid).unwrap();
+        assert_eq!(info.count, 3);
+        assert!(info.last_access >= before);
+        assert_eq!(store.access_info(&missing.cid), None);
+
+        store.del_block(&block.cid).await.unwrap();
+        assert_eq!(store.access_info(&block.cid), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_persist_accesses_in_batches() {
+        let root = tempdir().unwrap();
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(100)).collect();
+        let journal = root.path().join(ACCESS_FILE);
+
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = AccessStore::for_fs_store(store).await.unwrap();
+        store.put_many(&blocks).await.unwrap();
+        for _ in 0..10 {
+            store.get_block(&blocks[0].cid).await.unwrap();
+        }
+        store.del_block(&blocks[2].cid).await.unwrap();
+
+        // Nothing hits the journal until it's flushed, and then each block takes one line.
+        assert_eq!(std::fs::read_to_string(&journal).unwrap(), "");
+        assert_eq!(store.tracker().flush().await.unwrap(), 3);
+        assert_eq!(
+            std::fs::read_to_string(&journal).unwrap().lines().count(),
+            3
+        );
+        let info = store.access_info(&blocks[0].cid).unwrap();
+        drop(store);
+
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = AccessStore::for_fs_store(store).await.unwrap();
+        assert_eq!(store.access_info(&blocks[0].cid), Some(info));
+        assert_eq!(store.access_info(&blocks[1].cid).unwrap().count, 1);
+        assert_eq!(store.access_info(&blocks[2].cid), None);
+        let order: Vec<Cid> = store
+            .tracker()
+            .by_last_access()
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .collect();
+        assert_eq!(order, [blocks[1].cid, blocks[0].cid]);
+    }
+}
@@ -0,0 +1,217 @@
+//! Command line access to an [`FSStore`], for scripting and debugging.
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use blockstore::block::Block;
+use blockstore::blockstore::{Blockstore, BlockstoreError, FSStore};
+use blockstore::car;
+use blockstore::dag;
+use cid::Cid;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const USAGE: &str = "\
+usage: toyblocks [--store <dir>] <command> [<args>]
+
+The store defaults to $TOYBLOCKS_STORE. Use - for stdin or stdout in place of a file.
+
+commands:
+    put <file>                 store a file as a raw block, printing its CID
+    get <cid>                  write a block's data to stdout
+    has <cid>                  exit with status 0 if the block is stored, 1 if not
+    del <cid>                  delete a block
+    ls                         list the CIDs of all blocks
+    stat                       print block count, total size and disk usage
+    import-car <file>          store the blocks from a CARv1 file, printing its roots
+    export-car <file> [<cid>]  write the DAG under <cid>, or the whole store, as a CARv1 file";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Put(String),
+    Get(Cid),
+    Has(Cid),
+    Del(Cid),
+    Ls,
+    Stat,
+    ImportCar(String),
+    ExportCar(String, Option<Cid>),
+}
+
+impl Command {
+    fn writes(&self) -> bool {
+        matches!(
+            self,
+            Command::Put(_) | Command::Del(_) | Command::ImportCar(_)
+        )
+    }
+}
+
+// Splits the arguments, without the program name, into the store directory and the command.
+fn parse(args: &[String], default_store: Option<String>) -> Result<(PathBuf, Command), String> {
+    let mut args = args.iter().map(String::as_str);
+    let mut store = default_store;
+    let mut next = args.next();
+    if next == Some("--store") {
+        store = Some(args.next().ok_or("--store needs a directory")?.to_string());
+        next = args.next();
+    }
+    let store = store.ok_or("no store given: use --store or set TOYBLOCKS_STORE")?;
+
+    let rest: Vec<&str> = args.collect();
+    let cid = |arg: &str| Cid::try_from(arg).map_err(|e| format!("bad CID {:?}: {}", arg, e));
+    let command = match (next.ok_or("no command given")?, rest.as_slice()) {
+        ("put", [file]) => Command::Put(file.to_string()),
+        ("get", [arg]) => Command::Get(cid(arg)?),
+        ("has", [arg]) => Command::Has(cid(arg)?),
+        ("del", [arg]) => Command::Del(cid(arg)?),
+        ("ls", []) => Command::Ls,
+        ("stat", []) => Command::Stat,
+        ("import-car", [file]) => Command::ImportCar(file.to_string()),
+        ("export-car", [file]) => Command::ExportCar(file.to_string(), None),
+        ("export-car", [file, root]) => Command::ExportCar(file.to_string(), Some(cid(root)?)),
+        (
+            command @ ("put" | "get" | "has" | "del" | "ls" | "stat" | "import-car" | "export-car"),
+            _,
+        ) => return Err(format!("bad arguments for {}", command)),
+        (command, _) => return Err(format!("unknown command {:?}", command)),
+    };
+    Ok((PathBuf::from(store), command))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if matches!(args.first().map(String::as_str), Some("-h" | "--help")) {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let (root, command) = match parse(&args, env::var("TOYBLOCKS_STORE").ok()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("toyblocks: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    // Only commands that write get to create the store, or stamp it with its version.
+    let result = if command.writes() {
+        match FSStore::create(root).await {
+            Ok(store) => run(&store, command).await,
+            Err(e) => Err(e),
+        }
+    } else {
+        match FSStore::open_read_only(root).await {
+            Ok(store) => run(&store, command).await,
+            Err(e) => Err(e),
+        }
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("toyblocks: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Runs `command` against `store`, returning whether it succeeded. Only `has` fails without an
+// error.
+async fn run(store: &impl Blockstore, command: Command) -> Result<bool, io::Error> {
+    let mut stdout = tokio::io::stdout();
+    match command {
+        Command::Put(file) => {
+            let mut data = Vec::new();
+            input(&file).await?.read_to_end(&mut data).await?;
+            let block = Block::new(data).map_err(io::Error::other)?;
+            store.put_block(&block).await?;
+            stdout
+                .write_all(format!("{}\n", block.cid).as_bytes())
+                .await?;
+        }
+        Command::Get(cid) => {
+            let block = store
+                .get_block(&cid)
+                .await?
+                .ok_or(BlockstoreError::NotFound(cid))?;
+            stdout.write_all(&block.data).await?;
+        }
+        Command::Has(cid) => return Ok(store.has_block(&cid).await),
+        Command::Del(cid) => store.del_block(&cid).await?,
+        Command::Ls => {
+            let mut cids = store.blocks();
+            while let Some(cid) = cids.recv().await {
+                stdout.write_all(format!("{}\n", cid?).as_bytes()).await?;
+            }
+        }
+        Command::Stat => {
+            let stats = store.stats().await?;
+            let report = format!(
+                "blocks: {}\nbytes: {}\ndisk bytes: {}\n",
+                stats.blocks, stats.bytes, stats.disk_bytes
+            );
+            stdout.write_all(report.as_bytes()).await?;
+        }
+        Command::ImportCar(file) => {
+            let header = car::import_car(store, input(&file).await?).await?;
+            for root in header.roots {
+                stdout.write_all(format!("{}\n", root).as_bytes()).await?;
+            }
+        }
+        Command::ExportCar(file, None) => {
+            car::export_store(store, &[], output(&file).await?).await?;
+        }
+        Command::ExportCar(file, Some(root)) => {
+            let mut cids = Vec::new();
+            let mut walk = dag::walk(store, root);
+            while let Some(block) = walk.next().await {
+                cids.push(block?.cid);
+            }
+            car::export_car(store, &[root], cids, output(&file).await?).await?;
+        }
+    }
+    stdout.flush().await?;
+    Ok(true)
+}
+
+async fn input(file: &str) -> Result<Box<dyn AsyncRead + Unpin>, io::Error> {
+    Ok(match file {
+        "-" => Box::new(tokio::io::stdin()),
+        path => Box::new(File::open(path).await?),
+    })
+}
+
+async fn output(file: &str) -> Result<Box<dyn AsyncWrite + Unpin>, io::Error> {
+    Ok(match file {
+        "-" => Box::new(tokio::io::stdout()),
+        path => Box::new(File::create(path).await?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn should_parse_commands() {
+        let cid = blockstore::block::make_random_block(10).cid;
+        let parsed = parse(&args(&format!("--store blocks get {}", cid)), None).unwrap();
+        assert_eq!(parsed, (PathBuf::from("blocks"), Command::Get(cid)));
+
+        let parsed = parse(&args("export-car out.car"), Some("env".to_string())).unwrap();
+        let expected = Command::ExportCar("out.car".to_string(), None);
+        assert_eq!(parsed, (PathBuf::from("env"), expected));
+
+        assert!(parse(&args("ls"), None).is_err());
+        assert!(parse(&args("--store blocks get not-a-cid"), None).is_err());
+        assert!(parse(&args("--store blocks ls extra"), None).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! BLAKE3 in its default hashing mode with 32-byte output, for
+//! [`crate::block::Hasher::Blake3`]. A straightforward port of the specification's reference
+//! implementation: no SIMD, no multithreading.
+
+const IV: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+const MESSAGE_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+
+pub fn blake3(data: &[u8]) -> [u8; 32] {
+    // Chaining values of completed subtrees, largest first. A new chunk merges with the top of
+    // the stack once for every trailing zero bit in the number of chunks so far.
+    let mut stack: Vec<[u32; 8]> = Vec::new();
+    let mut chunks = data.chunks(CHUNK_LEN).enumerate().peekable();
+
+    let mut output = chunk_output(&[], 0);
+    while let Some((counter, chunk)) = chunks.next() {
+        output = chunk_output(chunk, counter as u64);
+        if chunks.peek().is_none() {
+            break;
+        }
+
+        let mut cv = output.chaining_value();
+        let mut total = counter as u64 + 1;
+        while total & 1 == 0 {
+            cv = parent_output(&stack.pop().unwrap(), &cv).chaining_value();
+            total >>= 1;
+        }
+        stack.push(cv);
+    }
+
+    // Whatever's left on the stack gets merged into the last chunk right to left, the final
+    // merge being the root.
+    while let Some(left) = stack.pop() {
+        output = parent_output(&left, &output.chaining_value());
+    }
+    output.root_hash()
+}
+
+// Everything needed to compress a node's last block, which is done differently depending on
+// whether the node turns out to be the root.
+struct Output {
+    input_cv: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        let state = compress(
+            &self.input_cv,
+            &self.block,
+            self.counter,
+            self.block_len,
+            self.flags,
+        );
+        state[..8].try_into().unwrap()
+    }
+
+    fn root_hash(&self) -> [u8; 32] {
+        let state = compress(
+            &self.input_cv,
+            &self.block,
+            0,
+            self.block_len,
+            self.flags | ROOT,
+        );
+        let mut hash = [0u8; 32];
+        for (bytes, word) in hash.chunks_mut(4).zip(state) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        hash
+    }
+}
+
+fn chunk_output(chunk: &[u8], counter: u64) -> Output {
+    let mut cv = IV;
+    let mut blocks = chunk.chunks(BLOCK_LEN).peekable();
+    let mut flags = CHUNK_START;
+
+    loop {
+        // An empty chunk still has one (empty) block.
+        let block = blocks.next().unwrap_or(&[]);
+        if blocks.peek().is_none() {
+            return Output {
+                input_cv: cv,
+                block: block_words(block),
+                counter,
+                block_len: block.len() as u32,
+                flags: flags | CHUNK_END,
+            };
+        }
+
+        let state = compress(&cv, &block_words(block), counter, BLOCK_LEN as u32, flags);
+        cv = state[..8].try_into().unwrap();
+        flags = 0;
+    }
+}
+
+fn parent_output(left: &[u32; 8], right: &[u32; 8]) -> Output {
+    let mut block = [0u32; 16];
+    block[..8].copy_from_slice(left);
+    block[8..].copy_from_slice(right);
+    Output {
+        input_cv: IV,
+        block,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+// Reads a (possibly short) block as little-endian words, zero-padded.
+fn block_words(block: &[u8]) -> [u32; 16] {
+    let mut padded = [0u8; BLOCK_LEN];
+    padded[..block.len()].copy_from_slice(block);
+    let mut words = [0u32; 16];
+    for (word, bytes) in words.iter_mut().zip(padded.chunks(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    words
+}
+
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, x: u32, y: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(x);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(y);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn compress(
+    cv: &[u32; 8],
+    block: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+
+    let mut message = *block;
+    for i in 0..7 {
+        round(&mut state, &message);
+        if i < 6 {
+            message = MESSAGE_PERMUTATION.map(|j| message[j]);
+        }
+    }
+
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn should_hash_empty_input() {
+        assert_eq!(
+            hex(&blake3(b"")),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+
+    #[test]
+    fn should_hash_across_chunk_boundaries() {
+        // Inputs like those of the official test vectors: bytes counting up, modulo 251.
+        let input: Vec<u8> = (0..31_744).map(|i| (i % 251) as u8).collect();
+        let expected = [
+            (
+                1,
+                "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+            ),
+            (
+                65,
+                "de1e5fa0be70df6d2be8fffd0e99ceaa8eb6e8c93a63f2d8d1c30ecb6b263dee",
+            ),
+            (
+                1_024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                1_025,
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+            (
+                2_049,
+                "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030",
+            ),
+            (
+                5_121,
+                "628bd2cb2004694adaab7bbd778a25df25c47b9d4155a55f8fbd79f2fe154cff",
+            ),
+            (
+                8_193,
+                "bab6c09cb8ce8cf459261398d2e7aef35700bf488116ceb94a36d0f5f1b7bc3b",
+            ),
+            (
+                31_744,
+                "62b6960e1a44bcc1eb1a611a8d6235b6b4b78f32e7abc4fb4c6cdcce94895c47",
+            ),
+        ];
+
+        for (len, hash) in expected {
+            assert_eq!(hex(&blake3(&input[..len])), hash, "input of {} bytes", len);
+        }
+    }
+}
@@ -1,24 +1,179 @@
+use bytes::Bytes;
 use cid::Cid;
 use multihash::{Error, Multihash};
 use rand::RngCore;
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha512};
 
+use crate::blake3::blake3;
+use crate::sha3::sha3_256;
+
+const IDENTITY: u64 = 0x00;
 const SHA2_256: u64 = 0x12;
 
-#[derive(Debug)]
+/// How a block's contents are encoded, as recorded in its CID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Codec {
+    /// Opaque bytes.
+    #[default]
+    Raw,
+    DagPb,
+    DagCbor,
+    DagJson,
+}
+
+impl Codec {
+    /// The codec's code in the multicodec table.
+    pub fn code(&self) -> u64 {
+        match self {
+            Codec::Raw => 0x55,
+            Codec::DagPb => 0x70,
+            Codec::DagCbor => 0x71,
+            Codec::DagJson => 0x0129,
+        }
+    }
+
+    pub fn from_code(code: u64) -> Option<Codec> {
+        [Codec::Raw, Codec::DagPb, Codec::DagCbor, Codec::DagJson]
+            .into_iter()
+            .find(|codec| codec.code() == code)
+    }
+}
+
+/// The hash functions blocks can be addressed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Hasher {
+    #[default]
+    Sha2_256,
+    Sha2_512,
+    Sha3_256,
+    /// BLAKE3 with its default 32-byte output.
+    Blake3,
+}
+
+impl Hasher {
+    /// The function's code in the multihash table.
+    pub fn code(&self) -> u64 {
+        match self {
+            Hasher::Sha2_256 => SHA2_256,
+            Hasher::Sha2_512 => 0x13,
+            Hasher::Sha3_256 => 0x16,
+            Hasher::Blake3 => 0x1e,
+        }
+    }
+
+    pub fn from_code(code: u64) -> Option<Hasher> {
+        [Hasher::Sha2_256, Hasher::Sha2_512, Hasher::Sha3_256, Hasher::Blake3]
+            .into_iter()
+            .find(|hasher| hasher.code() == code)
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Hasher::Sha2_256 => Sha256::digest(data).to_vec(),
+            Hasher::Sha2_512 => Sha512::digest(data).to_vec(),
+            Hasher::Sha3_256 => sha3_256(data).to_vec(),
+            Hasher::Blake3 => blake3(data).to_vec(),
+        }
+    }
+}
+
+/// A block and its CID. The contents are [`Bytes`], so cloning a block is cheap, and blocks can
+/// share the buffer they were sliced out of.
+#[derive(Debug, Clone)]
 pub struct Block {
     pub cid: Cid,
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 
 impl Block {
-    pub fn new(data: Vec<u8>) -> Result<Block, Error> {
-        let digest = Sha256::digest(&data);
-        let multihash = Multihash::wrap(SHA2_256, digest.as_slice())?;
-        Ok(Block { cid: Cid::new_v1(SHA2_256, multihash), data })
+    /// Creates a [`Codec::Raw`] block, addressed by its SHA2-256 hash.
+    ///
+    /// # Migrating existing stores
+    ///
+    /// Blocks used to be created with the hash function's code (0x12) in place of a codec, which
+    /// other IPFS tooling doesn't understand. Blocks stored that way stay readable, and verifiable,
+    /// under the CIDs they were stored with, but the same data now gets a different CID. To move
+    /// a store over, put each listed block whose CID has a codec of 0x12 again under
+    /// `Cid::new_v1(Codec::Raw.code(), *cid.hash())`, then delete the original.
+    pub fn new(data: impl Into<Bytes>) -> Result<Block, Error> {
+        Self::new_with_hasher(data, Hasher::Sha2_256)
+    }
+
+    /// Like [`Block::new`], but addressing the block by its `hasher` hash.
+    pub fn new_with_hasher(data: impl Into<Bytes>, hasher: Hasher) -> Result<Block, Error> {
+        Self::new_with_codec(data, Codec::Raw, hasher)
+    }
+
+    /// Creates a block whose contents are encoded with `codec`. The data isn't checked to be
+    /// valid in that encoding.
+    pub fn new_with_codec(
+        data: impl Into<Bytes>,
+        codec: Codec,
+        hasher: Hasher,
+    ) -> Result<Block, Error> {
+        let data = data.into();
+        let multihash = Multihash::wrap(hasher.code(), &hasher.digest(&data))?;
+        Ok(Block { cid: Cid::new_v1(codec.code(), multihash), data })
+    }
+
+    /// Creates a block addressed by an identity multihash, whose "digest" is the data itself:
+    /// the CID holds the whole block, so stores can hand it back without keeping it anywhere.
+    /// Fails for data too long to fit in a multihash, which is anything over 64 bytes.
+    pub fn new_inline(data: impl Into<Bytes>, codec: Codec) -> Result<Block, Error> {
+        let data = data.into();
+        let multihash = Multihash::wrap(IDENTITY, &data)?;
+        Ok(Block { cid: Cid::new_v1(codec.code(), multihash), data })
+    }
+
+    /// The block `cid` holds, if it's addressed by an identity multihash.
+    pub fn inline(cid: &Cid) -> Option<Block> {
+        is_inline(cid).then(|| Block {
+            cid: *cid,
+            data: Bytes::copy_from_slice(cid.hash().digest()),
+        })
+    }
+
+    /// Wraps `data` under an existing `cid`, checking first that the data actually hashes to
+    /// it. Returns `None` if it doesn't, or if the CID uses a hash function we don't support.
+    pub fn with_cid(cid: Cid, data: impl Into<Bytes>) -> Option<Block> {
+        let data = data.into();
+        let matches = Self::hash_matches(&cid, &data) == Some(true);
+        matches.then_some(Block { cid, data })
+    }
+
+    /// Checks whether `data` hashes to `cid`. Returns `None` if the CID uses a hash function (or
+    /// digest length) we don't support, and so can't tell.
+    pub fn hash_matches(cid: &Cid, data: &[u8]) -> Option<bool> {
+        let expected = cid.hash().digest();
+        if is_inline(cid) {
+            return Some(data == expected);
+        }
+
+        let digest = Hasher::from_code(cid.hash().code())?.digest(data);
+        (digest.len() == expected.len()).then(|| digest == expected)
     }
 }
 
+/// Whether `cid` is addressed by an identity multihash, and so holds its block's data itself.
+pub fn is_inline(cid: &Cid) -> bool {
+    cid.hash().code() == IDENTITY
+}
+
+/// Converts `cid` to version 1, which is what stores key blocks by. A CIDv0 becomes the dag-pb
+/// CIDv1 with the same multihash, so both name the same block.
+pub fn to_v1(cid: &Cid) -> Cid {
+    Cid::new_v1(cid.codec(), *cid.hash())
+}
+
+/// Converts `cid` to version 0, if it can be expressed as one: only dag-pb CIDs with a SHA2-256
+/// multihash can.
+pub fn to_v0(cid: &Cid) -> Option<Cid> {
+    if cid.codec() != Codec::DagPb.code() {
+        return None;
+    }
+    Cid::new_v0(*cid.hash()).ok()
+}
+
 impl PartialEq<Self> for Block {
     fn eq(&self, other: &Self) -> bool {
         self.cid == other.cid
@@ -50,4 +205,66 @@ pub mod tests {
 
         assert_ne!(block1, block2);
     }
+
+    #[test]
+    pub fn should_accept_data_matching_cid() {
+        let block = make_random_block(10);
+        let wrapped = Block::with_cid(block.cid, block.data.clone()).unwrap();
+
+        assert_eq!(wrapped, block);
+    }
+
+    #[test]
+    pub fn should_address_blocks_by_any_supported_hash() {
+        let data = b"hello".to_vec();
+        for hasher in [Hasher::Sha2_256, Hasher::Sha2_512, Hasher::Sha3_256, Hasher::Blake3] {
+            let block = Block::new_with_hasher(data.clone(), hasher).unwrap();
+            assert_eq!(block.cid.hash().code(), hasher.code());
+            assert_eq!(Hasher::from_code(hasher.code()), Some(hasher));
+            assert!(Block::with_cid(block.cid, data.clone()).is_some());
+            assert!(Block::with_cid(block.cid, b"world".to_vec()).is_none());
+        }
+
+        let sha2 = Block::new_with_hasher(data.clone(), Hasher::Sha2_256).unwrap();
+        let blake3 = Block::new_with_hasher(data, Hasher::Blake3).unwrap();
+        assert_ne!(sha2, blake3);
+    }
+
+    #[test]
+    pub fn should_use_raw_codec_by_default() {
+        let block = Block::new(b"hello".to_vec()).unwrap();
+        assert_eq!(block.cid.codec(), Codec::Raw.code());
+        // The same CID `ipfs block put` gives.
+        assert_eq!(
+            block.cid.to_string(),
+            "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq"
+        );
+
+        let cbor = Block::new_with_codec(vec![0xa0], Codec::DagCbor, Hasher::Sha2_256).unwrap();
+        assert_eq!(Codec::from_code(cbor.cid.codec()), Some(Codec::DagCbor));
+        assert!(Block::with_cid(cbor.cid, vec![0xa0]).is_some());
+    }
+
+    #[test]
+    pub fn should_convert_between_cid_versions() {
+        let block = make_random_block(100);
+        assert_eq!(to_v1(&block.cid), block.cid);
+        assert_eq!(to_v0(&block.cid), None);
+
+        let v1 = Cid::new_v1(Codec::DagPb.code(), *block.cid.hash());
+        let v0 = to_v0(&v1).unwrap();
+        assert_eq!(v0.version(), cid::Version::V0);
+        assert!(v0.to_string().starts_with("Qm"));
+        assert_eq!(to_v1(&v0), v1);
+        assert_eq!(to_v0(&v0), Some(v0));
+        assert!(Block::with_cid(v0, block.data).is_some());
+    }
+
+    #[test]
+    pub fn should_reject_data_not_matching_cid() {
+        let block1 = make_random_block(10);
+        let block2 = make_random_block(10);
+
+        assert!(Block::with_cid(block1.cid, block2.data).is_none());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,147 @@
+//! A synchronous face for any [`Blockstore`], for code that doesn't run an async runtime of its
+//! own, like simple command line tools.
+
+use std::io;
+use std::path::PathBuf;
+
+use cid::Cid;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, FSStore, Put, StoreStats};
+
+/// Wraps a [`Blockstore`] together with a runtime of its own, running each operation to
+/// completion before returning. The methods mirror [`Blockstore`]'s.
+///
+/// The methods block the calling thread, so they must not be called from async code: doing so
+/// from within a Tokio runtime panics.
+pub struct SyncBlockstore<S> {
+    store: S,
+    runtime: Runtime,
+}
+
+impl SyncBlockstore<FSStore> {
+    /// Opens the [`FSStore`] at `root`, as with [`FSStore::create`].
+    pub fn open(root: PathBuf) -> Result<Self, BlockstoreError> {
+        let runtime = runtime()?;
+        let store = runtime.block_on(FSStore::create(root))?;
+        Ok(SyncBlockstore { store, runtime })
+    }
+}
+
+impl<S: Blockstore> SyncBlockstore<S> {
+    pub fn new(store: S) -> Result<Self, io::Error> {
+        Ok(SyncBlockstore {
+            store,
+            runtime: runtime()?,
+        })
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        self.runtime.block_on(self.store.put_block(block))
+    }
+
+    pub fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        self.runtime.block_on(self.store.put_many(blocks))
+    }
+
+    pub fn has_block(&self, cid: &Cid) -> bool {
+        self.runtime.block_on(self.store.has_block(cid))
+    }
+
+    pub fn has_many(&self, cids: &[Cid]) -> Vec<bool> {
+        self.runtime.block_on(self.store.has_many(cids))
+    }
+
+    pub fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        self.runtime.block_on(self.store.get_block(cid))
+    }
+
+    pub fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, BlockstoreError> {
+        self.runtime.block_on(self.store.get_many(cids))
+    }
+
+    /// Like [`Blockstore::prefetch`]. With no runtime running in between calls, prefetching only
+    /// makes progress while other operations are waited for.
+    pub fn prefetch(&self, cids: &[Cid]) {
+        let _context = self.runtime.enter();
+        self.store.prefetch(cids)
+    }
+
+    pub fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.runtime.block_on(self.store.block_size(cid))
+    }
+
+    pub fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.runtime.block_on(self.store.del_block(cid))
+    }
+
+    pub fn del_many(&self, cids: &[Cid]) -> Result<(), BlockstoreError> {
+        self.runtime.block_on(self.store.del_many(cids))
+    }
+
+    /// Lists the CIDs of all blocks in the store, like [`Blockstore::blocks`].
+    pub fn blocks(&self) -> Blocks<'_> {
+        // Listings run as tasks of their own, which need to be spawned onto our runtime.
+        let _context = self.runtime.enter();
+        Blocks {
+            runtime: &self.runtime,
+            receiver: self.store.blocks(),
+        }
+    }
+
+    pub fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.runtime.block_on(self.store.stats())
+    }
+}
+
+/// The CIDs in a store, as listed by [`SyncBlockstore::blocks`].
+pub struct Blocks<'a> {
+    runtime: &'a Runtime,
+    receiver: CidStream,
+}
+
+impl Iterator for Blocks<'_> {
+    type Item = Result<Cid, BlockstoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runtime.block_on(self.receiver.recv())
+    }
+}
+
+// A single threaded runtime is enough, since every operation is waited for anyway. FSStore's
+// filesystem calls still get threads of their own from the blocking pool.
+fn runtime() -> Result<Runtime, io::Error> {
+    Builder::new_current_thread().enable_all().build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use std::collections::HashSet;
+    use tempfile::tempdir;
+
+    #[test]
+    fn should_use_fs_store_without_runtime() {
+        let root = tempdir().unwrap();
+        let store = SyncBlockstore::open(root.path().to_path_buf()).unwrap();
+        let blocks = [make_random_block(100), make_random_block(200)];
+
+        store.put_many(&blocks).unwrap();
+        assert_eq!(store.put_block(&blocks[0]).unwrap(), Put::Existing);
+        assert_eq!(store.get_block(&blocks[1].cid).unwrap().unwrap(), blocks[1]);
+        assert_eq!(store.block_size(&blocks[1].cid).unwrap(), Some(200));
+
+        let listed: HashSet<Cid> = store.blocks().map(Result::unwrap).collect();
+        assert_eq!(listed, blocks.iter().map(|block| block.cid).collect());
+
+        store.del_block(&blocks[0].cid).unwrap();
+        assert!(!store.has_block(&blocks[0].cid));
+        assert_eq!(store.stats().unwrap().blocks, 1);
+    }
+}
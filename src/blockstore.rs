@@ -1,169 +1,4760 @@
-use std::fs::{create_dir_all, File};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs::{DirEntry, File, Metadata};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::{fs, io};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::block::Block;
+use crate::block::{Block, is_inline};
+use crate::bloom::BloomFilter;
+use crate::index::{self, BlockIndex, Entries};
+use crate::migrate;
+use crate::mmap::map_file;
+use crate::packs::Packs;
+use crate::readonly::ReadOnlyStore;
+use crate::sharding::{self, Prefix, ShardingStrategy};
+use crate::timeout::Operation;
+use bytes::Bytes;
 use cid::Cid;
+use multihash::Multihash;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot};
+use tokio::task::{spawn_blocking, JoinError, JoinSet};
 
-pub trait Blockstore {
-    fn put_block(&self, block: &Block) -> impl Future<Output = Result<(), io::Error>> + Send;
+/// A lazily produced listing of CIDs, as returned by [`Blockstore::blocks`]. Items are pulled with
+/// `recv().await`, and `None` means the listing is complete. Dropping the receiver stops the
+/// listing early.
+pub type CidStream = mpsc::Receiver<Result<Cid, BlockstoreError>>;
+
+// How far ahead of the consumer a listing is allowed to run.
+const CID_STREAM_BUFFER: usize = 1024;
+
+/// Why a [`Blockstore`] operation failed.
+///
+/// Converting to an [`io::Error`] and back gives the same error, so code that deals in
+/// `io::Error`s can pass these along with `?` without losing anything.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BlockstoreError {
+    /// The store doesn't have the block.
+    NotFound(Cid),
+    /// The data stored for a block doesn't hash to its CID.
+    Corrupt(Box<Corruption>),
+    /// Storing `size` bytes would take the store over its limit of `max_bytes`.
+    QuotaExceeded { size: u64, max_bytes: u64 },
+    /// The put needed `needed` bytes of free space on the filesystem, but there were only
+    /// `available`.
+    OutOfSpace { needed: u64, available: u64 },
+    /// The block is bigger than the store accepts.
+    BlockTooLarge { cid: Cid, size: u64, max_size: u64 },
+    /// The store can't be modified.
+    ReadOnly,
+    /// A [`TimeoutStore`](crate::timeout::TimeoutStore) gave up on the operation `after` this
+    /// long.
+    Timeout { operation: Operation, after: Duration },
+    /// Anything else the backend ran into.
+    Backend(io::Error),
+}
+
+/// What a [`BlockstoreError::Corrupt`] found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corruption {
+    /// The block that was asked for.
+    pub expected: Cid,
+    /// What the stored data hashes to instead, with the same codec and hash function.
+    pub actual: Cid,
+    /// Where the bad file was moved to, under [`VerifyMode::Quarantine`].
+    pub quarantined: Option<PathBuf>,
+}
+
+impl BlockstoreError {
+    /// The [`io::ErrorKind`] of the `io::Error` this converts to.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            BlockstoreError::NotFound(_) => io::ErrorKind::NotFound,
+            BlockstoreError::Corrupt(_) => io::ErrorKind::InvalidData,
+            BlockstoreError::QuotaExceeded { .. } => io::ErrorKind::StorageFull,
+            BlockstoreError::OutOfSpace { .. } => io::ErrorKind::StorageFull,
+            BlockstoreError::BlockTooLarge { .. } => io::ErrorKind::InvalidInput,
+            BlockstoreError::ReadOnly => io::ErrorKind::ReadOnlyFilesystem,
+            BlockstoreError::Timeout { .. } => io::ErrorKind::TimedOut,
+            BlockstoreError::Backend(e) => e.kind(),
+        }
+    }
+
+    // A `Corrupt` error for block `expected`, whose stored data turned out to hash to `digest`.
+    pub(crate) fn corrupt(expected: &Cid, digest: &[u8], quarantined: Option<PathBuf>) -> Self {
+        // Only identity "digests" can be too long to wrap, and those are the data itself.
+        let hash = Multihash::wrap(expected.hash().code(), digest).unwrap_or_default();
+        BlockstoreError::Corrupt(Box::new(Corruption {
+            expected: *expected,
+            actual: Cid::new_v1(expected.codec(), hash),
+            quarantined,
+        }))
+    }
+}
+
+impl Display for BlockstoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockstoreError::NotFound(cid) => write!(f, "block {} not found", cid),
+            BlockstoreError::Corrupt(corruption) => {
+                let Corruption {
+                    expected,
+                    actual,
+                    quarantined,
+                } = corruption.as_ref();
+                write!(f, "data stored for block {} hashes to {}", expected, actual)?;
+                if let Some(path) = quarantined {
+                    write!(f, " (quarantined to {:?})", path)?;
+                }
+                Ok(())
+            }
+            BlockstoreError::QuotaExceeded { size, max_bytes } => write!(
+                f,
+                "block of {} bytes doesn't fit in quota of {} bytes",
+                size, max_bytes
+            ),
+            BlockstoreError::OutOfSpace { needed, available } => write!(
+                f,
+                "put needs {} bytes of free space, but only {} are available",
+                needed, available
+            ),
+            BlockstoreError::BlockTooLarge {
+                cid,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "block {} is {} bytes, more than the maximum of {}",
+                cid, size, max_size
+            ),
+            BlockstoreError::ReadOnly => write!(f, "store is read-only"),
+            BlockstoreError::Timeout { operation, after } => {
+                write!(f, "{:?} operation timed out after {:?}", operation, after)
+            }
+            BlockstoreError::Backend(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for BlockstoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BlockstoreError::Backend(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BlockstoreError {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<BlockstoreError>()) {
+            return *e.into_inner().unwrap().downcast().unwrap();
+        }
+        BlockstoreError::Backend(e)
+    }
+}
+
+impl From<BlockstoreError> for io::Error {
+    fn from(e: BlockstoreError) -> Self {
+        match e {
+            BlockstoreError::Backend(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+impl From<JoinError> for BlockstoreError {
+    fn from(e: JoinError) -> Self {
+        BlockstoreError::Backend(e.into())
+    }
+}
+
+pub trait Blockstore: Send + Sync {
+    /// Stores `block`, unless the store already has it: content addressing means the copy
+    /// that's there holds the exact same data, so there's no need to write it again.
+    fn put_block(&self, block: &Block) -> impl Future<Output = Result<Put, BlockstoreError>> + Send;
+    /// Stores several blocks at once. Backends can override this to amortize per-block
+    /// overhead; the default just puts them one by one.
+    fn put_many(&self, blocks: &[Block]) -> impl Future<Output = Result<(), BlockstoreError>> + Send {
+        async move {
+            for block in blocks {
+                self.put_block(block).await?;
+            }
+            Ok(())
+        }
+    }
     fn has_block(&self, cid: &Cid) -> impl Future<Output = bool> + Send;
-    fn get_block(&self, cid: &Cid) -> impl Future<Output = Result<Option<Block>, io::Error>> + Send;
-    fn del_block(&self, cid: &Cid) -> impl Future<Output = Result<(), io::Error>> + Send;
+    /// Checks for several blocks at once, answering in the same order. The default checks them
+    /// one by one.
+    fn has_many(&self, cids: &[Cid]) -> impl Future<Output = Vec<bool>> + Send {
+        async move {
+            let mut found = Vec::with_capacity(cids.len());
+            for cid in cids {
+                found.push(self.has_block(cid).await);
+            }
+            found
+        }
+    }
+    /// Returns the block, or `None` if it isn't in the store. Errors mean the store couldn't
+    /// tell, never that the block is simply missing.
+    fn get_block(&self, cid: &Cid) -> impl Future<Output = Result<Option<Block>, BlockstoreError>> + Send;
+    /// Fetches several blocks at once, in the same order, with `None` for the ones the store
+    /// doesn't have. Fails if reading any of them does.
+    fn get_many(&self, cids: &[Cid]) -> impl Future<Output = Result<Vec<Option<Block>>, BlockstoreError>> + Send {
+        async move {
+            let mut blocks = Vec::with_capacity(cids.len());
+            for cid in cids {
+                blocks.push(self.get_block(cid).await?);
+            }
+            Ok(blocks)
+        }
+    }
+    /// Hints that the given blocks are going to be read soon, so that the store can get them
+    /// ready in the background. Returns right away, and never fails: it's fine for the blocks
+    /// not to be there. The default does nothing.
+    fn prefetch(&self, _cids: &[Cid]) {}
+    /// Returns the size of a block's data, or `None` if the block isn't in the store. The
+    /// default fetches the whole block; backends that can do better should.
+    fn block_size(&self, cid: &Cid) -> impl Future<Output = Result<Option<u64>, BlockstoreError>> + Send {
+        async move {
+            let block = self.get_block(cid).await?;
+            Ok(block.map(|block| block.data.len() as u64))
+        }
+    }
+    /// Deletes a block. Fails with [`BlockstoreError::NotFound`] if the store doesn't have it.
+    fn del_block(&self, cid: &Cid) -> impl Future<Output = Result<(), BlockstoreError>> + Send;
+    /// Like [`Blockstore::del_block`], but returns whether there was a block to delete rather
+    /// than failing when there wasn't, for cleanups that only care that it's gone.
+    fn del_block_if_present(&self, cid: &Cid) -> impl Future<Output = Result<bool, BlockstoreError>> + Send {
+        async move {
+            match self.del_block(cid).await {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+    }
+    /// Deletes several blocks at once. Failing to delete one doesn't stop the others: once
+    /// they've all been tried, this fails with the first error, if any. As with
+    /// [`Blockstore::del_block`], blocks the store doesn't have fail with `NotFound`.
+    fn del_many(&self, cids: &[Cid]) -> impl Future<Output = Result<(), BlockstoreError>> + Send {
+        async move {
+            let mut result = Ok(());
+            for cid in cids {
+                if let Err(e) = self.del_block(cid).await
+                    && result.is_ok()
+                {
+                    result = Err(e);
+                }
+            }
+            result
+        }
+    }
+    /// Lists the CIDs of all blocks in the store, in no particular order. Blocks put or deleted
+    /// while the listing is in progress may or may not show up.
+    fn blocks(&self) -> CidStream;
+    fn stats(&self) -> impl Future<Output = Result<StoreStats, BlockstoreError>> + Send;
+}
+
+/// What [`Blockstore::put_block`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Put {
+    /// The store already had the block, so nothing was written.
+    Existing,
+    /// The block was written, taking `bytes` bytes. That's the size of the block for most
+    /// stores, but can be more or less for ones that transform blocks or keep copies of them.
+    Written { bytes: u64 },
+}
+
+impl Put {
+    /// Whether the put wrote the block, rather than finding it already there.
+    pub fn is_written(&self) -> bool {
+        matches!(self, Put::Written { .. })
+    }
+
+    /// How many bytes the put wrote, which is none if the block was already there.
+    pub fn bytes_written(&self) -> u64 {
+        match self {
+            Put::Existing => 0,
+            Put::Written { bytes } => *bytes,
+        }
+    }
+
+    // What a put that was this one and then `other` too did, as for stores that put the block
+    // into several others.
+    pub(crate) fn and(self, other: Put) -> Put {
+        match (self, other) {
+            (Put::Existing, put) | (put, Put::Existing) => put,
+            (Put::Written { bytes }, Put::Written { bytes: more }) => Put::Written {
+                bytes: bytes + more,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StoreStats {
+    /// Number of blocks in the store.
+    pub blocks: u64,
+    /// Sum of the sizes of all blocks.
+    pub bytes: u64,
+    /// Space the store actually takes up on disk, including filesystem overhead such as
+    /// partially used allocation units and directories. Zero for stores that don't use the disk.
+    pub disk_bytes: u64,
+}
+
+impl StoreStats {
+    fn of_file(metadata: &Metadata) -> Self {
+        StoreStats {
+            blocks: 1,
+            bytes: metadata.len(),
+            disk_bytes: disk_usage(metadata),
+        }
+    }
+}
+
+/// What the index of an [`FSStore`] opened with [`FSStore::with_index`] has on a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub size: u64,
+    /// When the block's file was last modified, or for a packed block, its pack's.
+    pub modified: SystemTime,
+}
+
+#[derive(Default)]
+struct Counters {
+    blocks: AtomicU64,
+    bytes: AtomicU64,
+    disk_bytes: AtomicU64,
+}
+
+impl Counters {
+    fn add(&self, delta: &StoreStats) {
+        self.blocks.fetch_add(delta.blocks, Ordering::Relaxed);
+        self.bytes.fetch_add(delta.bytes, Ordering::Relaxed);
+        self.disk_bytes.fetch_add(delta.disk_bytes, Ordering::Relaxed);
+    }
+
+    fn sub(&self, delta: &StoreStats) {
+        self.blocks.fetch_sub(delta.blocks, Ordering::Relaxed);
+        self.bytes.fetch_sub(delta.bytes, Ordering::Relaxed);
+        self.disk_bytes.fetch_sub(delta.disk_bytes, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> StoreStats {
+        StoreStats {
+            blocks: self.blocks.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            disk_bytes: self.disk_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// A cap on the bytes of blocks in a store, which puts have to fit under.
+struct Quota {
+    // `u64::MAX` for no cap at all.
+    max_bytes: AtomicU64,
+    // Bytes set aside by puts still in flight.
+    reserved: AtomicU64,
+}
+
+impl Quota {
+    fn new(max_bytes: Option<u64>) -> Self {
+        Quota {
+            max_bytes: AtomicU64::new(max_bytes.unwrap_or(u64::MAX)),
+            reserved: AtomicU64::new(0),
+        }
+    }
+
+    fn max_bytes(&self) -> Option<u64> {
+        Some(self.max_bytes.load(Ordering::Relaxed)).filter(|max| *max != u64::MAX)
+    }
+
+    // Sets aside `bytes` on top of the `used` ones, if they fit.
+    fn reserve(self: &Arc<Self>, bytes: u64, used: u64) -> Option<Reservation> {
+        let max_bytes = self.max_bytes.load(Ordering::Relaxed);
+        if max_bytes == u64::MAX {
+            return Some(Reservation(self.clone(), 0));
+        }
+        let reserved = self.reserved.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if used.saturating_add(reserved) > max_bytes {
+            self.reserved.fetch_sub(bytes, Ordering::Relaxed);
+            return None;
+        }
+        Some(Reservation(self.clone(), bytes))
+    }
+}
+
+// Room set aside under a quota, given back once dropped. By then, the put has to have added what
+// it wrote to the counters.
+struct Reservation(Arc<Quota>, u64);
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.0.reserved.fetch_sub(self.1, Ordering::Relaxed);
+    }
+}
+
+/// How hard [`FSStore`] tries to make a put survive a crash or power failure before reporting
+/// success. Stronger policies cost one or two extra `fsync`s per block, which on most disks is
+/// far more expensive than the write itself; `benches/random_rw.rs` has numbers for each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Leave it to the OS to flush data whenever it sees fit.
+    #[default]
+    None,
+    /// Sync the block file's data and metadata before renaming it into place.
+    DataOnly,
+    /// Like [`SyncPolicy::DataOnly`], and also sync the block's directory after the rename so that
+    /// the new directory entry itself is durable.
+    DataAndDir,
+}
+
+/// What [`FSStore::get_block`](Blockstore::get_block) does to make sure the bytes it read are
+/// the block that was asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerifyMode {
+    /// Trust the disk.
+    #[default]
+    Off,
+    /// Re-hash every block read, failing with [`BlockstoreError::Corrupt`] if it doesn't match
+    /// its CID.
+    /// Blocks whose hash function we don't support are passed through unchecked.
+    Verify,
+    /// Like [`VerifyMode::Verify`], and also move the bad file to [`QUARANTINE_DIR`], so it no
+    /// longer counts as stored but is still around for inspection.
+    Quarantine,
+}
+
+/// How [`FSStore::get_block`](Blockstore::get_block) reads block files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadMode {
+    /// Read the whole file into a buffer.
+    #[default]
+    Buffered,
+    /// Map the file into memory, and hand out blocks backed by the mapping, so their contents
+    /// are only paged in from the page cache as they're used. Mostly worth it for large blocks:
+    /// setting up a mapping costs more than reading a small file. Where mmap isn't available,
+    /// this is the same as [`ReadMode::Buffered`].
+    ///
+    /// Block files are never modified in place by the store, but a mapped block whose file gets
+    /// truncated by someone else will crash the process when read past the new end.
+    Mmap,
+}
+
+/// Directory inside an [`FSStore`]'s root where blocks that failed verification are moved to.
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+/// Directory inside an [`FSStore`]'s root that holds its namespaces, one store per subdirectory.
+pub const NAMESPACES_DIR: &str = ".namespaces";
+
+/// Directory inside an [`FSStore`]'s root that holds the pack files [`FSStore::compact`] writes.
+pub const PACKS_DIR: &str = ".packs";
+
+/// Name of the block index kept in the root of an [`FSStore`] opened with
+/// [`FSStore::with_index`].
+pub const INDEX_FILE: &str = ".index";
+
+/// Directory inside an [`FSStore`]'s root that holds the metadata attached to blocks with
+/// [`MetaBlockstore::put_block_with_meta`](crate::meta::MetaBlockstore::put_block_with_meta),
+/// laid out like the blocks themselves.
+pub const META_DIR: &str = ".meta";
+
+/// Directory inside an [`FSStore`]'s root that holds the tags given to blocks with
+/// [`MetaBlockstore::tag`](crate::meta::MetaBlockstore::tag): one subdirectory per tag, with an
+/// empty file named after each block that has it.
+pub const TAGS_DIR: &str = ".tags";
+
+// Suffix of the file beside a block's metadata that lists its tags, so that deleting the block
+// can find them.
+const TAGS_SUFFIX: &str = ".tags";
+
+// Holds a namespace's quota, in bytes, inside the namespace's root.
+const QUOTA_FILE: &str = ".quota";
+
+// The namespaces opened through a store so far, so that every view of one keeps the same
+// accounting, and their writes serialize the way a single store's do.
+type Namespaces = Mutex<HashMap<String, Namespace>>;
+
+#[derive(Clone)]
+struct Namespace {
+    counters: Arc<Counters>,
+    quota: Arc<Quota>,
+    packs: Arc<Packs>,
+    namespaces: Arc<Namespaces>,
+    write_locks: Arc<WriteLocks>,
+    shard_dirs: Arc<ShardDirs>,
+}
+
+
+pub struct FSStore {
+    root: PathBuf,
+    sharding: Arc<dyn ShardingStrategy>,
+    sync_policy: SyncPolicy,
+    verify_mode: VerifyMode,
+    read_mode: ReadMode,
+    temp_dir: Option<PathBuf>,
+    max_block_size: Option<u64>,
+    bloom: Option<Bloom>,
+    index: Option<Arc<BlockIndex>>,
+    journal: Option<Arc<Journal>>,
+    write_locks: Arc<WriteLocks>,
+    shard_dirs: Arc<ShardDirs>,
+    batch_permits: Arc<Semaphore>,
+    file_limit: Option<Arc<FileLimit>>,
+    space_check: SpaceCheck,
+    committer: Option<Committer>,
+    counters: Arc<Counters>,
+    quota: Arc<Quota>,
+    namespaces: Arc<Namespaces>,
+    packs: Arc<Packs>,
+}
+
+// Puts of fewer bytes than this, batches included, go ahead without checking for free space.
+const SPACE_CHECK_BYTES: u64 = 1 << 20;
+
+/// Called by an [`FSStore`] with the free bytes left on its filesystem, when they run low.
+pub type LowSpaceHook = Arc<dyn Fn(u64) + Send + Sync>;
+
+// What puts check free space for, as set with `FSStore::with_free_space_reserve` and
+// `FSStore::with_low_space_hook`.
+#[derive(Clone, Default)]
+struct SpaceCheck {
+    reserve: u64,
+    low_space: Option<(u64, LowSpaceHook)>,
+}
+
+impl SpaceCheck {
+    fn enabled(&self) -> bool {
+        self.reserve > 0 || self.low_space.is_some()
+    }
+
+    // Hands `available` to the hook if it's under the threshold.
+    fn report(&self, available: u64) {
+        if let Some((threshold, hook)) = &self.low_space
+            && available < *threshold
+        {
+            hook(available);
+        }
+    }
+}
+
+// How many blocks the batch methods and prefetching work on at once, across all the batches in
+// flight.
+const BATCH_CONCURRENCY: usize = 32;
+
+// How many locks writes are spread over. Writes to different blocks only contend when they
+// happen to hash to the same one.
+const WRITE_LOCK_STRIPES: usize = 64;
+
+// Serializes puts and deletes of the same block, so that each sees the previous one's outcome:
+// otherwise, two concurrent first puts of a block could both miss the file and count it twice,
+// or a delete could race a put and leave the counters off. Readers don't need these, since
+// they only ever see complete block files anyway.
+struct WriteLocks([Mutex<()>; WRITE_LOCK_STRIPES]);
+
+impl WriteLocks {
+    fn new() -> Self {
+        WriteLocks(std::array::from_fn(|_| Mutex::new(())))
+    }
+
+    // Takes the lock for `cid`, which must already be normalized with `FSStore::key`.
+    fn lock(&self, cid: &Cid) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        Hash::hash(cid, &mut hasher);
+        let stripe = hasher.finish() as usize % WRITE_LOCK_STRIPES;
+        self.0[stripe].lock().unwrap()
+    }
 }
 
-pub struct FSStore {
-    root: PathBuf,
-    chars_per_level: usize,
-}
+// The Bloom filter set up by `FSStore::with_bloom_filter`.
+//
+// Deleted blocks don't come off the filter, since there's no telling whether they were ever
+// counted in it: one written by another instance after the filter was built wasn't, and taking
+// it off anyway would take other blocks with it, making them look missing. Instead, the filter
+// gets rebuilt from the shard tree once it holds as many deleted blocks as half what it's sized
+// for.
+struct Bloom {
+    state: Mutex<BloomState>,
+    expected_items: usize,
+    fp_rate: f64,
+    // Held by whichever rebuild is running, so that there's only ever one.
+    rebuild: tokio::sync::Mutex<()>,
+}
+
+struct BloomState {
+    filter: BloomFilter,
+    // How many blocks `filter` is sized for, and how many of those it has were deleted since.
+    capacity: usize,
+    stale: usize,
+    // While a rebuild scans the shard tree, the filter it's filling in. Puts go into both, so
+    // that the rebuilt filter doesn't miss those the scan did.
+    next: Option<BloomFilter>,
+}
+
+impl Bloom {
+    fn new(filter: BloomFilter, capacity: usize, expected_items: usize, fp_rate: f64) -> Self {
+        Bloom {
+            state: Mutex::new(BloomState {
+                filter,
+                capacity,
+                stale: 0,
+                next: None,
+            }),
+            expected_items,
+            fp_rate,
+            rebuild: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn insert(&self, key: &Cid) {
+        let key = key.to_bytes();
+        let mut state = self.state.lock().unwrap();
+        state.filter.insert(&key);
+        if let Some(next) = &mut state.next {
+            next.insert(&key);
+        }
+    }
+
+    fn contains(&self, key: &Cid) -> bool {
+        self.state.lock().unwrap().filter.contains(&key.to_bytes())
+    }
+
+    fn forget(&self) {
+        self.state.lock().unwrap().stale += 1;
+    }
+
+    fn is_stale(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.stale * 2 > state.capacity
+    }
+}
+
+// Caps the file operations in flight, as set with `FSStore::with_max_open_files`. Every one
+// takes a permit from `ops`, which hands them out first come, first served. Writes take one from
+// `writes` before that, which has fewer: a flood of them can then only ever hold part of `ops`,
+// and queues up outside it, so that reads don't get stuck behind the whole flood.
+struct FileLimit {
+    ops: Arc<Semaphore>,
+    writes: Arc<Semaphore>,
+}
+
+// Room for one file operation under a `FileLimit`, given back when dropped.
+struct FilePermit {
+    _write: Option<OwnedSemaphorePermit>,
+    _op: OwnedSemaphorePermit,
+}
+
+impl FileLimit {
+    fn new(max_open_files: usize) -> Self {
+        assert!(max_open_files > 0, "at least one file has to be allowed open");
+        // A quarter goes to reads alone, unless there's only the one permit.
+        let writes = (max_open_files - max_open_files / 4).max(1);
+        FileLimit {
+            ops: Arc::new(Semaphore::new(max_open_files)),
+            writes: Arc::new(Semaphore::new(writes)),
+        }
+    }
+
+    async fn acquire(&self, write: bool) -> FilePermit {
+        let write = match write {
+            true => Some(self.writes.clone().acquire_owned().await.expect("never closed")),
+            false => None,
+        };
+        FilePermit {
+            _write: write,
+            _op: self.ops.clone().acquire_owned().await.expect("never closed"),
+        }
+    }
+}
+
+impl FSStore {
+    /// Opens the store at `root`, creating the directory if needed. Opening an existing store
+    /// walks it once to seed the counters behind [`Blockstore::stats`]. Existing stores keep the
+    /// [`ShardingStrategy`] they were created with, and Kubo repos' `blocks/` directories are
+    /// recognized by their `SHARDING` file; new stores get the default [`Prefix`] strategy.
+    /// Stores at an older on-disk version have to be upgraded with [`migrate::migrate`] first.
+    pub async fn create(root: PathBuf) -> Result<Self, io::Error> {
+        tokio::fs::create_dir_all(&root).await?;
+        let detect_root = root.clone();
+        let sharding = spawn_blocking(move || sharding::detect(&detect_root)).await??;
+        Self::create_with(root, sharding.unwrap_or_else(|| Arc::new(Prefix::default()))).await
+    }
+
+    /// Like [`FSStore::create`], but with the given sharding strategy. Fails if `root` holds a
+    /// store that was created with a different one.
+    pub async fn create_with_sharding(
+        root: PathBuf,
+        sharding: impl ShardingStrategy + 'static,
+    ) -> Result<Self, io::Error> {
+        Self::create_with(root, Arc::new(sharding)).await
+    }
+
+    async fn create_with(
+        root: PathBuf,
+        sharding: Arc<dyn ShardingStrategy>,
+    ) -> Result<Self, io::Error> {
+        tokio::fs::create_dir_all(&root).await?;
+        let setup_root = root.clone();
+        let setup_sharding = sharding.clone();
+        spawn_blocking(move || {
+            migrate::check_version(&setup_root, false)?;
+            match sharding::read_id(&setup_root)? {
+                Some(existing) if existing != setup_sharding.id() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("store at {:?} is sharded as {:?}", setup_root, existing),
+                    ));
+                }
+                Some(_) => {}
+                None => sharding::write_id(&setup_root, setup_sharding.as_ref())?,
+            }
+            recover_transactions(&setup_root, setup_sharding.as_ref())
+        })
+        .await??;
+        Self::open(root, sharding).await
+    }
+
+    /// Starts configuring a store, for when the defaults [`FSStore::create`] uses won't do.
+    pub fn builder() -> FSStoreBuilder {
+        FSStoreBuilder::default()
+    }
+
+    /// Opens the existing store at `root` for reading only. Unlike [`FSStore::create`], this
+    /// never creates anything, and fails if `root` isn't there.
+    pub async fn open_read_only(root: PathBuf) -> Result<ReadOnlyStore<FSStore>, io::Error> {
+        if !tokio::fs::metadata(&root).await?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        let detect_root = root.clone();
+        let sharding = spawn_blocking(move || {
+            migrate::check_version(&detect_root, true)?;
+            sharding::detect(&detect_root)
+        })
+        .await??;
+        let sharding = sharding.unwrap_or_else(|| Arc::new(Prefix::default()));
+        Ok(ReadOnlyStore::new(Self::open(root, sharding).await?))
+    }
+
+    /// Like [`FSStore::create`], but cleans up after whatever crashed while using the store
+    /// first: the temporary files of writes that never finished are removed, and so are
+    /// namespaces whose deletion got cut short. A store with an index (see
+    /// [`FSStore::with_index`]) gets it checked against the shard tree and rebuilt, and keeps it
+    /// up to date from then on. Nothing else may be using the store in the meantime, since its
+    /// writes in progress would look just like leftovers.
+    pub async fn open_checked(root: PathBuf) -> Result<(Self, Checked), io::Error> {
+        let mut store = Self::create(root.clone()).await?;
+        let cleanup_root = root.clone();
+        let temp_files = spawn_blocking(move || remove_leftovers(&cleanup_root)).await??;
+        let mut checked = Checked {
+            temp_files,
+            reindexed: None,
+        };
+
+        let path = root.join(INDEX_FILE);
+        if tokio::fs::try_exists(&path).await? {
+            let sharding = store.sharding.clone();
+            let packs = store.packs.clone();
+            let (index, reindexed) = spawn_blocking(move || {
+                let indexed = BlockIndex::load(&path)?.unwrap_or_default();
+                let found = scan_index(&root, sharding.as_ref(), &packs)?;
+                let size = |entry: &IndexEntry| entry.size;
+                let reindexed = indexed.keys().filter(|key| !found.contains_key(key)).count()
+                    + found
+                        .iter()
+                        .filter(|(key, entry)| indexed.get(key).map(size) != Some(entry.size))
+                        .count();
+                Ok::<_, io::Error>((BlockIndex::create(path, found)?, reindexed as u64))
+            })
+            .await??;
+            store.index = Some(Arc::new(index));
+            checked.reindexed = Some(reindexed);
+        }
+        Ok((store, checked))
+    }
+
+    async fn open(root: PathBuf, sharding: Arc<dyn ShardingStrategy>) -> Result<Self, io::Error> {
+        let measure_root = root.clone();
+        let measure_sharding = sharding.clone();
+        let counters = Counters::default();
+        let (stats, (packs, packed)) = spawn_blocking(move || {
+            let stats = measure(&measure_root, Path::new(""), measure_sharding.as_ref())?;
+            Ok::<_, io::Error>((stats, Packs::open(measure_root.join(PACKS_DIR))?))
+        })
+        .await??;
+        counters.add(&stats);
+        counters.add(&packed);
+
+        Ok(FSStore {
+            root,
+            sharding,
+            sync_policy: SyncPolicy::default(),
+            verify_mode: VerifyMode::default(),
+            read_mode: ReadMode::default(),
+            temp_dir: None,
+            max_block_size: None,
+            bloom: None,
+            index: None,
+            journal: None,
+            write_locks: Arc::new(WriteLocks::new()),
+            shard_dirs: Arc::new(ShardDirs::new()),
+            batch_permits: Arc::new(Semaphore::new(BATCH_CONCURRENCY)),
+            file_limit: None,
+            space_check: SpaceCheck::default(),
+            committer: None,
+            counters: Arc::new(counters),
+            quota: Arc::new(Quota::new(None)),
+            namespaces: Arc::default(),
+            packs: Arc::new(packs),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn sharding(&self) -> &dyn ShardingStrategy {
+        self.sharding.as_ref()
+    }
+
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn with_verify_mode(mut self, verify_mode: VerifyMode) -> Self {
+        self.verify_mode = verify_mode;
+        self
+    }
+
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    pub fn verify_mode(&self) -> VerifyMode {
+        self.verify_mode
+    }
+
+    pub fn with_read_mode(mut self, read_mode: ReadMode) -> Self {
+        self.read_mode = read_mode;
+        self
+    }
+
+    /// Keeps `reserve` bytes free on the store's filesystem: puts of 1 MiB or more, batches
+    /// counted as a whole, first check the free space, and fail with
+    /// [`BlockstoreError::OutOfSpace`] if they'd leave less than that. Every put that runs out of
+    /// space midway fails with it too, with or without a reserve.
+    pub fn with_free_space_reserve(mut self, reserve: u64) -> Self {
+        self.space_check.reserve = reserve;
+        self
+    }
+
+    /// Calls `hook` with the free bytes left whenever a put finds fewer than `threshold`, for
+    /// getting space back, with a GC or eviction, before puts start failing. Puts of 1 MiB or
+    /// more check before writing, and any put that runs out of space midway checks afterwards.
+    /// The hook runs in the put, so anything slow should be handed off to a task of its own.
+    pub fn with_low_space_hook(
+        mut self,
+        threshold: u64,
+        hook: impl Fn(u64) + Send + Sync + 'static,
+    ) -> Self {
+        self.space_check.low_space = Some((threshold, Arc::new(hook)));
+        self
+    }
+
+    /// Caps the file operations this store and its namespaces have in flight at
+    /// `max_open_files`, for keeping heavy concurrency from running out of file descriptors.
+    /// Operations over the cap wait their turn, which they get in the order they came in, except
+    /// that puts and deletes can only ever take three quarters of the room: reads keep getting
+    /// through however many writes are queued up. Panics if `max_open_files` is zero.
+    pub fn with_max_open_files(mut self, max_open_files: usize) -> Self {
+        self.file_limit = Some(Arc::new(FileLimit::new(max_open_files)));
+        self
+    }
+
+    /// Makes puts that sync, as set by the [`SyncPolicy`], share their syncs. Each put writes its
+    /// temporary file right away, but leaves syncing it and renaming it into place to a
+    /// committer thread, which does that for a whole batch of puts at once before any of them
+    /// returns. On Linux, a batch takes one `syncfs`, or two with [`SyncPolicy::DataAndDir`].
+    /// The thread stops when the store is dropped.
+    pub fn with_group_commit(mut self, group_commit: GroupCommit) -> Self {
+        self.committer = Some(Committer::start(
+            self.root.clone(),
+            group_commit,
+            self.write_locks.clone(),
+            self.shard_dirs.clone(),
+        ));
+        self
+    }
+
+    /// Puts an in-memory Bloom filter in front of `has_block`, so lookups for blocks we don't
+    /// have are answered without touching the disk. The filter is populated by scanning the
+    /// store, and is then kept up to date by puts made through this instance; it is sized for
+    /// whichever is larger of `expected_items` and the blocks already in the store.
+    ///
+    /// Deleted blocks stay in the filter, costing a trip to the disk to find missing, until it
+    /// gets rebuilt. That happens by itself once deletes have piled up, or when asked to with
+    /// [`FSStore::rebuild_bloom_filter`].
+    pub async fn with_bloom_filter(
+        mut self,
+        expected_items: usize,
+        fp_rate: f64,
+    ) -> Result<Self, io::Error> {
+        let root = self.root.clone();
+        let sharding = self.sharding.clone();
+        let mut cids = spawn_blocking(move || scan(&root, sharding.as_ref())).await??;
+        cids.extend(self.packs.keys());
+        let capacity = expected_items.max(cids.len());
+        let mut filter = BloomFilter::new(capacity, fp_rate);
+        for cid in &cids {
+            filter.insert(&cid.to_bytes());
+        }
+
+        self.bloom = Some(Bloom::new(filter, capacity, expected_items, fp_rate));
+        Ok(self)
+    }
+
+    /// Rebuilds the Bloom filter kept since [`FSStore::with_bloom_filter`] from the shard tree,
+    /// dropping the blocks deleted since it was built, and taking in those written by other
+    /// instances. Puts and deletes can go on in the meantime. Stores without a filter are left
+    /// alone.
+    pub async fn rebuild_bloom_filter(&self) -> Result<(), io::Error> {
+        let Some(bloom) = &self.bloom else {
+            return Ok(());
+        };
+        let _rebuild = bloom.rebuild.lock().await;
+        self.refill_bloom(bloom).await
+    }
+
+    // Rebuilds the Bloom filter if deletes have left it stale enough, and no other rebuild is
+    // running. A rebuild that fails leaves the filter as it was, to be tried again next time.
+    async fn settle_bloom(&self) {
+        if let Some(bloom) = &self.bloom
+            && bloom.is_stale()
+            && let Ok(_rebuild) = bloom.rebuild.try_lock()
+        {
+            let _ = self.refill_bloom(bloom).await;
+        }
+    }
+
+    // The part of a rebuild done while holding `bloom.rebuild`.
+    async fn refill_bloom(&self, bloom: &Bloom) -> Result<(), io::Error> {
+        let capacity = bloom
+            .expected_items
+            .max(self.counters.snapshot().blocks as usize);
+        let stale = {
+            let mut state = bloom.state.lock().unwrap();
+            state.next = Some(BloomFilter::new(capacity, bloom.fp_rate));
+            state.stale
+        };
+
+        let root = self.root.clone();
+        let sharding = self.sharding.clone();
+        let scanned = spawn_blocking(move || scan(&root, sharding.as_ref())).await;
+        let packed = self.packs.keys();
+
+        let mut state = bloom.state.lock().unwrap();
+        let mut next = state.next.take().unwrap();
+        for cid in scanned??.iter().chain(&packed) {
+            next.insert(&cid.to_bytes());
+        }
+        // Blocks deleted during the scan may or may not have been found by it.
+        state.filter = next;
+        state.capacity = capacity;
+        state.stale -= stale;
+        Ok(())
+    }
+
+    /// Keeps an index of the blocks in [`INDEX_FILE`], which [`Blockstore::has_block`],
+    /// [`Blockstore::block_size`] and [`Blockstore::blocks`] then answer from without going to
+    /// the shard tree. An index left by an earlier instance gets loaded if it still adds up to the
+    /// store's stats, and is rebuilt otherwise. From then on, it's kept up to date by puts and
+    /// deletes made through this instance; anything else that changes the tree leaves it stale
+    /// until [`FSStore::rebuild_index`].
+    pub async fn with_index(mut self) -> Result<Self, io::Error> {
+        let path = self.root.join(INDEX_FILE);
+        let stats = self.counters.snapshot();
+        let root = self.root.clone();
+        let sharding = self.sharding.clone();
+        let packs = self.packs.clone();
+        let index = spawn_blocking(move || {
+            let entries = match BlockIndex::load(&path)? {
+                Some(entries) if index::totals(&entries) == (stats.blocks, stats.bytes) => entries,
+                _ => scan_index(&root, sharding.as_ref(), &packs)?,
+            };
+            BlockIndex::create(path, entries)
+        })
+        .await??;
+
+        self.index = Some(Arc::new(index));
+        Ok(self)
+    }
+
+    /// Rebuilds the index kept since [`FSStore::with_index`] from the shard tree, for when it's
+    /// gone stale. Puts and deletes can go on in the meantime. Stores without an index are left
+    /// alone.
+    pub async fn rebuild_index(&self) -> Result<(), io::Error> {
+        let Some(index) = self.index.clone() else {
+            return Ok(());
+        };
+        let root = self.root.clone();
+        let sharding = self.sharding.clone();
+        let packs = self.packs.clone();
+        spawn_blocking(move || index.rebuild(|| scan_index(&root, sharding.as_ref(), &packs)))
+            .await?
+    }
+
+    /// What the index kept since [`FSStore::with_index`] has on block `cid`. Always `None` for
+    /// stores without an index.
+    pub fn indexed(&self, cid: &Cid) -> Option<IndexEntry> {
+        self.index.as_ref()?.get(&self.key(cid))
+    }
+
+    /// Makes puts and deletes record their intent in [`JOURNAL_FILE`] before touching the shard
+    /// tree. First, though, this replays whatever the journal says was in flight when the store
+    /// last went down: interrupted deletes are finished, and the temporary files of interrupted
+    /// puts are removed. Intents are synced to disk unless the [`SyncPolicy`] is `None`.
+    pub async fn with_journal(mut self) -> Result<Self, io::Error> {
+        let root = self.root.clone();
+        let sharding = self.sharding.clone();
+        let temp_dir = self.temp_dir.clone();
+        let (deleted, file) = spawn_blocking(move || {
+            let path = root.join(JOURNAL_FILE);
+            let deleted = recover(&root, sharding.as_ref(), temp_dir.as_deref(), &path)?;
+            // Everything in the journal is dealt with now, so we start over with an empty one.
+            let file = File::create(&path)?;
+            file.sync_all()?;
+            Ok::<_, io::Error>((deleted, file))
+        })
+        .await??;
+
+        for (_, metadata) in &deleted {
+            self.counters.sub(&StoreStats::of_file(metadata));
+            if let Some(bloom) = &self.bloom {
+                bloom.forget();
+            }
+        }
+        let deleted: Vec<Cid> = deleted.into_iter().map(|(cid, _)| cid).collect();
+        self.reindex(&deleted).await?;
+
+        self.journal = Some(Arc::new(Journal {
+            file: Mutex::new(file),
+            next_seq: AtomicU64::new(0),
+        }));
+        Ok(self)
+    }
+
+    /// Opens the namespace `name`, creating it if needed. A namespace is a store of its own,
+    /// nested under [`NAMESPACES_DIR`]: its blocks, listing and stats are separate from this
+    /// store's and from other namespaces'. It starts out with this store's sharding, sync policy,
+    /// verify and read modes, temporary directory and block size limit, as well as the quota set
+    /// with [`FSStore::set_namespace_quota`]. Names are made of ASCII letters, digits, `-` and `_`.
+    ///
+    /// Every store opened on the same namespace through this store, or through other stores
+    /// opened on this one, shares its stats and quota, and their puts and deletes of the same
+    /// block don't race.
+    pub async fn namespace(&self, name: &str) -> Result<FSStore, io::Error> {
+        let root = self.namespace_root(name)?;
+        let cached = self.namespaces.lock().unwrap().get(name).cloned();
+        let namespace = match cached {
+            // Already set up and measured by whoever opened it first.
+            Some(namespace) => namespace,
+            None => {
+                let store = Self::create_with(root.clone(), self.sharding.clone()).await?;
+                let quota_root = root.clone();
+                let max_bytes = spawn_blocking(move || read_quota(&quota_root)).await??;
+                let namespace = Namespace {
+                    counters: store.counters.clone(),
+                    quota: Arc::new(Quota::new(max_bytes)),
+                    namespaces: store.namespaces.clone(),
+                    packs: store.packs.clone(),
+                    write_locks: store.write_locks.clone(),
+                    shard_dirs: store.shard_dirs.clone(),
+                };
+                // Someone else may have opened it in the meantime, in which case theirs wins.
+                let mut namespaces = self.namespaces.lock().unwrap();
+                namespaces.entry(name.to_string()).or_insert(namespace).clone()
+            }
+        };
+        Ok(self.namespace_view(root, namespace))
+    }
+
+    // A store on the namespace at `root`, sharing what every store on it has to, and otherwise
+    // set up like this one.
+    fn namespace_view(&self, root: PathBuf, namespace: Namespace) -> FSStore {
+        FSStore {
+            root,
+            sharding: self.sharding.clone(),
+            sync_policy: self.sync_policy,
+            verify_mode: self.verify_mode,
+            read_mode: self.read_mode,
+            temp_dir: self.temp_dir.clone(),
+            max_block_size: self.max_block_size,
+            bloom: None,
+            index: None,
+            journal: None,
+            write_locks: namespace.write_locks,
+            shard_dirs: namespace.shard_dirs,
+            batch_permits: Arc::new(Semaphore::new(BATCH_CONCURRENCY)),
+            file_limit: self.file_limit.clone(),
+            space_check: self.space_check.clone(),
+            committer: None,
+            counters: namespace.counters,
+            quota: namespace.quota,
+            namespaces: namespace.namespaces,
+            packs: namespace.packs,
+        }
+    }
+
+    /// The names of the namespaces in this store, in no particular order.
+    pub async fn namespaces(&self) -> Result<Vec<String>, io::Error> {
+        let dir = self.root.join(NAMESPACES_DIR);
+        spawn_blocking(move || {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            };
+            let mut names = Vec::new();
+            for entry in entries {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                if valid_namespace(&name) {
+                    names.push(name);
+                }
+            }
+            Ok(names)
+        })
+        .await?
+    }
+
+    /// Deletes the namespace `name` with all of its blocks. It's moved out of the way first, so
+    /// opening `name` again right after gets a fresh, empty namespace, but stores already opened
+    /// on it are left pointing at nothing and shouldn't be used anymore.
+    pub async fn drop_namespace(&self, name: &str) -> Result<(), io::Error> {
+        let root = self.namespace_root(name)?;
+        self.namespaces.lock().unwrap().remove(name);
+        spawn_blocking(move || {
+            let doomed = root.with_file_name(format!(
+                "{}{}-{:016x}",
+                TEMP_PREFIX,
+                root.file_name().unwrap().to_string_lossy(),
+                rand::random::<u64>()
+            ));
+            fs::rename(&root, &doomed)?;
+            fs::remove_dir_all(&doomed)
+        })
+        .await?
+    }
+
+    /// Caps the bytes of blocks in the namespace `name`, creating it if needed, or lifts the cap
+    /// with `None`. Puts that would take the namespace over its quota fail with
+    /// [`BlockstoreError::QuotaExceeded`], though putting blocks it already has always succeeds.
+    /// Lowering the quota below what the namespace holds deletes nothing, but stops it from
+    /// growing. The quota is kept with the namespace, and applies to every store on it right away.
+    pub async fn set_namespace_quota(
+        &self,
+        name: &str,
+        max_bytes: Option<u64>,
+    ) -> Result<(), io::Error> {
+        let namespace = self.namespace(name).await?;
+        let root = namespace.root.clone();
+        spawn_blocking(move || {
+            let path = root.join(QUOTA_FILE);
+            match max_bytes {
+                Some(max_bytes) => write_block_file(
+                    &path,
+                    max_bytes.to_string().as_bytes(),
+                    SyncPolicy::DataAndDir,
+                    None,
+                ),
+                None => match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                },
+            }
+        })
+        .await??;
+        namespace.quota.max_bytes.store(max_bytes.unwrap_or(u64::MAX), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// What the namespace `name` holds, as its [`Blockstore::stats`] would say. Fails with
+    /// [`io::ErrorKind::NotFound`] if there's no such namespace.
+    pub async fn usage(&self, name: &str) -> Result<StoreStats, io::Error> {
+        let root = self.namespace_root(name)?;
+        if !tokio::fs::try_exists(&root).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no namespace {:?}", name),
+            ));
+        }
+        Ok(self.namespace(name).await?.counters.snapshot())
+    }
+
+    /// The quota puts into this store have to fit under, if it's a namespace with one.
+    pub fn quota(&self) -> Option<u64> {
+        self.quota.max_bytes()
+    }
+
+    /// Moves the blocks smaller than `max_size` bytes out of their own files and into pack files
+    /// under [`PACKS_DIR`], so that they stop taking up a filesystem block each, while bigger
+    /// blocks stay as they are. Packed blocks are read, listed and deleted like any other, though
+    /// reads always map their pack, whatever the [`ReadMode`]. A deleted block keeps taking up
+    /// room in its pack until a later compaction finds at least half of the pack deleted, and
+    /// rewrites it with what's left.
+    ///
+    /// The store stays usable while this runs, and only one compaction runs at a time. One that
+    /// gets interrupted leaves the store as it was, except that some blocks may be both packed
+    /// and not, and counted twice in the stats, until the next compaction.
+    pub async fn compact(&self, max_size: u64) -> Result<Compaction, io::Error> {
+        let root = self.root.clone();
+        let sharding = self.sharding.clone();
+        let packs = self.packs.clone();
+        let write_locks = self.write_locks.clone();
+        let shard_dirs = self.shard_dirs.clone();
+        let counters = self.counters.clone();
+        spawn_blocking(move || {
+            compact(
+                &root,
+                sharding.as_ref(),
+                max_size,
+                &packs,
+                &write_locks,
+                &shard_dirs,
+                &counters,
+            )
+        })
+        .await?
+    }
+
+    // The data of block `cid`, if it's packed.
+    pub(crate) fn packed_block(&self, cid: &Cid) -> Option<Bytes> {
+        self.packs.read(&self.key(cid))
+    }
+
+    fn namespace_root(&self, name: &str) -> Result<PathBuf, io::Error> {
+        if !valid_namespace(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad namespace name {:?}", name),
+            ));
+        }
+        Ok(self.root.join(NAMESPACES_DIR).join(name))
+    }
+
+    pub fn block_path_raw(chars_per_level: usize, cid: &Cid) -> PathBuf {
+        // This is a bit ugly but chunks only works on slices and I was feeling lazy. :-)
+        let parts: Vec<String> = format!("{}", cid)
+            .as_bytes()
+            .chunks(chars_per_level)
+            .map(|chunk| str::from_utf8(chunk).unwrap().to_string())
+            .collect();
+
+        parts.iter().collect()
+    }
+
+    /// Where the block `cid` lives, as decided by the store's [`ShardingStrategy`].
+    pub fn block_path(&self, cid: &Cid) -> PathBuf {
+        self.root.join(self.sharding.block_path(cid))
+    }
+
+    // Where the metadata of block `cid` lives, if it has any.
+    fn meta_path(&self, cid: &Cid) -> PathBuf {
+        self.root.join(META_DIR).join(self.sharding.block_path(cid))
+    }
+
+    // Where the list of block `cid`'s tags lives, if it has any.
+    fn tags_path(&self, cid: &Cid) -> PathBuf {
+        let mut path = self.meta_path(cid).into_os_string();
+        path.push(TAGS_SUFFIX);
+        path.into()
+    }
+
+    // Fails for blocks bigger than the configured maximum.
+    fn check_size(&self, block: &Block) -> Result<(), BlockstoreError> {
+        match self.max_block_size {
+            Some(max_size) if block.data.len() as u64 > max_size => {
+                Err(BlockstoreError::BlockTooLarge {
+                    cid: block.cid,
+                    size: block.data.len() as u64,
+                    max_size,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Before a put of `bytes`, fails it if it would eat into the free space reserve.
+    async fn check_space(&self, bytes: u64) -> Result<(), BlockstoreError> {
+        if bytes < SPACE_CHECK_BYTES || !self.space_check.enabled() {
+            return Ok(());
+        }
+        let root = self.root.clone();
+        let available = spawn_blocking(move || free_space(&root)).await??;
+        self.space_check.report(available);
+        let needed = bytes.saturating_add(self.space_check.reserve);
+        match available < needed {
+            true => Err(BlockstoreError::OutOfSpace { needed, available }),
+            false => Ok(()),
+        }
+    }
+
+    // Turns the error of a put of `bytes` into an `OutOfSpace` if the filesystem filled up.
+    fn out_of_space(&self, e: impl Into<BlockstoreError>, bytes: u64) -> BlockstoreError {
+        let e = e.into();
+        let full = matches!(
+            e.kind(),
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+        );
+        if !full || !matches!(e, BlockstoreError::Backend(_)) {
+            return e;
+        }
+        // Only on the way out of a failed put, so it's not worth a trip to the blocking pool.
+        let available = free_space(&self.root).unwrap_or(0);
+        self.space_check.report(available);
+        BlockstoreError::OutOfSpace {
+            needed: bytes.saturating_add(self.space_check.reserve),
+            available,
+        }
+    }
+
+    // What the block `cid` is keyed by in the Bloom filter, locks, and so on.
+    fn key(&self, cid: &Cid) -> Cid {
+        self.sharding.normalize(cid)
+    }
+}
+
+/// Configuration for an [`FSStore`], as started by [`FSStore::builder`]. Anything left unset
+/// gets the same default as with [`FSStore::create`].
+#[derive(Debug, Default)]
+pub struct FSStoreBuilder {
+    sharding: Option<Arc<dyn ShardingStrategy>>,
+    sync_policy: SyncPolicy,
+    verify_mode: VerifyMode,
+    read_mode: ReadMode,
+    temp_dir: Option<PathBuf>,
+    max_block_size: Option<u64>,
+    group_commit: Option<GroupCommit>,
+    max_open_files: Option<usize>,
+    free_space_reserve: u64,
+}
+
+impl FSStoreBuilder {
+    /// Shards blocks with the [`Prefix`] strategy, cutting CIDs into `chars_per_level`
+    /// characters per directory.
+    pub fn chars_per_level(self, chars_per_level: usize) -> Self {
+        self.sharding(Prefix { chars_per_level })
+    }
+
+    /// Shards blocks with `sharding`. As with [`FSStore::create_with_sharding`], opening a store
+    /// created with a different strategy fails.
+    pub fn sharding(mut self, sharding: impl ShardingStrategy + 'static) -> Self {
+        self.sharding = Some(Arc::new(sharding));
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn verify_mode(mut self, verify_mode: VerifyMode) -> Self {
+        self.verify_mode = verify_mode;
+        self
+    }
+
+    pub fn read_mode(mut self, read_mode: ReadMode) -> Self {
+        self.read_mode = read_mode;
+        self
+    }
+
+    /// Writes blocks into temporary files in `temp_dir` instead of next to where they end up.
+    /// Blocks get renamed into place from there, so `temp_dir` has to be on the same filesystem
+    /// as the store. It's created if needed; inside the root, its name should start with a dot.
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = Some(temp_dir.into());
+        self
+    }
+
+    /// Refuses puts of blocks bigger than `max_block_size` bytes with
+    /// [`io::ErrorKind::InvalidInput`].
+    pub fn max_block_size(mut self, max_block_size: u64) -> Self {
+        self.max_block_size = Some(max_block_size);
+        self
+    }
+
+    /// Batches up syncing puts, as with [`FSStore::with_group_commit`].
+    pub fn group_commit(mut self, group_commit: GroupCommit) -> Self {
+        self.group_commit = Some(group_commit);
+        self
+    }
+
+    /// Keeps room free on the filesystem, as with [`FSStore::with_free_space_reserve`].
+    pub fn free_space_reserve(mut self, reserve: u64) -> Self {
+        self.free_space_reserve = reserve;
+        self
+    }
+
+    /// Caps the file operations in flight, as with [`FSStore::with_max_open_files`].
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = Some(max_open_files);
+        self
+    }
+
+    /// Opens the store at `root` with this configuration, creating it if needed.
+    pub async fn open(self, root: PathBuf) -> Result<FSStore, io::Error> {
+        let mut store = match self.sharding {
+            Some(sharding) => FSStore::create_with(root, sharding).await?,
+            None => FSStore::create(root).await?,
+        };
+        if let Some(temp_dir) = &self.temp_dir {
+            tokio::fs::create_dir_all(temp_dir).await?;
+        }
+
+        store.sync_policy = self.sync_policy;
+        store.verify_mode = self.verify_mode;
+        store.read_mode = self.read_mode;
+        store.temp_dir = self.temp_dir;
+        store.max_block_size = self.max_block_size;
+        store.space_check.reserve = self.free_space_reserve;
+        if let Some(group_commit) = self.group_commit {
+            store = store.with_group_commit(group_commit);
+        }
+        if let Some(max_open_files) = self.max_open_files {
+            store = store.with_max_open_files(max_open_files);
+        }
+        Ok(store)
+    }
+}
+
+/// What [`FSStore::open_checked`] found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Checked {
+    /// The leftovers of interrupted writes and namespace deletions, which were removed.
+    pub temp_files: Vec<PathBuf>,
+    /// How many blocks the index had wrong, by either missing them, listing them though they
+    /// were gone, or having the wrong size for them. `None` if the store has no index.
+    pub reindexed: Option<u64>,
+}
+
+/// What [`FSStore::compact`] got done.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Compaction {
+    /// How many blocks were moved out of their own files and into packs.
+    pub packed: u64,
+    /// How many packs were rewritten without the blocks deleted from them.
+    pub repacked: u64,
+}
+
+// The blocking part of `FSStore::compact`. Blocks are copied into the new packs first, then
+// those are swapped in for the packs they replace, and only then are the loose copies removed.
+fn compact(
+    root: &Path,
+    sharding: &dyn ShardingStrategy,
+    max_size: u64,
+    packs: &Packs,
+    write_locks: &WriteLocks,
+    shard_dirs: &ShardDirs,
+    counters: &Counters,
+) -> Result<Compaction, io::Error> {
+    let mut packer = packs.packer()?;
+    let mut loose = Vec::new();
+    let mut error = None;
+    walk(root, Path::new(""), sharding, &mut |item| match item {
+        Ok(cid) => {
+            loose.push(cid);
+            true
+        }
+        // A shard directory that deletes pruned since it was listed.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+        Err(e) => {
+            error = Some(e);
+            false
+        }
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    for cid in loose {
+        let key = sharding.normalize(&cid);
+        let block_path = root.join(sharding.block_path(&key));
+        let _lock = write_locks.lock(&key);
+        let metadata = match fs::metadata(&block_path) {
+            Ok(metadata) => metadata,
+            // Deleted since the scan.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if packs.contains(&key) {
+            // Left behind by a compaction that got interrupted before it was done with the block.
+            counters.sub(&remove_loose(root, &block_path, &metadata, shard_dirs)?);
+        } else if metadata.len() < max_size {
+            packer.add(&key, &fs::read(&block_path)?, None)?;
+        }
+    }
+
+    let sparse = packs.sparse();
+    for &pack in &sparse {
+        for (key, data) in packs.live_blocks(pack) {
+            let _lock = write_locks.lock(&key);
+            packer.add(&key, &data, Some(pack))?;
+        }
+    }
+
+    let installed = packer.install(&sparse)?;
+    counters.add(&StoreStats {
+        disk_bytes: installed.added_bytes,
+        ..StoreStats::default()
+    });
+    counters.sub(&StoreStats {
+        disk_bytes: installed.removed_bytes,
+        ..StoreStats::default()
+    });
+
+    let mut compaction = Compaction {
+        packed: 0,
+        repacked: sparse.len() as u64,
+    };
+    for (key, location) in installed.loose {
+        let block_path = root.join(sharding.block_path(&key));
+        let _lock = write_locks.lock(&key);
+        let metadata = match fs::metadata(&block_path) {
+            Ok(metadata) => metadata,
+            // Quarantined since it was copied, which only takes the file.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                packs.delete(&key, true)?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        // Deleted since it was copied.
+        if !packs.install_loose(&key, location) {
+            continue;
+        }
+        // The block is still there, only packed now, so all that goes is its file.
+        let removed = remove_loose(root, &block_path, &metadata, shard_dirs)?;
+        counters.sub(&StoreStats {
+            disk_bytes: removed.disk_bytes,
+            ..StoreStats::default()
+        });
+        compaction.packed += 1;
+    }
+    Ok(compaction)
+}
+
+// Removes the file of a block that's packed too, returning how that changes the store's stats.
+fn remove_loose(
+    root: &Path,
+    block_path: &Path,
+    metadata: &Metadata,
+    shard_dirs: &ShardDirs,
+) -> Result<StoreStats, io::Error> {
+    fs::remove_file(block_path)?;
+    let mut delta = StoreStats::of_file(metadata);
+    delta.disk_bytes += shard_dirs.prune(root, block_path.parent().unwrap());
+    Ok(delta)
+}
+
+fn read_quota(root: &Path) -> Result<Option<u64>, io::Error> {
+    match fs::read_to_string(root.join(QUOTA_FILE)) {
+        Ok(contents) => contents.trim().parse().map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad quota in {:?}: {}", root, e),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn valid_namespace(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+// Lists every block under the root, failing on the first error.
+fn scan(root: &Path, sharding: &dyn ShardingStrategy) -> Result<Vec<Cid>, io::Error> {
+    let mut cids = Vec::new();
+    let mut error = None;
+    walk(root, Path::new(""), sharding, &mut |item| match item {
+        Ok(cid) => {
+            cids.push(cid);
+            true
+        }
+        Err(e) => {
+            error = Some(e);
+            false
+        }
+    });
+
+    error.map_or(Ok(cids), Err)
+}
+
+// Finds every block in the store for its index. Blocks deleted while we're at it get skipped.
+fn scan_index(
+    root: &Path,
+    sharding: &dyn ShardingStrategy,
+    packs: &Packs,
+) -> Result<Entries, io::Error> {
+    let mut entries = HashMap::new();
+    let mut error = None;
+    walk(root, Path::new(""), sharding, &mut |item| {
+        let indexed = item.and_then(|cid| {
+            let key = sharding.normalize(&cid);
+            if let Some(entry) = index_entry(&key, &root.join(sharding.block_path(&cid)), packs)? {
+                entries.insert(key, entry);
+            }
+            Ok(())
+        });
+        match indexed {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                error = Some(e);
+                false
+            }
+            _ => true,
+        }
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    for key in packs.keys() {
+        if let Some(size) = packs.size(&key) {
+            entries.entry(key).or_insert(IndexEntry {
+                size,
+                modified: packs.modified(&key).unwrap_or(UNIX_EPOCH),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+// What the index should have on block `key`, whose file would be at `block_path`.
+fn index_entry(
+    key: &Cid,
+    block_path: &Path,
+    packs: &Packs,
+) -> Result<Option<IndexEntry>, io::Error> {
+    match fs::metadata(block_path) {
+        Ok(metadata) => Ok(Some(IndexEntry {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(packs.size(key).map(|size| IndexEntry {
+            size,
+            modified: packs.modified(key).unwrap_or(UNIX_EPOCH),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// Walks the shard tree under `dir`, relative to `root`, reconstructing a CID from the path of
+// every block file and handing it to `visit`. Errors get handed over too instead of aborting the
+// walk. `visit` returns false to stop early, in which case we return false as well.
+fn walk(
+    root: &Path,
+    dir: &Path,
+    sharding: &dyn ShardingStrategy,
+    visit: &mut dyn FnMut(Result<Cid, io::Error>) -> bool,
+) -> bool {
+    let entries = match fs::read_dir(root.join(dir)) {
+        Ok(entries) => entries,
+        Err(e) => return visit(Err(e)),
+    };
+
+    for entry in entries {
+        let keep_going = match entry {
+            Ok(entry) => walk_entry(&entry, root, dir, sharding, visit),
+            Err(e) => visit(Err(e)),
+        };
+        if !keep_going {
+            return false;
+        }
+    }
+    true
+}
+
+fn walk_entry(
+    entry: &DirEntry,
+    root: &Path,
+    dir: &Path,
+    sharding: &dyn ShardingStrategy,
+    visit: &mut dyn FnMut(Result<Cid, io::Error>) -> bool,
+) -> bool {
+    let path = entry.path();
+    let name = entry.file_name();
+    let Some(name) = name.to_str() else {
+        return visit(Err(unexpected_entry(&path, "name is not valid UTF-8")));
+    };
+
+    // Dot-prefixed entries hold store metadata or temporary files, never blocks.
+    if name.starts_with('.') {
+        return true;
+    }
+
+    let relative = dir.join(name);
+    match entry.file_type() {
+        Ok(file_type) if file_type.is_dir() => walk(root, &relative, sharding, visit),
+        Ok(_) => match sharding.cid_from_path(&relative) {
+            Some(cid) => visit(cid.map_err(|e| unexpected_entry(&path, e))),
+            None => true,
+        },
+        Err(e) => visit(Err(e)),
+    }
+}
+
+// Adds up the stats for the tree under `dir`, relative to `root`, which is counted too. Files
+// that aren't blocks only count towards disk usage.
+fn measure(root: &Path, dir: &Path, sharding: &dyn ShardingStrategy) -> Result<StoreStats, io::Error> {
+    let mut stats = StoreStats {
+        disk_bytes: disk_usage(&fs::metadata(root.join(dir))?),
+        ..StoreStats::default()
+    };
+
+    for entry in fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let relative = dir.join(entry.file_name());
+        let entry_stats = if metadata.is_dir() {
+            measure(root, &relative, sharding)?
+        } else if sharding.cid_from_path(&relative).is_some() {
+            StoreStats::of_file(&metadata)
+        } else {
+            StoreStats {
+                disk_bytes: disk_usage(&metadata),
+                ..StoreStats::default()
+            }
+        };
+
+        stats.blocks += entry_stats.blocks;
+        stats.bytes += entry_stats.bytes;
+        stats.disk_bytes += entry_stats.disk_bytes;
+    }
+
+    Ok(stats)
+}
+
+#[cfg(unix)]
+pub(crate) fn disk_usage(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    // st_blocks is always in 512-byte units, regardless of the filesystem's block size.
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+pub(crate) fn disk_usage(metadata: &Metadata) -> u64 {
+    metadata.len()
+}
+
+// The bytes free for us on the filesystem `path` is on.
+#[cfg(unix)]
+fn free_space(path: &Path) -> Result<u64, io::Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: statvfs only writes to the struct we hand it, which it fills in on success.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field types vary across platforms, and are already `u64` on some.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// Without statvfs, there's no telling, and puts just go ahead.
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Result<u64, io::Error> {
+    Ok(u64::MAX)
+}
+
+// Gets the OS to start reading the file at `path` into its page cache, without waiting for it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn warm_file(path: &Path) -> Result<(), io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let file = File::open(path)?;
+    // SAFETY: the advice only concerns a file we hold open, and doesn't touch our memory.
+    let err = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+    Ok(())
+}
+
+// Without `posix_fadvise`, reading the file is what gets it cached.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn warm_file(path: &Path) -> Result<(), io::Error> {
+    fs::read(path).map(drop)
+}
+
+fn unexpected_entry(path: &Path, reason: impl Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected entry {:?} in store: {}", path, reason),
+    )
+}
+
+// Creates shard directories as blocks need them, and prunes them again once deletes leave them
+// empty. Both happen under one lock, so a directory's disk usage is counted exactly once when
+// it's created and taken back exactly once when it's pruned. A put can still find the directory
+// it just created gone by the time it writes into it, which `write_into` takes care of.
+struct ShardDirs(Mutex<()>);
+
+impl ShardDirs {
+    fn new() -> Self {
+        ShardDirs(Mutex::new(()))
+    }
+
+    // Creates `dir` and whatever parents it's missing, returning the disk usage of the
+    // directories this call created.
+    fn create(&self, dir: &Path) -> Result<u64, io::Error> {
+        if dir.is_dir() {
+            return Ok(0);
+        }
+
+        let _lock = self.0.lock().unwrap();
+        let missing: Vec<&Path> = dir.ancestors().take_while(|dir| !dir.is_dir()).collect();
+        let mut created = 0;
+        for dir in missing.into_iter().rev() {
+            match fs::create_dir(dir) {
+                Ok(()) => created += disk_usage(&fs::metadata(dir)?),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(created)
+    }
+
+    // Runs `write`, which puts a file into `dir`, again for as long as it fails because a
+    // delete pruned `dir` in the meantime, recreating it first. The disk usage of the
+    // directories that takes gets added to `dir_bytes`.
+    fn write_into<T>(
+        &self,
+        dir: &Path,
+        dir_bytes: &mut u64,
+        mut write: impl FnMut() -> Result<T, io::Error>,
+    ) -> Result<T, io::Error> {
+        loop {
+            match write() {
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !dir.is_dir() => {
+                    *dir_bytes += self.create(dir)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Removes `dir` and then its parents, up to but not including `root`, for as long as they're
+    // empty, returning the disk usage of the directories it removed. `remove_dir` only ever
+    // removes empty directories, so a put that got a file into one first keeps it. This is
+    // best-effort: the block is already gone, and a directory left behind is harmless.
+    fn prune(&self, root: &Path, dir: &Path) -> u64 {
+        let _lock = self.0.lock().unwrap();
+        let mut removed = 0;
+        for dir in dir.ancestors().take_while(|dir| *dir != root && dir.starts_with(root)) {
+            let Ok(metadata) = fs::metadata(dir) else {
+                break;
+            };
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+            removed += disk_usage(&metadata);
+        }
+        removed
+    }
+}
+
+// Writes the file for block `cid` unless it's already there, returning how that changed the
+// store's stats. Only actual writes go through the journal.
+fn put_block_file(
+    cid: &Cid,
+    block_path: &Path,
+    data: &[u8],
+    sync_policy: SyncPolicy,
+    temp_dir: Option<&Path>,
+    journal: Option<&Journal>,
+    shard_dirs: &ShardDirs,
+) -> Result<(Put, StoreStats), io::Error> {
+    // The caller holds the block's write lock, so the file can't show up or go away between the
+    // check and the write.
+    if block_path.exists() {
+        return Ok((Put::Existing, StoreStats::default()));
+    }
+
+    let sync = sync_policy != SyncPolicy::None;
+    let block_dir = block_path.parent().unwrap();
+    let mut dir_bytes = 0;
+    journaled(journal, PUT, cid, sync, || {
+        shard_dirs.write_into(block_dir, &mut dir_bytes, || {
+            write_block_file(block_path, data, sync_policy, temp_dir)
+        })
+    })?;
+    let mut delta = StoreStats::of_file(&fs::metadata(block_path)?);
+    delta.disk_bytes += dir_bytes;
+    Ok((Put::Written { bytes: delta.bytes }, delta))
+}
+
+// Prefix for in-flight writes. Being dot-prefixed, these never get mistaken for blocks.
+const TEMP_PREFIX: &str = ".tmp-";
+
+fn write_block_file(
+    block_path: &Path,
+    data: &[u8],
+    sync_policy: SyncPolicy,
+    temp_dir: Option<&Path>,
+) -> Result<(), io::Error> {
+    // We write into a uniquely named temporary file, next to the block unless we're given a
+    // directory for those, and then rename it into place. Renames within a filesystem are
+    // atomic, so concurrent writers and readers (or a crash halfway through) can only ever see
+    // either no block or a complete one.
+    let temp_path = write_temp_file(block_path, data, sync_policy != SyncPolicy::None, temp_dir)?;
+    if let Err(e) = fs::rename(&temp_path, block_path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    if sync_policy == SyncPolicy::DataAndDir {
+        File::open(block_path.parent().unwrap())?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+// Writes `data` into a fresh temporary file for the block at `block_path`, returning its path.
+// Nothing is left behind if that fails.
+fn write_temp_file(
+    block_path: &Path,
+    data: &[u8],
+    sync: bool,
+    temp_dir: Option<&Path>,
+) -> Result<PathBuf, io::Error> {
+    let temp_path = temp_path(block_path, temp_dir);
+    let result = File::create_new(&temp_path).and_then(|mut file| {
+        file.write_all(data)?;
+        if sync {
+            file.sync_all()?;
+        }
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(temp_path)
+}
+
+fn temp_path(block_path: &Path, temp_dir: Option<&Path>) -> PathBuf {
+    let name = block_path.file_name().unwrap().to_string_lossy();
+    let temp_name = format!("{}{}-{:016x}", TEMP_PREFIX, name, rand::random::<u64>());
+    temp_dir.unwrap_or(block_path.parent().unwrap()).join(temp_name)
+}
+
+/// How [`FSStore::with_group_commit`] batches puts up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCommit {
+    /// How long a batch waits for more puts after its first one.
+    pub interval: Duration,
+    /// How many puts a batch takes at most.
+    pub max_blocks: usize,
+}
+
+impl Default for GroupCommit {
+    fn default() -> Self {
+        GroupCommit {
+            interval: Duration::from_millis(5),
+            max_blocks: 256,
+        }
+    }
+}
+
+// A put whose temporary file is written, waiting for its batch to be committed.
+struct Staged {
+    cid: Cid,
+    block_path: PathBuf,
+    temp_path: PathBuf,
+    // The disk usage of the directories the put created.
+    dir_bytes: u64,
+    sync_dir: bool,
+    journal: Option<(Arc<Journal>, u64)>,
+}
+
+// What the first half of a grouped put came to.
+enum Staging {
+    // The block was already there. Creating its directories may still have counted, though.
+    Existing(u64),
+    Staged(Staged),
+}
+
+type Committed = Result<(Put, StoreStats), io::Error>;
+
+struct Commit {
+    staged: Staged,
+    done: oneshot::Sender<Committed>,
+}
+
+// Hands puts to the committer thread, which stops once this is dropped.
+struct Committer(std::sync::mpsc::Sender<Commit>);
+
+impl Committer {
+    fn start(
+        root: PathBuf,
+        group_commit: GroupCommit,
+        write_locks: Arc<WriteLocks>,
+        shard_dirs: Arc<ShardDirs>,
+    ) -> Self {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            commit_batches(&root, group_commit, receiver, &write_locks, &shard_dirs)
+        });
+        Committer(sender)
+    }
+
+    // Hands `staging` over, returning what to wait on for the put's outcome.
+    fn submit(&self, staging: Staging) -> oneshot::Receiver<Committed> {
+        let (done, committed) = oneshot::channel();
+        match staging {
+            Staging::Existing(dir_bytes) => {
+                let delta = StoreStats {
+                    disk_bytes: dir_bytes,
+                    ..StoreStats::default()
+                };
+                let _ = done.send(Ok((Put::Existing, delta)));
+            }
+            Staging::Staged(staged) => {
+                // Should the thread be gone, dropping `done` lets the put know.
+                let _ = self.0.send(Commit { staged, done });
+            }
+        }
+        committed
+    }
+}
+
+fn committer_stopped() -> BlockstoreError {
+    BlockstoreError::Backend(io::Error::other("group commit thread stopped"))
+}
+
+// The first half of a grouped put, up to writing the block's temporary file. That isn't synced:
+// the batch's sync takes care of it, and of the journal entry with it, on Linux at least.
+fn stage_block(
+    cid: Cid,
+    block_path: PathBuf,
+    data: &[u8],
+    sync_policy: SyncPolicy,
+    temp_dir: Option<&Path>,
+    journal: Option<Arc<Journal>>,
+    shard_dirs: &ShardDirs,
+) -> Result<Staging, io::Error> {
+    let block_dir = block_path.parent().unwrap();
+    let mut dir_bytes = shard_dirs.create(block_dir)?;
+    // Checked again under the write lock when committing; this just saves writing for nothing.
+    if block_path.exists() {
+        return Ok(Staging::Existing(dir_bytes));
+    }
+
+    let journal = match journal {
+        Some(journal) => {
+            let seq = journal.begin(PUT, &cid, false)?;
+            Some((journal, seq))
+        }
+        None => None,
+    };
+    let written = shard_dirs.write_into(block_dir, &mut dir_bytes, || {
+        write_temp_file(&block_path, data, false, temp_dir)
+    });
+    let temp_path = match written {
+        Ok(temp_path) => temp_path,
+        Err(e) => {
+            if let Some((journal, seq)) = &journal {
+                let _ = journal.end(*seq);
+            }
+            return Err(e);
+        }
+    };
+
+    Ok(Staging::Staged(Staged {
+        cid,
+        block_path,
+        temp_path,
+        dir_bytes,
+        sync_dir: sync_policy == SyncPolicy::DataAndDir,
+        journal,
+    }))
+}
+
+fn commit_batches(
+    root: &Path,
+    group_commit: GroupCommit,
+    receiver: std::sync::mpsc::Receiver<Commit>,
+    write_locks: &WriteLocks,
+    shard_dirs: &ShardDirs,
+) {
+    while let Ok(first) = receiver.recv() {
+        let deadline = Instant::now() + group_commit.interval;
+        let mut batch = vec![first];
+        while batch.len() < group_commit.max_blocks {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(commit) => batch.push(commit),
+                Err(_) => break,
+            }
+        }
+
+        let staged: Vec<&Staged> = batch.iter().map(|commit| &commit.staged).collect();
+        let results = commit_batch(root, &staged, write_locks, shard_dirs);
+        for (commit, result) in batch.into_iter().zip(results) {
+            if let Some((journal, seq)) = &commit.staged.journal {
+                let _ = journal.end(*seq);
+            }
+            let _ = commit.done.send(result);
+        }
+    }
+}
+
+// Makes a batch of temporary files durable, renames them into place, and makes the renames
+// durable too where asked to, returning each put's outcome in order.
+fn commit_batch(
+    root: &Path,
+    batch: &[&Staged],
+    write_locks: &WriteLocks,
+    shard_dirs: &ShardDirs,
+) -> Vec<Committed> {
+    let shared = |e: &io::Error| io::Error::new(e.kind(), e.to_string());
+
+    // The data has to be on disk before any rename, or a crash could leave torn blocks in place.
+    let temp_paths: Vec<&Path> = batch.iter().map(|commit| commit.temp_path.as_path()).collect();
+    if let Err(e) = sync_paths(root, &temp_paths) {
+        for path in temp_paths {
+            let _ = fs::remove_file(path);
+        }
+        return batch.iter().map(|_| Err(shared(&e))).collect();
+    }
+
+    let mut results: Vec<_> = batch
+        .iter()
+        .map(|commit| {
+            // Under the block's write lock, so that concurrent puts of it only count it once.
+            let _lock = write_locks.lock(&commit.cid);
+            let mut result = rename_into_place(commit, shard_dirs);
+            if let Ok((_, delta)) = &mut result {
+                delta.disk_bytes += commit.dir_bytes;
+            }
+            result
+        })
+        .collect();
+
+    let mut dirs: Vec<&Path> = batch
+        .iter()
+        .filter(|commit| commit.sync_dir)
+        .map(|commit| commit.block_path.parent().unwrap())
+        .collect();
+    dirs.sort_unstable();
+    dirs.dedup();
+    if let Err(e) = sync_paths(root, &dirs) {
+        for (commit, result) in batch.iter().zip(&mut results) {
+            if commit.sync_dir && result.is_ok() {
+                *result = Err(shared(&e));
+            }
+        }
+    }
+    results
+}
+
+fn rename_into_place(commit: &Staged, shard_dirs: &ShardDirs) -> Committed {
+    if commit.block_path.exists() {
+        let _ = fs::remove_file(&commit.temp_path);
+        return Ok((Put::Existing, StoreStats::default()));
+    }
+
+    let mut dir_bytes = 0;
+    let block_dir = commit.block_path.parent().unwrap();
+    let renamed = shard_dirs.write_into(block_dir, &mut dir_bytes, || {
+        fs::rename(&commit.temp_path, &commit.block_path)
+    });
+    if let Err(e) = renamed {
+        let _ = fs::remove_file(&commit.temp_path);
+        return Err(e);
+    }
+
+    let mut delta = StoreStats::of_file(&fs::metadata(&commit.block_path)?);
+    delta.disk_bytes += dir_bytes;
+    Ok((Put::Written { bytes: delta.bytes }, delta))
+}
+
+// Makes the files or directories at `paths`, all on the same filesystem as `root`, durable.
+// There, a single `syncfs` does that for the whole filesystem, however many paths there are.
+#[cfg(target_os = "linux")]
+fn sync_paths(root: &Path, paths: &[&Path]) -> Result<(), io::Error> {
+    use std::os::fd::AsRawFd;
+
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let dir = File::open(root)?;
+    // SAFETY: syncfs only flushes the filesystem behind a descriptor we hold open.
+    if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Elsewhere, every path gets synced on its own. That's still one round of syncs per batch, which
+// gives the OS a chance to coalesce them.
+#[cfg(not(target_os = "linux"))]
+fn sync_paths(_root: &Path, paths: &[&Path]) -> Result<(), io::Error> {
+    for path in paths {
+        File::open(path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Name of the journal kept in the root of an [`FSStore`] opened with
+/// [`FSStore::with_journal`].
+pub const JOURNAL_FILE: &str = ".journal";
+
+const PUT: &str = "put";
+const DEL: &str = "del";
+const DONE: &str = "done";
+
+// An append-only log of the operations a journaling FSStore has started. Each one gets a line
+// `<seq> put|del <cid>` before it touches the shard tree, and a line `<seq> done` once it's over,
+// whether or not it succeeded. Any intent without a matching `done` was cut short by a crash.
+struct Journal {
+    file: Mutex<File>,
+    next_seq: AtomicU64,
+}
+
+impl Journal {
+    fn append(&self, line: &str, sync: bool) -> Result<(), io::Error> {
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Records the intent to run `op` on block `cid`, returning its sequence number for `end`.
+    fn begin(&self, op: &str, cid: &Cid, sync: bool) -> Result<u64, io::Error> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        self.append(&format!("{} {} {}\n", seq, op, cid), sync)?;
+        Ok(seq)
+    }
+
+    fn end(&self, seq: u64) -> Result<(), io::Error> {
+        self.append(&format!("{} {}\n", seq, DONE), false)
+    }
+}
+
+// Runs `operation` on the block `cid`, bracketing it with journal entries if there's a journal.
+// Only the intent needs syncing: losing a `done` just means replaying an operation that already
+// finished, which is harmless.
+fn journaled<T>(
+    journal: Option<&Journal>,
+    op: &str,
+    cid: &Cid,
+    sync: bool,
+    operation: impl FnOnce() -> Result<T, io::Error>,
+) -> Result<T, io::Error> {
+    let Some(journal) = journal else {
+        return operation();
+    };
+
+    let seq = journal.begin(op, cid, sync)?;
+    let result = operation();
+    journal.end(seq)?;
+    result
+}
+
+// Cleans up after the operations the journal at `path` says were interrupted, returning the
+// blocks whose deletion this finished along with what their files looked like.
+fn recover(
+    root: &Path,
+    sharding: &dyn ShardingStrategy,
+    temp_dir: Option<&Path>,
+    path: &Path,
+) -> Result<Vec<(Cid, Metadata)>, io::Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut deleted = Vec::new();
+    for (op, cid) in replay_journal(&contents)?.into_values() {
+        let block_path = root.join(sharding.block_path(&cid));
+        if op == DEL {
+            match fs::metadata(&block_path) {
+                Ok(metadata) => {
+                    fs::remove_file(&block_path)?;
+                    deleted.push((cid, metadata));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        } else {
+            remove_temp_files(&block_path, temp_dir)?;
+        }
+    }
+
+    Ok(deleted)
+}
+
+// Returns the intents in `contents` that never got a `done`, by sequence number.
+fn replay_journal(contents: &str) -> Result<BTreeMap<u64, (&str, Cid)>, io::Error> {
+    // A crash can leave the last line half-written. If it was an intent, its operation never
+    // started; if it was a `done`, replaying the operation again is harmless.
+    let complete = &contents[..contents.rfind('\n').map_or(0, |end| end + 1)];
+
+    let mut pending = BTreeMap::new();
+    for line in complete.lines().filter(|line| !line.is_empty()) {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed journal entry {:?}", line),
+            )
+        };
+
+        let fields: Vec<&str> = line.split(' ').collect();
+        let seq: u64 = fields[0].parse().map_err(|_| invalid())?;
+        match fields[1..] {
+            [DONE] => {
+                pending.remove(&seq);
+            }
+            [op @ (PUT | DEL), cid] => {
+                let cid = Cid::try_from(cid).map_err(|_| invalid())?;
+                pending.insert(seq, (op, cid));
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(pending)
+}
+
+// Removes every temporary file left behind by writes that never finished, in the shard trees of
+// the store at `root` and of its namespaces, along with the namespaces left behind by deletions
+// that never finished. Returns what was removed.
+fn remove_leftovers(root: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut removed = Vec::new();
+    remove_temp_entries(root, &mut removed)?;
+    match remove_temp_entries(&root.join(NAMESPACES_DIR), &mut removed) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    Ok(removed)
+}
+
+// Removes the temporary entries under `dir`, going into everything but metadata directories.
+fn remove_temp_entries(dir: &Path, removed: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let is_dir = entry.file_type()?.is_dir();
+        if name.starts_with(TEMP_PREFIX) {
+            if is_dir {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+            removed.push(entry.path());
+        } else if is_dir && !name.starts_with('.') {
+            remove_temp_entries(&entry.path(), removed)?;
+        }
+    }
+    Ok(())
+}
+
+// Removes any temporary files left behind for `block_path` by writes that never finished.
+fn remove_temp_files(block_path: &Path, temp_dir: Option<&Path>) -> Result<(), io::Error> {
+    let name = block_path.file_name().unwrap().to_string_lossy();
+    let prefix = format!("{}{}-", TEMP_PREFIX, name);
+    let entries = match fs::read_dir(temp_dir.unwrap_or(block_path.parent().unwrap())) {
+        Ok(entries) => entries,
+        // The put didn't even get to create the shard directory.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// Prefix for the staging directories of transactions in progress; see [`crate::txn`].
+const TXN_PREFIX: &str = ".txn-";
+
+// Written into a staging directory once its transaction commits, listing its blocks in the order
+// they're to be moved into the shard tree. A staging directory without one is rolled back.
+const COMMIT_FILE: &str = "COMMIT";
+
+impl FSStore {
+    // Creates an empty staging directory for a new transaction.
+    pub(crate) async fn create_staging_dir(&self) -> Result<PathBuf, io::Error> {
+        let dir = self
+            .root
+            .join(format!("{}{:016x}", TXN_PREFIX, rand::random::<u64>()));
+        tokio::fs::create_dir(&dir).await?;
+        Ok(dir)
+    }
+
+    // Writes `block` into the staging directory `dir`, where nobody but its transaction sees it.
+    pub(crate) async fn stage_block(&self, dir: &Path, block: &Block) -> Result<(), BlockstoreError> {
+        self.check_size(block)?;
+        let staged_path = dir.join(self.key(&block.cid).to_string());
+        let data = block.data.clone();
+        let sync_policy = self.sync_policy;
+        spawn_blocking(move || write_block_file(&staged_path, &data, sync_policy, None)).await??;
+        Ok(())
+    }
+
+    // Moves the blocks staged in `dir` into the shard tree in the given order, then removes
+    // `dir`. Once the commit file is written, the transaction will go through even if we crash
+    // before it's done: `create` finishes the job.
+    pub(crate) async fn commit_staged(&self, dir: PathBuf, order: Vec<Cid>) -> Result<(), BlockstoreError> {
+        // Packed blocks are already there. As with `put_block`'s group commits, this doesn't
+        // hold their write locks.
+        let order: Vec<Cid> = order
+            .iter()
+            .map(|cid| self.key(cid))
+            .filter(|cid| !self.packs.contains(cid))
+            .collect();
+        let _reservation = match self.quota() {
+            Some(_) => {
+                let mut cids = order.clone();
+                cids.sort();
+                cids.dedup();
+                let staged_dir = dir.clone();
+                let sizes = spawn_blocking(move || {
+                    cids.into_iter()
+                        .map(|cid| Ok((cid, fs::metadata(staged_dir.join(cid.to_string()))?.len())))
+                        .collect::<Result<Vec<_>, io::Error>>()
+                })
+                .await??;
+                Some(self.reserve(&sizes).await?)
+            }
+            None => None,
+        };
+        let root = self.root.clone();
+        let sharding = self.sharding.clone();
+        let sync_policy = self.sync_policy;
+        let write_locks = self.write_locks.clone();
+        let shard_dirs = self.shard_dirs.clone();
+        let cids = order.clone();
+        let delta = spawn_blocking(move || {
+            let contents: String = order.iter().map(|cid| format!("{}\n", cid)).collect();
+            // The commit has to be durable before any block shows up, or a crash could leave
+            // only part of the transaction in place.
+            let marker_policy = match sync_policy {
+                SyncPolicy::None => SyncPolicy::None,
+                _ => SyncPolicy::DataAndDir,
+            };
+            write_block_file(&dir.join(COMMIT_FILE), contents.as_bytes(), marker_policy, None)?;
+
+            let delta = move_staged(
+                &root,
+                sharding.as_ref(),
+                &dir,
+                &order,
+                sync_policy,
+                &write_locks,
+                &shard_dirs,
+            )?;
+            fs::remove_dir_all(&dir)?;
+            Ok::<_, io::Error>(delta)
+        })
+        .await??;
+
+        self.counters.add(&delta);
+        if let Some(bloom) = &self.bloom {
+            for cid in &cids {
+                bloom.insert(cid);
+            }
+        }
+        self.reindex(&cids).await?;
+        Ok(())
+    }
+}
+
+// Moves the blocks `order` staged in `dir` into the shard tree, returning how that changed the
+// store's stats. Blocks the store already has, and ones an earlier attempt already moved, are
+// skipped.
+fn move_staged(
+    root: &Path,
+    sharding: &dyn ShardingStrategy,
+    dir: &Path,
+    order: &[Cid],
+    sync_policy: SyncPolicy,
+    write_locks: &WriteLocks,
+    shard_dirs: &ShardDirs,
+) -> Result<StoreStats, io::Error> {
+    let mut delta = StoreStats::default();
+    for cid in order {
+        let _lock = write_locks.lock(cid);
+        let staged_path = dir.join(cid.to_string());
+        let block_path = root.join(sharding.block_path(cid));
+        let block_dir = block_path.parent().unwrap();
+        delta.disk_bytes += shard_dirs.create(block_dir)?;
+
+        if block_path.exists() {
+            continue;
+        }
+        let renamed = shard_dirs.write_into(block_dir, &mut delta.disk_bytes, || {
+            fs::rename(&staged_path, &block_path)
+        });
+        match renamed {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+        if sync_policy == SyncPolicy::DataAndDir {
+            File::open(block_dir)?.sync_all()?;
+        }
+
+        let file_stats = StoreStats::of_file(&fs::metadata(&block_path)?);
+        delta.blocks += file_stats.blocks;
+        delta.bytes += file_stats.bytes;
+        delta.disk_bytes += file_stats.disk_bytes;
+    }
+    Ok(delta)
+}
+
+// Deals with the staging directories left behind by the last run: transactions that got as far
+// as writing their commit file are finished, and the rest are rolled back.
+fn recover_transactions(root: &Path, sharding: &dyn ShardingStrategy) -> Result<(), io::Error> {
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with(TXN_PREFIX) {
+            continue;
+        }
+
+        let dir = entry.path();
+        match fs::read_to_string(dir.join(COMMIT_FILE)) {
+            Ok(contents) => {
+                let order = contents
+                    .lines()
+                    .map(|line| Cid::try_from(line).map_err(|e| unexpected_entry(&dir, e)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Nobody else has the store open yet, so there's nothing to lock out.
+                let write_locks = WriteLocks::new();
+                let shard_dirs = ShardDirs::new();
+                move_staged(
+                    root,
+                    sharding,
+                    &dir,
+                    &order,
+                    SyncPolicy::None,
+                    &write_locks,
+                    &shard_dirs,
+                )?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        fs::remove_dir_all(&dir)?;
+    }
+    Ok(())
+}
+
+impl FSStore {
+    // Whether the bloom filter, if any, lets block `cid` through to the disk.
+    fn might_have(&self, cid: &Cid) -> bool {
+        match &self.bloom {
+            Some(bloom) => bloom.contains(&self.key(cid)),
+            None => true,
+        }
+    }
+
+    // Turns reading block `cid`'s file into what `get_block` returns, verifying the data if
+    // asked to.
+    async fn finish_read(
+        &self,
+        cid: &Cid,
+        block_path: &Path,
+        read: Result<Bytes, io::Error>,
+    ) -> Result<Option<Block>, BlockstoreError> {
+        let (data, packed) = match read {
+            Ok(data) => (data, false),
+            // Compaction packs a block before removing its file, so one that's gone has to be
+            // looked for in the packs after the file.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match self.packed_block(cid) {
+                Some(data) => (data, true),
+                // A miss, as opposed to a block we couldn't read.
+                None => return Ok(None),
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        if self.verify_mode != VerifyMode::Off && Block::hash_matches(cid, &data) == Some(false) {
+            let quarantined = match self.verify_mode {
+                VerifyMode::Quarantine if packed => Some(self.quarantine_packed(cid, &data).await?),
+                VerifyMode::Quarantine => Some(self.quarantine(cid, block_path).await?),
+                _ => None,
+            };
+            let digest = match crate::block::Hasher::from_code(cid.hash().code()) {
+                Some(hasher) => hasher.digest(&data),
+                None => data.to_vec(),
+            };
+            return Err(BlockstoreError::corrupt(cid, &digest, quarantined));
+        }
+
+        Ok(Some(Block { cid: *cid, data }))
+    }
+
+    // The blocking part of deleting block `cid`: removing its file, and pruning the directories
+    // that leaves empty. Returns how that changed the store's stats, for `forget`.
+    fn delete_file(
+        &self,
+        cid: &Cid,
+    ) -> impl FnOnce() -> Result<StoreStats, io::Error> + Send + 'static {
+        let block_path = self.block_path(cid);
+        let meta_path = self.meta_path(cid);
+        let tags_path = self.tags_path(cid);
+        let sync = self.sync_policy != SyncPolicy::None;
+        let journal = self.journal.clone();
+        let write_locks = self.write_locks.clone();
+        let shard_dirs = self.shard_dirs.clone();
+        let root = self.root.clone();
+        let packs = self.packs.clone();
+        let key = self.key(cid);
+        move || {
+            let _lock = write_locks.lock(&key);
+            // The metadata and tags go first, so that a crash in between can't leave them behind
+            // for the next put of the block to pick up.
+            for tag in read_tags(&tags_path)? {
+                let tag_dir = root.join(TAGS_DIR).join(tag);
+                remove_if_there(&tag_dir.join(key.to_string()))?;
+                shard_dirs.prune(&root.join(TAGS_DIR), &tag_dir);
+            }
+            if remove_if_there(&tags_path)? | remove_if_there(&meta_path)? {
+                shard_dirs.prune(&root.join(META_DIR), meta_path.parent().unwrap());
+            }
+            let mut delta = StoreStats::default();
+            if let Some(size) = packs.delete(&key, sync)? {
+                delta.blocks += 1;
+                delta.bytes += size;
+            }
+            // A packed block can have its file still around too, if compaction got interrupted.
+            let deleted = journaled(journal.as_deref(), DEL, &key, sync, || {
+                let metadata = fs::metadata(&block_path)?;
+                fs::remove_file(&block_path)?;
+                Ok(metadata)
+            });
+            let metadata = match deleted {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && delta.blocks > 0 => {
+                    return Ok(delta);
+                }
+                Err(e) => return Err(e),
+            };
+            // Without pruning, churn would leave behind a growing trail of empty directories.
+            let file_stats = StoreStats::of_file(&metadata);
+            delta.blocks += file_stats.blocks;
+            delta.bytes += file_stats.bytes;
+            delta.disk_bytes += file_stats.disk_bytes;
+            delta.disk_bytes += shard_dirs.prune(&root, block_path.parent().unwrap());
+            Ok(delta)
+        }
+    }
+
+    // Brings the index, if there's one, up to date with blocks `cids` once they've been put or
+    // deleted. Each gets looked up again under its write lock, so that whichever operation on a
+    // block finished last has the last word, whatever order they get here in.
+    async fn reindex(&self, cids: &[Cid]) -> Result<(), io::Error> {
+        let Some(index) = self.index.clone() else {
+            return Ok(());
+        };
+        let blocks: Vec<(Cid, PathBuf)> = cids
+            .iter()
+            .filter(|cid| !is_inline(cid))
+            .map(|cid| (self.key(cid), self.block_path(cid)))
+            .collect();
+        let write_locks = self.write_locks.clone();
+        let packs = self.packs.clone();
+        spawn_blocking(move || {
+            for (key, block_path) in blocks {
+                let _lock = write_locks.lock(&key);
+                index.set(&key, index_entry(&key, &block_path, &packs)?)?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    fn forget(&self, delta: &StoreStats) {
+        self.counters.sub(delta);
+        if let Some(bloom) = &self.bloom {
+            bloom.forget();
+        }
+    }
+
+    // Waits for room under the open file limit, if there's one, for a read or a write.
+    async fn file_permit(&self, write: bool) -> Option<FilePermit> {
+        match &self.file_limit {
+            Some(limit) => Some(limit.acquire(write).await),
+            None => None,
+        }
+    }
+
+    // Waits for room to work on one more of a batch's blocks.
+    async fn batch_permit(&self) -> OwnedSemaphorePermit {
+        self.batch_permits.clone().acquire_owned().await.expect("never closed")
+    }
+
+    // The committer, if puts go through it with this store's sync policy.
+    fn grouped(&self) -> Option<&Committer> {
+        self.committer
+            .as_ref()
+            .filter(|_| self.sync_policy != SyncPolicy::None)
+    }
+
+    // Sets aside room under the quota for blocks of the given sizes that are about to be put.
+    // Blocks the store already has take up no room, but only get looked for when the rest won't
+    // fit.
+    async fn reserve(&self, blocks: &[(Cid, u64)]) -> Result<Reservation, BlockstoreError> {
+        let used = || self.counters.bytes.load(Ordering::Relaxed);
+        let bytes = blocks.iter().map(|(_, size)| size).sum();
+        if let Some(reservation) = self.quota.reserve(bytes, used()) {
+            return Ok(reservation);
+        }
+
+        let cids: Vec<Cid> = blocks.iter().map(|(cid, _)| *cid).collect();
+        let found = self.has_many(&cids).await;
+        let missing = blocks
+            .iter()
+            .zip(found)
+            .filter(|(_, found)| !found)
+            .map(|((_, size), _)| size)
+            .sum();
+        self.quota
+            .reserve(missing, used())
+            .ok_or_else(|| BlockstoreError::QuotaExceeded {
+                size: missing,
+                max_bytes: self.quota().unwrap_or(u64::MAX),
+            })
+    }
+
+    // Puts `block` by hard-linking `source`, a file holding its data, into place, which saves
+    // copying the data when both are on the same filesystem. When they aren't, or linking
+    // fails for any other reason, the block gets written as usual instead.
+    pub(crate) async fn link_block(&self, block: &Block, source: PathBuf) -> Result<Put, BlockstoreError> {
+        if is_inline(&block.cid) {
+            return Ok(Put::Existing);
+        }
+        self.check_size(block)?;
+        let size = block.data.len() as u64;
+        let _reservation = self.reserve(&[(block.cid, size)]).await?;
+        self.check_space(size).await?;
+        let _file = self.file_permit(true).await;
+        let block_path = self.block_path(&block.cid);
+        let data = block.data.clone();
+        let sync_policy = self.sync_policy;
+        let temp_dir = self.temp_dir.clone();
+        let journal = self.journal.clone();
+        let write_locks = self.write_locks.clone();
+        let shard_dirs = self.shard_dirs.clone();
+        let packs = self.packs.clone();
+        let cid = self.key(&block.cid);
+
+        let (put, delta) = spawn_blocking(move || {
+            let block_dir = block_path.parent().unwrap();
+            let mut dir_bytes = shard_dirs.create(block_dir)?;
+
+            let _lock = write_locks.lock(&cid);
+            if block_path.exists() || packs.contains(&cid) {
+                let delta = StoreStats {
+                    disk_bytes: dir_bytes,
+                    ..StoreStats::default()
+                };
+                return Ok::<_, io::Error>((Put::Existing, delta));
+            }
+            let linked = shard_dirs.write_into(block_dir, &mut dir_bytes, || {
+                fs::hard_link(&source, &block_path)
+            });
+            let (put, mut delta) = match linked {
+                Ok(()) => {
+                    if sync_policy == SyncPolicy::DataAndDir {
+                        File::open(block_dir)?.sync_all()?;
+                    }
+                    let stats = StoreStats::of_file(&fs::metadata(&block_path)?);
+                    (Put::Written { bytes: stats.bytes }, stats)
+                }
+                Err(_) => put_block_file(
+                    &cid,
+                    &block_path,
+                    &data,
+                    sync_policy,
+                    temp_dir.as_deref(),
+                    journal.as_deref(),
+                    &shard_dirs,
+                )?,
+            };
+            delta.disk_bytes += dir_bytes;
+            Ok((put, delta))
+        })
+        .await?
+        .map_err(|e| self.out_of_space(e, size))?;
+
+        self.counters.add(&delta);
+        if let Some(bloom) = &self.bloom {
+            bloom.insert(&cid);
+        }
+        self.reindex(&[cid]).await?;
+        Ok(put)
+    }
+
+    // `put_many` through the committer: all the blocks get staged before waiting on any of them,
+    // so that they share as few batches as possible.
+    async fn put_grouped(
+        &self,
+        committer: &Committer,
+        blocks: &[Block],
+    ) -> Result<(), BlockstoreError> {
+        let mut stages = JoinSet::new();
+        for block in blocks {
+            let permit = self.batch_permit().await;
+            let cid = self.key(&block.cid);
+            let block_path = self.block_path(&block.cid);
+            let data = block.data.clone();
+            let sync_policy = self.sync_policy;
+            let temp_dir = self.temp_dir.clone();
+            let journal = self.journal.clone();
+            let shard_dirs = self.shard_dirs.clone();
+            let file = self.file_permit(true).await;
+            stages.spawn_blocking(move || {
+                let _permit = permit;
+                let _file = file;
+                stage_block(
+                    cid,
+                    block_path,
+                    &data,
+                    sync_policy,
+                    temp_dir.as_deref(),
+                    journal,
+                    &shard_dirs,
+                )
+            });
+        }
+
+        // Every staged block has to be waited for, even after a failure, so that its counters
+        // are kept.
+        let mut first_error = None;
+        let mut commits = Vec::new();
+        while let Some(result) = stages.join_next().await {
+            match result {
+                Ok(Ok(staging)) => commits.push(committer.submit(staging)),
+                Ok(Err(e)) => {
+                    first_error.get_or_insert(e.into());
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e.into());
+                }
+            }
+        }
+        for committed in commits {
+            match committed.await.map_err(|_| committer_stopped()) {
+                Ok(Ok((_, delta))) => self.counters.add(&delta),
+                Ok(Err(e)) => {
+                    first_error.get_or_insert(e.into());
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        if let Some(bloom) = &self.bloom {
+            for block in blocks {
+                bloom.insert(&self.key(&block.cid));
+            }
+        }
+
+        let cids: Vec<Cid> = blocks.iter().map(|block| block.cid).collect();
+        let reindexed = self.reindex(&cids).await;
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(reindexed?),
+        }
+    }
+
+    // Attaches `meta` to block `cid`, replacing whatever it had. Fails with `NotFound` if the
+    // block isn't in the store, since nothing would ever delete the metadata then.
+    pub(crate) async fn put_meta(&self, cid: &Cid, meta: &[u8]) -> Result<(), BlockstoreError> {
+        let _file = self.file_permit(true).await;
+        let block_path = self.block_path(cid);
+        let meta_path = self.meta_path(cid);
+        let meta = meta.to_vec();
+        let inline = is_inline(cid);
+        let sync_policy = self.sync_policy;
+        let write_locks = self.write_locks.clone();
+        let shard_dirs = self.shard_dirs.clone();
+        let packs = self.packs.clone();
+        let key = self.key(cid);
+        spawn_blocking(move || {
+            let _lock = write_locks.lock(&key);
+            if !inline && !packs.contains(&key) && !block_path.exists() {
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+            let meta_dir = meta_path.parent().unwrap();
+            shard_dirs.write_into(meta_dir, &mut 0, || {
+                write_block_file(&meta_path, &meta, sync_policy, None)
+            })
+        })
+        .await?
+        .map_err(|e| missing(e, cid))
+    }
+
+    // The metadata attached to block `cid`, if any.
+    pub(crate) async fn read_meta(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreError> {
+        let _file = self.file_permit(false).await;
+        match tokio::fs::read(self.meta_path(cid)).await {
+            Ok(meta) => Ok(Some(meta)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Gives block `cid` the tag `tag`, which must be a valid file name. Like `put_meta`, fails
+    // with `NotFound` if the block isn't in the store.
+    pub(crate) async fn tag_block(&self, cid: &Cid, tag: &str) -> Result<(), BlockstoreError> {
+        let _file = self.file_permit(true).await;
+        let block_path = self.block_path(cid);
+        let tags_path = self.tags_path(cid);
+        let tag = tag.to_string();
+        let inline = is_inline(cid);
+        let sync_policy = self.sync_policy;
+        let root = self.root.clone();
+        let write_locks = self.write_locks.clone();
+        let shard_dirs = self.shard_dirs.clone();
+        let packs = self.packs.clone();
+        let key = self.key(cid);
+        spawn_blocking(move || {
+            let _lock = write_locks.lock(&key);
+            if !inline && !packs.contains(&key) && !block_path.exists() {
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+            let mut tags = read_tags(&tags_path)?;
+            if tags.contains(&tag) {
+                return Ok(());
+            }
+
+            // The block's own list goes first, so that deleting it always finds every tag.
+            tags.push(tag.clone());
+            let list: String = tags.iter().map(|tag| format!("{}\n", tag)).collect();
+            shard_dirs.write_into(tags_path.parent().unwrap(), &mut 0, || {
+                write_block_file(&tags_path, list.as_bytes(), sync_policy, None)
+            })?;
+            let tag_dir = root.join(TAGS_DIR).join(&tag);
+            let entry = tag_dir.join(key.to_string());
+            shard_dirs.write_into(&tag_dir, &mut 0, || {
+                write_block_file(&entry, &[], sync_policy, None)
+            })
+        })
+        .await?
+        .map_err(|e| missing(e, cid))
+    }
+
+    // Takes the tag `tag` off block `cid`, if it has it.
+    pub(crate) async fn untag_block(&self, cid: &Cid, tag: &str) -> Result<(), BlockstoreError> {
+        let _file = self.file_permit(true).await;
+        let tags_path = self.tags_path(cid);
+        let tag = tag.to_string();
+        let sync_policy = self.sync_policy;
+        let root = self.root.clone();
+        let write_locks = self.write_locks.clone();
+        let shard_dirs = self.shard_dirs.clone();
+        let key = self.key(cid);
+        spawn_blocking(move || {
+            let _lock = write_locks.lock(&key);
+            let mut tags = read_tags(&tags_path)?;
+            let Some(i) = tags.iter().position(|t| *t == tag) else {
+                return Ok(());
+            };
+
+            // The other way around from tagging, for the same reason.
+            let tag_dir = root.join(TAGS_DIR).join(&tag);
+            remove_if_there(&tag_dir.join(key.to_string()))?;
+            shard_dirs.prune(&root.join(TAGS_DIR), &tag_dir);
+            tags.remove(i);
+            if tags.is_empty() {
+                remove_if_there(&tags_path)?;
+                shard_dirs.prune(&root.join(META_DIR), tags_path.parent().unwrap());
+                return Ok(());
+            }
+            let list: String = tags.iter().map(|tag| format!("{}\n", tag)).collect();
+            write_block_file(&tags_path, list.as_bytes(), sync_policy, None)
+        })
+        .await?
+        .map_err(BlockstoreError::from)
+    }
+
+    // The tags of block `cid`, in the order they were given.
+    pub(crate) async fn block_tags(&self, cid: &Cid) -> Result<Vec<String>, BlockstoreError> {
+        let _file = self.file_permit(false).await;
+        let tags_path = self.tags_path(cid);
+        Ok(spawn_blocking(move || read_tags(&tags_path)).await??)
+    }
+
+    // Lists the blocks with the tag `tag`, which must be a valid file name.
+    pub(crate) fn list_tagged(&self, tag: &str) -> CidStream {
+        let (sender, receiver) = mpsc::channel(CID_STREAM_BUFFER);
+        let tag_dir = self.root.join(TAGS_DIR).join(tag);
+        spawn_blocking(move || {
+            let entries = match fs::read_dir(&tag_dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+                Err(e) => {
+                    let _ = sender.blocking_send(Err(e.into()));
+                    return;
+                }
+            };
+            for entry in entries {
+                let cid = match entry {
+                    Ok(entry) => {
+                        let name = entry.file_name().to_string_lossy().into_owned();
+                        // Dot-prefixed entries are temporary files.
+                        if name.starts_with('.') {
+                            continue;
+                        }
+                        Cid::try_from(name.as_str())
+                            .map_err(|e| unexpected_entry(&entry.path(), e).into())
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                let failed = cid.is_err();
+                if sender.blocking_send(cid).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        receiver
+    }
+
+    // Moves a block that failed verification out of the way, returning where it went.
+    async fn quarantine(&self, cid: &Cid, block_path: &Path) -> Result<PathBuf, io::Error> {
+        let dir = self.root.join(QUARANTINE_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        let target = dir.join(self.key(cid).to_string());
+
+        let block_path = block_path.to_path_buf();
+        let rename_target = target.clone();
+        let write_locks = self.write_locks.clone();
+        let key = self.key(cid);
+        let metadata = spawn_blocking(move || {
+            let _lock = write_locks.lock(&key);
+            let metadata = fs::metadata(&block_path)?;
+            fs::rename(&block_path, &rename_target)?;
+            Ok::<_, io::Error>(metadata)
+        })
+        .await??;
+        self.counters.sub(&StoreStats::of_file(&metadata));
+        if let Some(bloom) = &self.bloom {
+            bloom.forget();
+        }
+        self.reindex(std::slice::from_ref(cid)).await?;
+
+        Ok(target)
+    }
+
+    // Like `quarantine`, but for a packed block, which gets copied out before it's deleted.
+    async fn quarantine_packed(&self, cid: &Cid, data: &Bytes) -> Result<PathBuf, io::Error> {
+        let dir = self.root.join(QUARANTINE_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        let target = dir.join(self.key(cid).to_string());
+        tokio::fs::write(&target, data).await?;
+
+        let packs = self.packs.clone();
+        let write_locks = self.write_locks.clone();
+        let sync = self.sync_policy != SyncPolicy::None;
+        let key = self.key(cid);
+        let deleted = spawn_blocking(move || {
+            let _lock = write_locks.lock(&key);
+            packs.delete(&key, sync)
+        })
+        .await??;
+        if let Some(size) = deleted {
+            let delta = StoreStats {
+                blocks: 1,
+                bytes: size,
+                disk_bytes: 0,
+            };
+            self.forget(&delta);
+        }
+        self.reindex(std::slice::from_ref(cid)).await?;
+
+        Ok(target)
+    }
+}
+
+impl Drop for FSStore {
+    fn drop(&mut self) {}
+}
+
+// Turns the error from opening block `cid`'s file into a `NotFound` if it wasn't there.
+fn missing(e: io::Error, cid: &Cid) -> BlockstoreError {
+    match e.kind() {
+        io::ErrorKind::NotFound => BlockstoreError::NotFound(*cid),
+        _ => e.into(),
+    }
+}
+
+// The tags listed in the file at `path`, one per line, or none if there's no such file.
+fn read_tags(path: &Path) -> Result<Vec<String>, io::Error> {
+    match fs::read_to_string(path) {
+        Ok(list) => Ok(list.lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// Removes the file at `path`, returning whether it was there.
+fn remove_if_there(path: &Path) -> Result<bool, io::Error> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Blocks with identity CIDs never touch the disk: the CID holds the data, so they're served
+/// from it, and are always there. They aren't listed or counted either.
+impl Blockstore for FSStore {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        if is_inline(&block.cid) {
+            return Ok(Put::Existing);
+        }
+        self.check_size(block)?;
+        let size = block.data.len() as u64;
+        let _reservation = self.reserve(&[(block.cid, size)]).await?;
+        self.check_space(size).await?;
+        let _file = self.file_permit(true).await;
+        let block_path = self.block_path(&block.cid);
+        let data = block.data.clone();
+        let sync_policy = self.sync_policy;
+        let temp_dir = self.temp_dir.clone();
+        let journal = self.journal.clone();
+        let write_locks = self.write_locks.clone();
+        let shard_dirs = self.shard_dirs.clone();
+        let packs = self.packs.clone();
+        let cid = self.key(&block.cid);
+
+        if let Some(committer) = self.grouped() {
+            // Unlike the other puts, this one checks for a packed copy without holding the
+            // block's write lock: if compaction packs it in the meantime, it ends up in both
+            // places, as after an interrupted compaction.
+            if packs.contains(&cid) {
+                return Ok(Put::Existing);
+            }
+            let staging = spawn_blocking(move || {
+                stage_block(
+                    cid,
+                    block_path,
+                    &data,
+                    sync_policy,
+                    temp_dir.as_deref(),
+                    journal,
+                    &shard_dirs,
+                )
+            })
+            .await?
+            .map_err(|e| self.out_of_space(e, size))?;
+            let (put, delta) = committer
+                .submit(staging)
+                .await
+                .map_err(|_| committer_stopped())?
+                .map_err(|e| self.out_of_space(e, size))?;
+            self.counters.add(&delta);
+            if let Some(bloom) = &self.bloom {
+                bloom.insert(&cid);
+            }
+            self.reindex(&[cid]).await?;
+            return Ok(put);
+        }
+
+        // The whole write is a handful of blocking syscalls, so we ship it to the blocking pool
+        // as a single job rather than paying for a thread hop per `tokio::fs` call.
+        let (put, delta) = spawn_blocking(move || {
+            let block_dir = block_path.parent().unwrap(); // should always have a parent
+            let dir_bytes = shard_dirs.create(block_dir)?;
+
+            let _lock = write_locks.lock(&cid);
+            if packs.contains(&cid) {
+                let delta = StoreStats {
+                    disk_bytes: dir_bytes,
+                    ..StoreStats::default()
+                };
+                return Ok((Put::Existing, delta));
+            }
+            let (put, mut delta) = put_block_file(
+                &cid,
+                &block_path,
+                &data,
+                sync_policy,
+                temp_dir.as_deref(),
+                journal.as_deref(),
+                &shard_dirs,
+            )?;
+            delta.disk_bytes += dir_bytes;
+            Ok::<_, BlockstoreError>((put, delta))
+        })
+        .await?
+        .map_err(|e| self.out_of_space(e, size))?;
+
+        self.counters.add(&delta);
+
+        if let Some(bloom) = &self.bloom {
+            bloom.insert(&self.key(&block.cid));
+        }
+        self.reindex(std::slice::from_ref(&block.cid)).await?;
+
+        Ok(put)
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        let blocks: Vec<Block> = blocks
+            .iter()
+            .filter(|block| !is_inline(&block.cid))
+            .cloned()
+            .collect();
+        let blocks = blocks.as_slice();
+        for block in blocks {
+            self.check_size(block)?;
+        }
+        let sizes: Vec<(Cid, u64)> = blocks
+            .iter()
+            .map(|block| (block.cid, block.data.len() as u64))
+            .collect();
+        let _reservation = self.reserve(&sizes).await?;
+        let bytes = sizes.iter().map(|(_, size)| size).sum();
+        self.check_space(bytes).await?;
+        if let Some(committer) = self.grouped() {
+            // As with `put_block`, packed copies are looked for without the write locks.
+            let blocks: Vec<Block> = blocks
+                .iter()
+                .filter(|block| !self.packs.contains(&self.key(&block.cid)))
+                .cloned()
+                .collect();
+            return self
+                .put_grouped(committer, &blocks)
+                .await
+                .map_err(|e| self.out_of_space(e, bytes));
+        }
+
+        let mut by_dir: HashMap<PathBuf, Vec<(PathBuf, &Block)>> = HashMap::new();
+        for block in blocks {
+            let block_path = self.block_path(&block.cid);
+            let block_dir = block_path.parent().unwrap().to_path_buf();
+            by_dir.entry(block_dir).or_default().push((block_path, block));
+        }
+
+        let mut writes = JoinSet::new();
+        for (block_dir, entries) in by_dir {
+            let shard_dirs = self.shard_dirs.clone();
+            let dir_bytes = spawn_blocking(move || shard_dirs.create(&block_dir))
+                .await?
+                .map_err(|e| self.out_of_space(e, bytes))?;
+            self.counters.add(&StoreStats {
+                disk_bytes: dir_bytes,
+                ..StoreStats::default()
+            });
+
+            for (block_path, block) in entries {
+                let data = block.data.clone();
+                let sync_policy = self.sync_policy;
+                let temp_dir = self.temp_dir.clone();
+                let journal = self.journal.clone();
+                let write_locks = self.write_locks.clone();
+                let shard_dirs = self.shard_dirs.clone();
+                let packs = self.packs.clone();
+                let cid = self.key(&block.cid);
+                let file = self.file_permit(true).await;
+                writes.spawn_blocking(move || {
+                    let _file = file;
+                    let _lock = write_locks.lock(&cid);
+                    if packs.contains(&cid) {
+                        return Ok((Put::Existing, StoreStats::default()));
+                    }
+                    put_block_file(
+                        &cid,
+                        &block_path,
+                        &data,
+                        sync_policy,
+                        temp_dir.as_deref(),
+                        journal.as_deref(),
+                        &shard_dirs,
+                    )
+                });
+            }
+        }
+
+        // As with deletes, every write that went through has to be accounted for.
+        let mut first_error: Option<BlockstoreError> = None;
+        while let Some(result) = writes.join_next().await {
+            match result {
+                Ok(Ok((_, delta))) => self.counters.add(&delta),
+                Ok(Err(e)) => {
+                    first_error.get_or_insert_with(|| self.out_of_space(e, bytes));
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e.into());
+                }
+            }
+        }
+
+        if let Some(bloom) = &self.bloom {
+            for block in blocks {
+                bloom.insert(&self.key(&block.cid));
+            }
+        }
+
+        let cids: Vec<Cid> = blocks.iter().map(|block| block.cid).collect();
+        let reindexed = self.reindex(&cids).await;
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(reindexed?),
+        }
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        if is_inline(cid) {
+            return true;
+        }
+        if let Some(index) = &self.index {
+            return index.contains(&self.key(cid));
+        }
+        if !self.might_have(cid) {
+            return false;
+        }
+
+        // The file goes first, for the same reason as in `finish_read`.
+        let _file = self.file_permit(false).await;
+        tokio::fs::try_exists(self.block_path(cid))
+            .await
+            .unwrap_or(false)
+            || self.packs.contains(&self.key(cid))
+    }
+
+    async fn has_many(&self, cids: &[Cid]) -> Vec<bool> {
+        if let Some(index) = &self.index {
+            return cids
+                .iter()
+                .map(|cid| is_inline(cid) || index.contains(&self.key(cid)))
+                .collect();
+        }
+        let mut found = vec![false; cids.len()];
+        let mut checks = JoinSet::new();
+        for (i, cid) in cids.iter().enumerate() {
+            if is_inline(cid) {
+                found[i] = true;
+                continue;
+            }
+            if !self.might_have(cid) {
+                continue;
+            }
+            let permit = self.batch_permit().await;
+            let file = self.file_permit(false).await;
+            let block_path = self.block_path(cid);
+            checks.spawn_blocking(move || {
+                let _permit = permit;
+                let _file = file;
+                (i, block_path.try_exists().unwrap_or(false))
+            });
+        }
+
+        while let Some(result) = checks.join_next().await {
+            if let Ok((i, exists)) = result {
+                found[i] = exists;
+            }
+        }
+        for (found, cid) in found.iter_mut().zip(cids) {
+            *found = *found || (self.might_have(cid) && self.packs.contains(&self.key(cid)));
+        }
+        found
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if let Some(block) = Block::inline(cid) {
+            return Ok(Some(block));
+        }
+        let block_path = self.block_path(cid);
+        let file = self.file_permit(false).await;
+        let read = match self.read_mode {
+            ReadMode::Buffered => tokio::fs::read(&block_path).await.map(Bytes::from),
+            ReadMode::Mmap => {
+                let path = block_path.clone();
+                spawn_blocking(move || map_file(&path)).await?
+            }
+        };
+        drop(file);
+        self.finish_read(cid, &block_path, read).await
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, BlockstoreError> {
+        let mut blocks = vec![None; cids.len()];
+        let mut reads = JoinSet::new();
+        for (i, cid) in cids.iter().enumerate() {
+            if let Some(block) = Block::inline(cid) {
+                blocks[i] = Some(block);
+                continue;
+            }
+            let permit = self.batch_permit().await;
+            let file = self.file_permit(false).await;
+            let block_path = self.block_path(cid);
+            let read_mode = self.read_mode;
+            reads.spawn_blocking(move || {
+                let _permit = permit;
+                let _file = file;
+                let read = match read_mode {
+                    ReadMode::Buffered => fs::read(&block_path).map(Bytes::from),
+                    ReadMode::Mmap => map_file(&block_path),
+                };
+                (i, block_path, read)
+            });
+        }
+
+        let mut read = Vec::with_capacity(cids.len());
+        while let Some(result) = reads.join_next().await {
+            read.push(result?);
+        }
+        read.sort_unstable_by_key(|(i, _, _)| *i);
+
+        for (i, block_path, read) in read {
+            blocks[i] = self.finish_read(&cids[i], &block_path, read).await?;
+        }
+        Ok(blocks)
+    }
+
+    /// Warms the OS page cache for the blocks' files, a bounded number at a time.
+    fn prefetch(&self, cids: &[Cid]) {
+        let block_paths: Vec<PathBuf> = cids
+            .iter()
+            .filter(|cid| !is_inline(cid) && self.might_have(cid))
+            .map(|cid| self.block_path(cid))
+            .collect();
+        let permits = self.batch_permits.clone();
+        let file_limit = self.file_limit.clone();
+        tokio::spawn(async move {
+            for block_path in block_paths {
+                let permit = permits.clone().acquire_owned().await.expect("never closed");
+                let file = match &file_limit {
+                    Some(limit) => Some(limit.acquire(false).await),
+                    None => None,
+                };
+                spawn_blocking(move || {
+                    let _permit = permit;
+                    let _file = file;
+                    // It's only a hint, so missing blocks and errors alike are left for reads.
+                    let _ = warm_file(&block_path);
+                });
+            }
+        });
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(Some(cid.hash().size() as u64));
+        }
+        if let Some(index) = &self.index {
+            return Ok(index.get(&self.key(cid)).map(|entry| entry.size));
+        }
+        let _file = self.file_permit(false).await;
+        match tokio::fs::metadata(self.block_path(cid)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(self.packs.size(&self.key(cid))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(());
+        }
+        let file = self.file_permit(true).await;
+        let delta = spawn_blocking(self.delete_file(cid))
+            .await?
+            .map_err(|e| missing(e, cid))?;
+        drop(file);
+        self.forget(&delta);
+        self.reindex(std::slice::from_ref(cid)).await?;
+        self.settle_bloom().await;
+        Ok(())
+    }
+
+    async fn del_many(&self, cids: &[Cid]) -> Result<(), BlockstoreError> {
+        let mut deletes = JoinSet::new();
+        for (i, cid) in cids.iter().enumerate() {
+            if is_inline(cid) {
+                continue;
+            }
+            let permit = self.batch_permit().await;
+            let file = self.file_permit(true).await;
+            let delete = self.delete_file(cid);
+            deletes.spawn_blocking(move || {
+                let _permit = permit;
+                let _file = file;
+                (i, delete())
+            });
+        }
+
+        // Every delete that went through has to be accounted for, whatever happened to the others.
+        let mut first_error: Option<(usize, BlockstoreError)> = None;
+        while let Some(result) = deletes.join_next().await {
+            let (i, result) = result?;
+            match result {
+                Ok(delta) => self.forget(&delta),
+                Err(e) if first_error.as_ref().is_none_or(|(first, _)| i < *first) => {
+                    first_error = Some((i, missing(e, &cids[i])));
+                }
+                Err(_) => {}
+            }
+        }
+        let reindexed = self.reindex(cids).await;
+        self.settle_bloom().await;
+        match first_error {
+            Some((_, e)) => Err(e),
+            None => Ok(reindexed?),
+        }
+    }
+
+    /// Lists packed blocks after the rest. One that gets packed while the rest are being listed
+    /// can be listed twice, but never not at all.
+    fn blocks(&self) -> CidStream {
+        if let Some(index) = &self.index {
+            let keys = index.keys();
+            let (sender, receiver) = mpsc::channel(keys.len().max(1));
+            for key in keys {
+                // Can't fail: the channel has room for everything, and we hold the receiver.
+                sender.try_send(Ok(key)).unwrap();
+            }
+            return receiver;
+        }
+        let (sender, receiver) = mpsc::channel(CID_STREAM_BUFFER);
+        let root = self.root.clone();
+        let sharding = self.sharding.clone();
+        let packs = self.packs.clone();
+        spawn_blocking(move || {
+            let walked = walk(&root, Path::new(""), sharding.as_ref(), &mut |item| {
+                if let Ok(cid) = &item
+                    && packs.contains(&sharding.normalize(cid))
+                {
+                    return true;
+                }
+                sender.blocking_send(item.map_err(Into::into)).is_ok()
+            });
+            if walked {
+                for cid in packs.keys() {
+                    if sender.blocking_send(Ok(cid)).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        receiver
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        Ok(self.counters.snapshot())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::min;
+    use std::collections::HashSet;
+    use std::fs;
+    use tempfile::{tempdir, TempDir};
+    use crate::block::{make_random_block, to_v0, Codec, Hasher};
+    use crate::flatfs;
+    use crate::sharding::{Flat, FlatFs, Suffix, TwoLevel};
+
+    pub async fn make_fs_store() -> (FSStore, TempDir) {
+        let tempdir = tempdir().unwrap();
+        (
+            FSStore::create(PathBuf::from(tempdir.path()))
+                .await
+                .unwrap(),
+            tempdir,
+        )
+    }
+
+    crate::conformance::conformance_tests!(make_fs_store);
+
+    #[test]
+    fn should_compute_correct_block_path() {
+        let block = make_random_block(1_000);
+        let cid_str = format!("{}", &block.cid);
+        let cpl = Prefix::default().chars_per_level;
+        let block_path = FSStore::block_path_raw(cpl, &block.cid);
+
+        for (i, component) in block_path.components().enumerate() {
+            assert_eq!(
+                &cid_str[i * cpl..min(cid_str.len(), (i + 1) * cpl)],
+                component.as_os_str().to_str().unwrap()
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_put_block() {
+        let (store, _) = make_fs_store().await;
+        let block = make_random_block(1_000);
+
+        let put = store.put_block(&block).await.unwrap();
+        assert_eq!(put, Put::Written { bytes: 1_000 });
+
+        let path = store.block_path(&block.cid);
+        assert_eq!(fs::read(path).unwrap(), block.data);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_not_rewrite_existing_block() {
+        let (store, _root) = make_fs_store().await;
+        let block = make_random_block(1_000);
+        store.put_block(&block).await.unwrap();
+
+        // If the put touched the file, it would put the right contents back.
+        let path = store.block_path(&block.cid);
+        fs::write(&path, b"clobbered").unwrap();
+        assert_eq!(store.put_block(&block).await.unwrap(), Put::Existing);
+        assert_eq!(fs::read(path).unwrap(), b"clobbered");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_put_block_with_every_sync_policy() {
+        for policy in [SyncPolicy::None, SyncPolicy::DataOnly, SyncPolicy::DataAndDir] {
+            let (store, _root) = make_fs_store().await;
+            let store = store.with_sync_policy(policy);
+            let block = make_random_block(1_000);
+
+            store.put_block(&block).await.unwrap();
+
+            let path = store.block_path(&block.cid);
+            assert_eq!(fs::read(path).unwrap(), block.data);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn should_not_corrupt_block_under_concurrent_puts() {
+        let (store, _) = make_fs_store().await;
+        let store = std::sync::Arc::new(store);
+        let block = std::sync::Arc::new(make_random_block(100_000));
+
+        let mut puts = JoinSet::new();
+        for _ in 0..16 {
+            let (store, block) = (store.clone(), block.clone());
+            puts.spawn(async move { store.put_block(&block).await });
+        }
+        while let Some(result) = puts.join_next().await {
+            result.unwrap().unwrap();
+        }
+
+        let path = store.block_path(&block.cid);
+        assert_eq!(fs::read(&path).unwrap(), block.data);
+
+        // No temporary files should be left behind.
+        let entries: Vec<_> = fs::read_dir(path.parent().unwrap()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_put_many_blocks() {
+        let (store, _) = make_fs_store().await;
+        let blocks: Vec<Block> = (0..20).map(|_| make_random_block(1_000)).collect();
+
+        store.put_many(&blocks).await.unwrap();
+
+        for block in &blocks {
+            let path = store.block_path(&block.cid);
+            assert_eq!(fs::read(path).unwrap(), block.data);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_get_block() {
+        let (store, _) = make_fs_store().await;
+        let stored = make_random_block(1_000);
+
+        store.put_block(&stored).await.unwrap();
+        let retrieved = store.get_block(&stored.cid).await.unwrap().unwrap();
+        assert_eq!(stored, retrieved);
+    }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_get_block_size() {
+        let (store, _root) = make_fs_store().await;
+        let block = make_random_block(1_234);
+
+        assert_eq!(store.block_size(&block.cid).await.unwrap(), None);
+        store.put_block(&block).await.unwrap();
+        assert_eq!(store.block_size(&block.cid).await.unwrap(), Some(1_234));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_contain_stored_blocks() {
+        let (store, _) = make_fs_store().await;
+        let block = make_random_block(1_000);
+
+        assert!(!store.has_block(&block.cid).await);
+        store.put_block(&block).await.unwrap();
+        assert!(store.has_block(&block.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_delete_block() {
+        let (store, _) = make_fs_store().await;
+        let block = make_random_block(1_000);
 
-const DEFAULT_CHARS_PER_LEVEL: usize = 15;
+        store.put_block(&block).await.unwrap();
+        assert!(store.has_block(&block.cid).await);
+        store.del_block(&block.cid).await.unwrap();
+        assert!(!store.has_block(&block.cid).await);
 
-impl FSStore {
-    pub async fn create(root: PathBuf) -> Result<Self, io::Error> {
-        let root_ref = &root;
-        if !root_ref.exists() {
-            create_dir_all(root_ref)?
-        }
+        let path = store.block_path(&block.cid);
+        assert!(!path.exists());
+    }
 
-        Ok(FSStore {
-            root,
-            chars_per_level: DEFAULT_CHARS_PER_LEVEL,
-        })
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn should_keep_deleting_past_failures() {
+        let (store, root) = make_fs_store().await;
+        let blocks: Vec<Block> = (0..100).map(|_| make_random_block(100)).collect();
+        store.put_many(&blocks).await.unwrap();
+        let missing = make_random_block(100).cid;
+
+        let mut cids: Vec<Cid> = blocks.iter().map(|block| block.cid).collect();
+        cids.insert(50, missing);
+        let err = store.del_many(&cids).await.unwrap_err();
+        assert!(matches!(err, BlockstoreError::NotFound(cid) if cid == missing));
+
+        assert!(store.has_many(&cids).await.iter().all(|found| !found));
+        assert_eq!(store.stats().await.unwrap().blocks, 0);
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(reopened.stats().await.unwrap(), store.stats().await.unwrap());
     }
 
-    pub fn block_path_raw(chars_per_level: usize, cid: &Cid) -> PathBuf {
-        // This is a bit ugly but chunks only works on slices and I was feeling lazy. :-)
-        let parts: Vec<String> = format!("{}", cid)
-            .as_bytes()
-            .chunks(chars_per_level)
-            .map(|chunk| str::from_utf8(&chunk).unwrap().to_string())
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_prefetch_in_the_background() {
+        let (store, _root) = make_fs_store().await;
+        let blocks: Vec<Block> = (0..100).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks).await.unwrap();
+        assert!(warm_file(&store.block_path(&blocks[0].cid)).is_ok());
+
+        let mut cids: Vec<Cid> = blocks.iter().map(|block| block.cid).collect();
+        cids.push(make_random_block(1_000).cid);
+        store.prefetch(&cids);
+        // Prefetching hands back its permits, missing block and all, so batches don't starve.
+        let permits = store.batch_permits.acquire_many(BATCH_CONCURRENCY as u32).await;
+        drop(permits.unwrap());
+        assert_eq!(store.get_many(&cids).await.unwrap().iter().flatten().count(), 100);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_prune_empty_shard_dirs_on_delete() {
+        let (store, root) = make_fs_store().await;
+        let (kept, deleted) = (make_random_block(1_000), make_random_block(1_000));
+        store.put_many(&[kept.clone(), deleted.clone()]).await.unwrap();
+
+        store.del_block(&deleted.cid).await.unwrap();
+        assert!(!store.block_path(&deleted.cid).parent().unwrap().exists());
+        // Directories shared with the block still there stay.
+        assert!(store.block_path(&kept.cid).exists());
+
+        store.del_block(&kept.cid).await.unwrap();
+        let left: Vec<_> = fs::read_dir(root.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| !name.to_string_lossy().starts_with('.'))
             .collect();
+        assert!(left.is_empty(), "{:?}", left);
 
-        parts.iter().collect()
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(reopened.stats().await.unwrap(), store.stats().await.unwrap());
     }
 
-    pub fn block_path(&self, cid: &Cid) -> PathBuf {
-        let rawpath = Self::block_path_raw(self.chars_per_level, &cid);
-        self.root.join(rawpath)
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn should_not_lose_puts_to_concurrent_pruning() {
+        let root = tempdir().unwrap();
+        // With only a handful of shard directories, blocks share them all the time.
+        let store = FSStore::create_with_sharding(PathBuf::from(root.path()), Suffix { chars: 1 })
+            .await
+            .unwrap();
+        let store = Arc::new(store);
+
+        // Every task keeps its last block, while all the others get deleted right away, pruning
+        // the directories the other tasks' puts are writing into.
+        let mut tasks = JoinSet::new();
+        for _ in 0..8 {
+            let store = store.clone();
+            tasks.spawn(async move {
+                for _ in 0..50 {
+                    let block = make_random_block(100);
+                    store.put_block(&block).await.unwrap();
+                    store.del_block(&block.cid).await.unwrap();
+                }
+                let block = make_random_block(100);
+                store.put_block(&block).await.unwrap();
+                block
+            });
+        }
+        let kept = tasks.join_all().await;
+
+        for block in &kept {
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), *block);
+        }
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(reopened.stats().await.unwrap(), store.stats().await.unwrap());
     }
-}
 
-impl Drop for FSStore {
-    fn drop(&mut self) {}
-}
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_list_stored_blocks() {
+        let (store, root) = make_fs_store().await;
+        let blocks: Vec<Block> = (0..20).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks).await.unwrap();
+        // Leftovers from an interrupted write shouldn't show up as blocks.
+        fs::write(root.path().join(".tmp-leftover"), b"junk").unwrap();
 
-impl Blockstore for FSStore {
-    async fn put_block(&self, block: &Block) -> Result<(), io::Error> {
-        let block_path = self.block_path(&block.cid);
-        let block_dir = block_path.parent().unwrap(); // should always have a parent
+        let mut listed = HashSet::new();
+        let mut stream = store.blocks();
+        while let Some(cid) = stream.recv().await {
+            listed.insert(cid.unwrap());
+        }
 
-        // This is thread-safe, as per
-        // https://doc.rust-lang.org/stable/std/fs/fn.create_dir_all.html
-        create_dir_all(&block_dir)?;
+        assert_eq!(listed, blocks.iter().map(|b| b.cid).collect());
+    }
 
-        // This is not thread-safe, and might cause a block to be corrupted.
-        let mut file = File::create(&block_path)?;
-        file.write_all(&block.data)?;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_report_foreign_files_when_listing() {
+        let (store, root) = make_fs_store().await;
+        fs::write(root.path().join("not-a-cid"), b"junk").unwrap();
 
-        Ok(())
+        let mut stream = store.blocks();
+        let err = stream.recv().await.unwrap().unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(stream.recv().await.is_none());
     }
 
-    async fn has_block(&self, cid: &Cid) -> bool {
-        self.block_path(cid).exists()
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_stats_up_to_date() {
+        let (store, root) = make_fs_store().await;
+        let blocks: Vec<Block> = (0..10).map(|_| make_random_block(1_000)).collect();
+
+        store.put_many(&blocks[..5]).await.unwrap();
+        for block in &blocks[5..] {
+            store.put_block(block).await.unwrap();
+        }
+        // Re-putting an existing block shouldn't count it twice.
+        store.put_block(&blocks[0]).await.unwrap();
+        store.del_block(&blocks[1].cid).await.unwrap();
+
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.blocks, 9);
+        assert_eq!(stats.bytes, 9_000);
+        assert!(stats.disk_bytes >= stats.bytes);
+
+        // A freshly opened store measures the tree from scratch, and should agree.
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(reopened.stats().await.unwrap(), stats);
     }
 
-    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, io::Error> {
-        let block_path = self.block_path(&cid);
-        let contents = fs::read(block_path)?;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_serve_inline_blocks() {
+        let (store, _root) = make_fs_store().await;
+        let inline = Block::new_inline(&b"hello"[..], Codec::Raw).unwrap();
+        let stored = make_random_block(100);
+        assert!(Block::new_inline(vec![0; 65], Codec::Raw).is_err());
+
+        // Never having been put, it's there anyway.
+        assert!(store.has_block(&inline.cid).await);
+        assert_eq!(store.get_block(&inline.cid).await.unwrap(), Some(inline.clone()));
+        assert_eq!(store.block_size(&inline.cid).await.unwrap(), Some(5));
 
-        match Block::new(contents) {
-            Ok(block) => Ok(Some(block)),
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        assert_eq!(store.put_block(&inline).await.unwrap(), Put::Existing);
+        store.put_many(&[inline.clone(), stored.clone()]).await.unwrap();
+        assert!(!store.block_path(&inline.cid).exists());
+        let stats = store.stats().await.unwrap();
+        assert_eq!((stats.blocks, stats.bytes), (1, 100));
+        assert_eq!(
+            store.has_many(&[stored.cid, inline.cid]).await,
+            vec![true, true]
+        );
+        assert_eq!(
+            store.get_many(&[inline.cid, stored.cid]).await.unwrap(),
+            vec![Some(inline.clone()), Some(stored.clone())]
+        );
+        let mut listed = Vec::new();
+        let mut cids = store.blocks();
+        while let Some(cid) = cids.recv().await {
+            listed.push(cid.unwrap());
         }
+        assert_eq!(listed, vec![stored.cid]);
+
+        // Deleting it is a no-op, since the CID still holds it.
+        store.del_many(&[inline.cid, stored.cid]).await.unwrap();
+        store.del_block(&inline.cid).await.unwrap();
+        assert!(store.has_block(&inline.cid).await);
+        assert_eq!(store.stats().await.unwrap().blocks, 0);
     }
 
-    async fn del_block(&self, cid: &Cid) -> Result<(), io::Error> {
-        let block_path = self.block_path(&cid);
-         fs::remove_file(&block_path)
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_compact_small_blocks() {
+        let (store, root) = make_fs_store().await;
+        let small: Vec<Block> = (0..20).map(|_| make_random_block(100)).collect();
+        let large = make_random_block(10_000);
+        store.put_many(&small).await.unwrap();
+        store.put_block(&large).await.unwrap();
+        let before = store.stats().await.unwrap();
+
+        let compaction = store.compact(1_000).await.unwrap();
+        assert_eq!(compaction, Compaction { packed: 20, repacked: 0 });
+        assert!(!store.block_path(&small[0].cid).exists());
+        assert!(store.block_path(&large.cid).exists());
+        for block in small.iter().chain([&large]) {
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), *block);
+            assert!(store.has_block(&block.cid).await);
+            let size = block.data.len() as u64;
+            assert_eq!(store.block_size(&block.cid).await.unwrap(), Some(size));
+        }
+        let stats = store.stats().await.unwrap();
+        assert_eq!((stats.blocks, stats.bytes), (before.blocks, before.bytes));
+        assert!(stats.disk_bytes < before.disk_bytes);
+
+        let mut listed = Vec::new();
+        let mut cids = store.blocks();
+        while let Some(cid) = cids.recv().await {
+            listed.push(cid.unwrap());
+        }
+        listed.sort();
+        let mut expected: Vec<Cid> = small.iter().chain([&large]).map(|b| b.cid).collect();
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        assert_eq!(store.put_block(&small[0]).await.unwrap(), Put::Existing);
+        store.del_block(&small[0].cid).await.unwrap();
+        assert!(!store.has_block(&small[0].cid).await);
+        assert!(store.get_block(&small[0].cid).await.unwrap().is_none());
+        assert!(matches!(
+            store.del_block(&small[0].cid).await,
+            Err(BlockstoreError::NotFound(_))
+        ));
+
+        // Deletes are kept across reopens, and the stats still agree.
+        let stats = store.stats().await.unwrap();
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(reopened.stats().await.unwrap(), stats);
+        assert!(!reopened.has_block(&small[0].cid).await);
+        for block in &small[1..] {
+            assert_eq!(reopened.get_block(&block.cid).await.unwrap().unwrap(), *block);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::cmp::min;
-    use std::fs;
-    use tempfile::{tempdir, TempDir};
-    use crate::block::make_random_block;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_rewrite_mostly_deleted_packs() {
+        let (store, root) = make_fs_store().await;
+        let blocks: Vec<Block> = (0..10).map(|_| make_random_block(100)).collect();
+        store.put_many(&blocks).await.unwrap();
+        store.compact(1_000).await.unwrap();
+        for block in &blocks[..6] {
+            store.del_block(&block.cid).await.unwrap();
+        }
+        let extra = make_random_block(100);
+        store.put_block(&extra).await.unwrap();
 
-    pub async fn make_fs_store() -> (FSStore, TempDir) {
-        let tempdir = tempdir().unwrap();
-        (
-            FSStore::create(PathBuf::from(tempdir.path()))
-                .await
-                .unwrap(),
-            tempdir,
-        )
+        let compaction = store.compact(1_000).await.unwrap();
+        assert_eq!(compaction, Compaction { packed: 1, repacked: 1 });
+        let packs = |root: &Path| {
+            fs::read_dir(root.join(PACKS_DIR))
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("car".as_ref()))
+                .count()
+        };
+        assert_eq!(packs(root.path()), 1);
+        for block in blocks[6..].iter().chain([&extra]) {
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), *block);
+        }
+        assert_eq!(store.stats().await.unwrap().blocks, 5);
+
+        // Nothing's left to do, and the old pack's deletes don't come back to the new one.
+        assert_eq!(store.compact(1_000).await.unwrap(), Compaction::default());
+        let stats = store.stats().await.unwrap();
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(reopened.stats().await.unwrap(), stats);
+        assert!(!reopened.has_block(&blocks[0].cid).await);
+        assert!(reopened.has_block(&blocks[6].cid).await);
     }
 
-    #[test]
-    fn should_compute_correct_block_path() {
-        let block = make_random_block(1_000);
-        let cid_str = format!("{}", &block.cid);
-        let cpl = DEFAULT_CHARS_PER_LEVEL;
-        let block_path = FSStore::block_path_raw(cpl, &block.cid);
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn should_compact_while_deleting() {
+        let (store, root) = make_fs_store().await;
+        let store = Arc::new(store);
+        let blocks: Vec<Block> = (0..200).map(|_| make_random_block(100)).collect();
+        store.put_many(&blocks).await.unwrap();
 
-        for (i, component) in block_path.components().enumerate() {
-            assert_eq!(
-                &cid_str[i * cpl..min(cid_str.len(), (i + 1) * cpl)],
-                component.as_os_str().to_str().unwrap()
-            );
+        let doomed: Vec<Cid> = blocks.iter().step_by(2).map(|block| block.cid).collect();
+        let deleter = {
+            let store = store.clone();
+            tokio::spawn(async move {
+                for cid in doomed {
+                    store.del_block(&cid).await.unwrap();
+                }
+            })
+        };
+        store.compact(1_000).await.unwrap();
+        deleter.await.unwrap();
+        store.compact(1_000).await.unwrap();
+
+        let stats = store.stats().await.unwrap();
+        assert_eq!((stats.blocks, stats.bytes), (100, 10_000));
+        // Disk usage is left out, since the root directory can have grown with all the shard
+        // directories that were in it.
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let reopened_stats = reopened.stats().await.unwrap();
+        assert_eq!((reopened_stats.blocks, reopened_stats.bytes), (100, 10_000));
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(reopened.has_block(&block.cid).await, i % 2 == 1);
         }
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn should_put_block() {
-        let (store, _) = make_fs_store().await;
-        let block = make_random_block(1_000);
+    async fn should_track_blocks_in_bloom_filter() {
+        let (store, root) = make_fs_store().await;
+        let existing = make_random_block(1_000);
+        store.put_block(&existing).await.unwrap();
+
+        let store = FSStore::create(PathBuf::from(root.path()))
+            .await
+            .unwrap()
+            .with_bloom_filter(100, 0.01)
+            .await
+            .unwrap();
+        assert!(store.has_block(&existing.cid).await);
 
+        let block = make_random_block(1_000);
+        assert!(!store.has_block(&block.cid).await);
         store.put_block(&block).await.unwrap();
+        assert!(store.has_block(&block.cid).await);
 
-        let path = store.block_path(&block.cid);
-        assert_eq!(fs::read(path).unwrap(), block.data);
+        store.del_block(&existing.cid).await.unwrap();
+        assert!(!store.has_block(&existing.cid).await);
+        // Deleted blocks only leave the filter once it gets rebuilt.
+        let bloom = store.bloom.as_ref().unwrap();
+        assert!(bloom.contains(&existing.cid));
+        store.rebuild_bloom_filter().await.unwrap();
+        assert!(!bloom.contains(&existing.cid));
+        assert!(bloom.contains(&block.cid));
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn should_get_block() {
-        let (store, _) = make_fs_store().await;
-        let stored = make_random_block(1_000);
+    async fn should_not_lose_blocks_from_bloom_filter_on_delete() {
+        let (store, root) = make_fs_store().await;
+        let kept = make_random_block(1_000);
+        store.put_block(&kept).await.unwrap();
 
-        store.put_block(&stored).await.unwrap();
-        let retrieved = store.get_block(&stored.cid).await.unwrap().unwrap();
-        assert_eq!(stored, retrieved);
+        // A filter this small has everything collide, so it takes any block for the one it has.
+        let filtered = FSStore::create(PathBuf::from(root.path()))
+            .await
+            .unwrap()
+            .with_bloom_filter(1, 0.99)
+            .await
+            .unwrap();
+        let other = make_random_block(1_000);
+        store.put_block(&other).await.unwrap();
+        assert!(filtered.has_block(&other.cid).await);
+
+        // Taking the block put by the other instance off the filter would take `kept` with it.
+        filtered.del_block(&other.cid).await.unwrap();
+        assert!(filtered.has_block(&kept.cid).await);
+        assert!(!filtered.has_block(&other.cid).await);
+        // Deleting as many blocks as the filter is sized for got it rebuilt.
+        let state = filtered.bloom.as_ref().unwrap().state.lock().unwrap();
+        assert_eq!(state.stale, 0);
     }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn should_contain_stored_blocks() {
-        let (store, _) = make_fs_store().await;
-        let block = make_random_block(1_000);
+    async fn should_answer_from_index() {
+        let root = tempdir().unwrap();
+        let open = || async {
+            FSStore::create(PathBuf::from(root.path()))
+                .await
+                .unwrap()
+                .with_index()
+                .await
+                .unwrap()
+        };
+        let blocks: Vec<Block> = (0..4).map(|_| make_random_block(1_000)).collect();
+        let store = open().await;
+        store.put_many(&blocks[..3]).await.unwrap();
+        store.del_block(&blocks[2].cid).await.unwrap();
 
-        assert!(!store.has_block(&block.cid).await);
+        let modified = fs::metadata(store.block_path(&blocks[0].cid))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(
+            store.indexed(&blocks[0].cid),
+            Some(IndexEntry {
+                size: 1_000,
+                modified
+            })
+        );
+        assert_eq!(store.indexed(&blocks[2].cid), None);
+        assert_eq!(
+            store.has_many(&[blocks[0].cid, blocks[2].cid]).await,
+            vec![true, false]
+        );
+
+        // Blocks the index doesn't know about might as well not be there, until it's rebuilt.
+        let path = store.block_path(&blocks[3].cid);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, &blocks[3].data).unwrap();
+        assert!(!store.has_block(&blocks[3].cid).await);
+        assert_eq!(store.block_size(&blocks[3].cid).await.unwrap(), None);
+        store.rebuild_index().await.unwrap();
+        assert!(store.has_block(&blocks[3].cid).await);
+        assert_eq!(store.block_size(&blocks[3].cid).await.unwrap(), Some(1_000));
+
+        // Packed blocks stay indexed, and are found by rebuilds too.
+        store.compact(10_000).await.unwrap();
+        store.rebuild_index().await.unwrap();
+        let mut listed = Vec::new();
+        let mut cids = store.blocks();
+        while let Some(cid) = cids.recv().await {
+            listed.push(cid.unwrap());
+        }
+        listed.sort();
+        let mut expected = vec![blocks[0].cid, blocks[1].cid, blocks[3].cid];
+        expected.sort();
+        assert_eq!(listed, expected);
+        drop(store);
+
+        // The index gets reused when the store is reopened, unless it no longer adds up.
+        let store = open().await;
+        assert_eq!(store.indexed(&blocks[0].cid).unwrap().size, 1_000);
+        FSStore::create(PathBuf::from(root.path()))
+            .await
+            .unwrap()
+            .put_block(&blocks[2])
+            .await
+            .unwrap();
+        assert!(!store.has_block(&blocks[2].cid).await);
+        drop(store);
+        let store = open().await;
+        assert!(store.has_block(&blocks[2].cid).await);
+
+        // A log cut short loses its last line, and the mismatch gets it rebuilt.
+        drop(store);
+        let path = root.path().join(INDEX_FILE);
+        let log = fs::read(&path).unwrap();
+        fs::write(&path, &log[..log.len() - 1]).unwrap();
+        assert_eq!(BlockIndex::load(&path).unwrap().unwrap().len(), 3);
+        let store = open().await;
+        assert_eq!(store.stats().await.unwrap().blocks, 4);
+        for block in &blocks {
+            assert!(store.has_block(&block.cid).await);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_clean_up_when_opened_checked() {
+        let (store, root) = make_fs_store().await;
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks[..2]).await.unwrap();
+        let dropped = store.namespace("dropped").await.unwrap();
+        dropped.put_block(&blocks[0]).await.unwrap();
+
+        // What a crash in the middle of a put, and of dropping a namespace, would leave behind.
+        let block_path = store.block_path(&blocks[0].cid);
+        let temp_path = temp_path(&block_path, None);
+        fs::write(&temp_path, &blocks[0].data).unwrap();
+        let namespaces = root.path().join(NAMESPACES_DIR);
+        let doomed = namespaces.join(format!("{}dropped-0123456789abcdef", TEMP_PREFIX));
+        fs::rename(namespaces.join("dropped"), &doomed).unwrap();
+        drop(dropped);
+        drop(store);
+
+        let (store, checked) = FSStore::open_checked(PathBuf::from(root.path()))
+            .await
+            .unwrap();
+        let mut expected = vec![temp_path.clone(), doomed.clone()];
+        expected.sort();
+        let mut removed = checked.temp_files.clone();
+        removed.sort();
+        assert_eq!(removed, expected);
+        assert_eq!(checked.reindexed, None);
+        assert!(!temp_path.exists() && !doomed.exists());
+        assert!(block_path.exists());
+        assert!(store.indexed(&blocks[0].cid).is_none());
+
+        // An index that's fallen behind gets caught up.
+        let store = store.with_index().await.unwrap();
+        fs::remove_file(&block_path).unwrap();
+        let other_path = store.block_path(&blocks[2].cid);
+        fs::create_dir_all(other_path.parent().unwrap()).unwrap();
+        fs::write(&other_path, &blocks[2].data).unwrap();
+        drop(store);
+        let (store, checked) = FSStore::open_checked(PathBuf::from(root.path()))
+            .await
+            .unwrap();
+        assert_eq!(checked.temp_files, Vec::<PathBuf>::new());
+        assert_eq!(checked.reindexed, Some(2));
+        assert!(!store.has_block(&blocks[0].cid).await);
+        assert!(store.has_block(&blocks[1].cid).await);
+        assert!(store.has_block(&blocks[2].cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_detect_corrupted_blocks_when_verifying() {
+        let (store, _root) = make_fs_store().await;
+        let store = store.with_verify_mode(VerifyMode::Verify);
+        let block = make_random_block(1_000);
         store.put_block(&block).await.unwrap();
+        fs::write(store.block_path(&block.cid), b"bit rot").unwrap();
+
+        let err = store.get_block(&block.cid).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let BlockstoreError::Corrupt(corruption) = err else {
+            panic!("expected corruption, got {:?}", err);
+        };
+        let expected = Corruption {
+            expected: block.cid,
+            actual: Block::new(&b"bit rot"[..]).unwrap().cid,
+            quarantined: None,
+        };
+        assert_eq!(*corruption, expected);
         assert!(store.has_block(&block.cid).await);
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn should_delete_block() {
-        let (store, _) = make_fs_store().await;
+    async fn should_quarantine_corrupted_blocks() {
+        let (store, root) = make_fs_store().await;
+        let store = store.with_verify_mode(VerifyMode::Quarantine);
         let block = make_random_block(1_000);
+        store.put_block(&block).await.unwrap();
+        fs::write(store.block_path(&block.cid), b"bit rot").unwrap();
+
+        let err = store.get_block(&block.cid).await.unwrap_err();
+        let BlockstoreError::Corrupt(corruption) = err else {
+            panic!("expected corruption, got {:?}", err);
+        };
+        let quarantined = corruption.quarantined.unwrap();
+        assert_eq!(quarantined, root.path().join(QUARANTINE_DIR).join(block.cid.to_string()));
+        assert_eq!(fs::read(quarantined).unwrap(), b"bit rot");
+
+        assert!(!store.has_block(&block.cid).await);
+        assert_eq!(store.stats().await.unwrap().blocks, 0);
+        let mut cids = store.blocks();
+        assert!(cids.recv().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_store_v0_blocks_under_v1_path() {
+        let (store, _root) = make_fs_store().await;
+        let data = make_random_block(1_000).data;
+        let v1 = Block::new_with_codec(data.clone(), Codec::DagPb, Hasher::Sha2_256)
+            .unwrap()
+            .cid;
+        let v0 = to_v0(&v1).unwrap();
+        store.put_block(&Block::with_cid(v0, data).unwrap()).await.unwrap();
+
+        assert!(store.block_path(&v1).exists());
+        assert_eq!(store.block_path(&v0), store.block_path(&v1));
+        assert!(store.has_block(&v1).await);
+        assert_eq!(store.get_block(&v0).await.unwrap().unwrap().cid, v0);
+        assert_eq!(store.get_block(&v1).await.unwrap().unwrap().cid, v1);
+        let mut cids = store.blocks();
+        assert_eq!(cids.recv().await.unwrap().unwrap(), v1);
+
+        store.del_block(&v1).await.unwrap();
+        assert!(!store.has_block(&v0).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_read_mapped_blocks() {
+        let (store, _root) = make_fs_store().await;
+        let store = store
+            .with_read_mode(ReadMode::Mmap)
+            .with_verify_mode(VerifyMode::Verify);
+        let blocks = [make_random_block(4 << 20), make_random_block(0)];
+        store.put_many(&blocks).await.unwrap();
+
+        for block in &blocks {
+            let read = store.get_block(&block.cid).await.unwrap().unwrap();
+            assert_eq!(read.data, block.data);
+        }
+        let missing = make_random_block(100);
+        assert_eq!(store.get_block(&missing.cid).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_close_journal_entries() {
+        let (store, root) = make_fs_store().await;
+        let store = store.with_journal().await.unwrap();
+        let block = make_random_block(100);
 
         store.put_block(&block).await.unwrap();
-        assert!(store.has_block(&block.cid).await);
         store.del_block(&block.cid).await.unwrap();
-        assert!(!store.has_block(&block.cid).await);
+        assert!(store.del_block(&block.cid).await.is_err());
 
-        let path = store.block_path(&block.cid);
-        assert!(!path.exists());
+        let journal = fs::read_to_string(root.path().join(JOURNAL_FILE)).unwrap();
+        let expected = format!(
+            "0 put {0}\n0 done\n1 del {0}\n1 done\n2 del {0}\n2 done\n",
+            block.cid
+        );
+        assert_eq!(journal, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_recover_interrupted_operations() {
+        let (store, root) = make_fs_store().await;
+        let kept = make_random_block(100);
+        let deleted = make_random_block(100);
+        let written = make_random_block(100);
+        store.put_many(&[kept.clone(), deleted.clone()]).await.unwrap();
+
+        // What a crash halfway through deleting one block and putting another would leave, with
+        // the `done` of the put cut short.
+        let temp_path = temp_path(&store.block_path(&written.cid), None);
+        fs::create_dir_all(temp_path.parent().unwrap()).unwrap();
+        fs::write(&temp_path, &written.data).unwrap();
+        let journal = format!(
+            "0 put {}\n0 done\n1 del {}\n2 put {}\n2 do",
+            kept.cid, deleted.cid, written.cid
+        );
+        fs::write(root.path().join(JOURNAL_FILE), journal).unwrap();
+        drop(store);
+
+        let store = FSStore::create(root.path().to_path_buf())
+            .await
+            .unwrap()
+            .with_journal()
+            .await
+            .unwrap();
+
+        assert!(!temp_path.exists());
+        assert!(store.has_block(&kept.cid).await);
+        assert!(!store.has_block(&deleted.cid).await);
+        assert!(!store.has_block(&written.cid).await);
+        assert_eq!(store.stats().await.unwrap().blocks, 1);
+        assert!(fs::read_to_string(root.path().join(JOURNAL_FILE)).unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn should_count_concurrent_puts_of_a_block_once() {
+        let (store, _root) = make_fs_store().await;
+        let store = Arc::new(store);
+        let block = make_random_block(1_000);
+
+        let mut puts = JoinSet::new();
+        for _ in 0..32 {
+            let store = store.clone();
+            let block = block.clone();
+            puts.spawn(async move { store.put_block(&block).await });
+        }
+        while let Some(result) = puts.join_next().await {
+            result.unwrap().unwrap();
+        }
+
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.blocks, 1);
+        assert_eq!(stats.bytes, 1_000);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn should_count_concurrent_puts_through_namespace_views_once() {
+        let (store, _root) = make_fs_store().await;
+        let views = [
+            Arc::new(store.namespace("tenant").await.unwrap()),
+            Arc::new(store.namespace("tenant").await.unwrap()),
+        ];
+        assert!(Arc::ptr_eq(&views[0].write_locks, &views[1].write_locks));
+        assert!(Arc::ptr_eq(&views[0].shard_dirs, &views[1].shard_dirs));
+        let block = make_random_block(1_000);
+
+        let mut puts = JoinSet::new();
+        for i in 0..32 {
+            let view = views[i % 2].clone();
+            let block = block.clone();
+            puts.spawn(async move { view.put_block(&block).await });
+        }
+        let mut written = 0;
+        while let Some(result) = puts.join_next().await {
+            written += result.unwrap().unwrap().is_written() as usize;
+        }
+
+        assert_eq!(written, 1);
+        let stats = views[0].stats().await.unwrap();
+        assert_eq!((stats.blocks, stats.bytes), (1, 1_000));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_namespaces_apart() {
+        let (store, root) = make_fs_store().await;
+        let store = store.with_sync_policy(SyncPolicy::DataOnly);
+        let tenant_a = store.namespace("tenant-a").await.unwrap();
+        let tenant_b = store.namespace("tenant_b").await.unwrap();
+        assert_eq!(tenant_a.sync_policy(), SyncPolicy::DataOnly);
+        let (shared, own) = (make_random_block(100), make_random_block(200));
+
+        store.put_block(&shared).await.unwrap();
+        tenant_a.put_block(&shared).await.unwrap();
+        tenant_a.put_block(&own).await.unwrap();
+
+        assert!(tenant_a.has_block(&own.cid).await);
+        assert!(!tenant_b.has_block(&own.cid).await);
+        assert!(!store.has_block(&own.cid).await);
+        assert_eq!(tenant_a.stats().await.unwrap().bytes, 300);
+        assert_eq!(tenant_b.stats().await.unwrap().blocks, 0);
+        // The parent sees neither the namespaces' blocks nor their disk usage.
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.blocks, 1);
+        let mut listed = Vec::new();
+        let mut cids = store.blocks();
+        while let Some(cid) = cids.recv().await {
+            listed.push(cid.unwrap());
+        }
+        assert_eq!(listed, vec![shared.cid]);
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(reopened.stats().await.unwrap(), stats);
+
+        let mut names = store.namespaces().await.unwrap();
+        names.sort();
+        assert_eq!(names, vec!["tenant-a", "tenant_b"]);
+
+        store.drop_namespace("tenant-a").await.unwrap();
+        assert_eq!(store.namespaces().await.unwrap(), vec!["tenant_b"]);
+        let tenant_a = store.namespace("tenant-a").await.unwrap();
+        assert!(!tenant_a.has_block(&own.cid).await);
+        assert_eq!(tenant_a.stats().await.unwrap().blocks, 0);
+        assert!(store.has_block(&shared.cid).await);
+
+        for name in ["", ".hidden", "a/b", "..", "tenant a"] {
+            assert!(store.namespace(name).await.is_err(), "{:?}", name);
+        }
+        assert!(store.drop_namespace("missing").await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_enforce_namespace_quotas() {
+        let (store, root) = make_fs_store().await;
+        let tenant = store.namespace("tenant").await.unwrap();
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+        tenant.put_block(&blocks[0]).await.unwrap();
+
+        // Stores opened before the quota was set are held to it too.
+        store.set_namespace_quota("tenant", Some(1_500)).await.unwrap();
+        assert_eq!(tenant.quota(), Some(1_500));
+        let err = tenant.put_block(&blocks[1]).await.unwrap_err();
+        assert!(matches!(
+            err,
+            BlockstoreError::QuotaExceeded {
+                size: 1_000,
+                max_bytes: 1_500
+            }
+        ));
+        assert!(tenant.put_many(&blocks[..2]).await.is_err());
+        assert_eq!(tenant.put_block(&blocks[0]).await.unwrap(), Put::Existing);
+        // The parent has no quota of its own.
+        store.put_many(&blocks).await.unwrap();
+
+        let usage = store.usage("tenant").await.unwrap();
+        assert_eq!((usage.blocks, usage.bytes), (1, 1_000));
+        assert_eq!(usage, tenant.stats().await.unwrap());
+        assert!(store.usage("missing").await.is_err());
+
+        // The quota sticks with the namespace.
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let tenant = reopened.namespace("tenant").await.unwrap();
+        assert_eq!(tenant.quota(), Some(1_500));
+        tenant.del_block(&blocks[0].cid).await.unwrap();
+        tenant.put_block(&blocks[1]).await.unwrap();
+        reopened.set_namespace_quota("tenant", None).await.unwrap();
+        tenant.put_block(&blocks[2]).await.unwrap();
+        assert_eq!(reopened.usage("tenant").await.unwrap().bytes, 2_000);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_not_reopen_namespaces_already_open() {
+        let (store, root) = make_fs_store().await;
+        let tenant = store.namespace("tenant").await.unwrap();
+        tenant.put_block(&make_random_block(100)).await.unwrap();
+
+        // Reopening the namespace would have to check its version, and fail.
+        let tenant_root = root.path().join(NAMESPACES_DIR).join("tenant");
+        fs::write(tenant_root.join(migrate::VERSION_FILE), "99\n").unwrap();
+        assert_eq!(store.usage("tenant").await.unwrap().bytes, 100);
+        store.set_namespace_quota("tenant", Some(1_000)).await.unwrap();
+        assert_eq!(store.namespace("tenant").await.unwrap().quota(), Some(1_000));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn should_group_commit_puts() {
+        let (store, root) = make_fs_store().await;
+        let group_commit = GroupCommit {
+            interval: Duration::from_millis(20),
+            max_blocks: 8,
+        };
+        let store = Arc::new(
+            store
+                .with_sync_policy(SyncPolicy::DataAndDir)
+                .with_group_commit(group_commit),
+        );
+        let blocks: Vec<Block> = (0..20).map(|_| make_random_block(1_000)).collect();
+
+        // Every block gets put twice at once, which should still count it once.
+        let mut puts = JoinSet::new();
+        for block in blocks[..10].iter().chain(&blocks[..10]) {
+            let store = store.clone();
+            let block = block.clone();
+            puts.spawn(async move { store.put_block(&block).await });
+        }
+        let mut written = 0;
+        while let Some(result) = puts.join_next().await {
+            written += result.unwrap().unwrap().is_written() as usize;
+        }
+        assert_eq!(written, 10);
+        store.put_many(&blocks[5..]).await.unwrap();
+
+        // Once the puts are back, the blocks are in place, with no temporary files around them.
+        let paths: HashSet<PathBuf> = blocks.iter().map(|b| store.block_path(&b.cid)).collect();
+        for block in &blocks {
+            let path = store.block_path(&block.cid);
+            assert_eq!(fs::read(&path).unwrap(), block.data);
+            for entry in fs::read_dir(path.parent().unwrap()).unwrap() {
+                assert!(paths.contains(&entry.unwrap().path()));
+            }
+        }
+
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.blocks, 20);
+        assert_eq!(stats.bytes, 20_000);
+        let reopened = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(reopened.stats().await.unwrap(), stats);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn should_cap_open_files() {
+        let (store, _root) = make_fs_store().await;
+        let store = Arc::new(store.with_max_open_files(4));
+        let limit = store.file_limit.clone().unwrap();
+        let stored = make_random_block(1_000);
+        store.put_block(&stored).await.unwrap();
+
+        // With all the room for writes taken, puts wait, but gets still go through.
+        let writes = limit.writes.clone().acquire_many_owned(3).await.unwrap();
+        let block = make_random_block(1_000);
+        let put = tokio::spawn({
+            let store = store.clone();
+            let block = block.clone();
+            async move { store.put_block(&block).await }
+        });
+        let get = tokio::time::timeout(Duration::from_secs(5), store.get_block(&stored.cid));
+        assert_eq!(get.await.unwrap().unwrap().unwrap(), stored);
+        assert!(!put.is_finished());
+        drop(writes);
+        assert!(put.await.unwrap().unwrap().is_written());
+
+        // Many more operations than permits all get done, and give their permits back.
+        let blocks: Vec<Block> = (0..50).map(|_| make_random_block(100)).collect();
+        let cids: Vec<Cid> = blocks.iter().map(|b| b.cid).collect();
+        let mut tasks = JoinSet::new();
+        for chunk in blocks.chunks(10) {
+            let store = store.clone();
+            let chunk = chunk.to_vec();
+            tasks.spawn(async move {
+                store.put_many(&chunk).await.unwrap();
+                let cids: Vec<Cid> = chunk.iter().map(|b| b.cid).collect();
+                assert!(store.get_many(&cids).await.unwrap().iter().all(Option::is_some));
+                store.del_many(&cids[..5]).await.unwrap();
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result.unwrap();
+        }
+        assert_eq!(store.has_many(&cids).await.iter().filter(|has| **has).count(), 25);
+        assert_eq!(limit.ops.available_permits(), 4);
+        assert_eq!(limit.writes.available_permits(), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_free_space_reserve() {
+        let (store, _root) = make_fs_store().await;
+        let reported = Arc::new(AtomicU64::new(0));
+        let hook_reported = reported.clone();
+        let store = store
+            .with_free_space_reserve(u64::MAX / 2)
+            .with_low_space_hook(u64::MAX, move |available| {
+                hook_reported.store(available, Ordering::Relaxed)
+            });
+
+        // No disk has that much to spare, so large puts get turned away before writing.
+        let large = make_random_block(1 << 20);
+        let err = store.put_block(&large).await.unwrap_err();
+        let BlockstoreError::OutOfSpace { needed, available } = err else {
+            panic!("expected OutOfSpace, got {:?}", err);
+        };
+        assert_eq!(needed, u64::MAX / 2 + (1 << 20));
+        assert_eq!(reported.load(Ordering::Relaxed), available);
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        let batch = vec![make_random_block(1 << 19), make_random_block(1 << 19)];
+        let err = store.put_many(&batch).await.unwrap_err();
+        assert!(matches!(err, BlockstoreError::OutOfSpace { .. }));
+        assert!(!store.has_block(&large.cid).await);
+        assert!(!store.has_block(&batch[0].cid).await);
+
+        // Small ones aren't worth checking for.
+        let small = make_random_block(1_000);
+        store.put_block(&small).await.unwrap();
+
+        // A disk that fills up midway fails the put the same way.
+        let full = io::Error::from(io::ErrorKind::StorageFull);
+        assert!(matches!(
+            store.out_of_space(full, 10),
+            BlockstoreError::OutOfSpace { .. }
+        ));
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(matches!(
+            store.out_of_space(denied, 10),
+            BlockstoreError::Backend(_)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_open_kubo_blocks_dir() {
+        let root = tempdir().unwrap();
+        let block = make_random_block(1_000);
+        // What Kubo leaves in its `blocks/` directory, besides the blocks themselves.
+        fs::write(root.path().join("SHARDING"), "/repo/flatfs/shard/v1/next-to-last/2\n").unwrap();
+        fs::write(root.path().join("_README"), "This is a repository of IPLD objects.").unwrap();
+        let path = root.path().join(flatfs::block_path(block.cid.hash()));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &block.data).unwrap();
+
+        let store = FSStore::create(root.path().to_path_buf()).await.unwrap();
+        assert_eq!(store.sharding().id(), FlatFs.id());
+        assert_eq!(store.stats().await.unwrap().blocks, 1);
+
+        // Only the multihash matters, so the block can be found under any codec.
+        let dag_pb = Cid::new_v1(Codec::DagPb.code(), *block.cid.hash());
+        let found = store.get_block(&dag_pb).await.unwrap().unwrap();
+        assert_eq!(found.data, block.data);
+
+        let mut cids = store.blocks();
+        assert_eq!(cids.recv().await.unwrap().unwrap(), block.cid);
+        assert!(cids.recv().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_sharding_across_reopens() {
+        let root = tempdir().unwrap();
+        let strategies: [Arc<dyn ShardingStrategy>; 4] = [
+            Arc::new(Suffix { chars: 2 }),
+            Arc::new(TwoLevel { fanout: 16 }),
+            Arc::new(Flat),
+            Arc::new(FlatFs),
+        ];
+
+        for (i, sharding) in strategies.into_iter().enumerate() {
+            let root = root.path().join(i.to_string());
+            let store = FSStore::create_with(root.clone(), sharding.clone()).await.unwrap();
+            let block = make_random_block(1_000);
+            store.put_block(&block).await.unwrap();
+            drop(store);
+
+            let store = FSStore::create(root.clone()).await.unwrap();
+            assert_eq!(store.sharding().id(), sharding.id());
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+            assert_eq!(store.stats().await.unwrap().blocks, 1);
+            let mut cids = store.blocks();
+            assert_eq!(cids.recv().await.unwrap().unwrap(), block.cid);
+            drop(store);
+
+            let result = FSStore::create_with_sharding(root, Prefix::default()).await;
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_errors_through_io_errors() {
+        let (store, _root) = make_fs_store().await;
+        let cid = make_random_block(100).cid;
+
+        let err = store.del_block(&cid).await.unwrap_err();
+        assert!(matches!(err, BlockstoreError::NotFound(missing) if missing == cid));
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(BlockstoreError::from(err), BlockstoreError::NotFound(missing) if missing == cid));
+
+        let err = BlockstoreError::from(io::Error::other("disk on fire"));
+        assert!(matches!(&err, BlockstoreError::Backend(e) if e.to_string() == "disk on fire"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_apply_builder_options() {
+        let root = tempdir().unwrap();
+        let temp_dir = root.path().join(".tmp");
+        let store = FSStore::builder()
+            .chars_per_level(10)
+            .sync_policy(SyncPolicy::DataOnly)
+            .temp_dir(&temp_dir)
+            .max_block_size(500)
+            .max_open_files(8)
+            .open(root.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(store.sync_policy(), SyncPolicy::DataOnly);
+        assert!(store.file_limit.is_some());
+
+        let block = make_random_block(500);
+        store.put_block(&block).await.unwrap();
+        let relative = store.block_path(&block.cid);
+        let relative = relative.strip_prefix(root.path()).unwrap();
+        assert!(relative.iter().rev().skip(1).all(|part| part.len() == 10));
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        let too_big = make_random_block(501);
+        let err = store.put_block(&too_big).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // Oversized blocks fail the whole batch before anything gets written.
+        let batch = [make_random_block(100), too_big];
+        let err = store.put_many(&batch).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!store.has_block(&batch[0].cid).await);
+        assert!(!store.has_block(&batch[1].cid).await);
+        drop(store);
+
+        let store = FSStore::create(root.path().to_path_buf()).await.unwrap();
+        assert_eq!(store.sharding().id(), "prefix/10");
+        assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_refuse_unknown_sharding() {
+        let root = tempdir().unwrap();
+        fs::write(root.path().join(sharding::SHARDING_FILE), "mystery/3\n").unwrap();
+
+        let result = FSStore::create(root.path().to_path_buf()).await;
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::Unsupported);
     }
 }
@@ -0,0 +1,109 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// A counting Bloom filter. Unlike a plain Bloom filter it supports removal, at the cost of a
+/// byte per slot instead of a bit. Slots saturate at 255 and are never decremented after that,
+/// which can only ever produce false positives, never false negatives.
+pub struct BloomFilter {
+    counters: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Sizes a filter so that, once it holds `expected_items` keys, lookups for absent keys
+    /// come back positive with probability roughly `fp_rate`.
+    pub fn new(expected_items: usize, fp_rate: f64) -> Self {
+        assert!(fp_rate > 0.0 && fp_rate < 1.0, "false-positive rate must be in (0, 1)");
+
+        let n = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let slots = (-n * fp_rate.ln() / (ln2 * ln2)).ceil().max(1.0);
+        let hashes = ((slots / n) * ln2).round().max(1.0);
+
+        BloomFilter {
+            counters: vec![0; slots as usize],
+            hashes: hashes as u32,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for slot in self.slots(key) {
+            self.counters[slot] = self.counters[slot].saturating_add(1);
+        }
+    }
+
+    /// Takes `key` out of the filter. Only keys that were inserted should be: removing one that
+    /// wasn't, but that `contains` happens to be true for, takes some of the others out with it.
+    pub fn remove(&mut self, key: &[u8]) {
+        if !self.contains(key) {
+            return;
+        }
+
+        for slot in self.slots(key) {
+            let counter = &mut self.counters[slot];
+            if *counter != u8::MAX {
+                *counter -= 1;
+            }
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.slots(key).all(|slot| self.counters[slot] > 0)
+    }
+
+    // Double hashing: slot i is h1 + i * h2, which behaves about as well as k independent hashes.
+    fn slots(&self, key: &[u8]) -> impl Iterator<Item = usize> + use<> {
+        let h1 = hash(key, 0);
+        let h2 = hash(key, 1) | 1;
+        let len = self.counters.len() as u64;
+
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+fn hash(key: &[u8], seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_contain_inserted_keys() {
+        let mut filter = BloomFilter::new(1_000, 0.01);
+        for i in 0..1_000u32 {
+            filter.insert(&i.to_be_bytes());
+        }
+
+        for i in 0..1_000u32 {
+            assert!(filter.contains(&i.to_be_bytes()));
+        }
+    }
+
+    #[test]
+    fn should_not_contain_removed_keys() {
+        let mut filter = BloomFilter::new(100, 0.01);
+        filter.insert(b"key");
+        filter.remove(b"key");
+
+        assert!(!filter.contains(b"key"));
+    }
+
+    #[test]
+    fn should_roughly_honor_false_positive_rate() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+        for i in 0..10_000u32 {
+            filter.insert(&i.to_be_bytes());
+        }
+
+        let false_positives = (10_000..20_000u32)
+            .filter(|i| filter.contains(&i.to_be_bytes()))
+            .count();
+
+        // Expected ~100; leave plenty of room so the test isn't flaky.
+        assert!(false_positives < 300, "got {} false positives", false_positives);
+    }
+}
@@ -0,0 +1,561 @@
+use std::fs;
+use std::io::{self, Write as _};
+use std::ops::Range;
+use std::path::Path;
+use std::pin::Pin;
+
+use bytes::Bytes;
+use cid::Cid;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::block::{Block, Codec};
+use crate::blockstore::Blockstore;
+use crate::ipld::{Decode, Ipld};
+
+/// Frames (header, or CID + block data) larger than this are rejected instead of buffered, so a
+/// corrupt length prefix can't make us allocate arbitrary amounts of memory.
+pub const MAX_FRAME_SIZE: u64 = 4 << 20;
+
+// How many bytes worth of blocks we accumulate before handing them to the store in one go.
+const BATCH_BYTES: usize = 4 << 20;
+
+// CBOR tag used by DAG-CBOR for CIDs.
+const CID_TAG: u64 = 42;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CarHeader {
+    pub roots: Vec<Cid>,
+}
+
+/// Reads a CARv1 file from `reader` and stores all of its blocks into `store`, checking that each
+/// block's data matches its CID. Blocks are read one frame at a time and flushed to the store in
+/// bounded batches, so memory use doesn't grow with the size of the file.
+pub async fn import_car<R: AsyncRead>(
+    store: &impl Blockstore,
+    reader: R,
+) -> Result<CarHeader, io::Error> {
+    let mut reader = CarReader::new(reader).await?;
+
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    while let Some((cid, data)) = reader.next_block().await? {
+        let block = Block::with_cid(cid, data)
+            .ok_or_else(|| invalid_data(format!("block data does not match CID {}", cid)))?;
+        batch_bytes += block.data.len();
+        batch.push(block);
+
+        if batch_bytes >= BATCH_BYTES {
+            store.put_many(&batch).await?;
+            batch.clear();
+            batch_bytes = 0;
+        }
+    }
+    store.put_many(&batch).await?;
+
+    Ok(reader.header)
+}
+
+// Reads a CARv1 file one block at a time, leaving it to the caller to check blocks against their
+// CIDs.
+pub(crate) struct CarReader<R> {
+    reader: Pin<Box<BufReader<R>>>,
+    pub header: CarHeader,
+}
+
+impl<R: AsyncRead> CarReader<R> {
+    pub async fn new(reader: R) -> Result<Self, io::Error> {
+        let mut reader = Box::pin(BufReader::new(reader));
+        let header = match read_frame(&mut reader).await? {
+            Some(frame) => decode_header(&frame)?,
+            None => return Err(invalid_data("empty CAR file")),
+        };
+        Ok(CarReader { reader, header })
+    }
+
+    // The next block's CID and data, as they come.
+    pub async fn next_block(&mut self) -> Result<Option<(Cid, Bytes)>, io::Error> {
+        match read_frame(&mut self.reader).await? {
+            Some(frame) => decode_block(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Writes a CARv1 file to `writer` listing `roots` in its header, followed by the blocks for
+/// `cids` in the order given. Blocks are fetched from `store` and written one at a time.
+pub async fn export_car<W, I>(
+    store: &impl Blockstore,
+    roots: &[Cid],
+    cids: I,
+    writer: W,
+) -> Result<(), io::Error>
+where
+    W: AsyncWrite,
+    I: IntoIterator<Item = Cid>,
+{
+    let writer = BufWriter::new(writer);
+    tokio::pin!(writer);
+
+    write_frame(&mut writer, &[&encode_header(roots)]).await?;
+    for cid in cids {
+        write_block(store, &cid, &mut writer).await?;
+    }
+
+    writer.flush().await
+}
+
+/// Like [`export_car`], but exports every block in `store`.
+pub async fn export_store<W: AsyncWrite>(
+    store: &impl Blockstore,
+    roots: &[Cid],
+    writer: W,
+) -> Result<(), io::Error> {
+    let writer = BufWriter::new(writer);
+    tokio::pin!(writer);
+
+    write_frame(&mut writer, &[&encode_header(roots)]).await?;
+    let mut cids = store.blocks();
+    while let Some(cid) = cids.recv().await {
+        write_block(store, &cid?, &mut writer).await?;
+    }
+
+    writer.flush().await
+}
+
+async fn write_block<W: AsyncWrite + Unpin>(
+    store: &impl Blockstore,
+    cid: &Cid,
+    writer: &mut W,
+) -> Result<(), io::Error> {
+    let block = store.get_block(cid).await?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("block {} not found", cid))
+    })?;
+    write_frame(writer, &[&block.cid.to_bytes(), &block.data]).await
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    parts: &[&[u8]],
+) -> Result<(), io::Error> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let mut prefix = Vec::new();
+    write_varint(len as u64, &mut prefix);
+    writer.write_all(&prefix).await?;
+
+    for part in parts {
+        writer.write_all(part).await?;
+    }
+    Ok(())
+}
+
+// The framed header of a CARv1 file listing `roots`, for writing one without going through
+// `AsyncWrite`.
+pub(crate) fn header_frame(roots: &[Cid]) -> Vec<u8> {
+    let header = encode_header(roots);
+    let mut frame = Vec::new();
+    write_varint(header.len() as u64, &mut frame);
+    frame.extend_from_slice(&header);
+    frame
+}
+
+// What goes in front of `data_len` bytes of block data to make them block `cid`'s frame.
+pub(crate) fn block_frame_prefix(cid: &Cid, data_len: usize) -> Vec<u8> {
+    let cid = cid.to_bytes();
+    let mut prefix = Vec::new();
+    write_varint((cid.len() + data_len) as u64, &mut prefix);
+    prefix.extend_from_slice(&cid);
+    prefix
+}
+
+// Each block's CID, along with the range of the file its data takes up.
+pub(crate) type CarIndex = Vec<(Cid, Range<usize>)>;
+
+// Finds the blocks in `data`, a whole CARv1 file, returning its header along with them.
+pub(crate) fn index_car(data: &[u8]) -> Result<(CarHeader, CarIndex), io::Error> {
+    let mut position = 0;
+    let header = next_header(data, &mut position)?;
+    let mut blocks = Vec::new();
+    while let Some(frame) = next_frame(data, &mut position)? {
+        let cid = Cid::read_bytes(&data[frame.clone()]).map_err(invalid_data)?;
+        blocks.push((cid, frame.start + cid.encoded_len()..frame.end));
+    }
+    Ok((header, blocks))
+}
+
+// Reads just the header of `data`, a whole CARv1 file.
+pub(crate) fn index_car_header(data: &[u8]) -> Result<CarHeader, io::Error> {
+    next_header(data, &mut 0)
+}
+
+fn next_header(data: &[u8], position: &mut usize) -> Result<CarHeader, io::Error> {
+    match next_frame(data, position)? {
+        Some(frame) => decode_header(&data[frame]),
+        None => Err(invalid_data("empty CAR file")),
+    }
+}
+
+// Like `read_frame`, but for CAR files held in memory: returns where the frame at `position`
+// is, and moves `position` past it.
+fn next_frame(data: &[u8], position: &mut usize) -> Result<Option<Range<usize>>, io::Error> {
+    if *position == data.len() {
+        return Ok(None);
+    }
+
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame");
+    let mut len = 0u64;
+    let mut start = *position;
+    for i in 0..10 {
+        let byte = *data.get(start).ok_or_else(truncated)?;
+        start += 1;
+        len |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if i == 9 {
+            return Err(invalid_data("varint is too long"));
+        }
+    }
+
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid_data(format!(
+            "frame of {} bytes exceeds maximum of {} bytes",
+            len, MAX_FRAME_SIZE
+        )));
+    }
+    let end = start + len as usize;
+    if end > data.len() {
+        return Err(truncated());
+    }
+    *position = end;
+    Ok(Some(start..end))
+}
+
+// First line of an index file, followed by the length of the CAR file it indexes.
+pub(crate) const INDEX_MAGIC: &str = "car-index/1";
+
+// Loads the index of a CAR file of `car_len` bytes saved at `path`, if there's one there for a
+// CAR file that long. Ranges are checked to be within the file, so a bad index can't make reads
+// go out of bounds.
+pub(crate) fn read_index(path: &Path, car_len: usize) -> Result<Option<CarIndex>, io::Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut lines = contents.lines();
+    if lines.next() != Some(&format!("{} {}", INDEX_MAGIC, car_len)) {
+        return Ok(None);
+    }
+
+    let mut blocks = Vec::new();
+    for line in lines {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed index entry {:?} in {:?}", line, path),
+            )
+        };
+
+        let mut fields = line.split(' ');
+        let (Some(cid), Some(start), Some(end), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let cid = Cid::try_from(cid).map_err(|_| invalid())?;
+        let start: usize = start.parse().map_err(|_| invalid())?;
+        let end: usize = end.parse().map_err(|_| invalid())?;
+        if start > end || end > car_len {
+            return Err(invalid());
+        }
+        blocks.push((cid, start..end));
+    }
+    Ok(Some(blocks))
+}
+
+// Saves the index of a CAR file of `car_len` bytes at `path`, through a temporary file so a
+// crash can't leave half of one behind.
+pub(crate) fn write_index(path: &Path, car_len: usize, blocks: &CarIndex) -> Result<(), io::Error> {
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".tmp");
+    let mut file = io::BufWriter::new(fs::File::create(&temp_path)?);
+    writeln!(file, "{} {}", INDEX_MAGIC, car_len)?;
+    for (cid, range) in blocks {
+        writeln!(file, "{} {} {}", cid, range.start, range.end)?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+// Unsigned LEB128, which protobuf (and so UnixFS) uses too.
+pub(crate) fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, io::Error> {
+    let len = match read_varint(reader).await? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid_data(format!(
+            "frame of {} bytes exceeds maximum of {} bytes",
+            len, MAX_FRAME_SIZE
+        )));
+    }
+
+    let mut frame = vec![0u8; len as usize];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+// Reads an unsigned LEB128 varint. Returns `None` if the reader is at EOF before the first byte,
+// which is how a well-formed CAR file ends.
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<u64>, io::Error> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && i == 0 => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+
+    Err(invalid_data("varint is too long"))
+}
+
+fn decode_block(frame: Vec<u8>) -> Result<(Cid, Bytes), io::Error> {
+    let cid = Cid::read_bytes(frame.as_slice()).map_err(invalid_data)?;
+    // The block keeps the frame's buffer, minus the CID in front.
+    let data = Bytes::from(frame).slice(cid.encoded_len()..);
+    Ok((cid, data))
+}
+
+fn decode_header(frame: &[u8]) -> Result<CarHeader, io::Error> {
+    let header = Ipld::decode(Codec::DagCbor, frame)?;
+
+    match header.get("version") {
+        Some(Ipld::Integer(1)) => {}
+        Some(Ipld::Integer(other)) => {
+            return Err(invalid_data(format!("unsupported CAR version {}", other)));
+        }
+        _ => return Err(invalid_data("CAR header has no version")),
+    }
+
+    let Some(Ipld::List(roots)) = header.get("roots") else {
+        return Err(invalid_data("CAR header has no roots"));
+    };
+    let roots = roots
+        .iter()
+        .map(|root| match root {
+            Ipld::Link(cid) => Ok(*cid),
+            _ => Err(invalid_data("CAR header roots must be CIDs")),
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(CarHeader { roots })
+}
+
+// DAG-CBOR for `{"roots": [...], "version": 1}`, keys in canonical (length-first) order.
+fn encode_header(roots: &[Cid]) -> Vec<u8> {
+    let mut out = Vec::new();
+    cbor_head(MAP, 2, &mut out);
+
+    cbor_head(TEXT, 5, &mut out);
+    out.extend_from_slice(b"roots");
+    cbor_head(ARRAY, roots.len() as u64, &mut out);
+    for root in roots {
+        let cid = root.to_bytes();
+        cbor_head(TAG, CID_TAG, &mut out);
+        cbor_head(BYTES, cid.len() as u64 + 1, &mut out);
+        out.push(0x00);
+        out.extend_from_slice(&cid);
+    }
+
+    cbor_head(TEXT, 7, &mut out);
+    out.extend_from_slice(b"version");
+    cbor_head(UINT, 1, &mut out);
+
+    out
+}
+
+fn cbor_head(major: u8, argument: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    match argument {
+        0..=23 => out.push(major | argument as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, argument as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(argument as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(argument as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&argument.to_be_bytes());
+        }
+    }
+}
+
+const UINT: u8 = 0;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+
+    fn frame(payload: &[u8], out: &mut Vec<u8>) {
+        write_varint(payload.len() as u64, out);
+        out.extend_from_slice(payload);
+    }
+
+    // Hand-rolled DAG-CBOR for `{"roots": [root], "version": version}`.
+    fn header(root: &Cid, version: u8) -> Vec<u8> {
+        let cid = root.to_bytes();
+        let mut header = vec![0xa2, 0x65];
+        header.extend_from_slice(b"roots");
+        header.extend_from_slice(&[0x81, 0xd8, 0x2a, 0x58, cid.len() as u8 + 1, 0x00]);
+        header.extend_from_slice(&cid);
+        header.push(0x67);
+        header.extend_from_slice(b"version");
+        header.push(version);
+        header
+    }
+
+    fn car(version: u8, blocks: &[(Cid, &[u8])]) -> Vec<u8> {
+        let mut car = Vec::new();
+        frame(&header(&blocks[0].0, version), &mut car);
+        for (cid, data) in blocks {
+            let mut payload = cid.to_bytes();
+            payload.extend_from_slice(data);
+            frame(&payload, &mut car);
+        }
+        car
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_import_car_blocks() {
+        let store = MemStore::new();
+        let blocks: Vec<Block> = (0..10).map(|_| make_random_block(1_000)).collect();
+        let frames: Vec<(Cid, &[u8])> = blocks.iter().map(|b| (b.cid, &b.data[..])).collect();
+
+        let header = import_car(&store, car(1, &frames).as_slice()).await.unwrap();
+
+        assert_eq!(header.roots, vec![blocks[0].cid]);
+        for block in &blocks {
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), *block);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_reject_block_not_matching_cid() {
+        let store = MemStore::new();
+        let block1 = make_random_block(1_000);
+        let block2 = make_random_block(1_000);
+
+        let car = car(1, &[(block1.cid, &block2.data)]);
+        let err = import_car(&store, car.as_slice()).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_reject_unsupported_version() {
+        let store = MemStore::new();
+        let block = make_random_block(1_000);
+
+        let car = car(2, &[(block.cid, &block.data)]);
+        let err = import_car(&store, car.as_slice()).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_reject_truncated_frame() {
+        let store = MemStore::new();
+        let block = make_random_block(1_000);
+
+        let car = car(1, &[(block.cid, &block.data)]);
+        let err = import_car(&store, &car[..car.len() - 1]).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_export_car_readable_by_import() {
+        let source = MemStore::new();
+        let blocks: Vec<Block> = (0..10).map(|_| make_random_block(1_000)).collect();
+        source.put_many(&blocks).await.unwrap();
+
+        let mut car = Vec::new();
+        let cids: Vec<Cid> = blocks.iter().map(|b| b.cid).collect();
+        export_car(&source, &cids[..2], cids.clone(), &mut car).await.unwrap();
+
+        let target = MemStore::new();
+        let header = import_car(&target, car.as_slice()).await.unwrap();
+
+        assert_eq!(header.roots, &cids[..2]);
+        for block in &blocks {
+            assert_eq!(target.get_block(&block.cid).await.unwrap().unwrap(), *block);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_export_whole_store() {
+        let source = MemStore::new();
+        let blocks: Vec<Block> = (0..10).map(|_| make_random_block(1_000)).collect();
+        source.put_many(&blocks).await.unwrap();
+
+        let mut car = Vec::new();
+        export_store(&source, &[blocks[0].cid], &mut car).await.unwrap();
+
+        let target = MemStore::new();
+        import_car(&target, car.as_slice()).await.unwrap();
+        assert_eq!(target.len(), blocks.len());
+    }
+
+    #[test]
+    fn should_encode_header_as_expected_cbor() {
+        let block = make_random_block(1_000);
+
+        assert_eq!(encode_header(&[block.cid]), header(&block.cid, 1));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_fail_export_of_missing_block() {
+        let store = MemStore::new();
+        let block = make_random_block(1_000);
+
+        let mut car = Vec::new();
+        let err = export_car(&store, &[], [block.cid], &mut car).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,275 @@
+//! Serving blocks straight out of a CAR file, for immutable datasets shipped as one file.
+
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use cid::Cid;
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+
+use crate::block::{Block, is_inline, to_v1};
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+use crate::car::{self, CarHeader, CarIndex};
+use crate::mmap::map_file;
+
+/// A read-only [`Blockstore`] over a CARv1 file, which gets memory-mapped and read in place
+/// rather than unpacked. Finding the blocks takes a pass over the whole file, unless an index
+/// saved by an earlier [`CarStore::open_indexed`] can be used instead.
+///
+/// Block data isn't checked against CIDs, and the file must not change while it's open: a
+/// mapped file that gets truncated crashes the process when read past its new end.
+pub struct CarStore {
+    path: PathBuf,
+    data: Bytes,
+    header: CarHeader,
+    // Keyed by CIDv1, so a block listed under its v0 CID is found under either.
+    index: HashMap<Cid, Entry>,
+    bytes: u64,
+}
+
+struct Entry {
+    // As the CAR file has it.
+    cid: Cid,
+    range: Range<usize>,
+}
+
+impl CarStore {
+    /// Opens the CAR file at `path`, indexing it from scratch.
+    pub async fn open(path: PathBuf) -> Result<Self, io::Error> {
+        spawn_blocking(move || {
+            let data = map_file(&path)?;
+            let (header, blocks) = car::index_car(&data)?;
+            Ok(Self::new(path, data, header, blocks))
+        })
+        .await?
+    }
+
+    /// Like [`CarStore::open`], but loads the index from `index_path` if there's one there for
+    /// this file, and saves it there otherwise.
+    pub async fn open_indexed(path: PathBuf, index_path: PathBuf) -> Result<Self, io::Error> {
+        spawn_blocking(move || {
+            let data = map_file(&path)?;
+            let header = car::index_car_header(&data)?;
+            if let Some(blocks) = car::read_index(&index_path, data.len())? {
+                return Ok(Self::new(path, data, header, blocks));
+            }
+
+            let (header, blocks) = car::index_car(&data)?;
+            car::write_index(&index_path, data.len(), &blocks)?;
+            Ok(Self::new(path, data, header, blocks))
+        })
+        .await?
+    }
+
+    fn new(path: PathBuf, data: Bytes, header: CarHeader, blocks: CarIndex) -> Self {
+        let mut index = HashMap::with_capacity(blocks.len());
+        let mut bytes = 0;
+        for (cid, range) in blocks {
+            // A block that's in the file twice is served from its first copy.
+            index.entry(to_v1(&cid)).or_insert_with(|| {
+                bytes += range.len() as u64;
+                Entry { cid, range }
+            });
+        }
+
+        CarStore {
+            path,
+            data,
+            header,
+            index,
+            bytes,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The roots listed in the CAR file's header.
+    pub fn roots(&self) -> &[Cid] {
+        &self.header.roots
+    }
+
+    fn entry(&self, cid: &Cid) -> Option<&Entry> {
+        self.index.get(&to_v1(cid))
+    }
+}
+
+/// Blocks with identity CIDs are served from the CID, whether the CAR file has them or not.
+impl Blockstore for CarStore {
+    async fn put_block(&self, _block: &Block) -> Result<Put, BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    async fn put_many(&self, _blocks: &[Block]) -> Result<(), BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        is_inline(cid) || self.entry(cid).is_some()
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if let Some(block) = Block::inline(cid) {
+            return Ok(Some(block));
+        }
+        Ok(self.entry(cid).map(|entry| Block {
+            cid: *cid,
+            data: self.data.slice(entry.range.clone()),
+        }))
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(Some(cid.hash().size() as u64));
+        }
+        Ok(self.entry(cid).map(|entry| entry.range.len() as u64))
+    }
+
+    async fn del_block(&self, _cid: &Cid) -> Result<(), BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    async fn del_many(&self, _cids: &[Cid]) -> Result<(), BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    /// Lists blocks under the CIDs the CAR file has them as.
+    fn blocks(&self) -> CidStream {
+        let (sender, receiver) = mpsc::channel(self.index.len().max(1));
+        for entry in self.index.values() {
+            // Can't fail: the channel has room for everything, and we hold the receiver.
+            sender.try_send(Ok(entry.cid)).unwrap();
+        }
+        receiver
+    }
+
+    /// Disk usage is the CAR file's size.
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        Ok(StoreStats {
+            blocks: self.index.len() as u64,
+            bytes: self.bytes,
+            disk_bytes: self.data.len() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Codec, make_random_block};
+    use crate::memstore::MemStore;
+    use std::fs;
+    use tempfile::tempdir;
+
+    async fn write_car(path: &Path, blocks: &[Block]) {
+        let store = MemStore::new();
+        store.put_many(blocks).await.unwrap();
+        let mut car = Vec::new();
+        car::export_car(
+            &store,
+            &[blocks[0].cid],
+            blocks.iter().map(|b| b.cid),
+            &mut car,
+        )
+        .await
+        .unwrap();
+        fs::write(path, car).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_read_blocks_in_place() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.car");
+        let blocks: Vec<Block> = (0..5).map(|_| make_random_block(1_000)).collect();
+        write_car(&path, &blocks).await;
+
+        let store = CarStore::open(path.clone()).await.unwrap();
+        assert_eq!(store.roots(), &[blocks[0].cid]);
+        for block in &blocks {
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), *block);
+            assert_eq!(store.block_size(&block.cid).await.unwrap(), Some(1_000));
+        }
+        let missing = make_random_block(10);
+        assert_eq!(store.get_block(&missing.cid).await.unwrap(), None);
+        let inline = Block::new_inline(&b"inline"[..], Codec::Raw).unwrap();
+        assert!(store.has_block(&inline.cid).await);
+        assert_eq!(store.get_block(&inline.cid).await.unwrap().unwrap(), inline);
+        assert_eq!(store.block_size(&inline.cid).await.unwrap(), Some(6));
+        let stats = store.stats().await.unwrap();
+        assert_eq!((stats.blocks, stats.bytes), (5, 5_000));
+        assert_eq!(stats.disk_bytes, fs::metadata(&path).unwrap().len());
+
+        let mut listed = Vec::new();
+        let mut cids = store.blocks();
+        while let Some(cid) = cids.recv().await {
+            listed.push(cid.unwrap());
+        }
+        listed.sort();
+        let mut expected: Vec<Cid> = blocks.iter().map(|b| b.cid).collect();
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        assert!(matches!(
+            store.put_block(&missing).await,
+            Err(BlockstoreError::ReadOnly)
+        ));
+        assert!(matches!(
+            store.del_block(&blocks[0].cid).await,
+            Err(BlockstoreError::ReadOnly)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_save_and_reuse_index() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.car");
+        let index_path = dir.path().join("data.car.idx");
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+        write_car(&path, &blocks).await;
+
+        CarStore::open_indexed(path.clone(), index_path.clone())
+            .await
+            .unwrap();
+        let index = fs::read_to_string(&index_path).unwrap();
+        assert_eq!(index.lines().count(), 4);
+
+        // An index for this file gets used as it is, even if it only lists some of the blocks.
+        let partial: String = index
+            .lines()
+            .take(2)
+            .map(|line| format!("{}\n", line))
+            .collect();
+        fs::write(&index_path, partial).unwrap();
+        let store = CarStore::open_indexed(path.clone(), index_path.clone())
+            .await
+            .unwrap();
+        assert_eq!(store.stats().await.unwrap().blocks, 1);
+
+        // Once the file changes, the index no longer applies and gets rebuilt.
+        write_car(&path, &blocks[..2]).await;
+        let store = CarStore::open_indexed(path.clone(), index_path.clone())
+            .await
+            .unwrap();
+        assert_eq!(store.stats().await.unwrap().blocks, 2);
+        assert_eq!(fs::read_to_string(&index_path).unwrap().lines().count(), 3);
+
+        // An index that points outside the file is refused, rather than read from.
+        let len = fs::metadata(&path).unwrap().len();
+        let bogus = format!("{} {}\n{} 0 {}\n", car::INDEX_MAGIC, len, blocks[0].cid, len + 1);
+        fs::write(&index_path, bogus).unwrap();
+        assert!(
+            CarStore::open_indexed(path.clone(), index_path)
+                .await
+                .is_err()
+        );
+
+        // So is a truncated file.
+        let car = fs::read(&path).unwrap();
+        fs::write(&path, &car[..car.len() - 10]).unwrap();
+        assert!(CarStore::open(path).await.is_err());
+    }
+}
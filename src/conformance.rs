@@ -0,0 +1,180 @@
+//! Tests every [`Blockstore`](crate::blockstore::Blockstore) backend should pass. Backends pull
+//! them into their own test module with `conformance_tests!(make_store)`, where `make_store` is an
+//! async fn returning the store plus whatever guard (e.g. a temp dir) must outlive it.
+
+macro_rules! conformance_tests {
+    ($make_store:path) => {
+        mod conformance {
+            use super::*;
+            use crate::block::{make_random_block, Block, Codec};
+            use crate::blockstore::{Blockstore, Put};
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_get_stored_block() {
+                let (store, _guard) = $make_store().await;
+                let block = make_random_block(1_000);
+
+                store.put_block(&block).await.unwrap();
+
+                let retrieved = store.get_block(&block.cid).await.unwrap().unwrap();
+                assert_eq!(retrieved, block);
+                assert_eq!(retrieved.data, block.data);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_miss_absent_blocks() {
+                let (store, _guard) = $make_store().await;
+                let block = make_random_block(1_000);
+
+                assert_eq!(store.get_block(&block.cid).await.unwrap(), None);
+                store.put_block(&block).await.unwrap();
+                store.del_block(&block.cid).await.unwrap();
+                assert_eq!(store.get_block(&block.cid).await.unwrap(), None);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_only_write_new_blocks() {
+                let (store, _guard) = $make_store().await;
+                let block = make_random_block(1_000);
+
+                let put = store.put_block(&block).await.unwrap();
+                assert!(put.is_written() && put.bytes_written() > 0);
+                assert_eq!(store.put_block(&block).await.unwrap(), Put::Existing);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_contain_stored_blocks() {
+                let (store, _guard) = $make_store().await;
+                let block = make_random_block(1_000);
+
+                assert!(!store.has_block(&block.cid).await);
+                store.put_block(&block).await.unwrap();
+                assert!(store.has_block(&block.cid).await);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_delete_block() {
+                let (store, _guard) = $make_store().await;
+                let block = make_random_block(1_000);
+
+                store.put_block(&block).await.unwrap();
+                store.del_block(&block.cid).await.unwrap();
+
+                assert!(!store.has_block(&block.cid).await);
+                assert_eq!(store.block_size(&block.cid).await.unwrap(), None);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_fail_to_delete_missing_block() {
+                let (store, _guard) = $make_store().await;
+                let block = make_random_block(1_000);
+
+                let err = store.del_block(&block.cid).await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_tell_whether_block_was_deleted() {
+                let (store, _guard) = $make_store().await;
+                let block = make_random_block(1_000);
+                store.put_block(&block).await.unwrap();
+
+                assert!(store.del_block_if_present(&block.cid).await.unwrap());
+                assert!(!store.del_block_if_present(&block.cid).await.unwrap());
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_report_block_size() {
+                let (store, _guard) = $make_store().await;
+                let block = make_random_block(1_234);
+
+                assert_eq!(store.block_size(&block.cid).await.unwrap(), None);
+                store.put_block(&block).await.unwrap();
+                assert_eq!(store.block_size(&block.cid).await.unwrap(), Some(1_234));
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_put_many_and_list_them() {
+                let (store, _guard) = $make_store().await;
+                let blocks: Vec<Block> = (0..20).map(|_| make_random_block(1_000)).collect();
+
+                store.put_many(&blocks).await.unwrap();
+
+                let mut listed = Vec::new();
+                let mut cids = store.blocks();
+                while let Some(cid) = cids.recv().await {
+                    listed.push(cid.unwrap());
+                }
+                listed.sort();
+                let mut expected: Vec<_> = blocks.iter().map(|b| b.cid).collect();
+                expected.sort();
+                assert_eq!(listed, expected);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_handle_batches() {
+                let (store, _guard) = $make_store().await;
+                let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+                let missing = make_random_block(1_000);
+                store.put_many(&blocks).await.unwrap();
+
+                let cids = [blocks[0].cid, missing.cid, blocks[2].cid];
+                assert_eq!(store.has_many(&cids).await, [true, false, true]);
+                let expected = [Some(blocks[0].clone()), None, Some(blocks[2].clone())];
+                assert_eq!(store.get_many(&cids).await.unwrap(), expected);
+
+                store.del_many(&[blocks[0].cid, blocks[1].cid]).await.unwrap();
+                let cids = [blocks[0].cid, blocks[1].cid, blocks[2].cid];
+                assert_eq!(store.has_many(&cids).await, [false, false, true]);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_take_prefetch_hints() {
+                let (store, _guard) = $make_store().await;
+                let block = make_random_block(1_000);
+                store.put_block(&block).await.unwrap();
+
+                store.prefetch(&[block.cid, make_random_block(1_000).cid]);
+                assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_count_blocks_once_in_stats() {
+                let (store, _guard) = $make_store().await;
+                let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+
+                store.put_many(&blocks).await.unwrap();
+                store.put_block(&blocks[0]).await.unwrap();
+                store.del_block(&blocks[1].cid).await.unwrap();
+
+                let stats = store.stats().await.unwrap();
+                assert_eq!(stats.blocks, 2);
+                assert_eq!(stats.bytes, 2_000);
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+            async fn should_serve_inline_blocks() {
+                let (store, _guard) = $make_store().await;
+                let block = Block::new_inline(&b"inline"[..], Codec::Raw).unwrap();
+                let missing = make_random_block(1_000);
+
+                // Identity CIDs hold their data, so every store has them without being told.
+                assert!(store.has_block(&block.cid).await);
+                assert_eq!(store.get_block(&block.cid).await.unwrap(), Some(block.clone()));
+                assert_eq!(store.block_size(&block.cid).await.unwrap(), Some(6));
+                let cids = [block.cid, missing.cid];
+                assert_eq!(store.has_many(&cids).await, [true, false]);
+                let expected = [Some(block.clone()), None];
+                assert_eq!(store.get_many(&cids).await.unwrap(), expected);
+
+                assert_eq!(store.put_block(&block).await.unwrap(), Put::Existing);
+                store.put_many(std::slice::from_ref(&block)).await.unwrap();
+                store.del_block(&block.cid).await.unwrap();
+                assert!(store.has_block(&block.cid).await);
+                assert_eq!(store.stats().await.unwrap().blocks, 0);
+            }
+        }
+    };
+}
+
+pub(crate) use conformance_tests;
@@ -0,0 +1,372 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::{self, Future};
+use std::io;
+use std::pin::Pin;
+use std::task::Poll;
+
+use cid::Cid;
+
+use crate::block::{Block, to_v1};
+use crate::blockstore::Blockstore;
+use crate::ipld::Ipld;
+
+/// Returns the CIDs `block` links to, in the order they appear in it. Raw blocks have none.
+/// Fails if the block's codec isn't one [`Block::decode`] supports, or its contents don't decode.
+pub fn links(block: &Block) -> Result<Vec<Cid>, io::Error> {
+    let mut links = Vec::new();
+    collect_links(&block.decode::<Ipld>()?, &mut links);
+    Ok(links)
+}
+
+fn collect_links(value: &Ipld, links: &mut Vec<Cid>) {
+    match value {
+        Ipld::Link(cid) => links.push(*cid),
+        Ipld::List(list) => list.iter().for_each(|value| collect_links(value, links)),
+        Ipld::Map(map) => map.values().for_each(|value| collect_links(value, links)),
+        _ => {}
+    }
+}
+
+/// Walks the DAG under `root` in `store` breadth first, yielding each block once, however many
+/// times it's linked to.
+pub fn walk<S: Blockstore>(store: &S, root: Cid) -> Walk<'_, S> {
+    Walk {
+        store,
+        queue: VecDeque::from([root]),
+        visited: HashSet::from([to_v1(&root)]),
+    }
+}
+
+/// The blocks of a DAG, as returned by [`walk`].
+pub struct Walk<'a, S> {
+    store: &'a S,
+    queue: VecDeque<Cid>,
+    // Normalized to v1, so a block linked to by both its v0 and v1 CID is only visited once.
+    visited: HashSet<Cid>,
+}
+
+impl<S: Blockstore> Walk<'_, S> {
+    /// Fetches the next block. A block that's missing from the store, or whose links can't be
+    /// read, ends the walk with an error.
+    pub async fn next(&mut self) -> Option<Result<Block, io::Error>> {
+        let cid = self.queue.pop_front()?;
+        let result = self.visit(&cid).await;
+        if result.is_err() {
+            self.queue.clear();
+        }
+        Some(result)
+    }
+
+    async fn visit(&mut self, cid: &Cid) -> Result<Block, io::Error> {
+        let block = fetch(self.store, cid).await?;
+        for link in links(&block)? {
+            if self.visited.insert(to_v1(&link)) {
+                self.queue.push_back(link);
+            }
+        }
+        Ok(block)
+    }
+}
+
+/// Copies the DAG under `root` from `src` to `dst`, fetching at most `concurrency` blocks at a
+/// time. Blocks `dst` already has aren't copied again, but their links are still followed, in
+/// case it's missing some of what's below them. Returns how many blocks were copied.
+pub async fn copy_dag(
+    src: &impl Blockstore,
+    dst: &impl Blockstore,
+    root: Cid,
+    concurrency: usize,
+) -> Result<usize, io::Error> {
+    assert!(concurrency > 0, "concurrency must be at least 1");
+
+    let mut queue = VecDeque::from([root]);
+    let mut visited = HashSet::from([to_v1(&root)]);
+    let mut in_flight = Vec::new();
+    let mut copied = 0;
+    loop {
+        while in_flight.len() < concurrency
+            && let Some(cid) = queue.pop_front()
+        {
+            in_flight.push(Box::pin(copy_block(src, dst, cid)));
+        }
+        let Some(result) = next_finished(&mut in_flight).await else {
+            return Ok(copied);
+        };
+
+        let (links, was_copied) = result?;
+        copied += was_copied as usize;
+        for link in links {
+            if visited.insert(to_v1(&link)) {
+                queue.push_back(link);
+            }
+        }
+    }
+}
+
+// Copies a single block unless `dst` already has it, returning its links and whether it was
+// copied.
+async fn copy_block(
+    src: &impl Blockstore,
+    dst: &impl Blockstore,
+    cid: Cid,
+) -> Result<(Vec<Cid>, bool), io::Error> {
+    if let Some(block) = dst.get_block(&cid).await? {
+        return Ok((links(&block)?, false));
+    }
+
+    let block = fetch(src, &cid).await?;
+    let links = links(&block)?;
+    dst.put_block(&block).await?;
+    Ok((links, true))
+}
+
+/// Finds the blocks linking `root` to `target` in `store`: `root`'s block first, then each
+/// block down to `target`'s, which comes last. Given those, [`verify_path`] can check that
+/// `target` is part of the DAG without needing the store. The DAG is searched breadth first,
+/// so the path is as short as it gets. Fails with [`io::ErrorKind::NotFound`] if `target` isn't
+/// under `root`.
+pub async fn prove_path<S: Blockstore>(
+    store: &S,
+    root: Cid,
+    target: Cid,
+) -> Result<Vec<Block>, io::Error> {
+    let target = to_v1(&target);
+    // Maps each block found so far to the block it was first found under.
+    let mut parents = HashMap::from([(to_v1(&root), None)]);
+    let mut queue = VecDeque::from([root]);
+    let mut found = to_v1(&root) == target;
+    while !found && let Some(cid) = queue.pop_front() {
+        let block = fetch(store, &cid).await?;
+        for link in links(&block)? {
+            let link_v1 = to_v1(&link);
+            if parents.contains_key(&link_v1) {
+                continue;
+            }
+            parents.insert(link_v1, Some(cid));
+            if link_v1 == target {
+                found = true;
+                break;
+            }
+            queue.push_back(link);
+        }
+    }
+    if !found {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not under {}", target, root),
+        ));
+    }
+
+    let mut path = vec![fetch(store, &target).await?];
+    while let Some(Some(parent)) = parents.get(&to_v1(&path.last().unwrap().cid)) {
+        path.push(fetch(store, parent).await?);
+    }
+    path.reverse();
+    Ok(path)
+}
+
+/// Checks a path of blocks from [`prove_path`]: that it starts at `root` and ends at `target`,
+/// that every block matches its CID, and that each one links to the next. Fails with
+/// [`io::ErrorKind::InvalidData`] saying what's wrong otherwise. Blocks hashed with a function
+/// [`Block::hash_matches`] doesn't support can't be checked, so they fail too.
+pub fn verify_path(root: &Cid, target: &Cid, path: &[Block]) -> Result<(), io::Error> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+
+    let (Some(first), Some(last)) = (path.first(), path.last()) else {
+        return Err(invalid("empty path".to_string()));
+    };
+    if to_v1(&first.cid) != to_v1(root) || to_v1(&last.cid) != to_v1(target) {
+        return Err(invalid(format!(
+            "path doesn't lead from {} to {}",
+            root, target
+        )));
+    }
+    for block in path {
+        if Block::hash_matches(&block.cid, &block.data) != Some(true) {
+            return Err(invalid(format!(
+                "block {} doesn't match its CID",
+                block.cid
+            )));
+        }
+    }
+    for pair in path.windows(2) {
+        let child = to_v1(&pair[1].cid);
+        if !links(&pair[0])?.iter().any(|link| to_v1(link) == child) {
+            return Err(invalid(format!(
+                "{} doesn't link to {}",
+                pair[0].cid, pair[1].cid
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Gets a block that has to be in `store`.
+async fn fetch(store: &impl Blockstore, cid: &Cid) -> Result<Block, io::Error> {
+    store
+        .get_block(cid)
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("block {} not found", cid)))
+}
+
+// Polls `futures` until one of them finishes, removing and returning its output. Returns `None`
+// if there's nothing left.
+pub(crate) async fn next_finished<F: Future>(futures: &mut Vec<Pin<Box<F>>>) -> Option<F::Output> {
+    if futures.is_empty() {
+        return None;
+    }
+
+    future::poll_fn(|cx| {
+        for i in 0..futures.len() {
+            if let Poll::Ready(output) = futures[i].as_mut().poll(cx) {
+                futures.swap_remove(i);
+                return Poll::Ready(Some(output));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Codec, Hasher, make_random_block};
+    use crate::memstore::MemStore;
+
+    // A dag-cbor block holding a list of links.
+    fn node(children: &[Cid]) -> Block {
+        let mut data = vec![0x80 | children.len() as u8];
+        for child in children {
+            let cid = child.to_bytes();
+            data.extend_from_slice(&[0xd8, 0x2a, 0x58, cid.len() as u8 + 1, 0x00]);
+            data.extend_from_slice(&cid);
+        }
+        Block::new_with_codec(data, Codec::DagCbor, Hasher::Sha2_256).unwrap()
+    }
+
+    #[test]
+    fn should_extract_links() {
+        let leaves: Vec<Cid> = (0..3).map(|_| make_random_block(100).cid).collect();
+
+        assert_eq!(links(&node(&leaves)).unwrap(), leaves);
+        assert!(links(&make_random_block(100)).unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_walk_dag_breadth_first() {
+        let store = MemStore::new();
+        let leaves: Vec<Block> = (0..3).map(|_| make_random_block(100)).collect();
+        // Both branches share the middle leaf, which should only turn up once.
+        let left = node(&[leaves[0].cid, leaves[1].cid]);
+        let right = node(&[leaves[1].cid, leaves[2].cid]);
+        let root = node(&[left.cid, right.cid]);
+        store.put_many(&leaves).await.unwrap();
+        store
+            .put_many(&[left.clone(), right.clone(), root.clone()])
+            .await
+            .unwrap();
+
+        let mut walk = walk(&store, root.cid);
+        let mut visited = Vec::new();
+        while let Some(block) = walk.next().await {
+            visited.push(block.unwrap().cid);
+        }
+
+        let expected = [
+            root.cid,
+            left.cid,
+            right.cid,
+            leaves[0].cid,
+            leaves[1].cid,
+            leaves[2].cid,
+        ];
+        assert_eq!(visited, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_fail_walk_on_missing_block() {
+        let store = MemStore::new();
+        let missing = make_random_block(100);
+        let root = node(&[missing.cid]);
+        store.put_block(&root).await.unwrap();
+
+        let mut walk = walk(&store, root.cid);
+        assert_eq!(walk.next().await.unwrap().unwrap(), root);
+        let err = walk.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(walk.next().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_copy_only_missing_blocks() {
+        let src = MemStore::new();
+        let leaves: Vec<Block> = (0..10).map(|_| make_random_block(100)).collect();
+        let cids: Vec<Cid> = leaves.iter().map(|leaf| leaf.cid).collect();
+        let root = node(&cids);
+        src.put_many(&leaves).await.unwrap();
+        src.put_block(&root).await.unwrap();
+        // Something unrelated, which shouldn't get copied.
+        src.put_block(&make_random_block(100)).await.unwrap();
+
+        let dst = MemStore::new();
+        dst.put_many(&leaves[..4]).await.unwrap();
+
+        let copied = copy_dag(&src, &dst, root.cid, 3).await.unwrap();
+        assert_eq!(copied, 7);
+        assert_eq!(dst.len(), 11);
+        assert!(dst.has_block(&root.cid).await);
+        assert_eq!(copy_dag(&src, &dst, root.cid, 3).await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_prove_paths() {
+        let store = MemStore::new();
+        let leaves: Vec<Block> = (0..3).map(|_| make_random_block(100)).collect();
+        let left = node(&[leaves[0].cid]);
+        let right = node(&[leaves[1].cid, leaves[2].cid]);
+        let root = node(&[left.cid, right.cid]);
+        store.put_many(&leaves).await.unwrap();
+        store
+            .put_many(&[left.clone(), right.clone(), root.clone()])
+            .await
+            .unwrap();
+
+        let path = prove_path(&store, root.cid, leaves[2].cid).await.unwrap();
+        assert_eq!(path, [root.clone(), right.clone(), leaves[2].clone()]);
+        verify_path(&root.cid, &leaves[2].cid, &path).unwrap();
+        let path = prove_path(&store, root.cid, root.cid).await.unwrap();
+        verify_path(&root.cid, &root.cid, &path).unwrap();
+
+        let unrelated = make_random_block(100);
+        store.put_block(&unrelated).await.unwrap();
+        let result = prove_path(&store, root.cid, unrelated.cid).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn should_reject_bad_paths() {
+        let leaves: Vec<Block> = (0..2).map(|_| make_random_block(100)).collect();
+        let parent = node(&[leaves[0].cid]);
+        let root = node(&[parent.cid]);
+
+        // Skipping a level, a block that isn't linked to, and one that's been tampered with.
+        let skipped = [root.clone(), leaves[0].clone()];
+        let unlinked = [root.clone(), parent.clone(), leaves[1].clone()];
+        let tampered = [
+            root.clone(),
+            Block {
+                cid: parent.cid,
+                data: leaves[1].data.clone(),
+            },
+            leaves[0].clone(),
+        ];
+        for path in [&skipped[..], &unlinked, &tampered] {
+            let target = &path.last().unwrap().cid;
+            assert!(verify_path(&root.cid, target, path).is_err());
+        }
+        let path = [root.clone(), parent.clone(), leaves[0].clone()];
+        assert!(verify_path(&root.cid, &leaves[1].cid, &path).is_err());
+        verify_path(&root.cid, &leaves[0].cid, &path).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! Comparing the contents of two stores, such as to check that a replica has caught up.
+
+use std::collections::VecDeque;
+
+use cid::Cid;
+
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream};
+
+// How many listed CIDs get checked against the other store at a time.
+const DIFF_BATCH: usize = 256;
+
+/// A way in which the two stores given to [`diff`] differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Difference {
+    OnlyInA(Cid),
+    OnlyInB(Cid),
+    /// Both stores have the block, but with data of different sizes, as `a` and `b` bytes.
+    SizeMismatch {
+        cid: Cid,
+        a: u64,
+        b: u64,
+    },
+}
+
+/// The differences between two stores, as found by [`diff`]. They're worked out as they're
+/// asked for, a batch at a time, so comparing huge stores doesn't take listing all of either in
+/// memory.
+pub struct DiffReport<'a, A, B> {
+    a: &'a A,
+    b: &'a B,
+    check_sizes: bool,
+    // Which store's listing is being gone through: first `a`'s, then `b`'s.
+    listing_b: bool,
+    cids: Option<CidStream>,
+    found: VecDeque<Difference>,
+}
+
+/// Compares the blocks in `a` with those in `b`, listing first the ones only in `a`, then the
+/// ones only in `b`. Blocks put or deleted while this runs may or may not show up as different.
+pub fn diff<'a, A: Blockstore, B: Blockstore>(a: &'a A, b: &'a B) -> DiffReport<'a, A, B> {
+    DiffReport {
+        a,
+        b,
+        check_sizes: false,
+        listing_b: false,
+        cids: None,
+        found: VecDeque::new(),
+    }
+}
+
+impl<A: Blockstore, B: Blockstore> DiffReport<'_, A, B> {
+    /// Also reports blocks that both stores have but with different sizes, listing them among
+    /// the blocks only in `a`. This asks both stores for the size of every block they have in
+    /// common.
+    pub fn with_size_check(mut self) -> Self {
+        self.check_sizes = true;
+        self
+    }
+
+    /// Returns the next difference, or `None` once there are no more. An error ends the report.
+    pub async fn next(&mut self) -> Option<Result<Difference, BlockstoreError>> {
+        loop {
+            if let Some(difference) = self.found.pop_front() {
+                return Some(Ok(difference));
+            }
+            match self.next_batch().await {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => {
+                    self.cids = None;
+                    self.listing_b = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    /// Goes through the whole report, returning every difference in it.
+    pub async fn collect(mut self) -> Result<Vec<Difference>, BlockstoreError> {
+        let mut differences = Vec::new();
+        while let Some(difference) = self.next().await {
+            differences.push(difference?);
+        }
+        Ok(differences)
+    }
+
+    // Checks the next batch of listed CIDs against the other store, returning false once both
+    // listings are done.
+    async fn next_batch(&mut self) -> Result<bool, BlockstoreError> {
+        let cids = match &mut self.cids {
+            Some(cids) => cids,
+            None => self.cids.insert(match self.listing_b {
+                false => self.a.blocks(),
+                true => self.b.blocks(),
+            }),
+        };
+
+        let mut batch = Vec::with_capacity(DIFF_BATCH);
+        match cids.recv().await {
+            Some(cid) => batch.push(cid?),
+            None if self.listing_b => return Ok(false),
+            None => {
+                self.listing_b = true;
+                self.cids = None;
+                return Ok(true);
+            }
+        }
+        while batch.len() < DIFF_BATCH
+            && let Ok(cid) = cids.try_recv()
+        {
+            batch.push(cid?);
+        }
+
+        if self.listing_b {
+            let found = self.a.has_many(&batch).await;
+            for (cid, found) in batch.into_iter().zip(found) {
+                if !found {
+                    self.found.push_back(Difference::OnlyInB(cid));
+                }
+            }
+            return Ok(true);
+        }
+
+        let found = self.b.has_many(&batch).await;
+        for (cid, found) in batch.into_iter().zip(found) {
+            if !found {
+                self.found.push_back(Difference::OnlyInA(cid));
+            } else if self.check_sizes {
+                // Either size can be missing if the block was deleted since it was listed.
+                let a = self.a.block_size(&cid).await?;
+                let b = self.b.block_size(&cid).await?;
+                if let (Some(a), Some(b)) = (a, b)
+                    && a != b
+                {
+                    self.found.push_back(Difference::SizeMismatch { cid, a, b });
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, make_random_block};
+    use crate::memstore::MemStore;
+    use bytes::Bytes;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_list_blocks_in_only_one_store() {
+        let a = MemStore::new();
+        let b = MemStore::new();
+        let blocks: Vec<Block> = (0..1_000).map(|_| make_random_block(10)).collect();
+        a.put_many(&blocks[..600]).await.unwrap();
+        b.put_many(&blocks[500..]).await.unwrap();
+
+        let differences = diff(&a, &b).collect().await.unwrap();
+        assert_eq!(differences.len(), 900);
+        let (only_a, only_b) = differences.split_at(500);
+        for difference in only_a {
+            let Difference::OnlyInA(cid) = difference else {
+                panic!("unexpected {:?}", difference);
+            };
+            assert!(blocks[..500].iter().any(|block| block.cid == *cid));
+        }
+        for difference in only_b {
+            let Difference::OnlyInB(cid) = difference else {
+                panic!("unexpected {:?}", difference);
+            };
+            assert!(blocks[600..].iter().any(|block| block.cid == *cid));
+        }
+
+        assert!(diff(&a, &a).next().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_check_sizes_if_asked() {
+        let a = MemStore::new();
+        let b = MemStore::new();
+        let block = make_random_block(100);
+        a.put_block(&block).await.unwrap();
+        // Stores don't check what they're given, so a bad replica can have another size.
+        let truncated = Block {
+            cid: block.cid,
+            data: Bytes::from_static(b"short"),
+        };
+        b.put_block(&truncated).await.unwrap();
+
+        assert!(diff(&a, &b).collect().await.unwrap().is_empty());
+        let differences = diff(&a, &b).with_size_check().collect().await.unwrap();
+        let mismatch = Difference::SizeMismatch {
+            cid: block.cid,
+            a: 100,
+            b: 5,
+        };
+        assert_eq!(differences, [mismatch]);
+    }
+}
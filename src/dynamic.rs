@@ -0,0 +1,202 @@
+//! A dyn-compatible companion to [`Blockstore`], for picking a backend at runtime, e.g. from
+//! command line flags. Every [`Blockstore`] is a [`DynBlockstore`], and boxed ones are
+//! [`Blockstore`]s again, so they still fit in the generic wrappers.
+
+use std::pin::Pin;
+use std::sync::Arc;
+
+use cid::Cid;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+
+/// A future boxed up so that it can be returned through a trait object.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// [`Blockstore`], with its futures boxed so that it can live behind a `dyn`. Implemented for
+/// every [`Blockstore`]; there's no need to implement it directly.
+///
+/// The methods are named like [`Blockstore`]'s, so calls get ambiguous with both traits in
+/// scope. Since `Box<dyn DynBlockstore>` and `Arc<dyn DynBlockstore>` are [`Blockstore`]s, it's
+/// usually enough to import just that.
+pub trait DynBlockstore: Send + Sync {
+    fn put_block<'a>(&'a self, block: &'a Block) -> BoxFuture<'a, Result<Put, BlockstoreError>>;
+    fn put_many<'a>(&'a self, blocks: &'a [Block]) -> BoxFuture<'a, Result<(), BlockstoreError>>;
+    fn has_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, bool>;
+    fn has_many<'a>(&'a self, cids: &'a [Cid]) -> BoxFuture<'a, Vec<bool>>;
+    fn get_block<'a>(
+        &'a self,
+        cid: &'a Cid,
+    ) -> BoxFuture<'a, Result<Option<Block>, BlockstoreError>>;
+    fn get_many<'a>(
+        &'a self,
+        cids: &'a [Cid],
+    ) -> BoxFuture<'a, Result<Vec<Option<Block>>, BlockstoreError>>;
+    fn prefetch(&self, cids: &[Cid]);
+    fn block_size<'a>(
+        &'a self,
+        cid: &'a Cid,
+    ) -> BoxFuture<'a, Result<Option<u64>, BlockstoreError>>;
+    fn del_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<(), BlockstoreError>>;
+    fn del_many<'a>(&'a self, cids: &'a [Cid]) -> BoxFuture<'a, Result<(), BlockstoreError>>;
+    fn blocks(&self) -> CidStream;
+    fn stats(&self) -> BoxFuture<'_, Result<StoreStats, BlockstoreError>>;
+}
+
+impl<S: Blockstore> DynBlockstore for S {
+    fn put_block<'a>(&'a self, block: &'a Block) -> BoxFuture<'a, Result<Put, BlockstoreError>> {
+        Box::pin(Blockstore::put_block(self, block))
+    }
+
+    fn put_many<'a>(&'a self, blocks: &'a [Block]) -> BoxFuture<'a, Result<(), BlockstoreError>> {
+        Box::pin(Blockstore::put_many(self, blocks))
+    }
+
+    fn has_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, bool> {
+        Box::pin(Blockstore::has_block(self, cid))
+    }
+
+    fn has_many<'a>(&'a self, cids: &'a [Cid]) -> BoxFuture<'a, Vec<bool>> {
+        Box::pin(Blockstore::has_many(self, cids))
+    }
+
+    fn get_block<'a>(
+        &'a self,
+        cid: &'a Cid,
+    ) -> BoxFuture<'a, Result<Option<Block>, BlockstoreError>> {
+        Box::pin(Blockstore::get_block(self, cid))
+    }
+
+    fn get_many<'a>(
+        &'a self,
+        cids: &'a [Cid],
+    ) -> BoxFuture<'a, Result<Vec<Option<Block>>, BlockstoreError>> {
+        Box::pin(Blockstore::get_many(self, cids))
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        Blockstore::prefetch(self, cids)
+    }
+
+    fn block_size<'a>(
+        &'a self,
+        cid: &'a Cid,
+    ) -> BoxFuture<'a, Result<Option<u64>, BlockstoreError>> {
+        Box::pin(Blockstore::block_size(self, cid))
+    }
+
+    fn del_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<(), BlockstoreError>> {
+        Box::pin(Blockstore::del_block(self, cid))
+    }
+
+    fn del_many<'a>(&'a self, cids: &'a [Cid]) -> BoxFuture<'a, Result<(), BlockstoreError>> {
+        Box::pin(Blockstore::del_many(self, cids))
+    }
+
+    fn blocks(&self) -> CidStream {
+        Blockstore::blocks(self)
+    }
+
+    fn stats(&self) -> BoxFuture<'_, Result<StoreStats, BlockstoreError>> {
+        Box::pin(Blockstore::stats(self))
+    }
+}
+
+// Forwards to the boxed store, for both boxed and shared trait objects.
+macro_rules! forward_to_dyn {
+    ($store:ty) => {
+        impl Blockstore for $store {
+            async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+                DynBlockstore::put_block(&**self, block).await
+            }
+
+            async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+                DynBlockstore::put_many(&**self, blocks).await
+            }
+
+            async fn has_block(&self, cid: &Cid) -> bool {
+                DynBlockstore::has_block(&**self, cid).await
+            }
+
+            async fn has_many(&self, cids: &[Cid]) -> Vec<bool> {
+                DynBlockstore::has_many(&**self, cids).await
+            }
+
+            async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+                DynBlockstore::get_block(&**self, cid).await
+            }
+
+            async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, BlockstoreError> {
+                DynBlockstore::get_many(&**self, cids).await
+            }
+
+            fn prefetch(&self, cids: &[Cid]) {
+                DynBlockstore::prefetch(&**self, cids)
+            }
+
+            async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+                DynBlockstore::block_size(&**self, cid).await
+            }
+
+            async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+                DynBlockstore::del_block(&**self, cid).await
+            }
+
+            async fn del_many(&self, cids: &[Cid]) -> Result<(), BlockstoreError> {
+                DynBlockstore::del_many(&**self, cids).await
+            }
+
+            fn blocks(&self) -> CidStream {
+                DynBlockstore::blocks(&**self)
+            }
+
+            async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+                DynBlockstore::stats(&**self).await
+            }
+        }
+    };
+}
+
+forward_to_dyn!(Box<dyn DynBlockstore>);
+forward_to_dyn!(Arc<dyn DynBlockstore>);
+
+#[cfg(test)]
+mod tests {
+    use super::{Arc, Blockstore};
+    use crate::block::make_random_block;
+    use crate::blockstore::FSStore;
+    use crate::events::EventStore;
+    use crate::memstore::MemStore;
+    use tempfile::{TempDir, tempdir};
+
+    async fn make_dyn_store() -> (Box<dyn super::DynBlockstore>, TempDir) {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(root.path().to_path_buf()).await.unwrap();
+        (Box::new(store), root)
+    }
+
+    crate::conformance::conformance_tests!(make_dyn_store);
+
+    // How a CLI might pick its backend.
+    async fn open(backend: &str, root: &TempDir) -> Arc<dyn super::DynBlockstore> {
+        match backend {
+            "mem" => Arc::new(MemStore::new()),
+            "fs" => Arc::new(FSStore::create(root.path().to_path_buf()).await.unwrap()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_pick_backends_at_runtime() {
+        let root = tempdir().unwrap();
+        for backend in ["mem", "fs"] {
+            let store = EventStore::new(open(backend, &root).await);
+            let mut events = store.subscribe();
+            let block = make_random_block(100);
+
+            store.put_block(&block).await.unwrap();
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+            assert!(events.recv().await.is_ok());
+        }
+    }
+}
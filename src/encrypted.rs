@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::RwLock;
+
+use bytes::Bytes;
+use cid::Cid;
+use rand::RngCore;
+
+use crate::block::{Block, is_inline, to_v1};
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, StoreStats};
+use crate::xchacha::{self, KEY_LEN, NONCE_LEN, TAG_LEN};
+
+pub type Key = [u8; KEY_LEN];
+
+// Format version, key id, nonce, then the ciphertext and its tag.
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 1 + 4 + NONCE_LEN;
+
+/// How many bytes encryption adds to every block.
+pub const OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+
+/// Supplies the keys an [`EncryptedStore`] encrypts and decrypts with. Keys have numeric ids,
+/// which get stored alongside each block, so that rotating to a new key leaves blocks written
+/// under older ones readable for as long as the provider still hands those out.
+pub trait KeyProvider: Send + Sync {
+    /// The id and key new blocks get encrypted with.
+    fn current_key(&self) -> (u32, Key);
+    /// Looks up a key by id, for decrypting blocks written under it.
+    fn key(&self, id: u32) -> Option<Key>;
+}
+
+/// A [`KeyProvider`] holding its keys in memory.
+pub struct Keyring {
+    keys: RwLock<(u32, HashMap<u32, Key>)>,
+}
+
+impl Keyring {
+    pub fn new(id: u32, key: Key) -> Self {
+        Keyring {
+            keys: RwLock::new((id, HashMap::from([(id, key)]))),
+        }
+    }
+
+    /// Adds a key and makes it the current one. The previous keys stay around for decryption.
+    pub fn rotate(&self, id: u32, key: Key) {
+        let mut keys = self.keys.write().unwrap();
+        keys.1.insert(id, key);
+        keys.0 = id;
+    }
+
+    /// Forgets a key, making the blocks written under it unreadable. The current key can't be
+    /// removed; returns whether the key was.
+    pub fn remove(&self, id: u32) -> bool {
+        let mut keys = self.keys.write().unwrap();
+        keys.0 != id && keys.1.remove(&id).is_some()
+    }
+}
+
+impl KeyProvider for Keyring {
+    fn current_key(&self) -> (u32, Key) {
+        let keys = self.keys.read().unwrap();
+        (keys.0, keys.1[&keys.0])
+    }
+
+    fn key(&self, id: u32) -> Option<Key> {
+        self.keys.read().unwrap().1.get(&id).copied()
+    }
+}
+
+/// Returned (wrapped in an [`io::Error`] of kind [`io::ErrorKind::InvalidData`]) when an
+/// [`EncryptedStore`] can't decrypt a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecryptionError {
+    /// The block was encrypted under a key the provider doesn't have.
+    UnknownKey(u32),
+    /// The stored data isn't something we wrote.
+    Malformed,
+    /// The data (or the CID it's stored under) has been tampered with, or it was encrypted under
+    /// a different key with the same id.
+    Unauthenticated,
+}
+
+impl fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptionError::UnknownKey(id) => {
+                write!(f, "block encrypted under unknown key {}", id)
+            }
+            DecryptionError::Malformed => write!(f, "encrypted block is malformed"),
+            DecryptionError::Unauthenticated => write!(f, "encrypted block failed authentication"),
+        }
+    }
+}
+
+impl Error for DecryptionError {}
+
+/// Wraps a [`Blockstore`] so that block contents are encrypted before they reach it, with
+/// XChaCha20-Poly1305. CIDs stay those of the plaintext, and each ciphertext is bound to its CID,
+/// so that blocks can't be swapped around undetected. That's the CID as version 1, so that a
+/// block put under its CIDv0 can be read under its CIDv1, and the other way around.
+///
+/// Each block gets a random nonce, and grows by [`OVERHEAD`] bytes on its way down;
+/// [`Blockstore::block_size`] and [`Blockstore::stats`] report plaintext sizes.
+///
+/// Blocks with identity CIDs never reach the store: their CID holds their data, so there's
+/// nothing to hide, and they're served from it, as [`FSStore`] does.
+///
+/// [`FSStore`]: crate::blockstore::FSStore
+pub struct EncryptedStore<S, K> {
+    store: S,
+    keys: K,
+}
+
+impl<S: Blockstore, K: KeyProvider> EncryptedStore<S, K> {
+    pub fn new(store: S, keys: K) -> Self {
+        EncryptedStore { store, keys }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn keys(&self) -> &K {
+        &self.keys
+    }
+
+    fn encrypt(&self, block: &Block) -> Block {
+        let (id, key) = self.keys.current_key();
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::rng().fill_bytes(&mut nonce);
+
+        let mut data = Vec::with_capacity(block.data.len() + OVERHEAD);
+        data.push(VERSION);
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&block.data);
+        let aad = to_v1(&block.cid).to_bytes();
+        let tag = xchacha::seal(&key, &nonce, &aad, &mut data[HEADER_LEN..]);
+        data.extend_from_slice(&tag);
+
+        Block {
+            cid: block.cid,
+            data: data.into(),
+        }
+    }
+
+    fn decrypt(&self, block: Block) -> Result<Block, io::Error> {
+        let error = |e: DecryptionError| io::Error::new(io::ErrorKind::InvalidData, e);
+
+        let mut data = Vec::from(block.data);
+        if data.len() < OVERHEAD || data[0] != VERSION {
+            return Err(error(DecryptionError::Malformed));
+        }
+        let id = u32::from_be_bytes(data[1..5].try_into().unwrap());
+        let key = self
+            .keys
+            .key(id)
+            .ok_or_else(|| error(DecryptionError::UnknownKey(id)))?;
+        let nonce: [u8; NONCE_LEN] = data[5..HEADER_LEN].try_into().unwrap();
+        let tag: [u8; TAG_LEN] = data.split_off(data.len() - TAG_LEN).try_into().unwrap();
+
+        if !xchacha::open(
+            &key,
+            &nonce,
+            &to_v1(&block.cid).to_bytes(),
+            &mut data[HEADER_LEN..],
+            &tag,
+        ) {
+            return Err(error(DecryptionError::Unauthenticated));
+        }
+        Ok(Block {
+            cid: block.cid,
+            data: Bytes::from(data).slice(HEADER_LEN..),
+        })
+    }
+}
+
+impl<S: Blockstore, K: KeyProvider> Blockstore for EncryptedStore<S, K> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        if is_inline(&block.cid) {
+            return Ok(Put::Existing);
+        }
+        self.store.put_block(&self.encrypt(block)).await
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        let encrypted: Vec<Block> = blocks
+            .iter()
+            .filter(|block| !is_inline(&block.cid))
+            .map(|block| self.encrypt(block))
+            .collect();
+        self.store.put_many(&encrypted).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        is_inline(cid) || self.store.has_block(cid).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if let Some(block) = Block::inline(cid) {
+            return Ok(Some(block));
+        }
+        match self.store.get_block(cid).await? {
+            Some(block) => Ok(Some(self.decrypt(block)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(Some(cid.hash().size() as u64));
+        }
+        let size = self.store.block_size(cid).await?;
+        Ok(size.map(|size| size.saturating_sub(OVERHEAD as u64)))
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(());
+        }
+        self.store.del_block(cid).await
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let mut stats = self.store.stats().await?;
+        stats.bytes = stats.bytes.saturating_sub(stats.blocks * OVERHEAD as u64);
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Codec, Hasher, make_random_block, to_v0};
+    use crate::blockstore::FSStore;
+    use crate::memstore::MemStore;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    async fn make_encrypted_store() -> (EncryptedStore<MemStore, Keyring>, ()) {
+        let store = EncryptedStore::new(MemStore::new(), Keyring::new(1, [1; KEY_LEN]));
+        (store, ())
+    }
+
+    crate::conformance::conformance_tests!(make_encrypted_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_not_store_plaintext() {
+        let (store, _) = make_encrypted_store().await;
+        let block = Block::new(vec![b'a'; 1_000]).unwrap();
+
+        store.put_block(&block).await.unwrap();
+
+        let stored = store.store().get_block(&block.cid).await.unwrap().unwrap();
+        assert_eq!(stored.data.len(), 1_000 + OVERHEAD);
+        assert!(!stored.data.windows(16).any(|window| window == [b'a'; 16]));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_read_blocks_written_before_rotation() {
+        let (store, _) = make_encrypted_store().await;
+        let old = make_random_block(100);
+        store.put_block(&old).await.unwrap();
+
+        store.keys().rotate(2, [2; KEY_LEN]);
+        let new = make_random_block(100);
+        store.put_block(&new).await.unwrap();
+
+        assert_eq!(
+            store.get_block(&old.cid).await.unwrap().unwrap().data,
+            old.data
+        );
+        assert_eq!(
+            store.get_block(&new.cid).await.unwrap().unwrap().data,
+            new.data
+        );
+
+        assert!(store.keys().remove(1));
+        let err = io::Error::from(store.get_block(&old.cid).await.unwrap_err());
+        let reason = err.get_ref().unwrap().downcast_ref::<DecryptionError>();
+        assert_eq!(reason, Some(&DecryptionError::UnknownKey(1)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_detect_tampering() {
+        let (store, _) = make_encrypted_store().await;
+        let block = make_random_block(100);
+        store.put_block(&block).await.unwrap();
+
+        let mut stored = store.store().get_block(&block.cid).await.unwrap().unwrap();
+        let mut data = stored.data.to_vec();
+        data[HEADER_LEN] ^= 1;
+        stored.data = data.into();
+        let tampered = EncryptedStore::new(MemStore::new(), Keyring::new(1, [1; KEY_LEN]));
+        tampered.store().put_block(&stored).await.unwrap();
+
+        let err = io::Error::from(tampered.get_block(&block.cid).await.unwrap_err());
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let reason = err.get_ref().unwrap().downcast_ref::<DecryptionError>();
+        assert_eq!(reason, Some(&DecryptionError::Unauthenticated));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_serve_inline_blocks_over_fs_store() {
+        let root = tempdir().unwrap();
+        let fs = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = EncryptedStore::new(fs, Keyring::new(1, [1; KEY_LEN]));
+        let inline = Block::new_inline(&b"hello"[..], Codec::Raw).unwrap();
+
+        assert_eq!(store.put_block(&inline).await.unwrap(), Put::Existing);
+        assert!(store.has_block(&inline.cid).await);
+        assert_eq!(
+            store.get_block(&inline.cid).await.unwrap(),
+            Some(inline.clone())
+        );
+        assert_eq!(store.block_size(&inline.cid).await.unwrap(), Some(5));
+        store.del_block(&inline.cid).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_read_v0_blocks_under_v1() {
+        let root = tempdir().unwrap();
+        let fs = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = EncryptedStore::new(fs, Keyring::new(1, [1; KEY_LEN]));
+        let block =
+            Block::new_with_codec(b"legacy".to_vec(), Codec::DagPb, Hasher::Sha2_256).unwrap();
+        let v0 = Block {
+            cid: to_v0(&block.cid).unwrap(),
+            data: block.data.clone(),
+        };
+
+        store.put_block(&v0).await.unwrap();
+        let read = store.get_block(&block.cid).await.unwrap().unwrap();
+        assert_eq!(read.data, block.data);
+        let read = store.get_block(&v0.cid).await.unwrap().unwrap();
+        assert_eq!(read.data, block.data);
+    }
+}
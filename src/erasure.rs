@@ -0,0 +1,424 @@
+//! Erasure coding blocks across several stores, for keeping archives around through the loss of
+//! some of them at a fraction of the cost of full copies.
+
+use std::io;
+
+use bytes::Bytes;
+use cid::Cid;
+
+use crate::block::{Block, Hasher, is_inline};
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+use crate::mirrored::merge_listings;
+use crate::reed_solomon::ReedSolomon;
+
+// Format version and the length of the block, then the shard itself.
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 1 + 8;
+
+/// Splits every block into `data_shards` shards, plus a Reed–Solomon parity shard for each
+/// store beyond those, and keeps each shard in a store of its own. Blocks survive losing up to
+/// as many shards as there are parity shards, whether the stores holding them are down or the
+/// shards are gone.
+///
+/// Shards are stored under the CID of the block they're part of, so the stores mustn't verify
+/// what they read, and each one only ever holds shards of a single index. Reconstructed blocks
+/// are verified instead. Writes fail unless every store takes its shard, so that blocks start
+/// out with their full margin; stores that lost shards aren't repaired.
+///
+/// [`Blockstore::stats`] counts blocks by the store holding the most shards, and bytes by the
+/// data shards, so it's only exact while no shards are missing.
+///
+/// Blocks with identity CIDs never reach the stores: their CID holds their data, so there's no
+/// losing them, and they're served from it, as [`FSStore`] does.
+///
+/// [`FSStore`]: crate::blockstore::FSStore
+pub struct ErasureStore<S> {
+    stores: Vec<S>,
+    data_shards: usize,
+    codec: ReedSolomon,
+}
+
+impl<S: Blockstore> ErasureStore<S> {
+    /// Spreads blocks over `stores`, `data_shards` of which hold data, and the rest parity.
+    /// There must be at least that many stores, and at most 256.
+    pub fn new(stores: Vec<S>, data_shards: usize) -> Self {
+        assert!(
+            data_shards > 0 && data_shards <= stores.len() && stores.len() <= 256,
+            "need between {} and 256 stores for {} data shards",
+            data_shards,
+            data_shards
+        );
+        ErasureStore {
+            codec: ReedSolomon::new(data_shards, stores.len() - data_shards),
+            stores,
+            data_shards,
+        }
+    }
+
+    pub fn stores(&self) -> &[S] {
+        &self.stores
+    }
+
+    pub fn data_shards(&self) -> usize {
+        self.data_shards
+    }
+
+    pub fn parity_shards(&self) -> usize {
+        self.stores.len() - self.data_shards
+    }
+
+    // Splits `block` into one shard per store. Data shards are stored without the padding that
+    // evens out their lengths, so that the data shards of a block add up to its size.
+    fn encode(&self, block: &Block) -> Vec<Block> {
+        let len = block.data.len();
+        let size = len.div_ceil(self.data_shards);
+        let data: Vec<&[u8]> = (0..self.data_shards)
+            .map(|i| &block.data[(i * size).min(len)..((i + 1) * size).min(len)])
+            .collect();
+        let padded: Vec<Vec<u8>> = data.iter().map(|shard| pad(shard, size)).collect();
+        let padded: Vec<&[u8]> = padded.iter().map(Vec::as_slice).collect();
+        let parity = self.codec.encode(&padded);
+
+        let payloads = data.into_iter().chain(parity.iter().map(Vec::as_slice));
+        payloads
+            .map(|payload| {
+                let mut shard = Vec::with_capacity(HEADER_LEN + payload.len());
+                shard.push(VERSION);
+                shard.extend_from_slice(&(len as u64).to_be_bytes());
+                shard.extend_from_slice(payload);
+                Block {
+                    cid: block.cid,
+                    data: shard.into(),
+                }
+            })
+            .collect()
+    }
+
+    // The length of the block shard `index` is part of, and the shard's payload, if the shard
+    // is well formed.
+    fn parse(&self, index: usize, shard: &Bytes) -> Option<(usize, Bytes)> {
+        if shard.len() < HEADER_LEN || shard[0] != VERSION {
+            return None;
+        }
+        let len = u64::from_be_bytes(shard[1..HEADER_LEN].try_into().unwrap()) as usize;
+        let size = len.div_ceil(self.data_shards);
+        let expected = if index < self.data_shards {
+            ((index + 1) * size).min(len).saturating_sub(index * size)
+        } else {
+            size
+        };
+        (shard.len() - HEADER_LEN == expected).then(|| (len, shard.slice(HEADER_LEN..)))
+    }
+
+    // Puts block `cid` back together from `data_shards` well formed shards of it.
+    fn decode(
+        &self,
+        cid: &Cid,
+        len: usize,
+        shards: &[(usize, Bytes)],
+    ) -> Result<Block, BlockstoreError> {
+        let data = if shards.iter().all(|(index, _)| *index < self.data_shards) {
+            shards
+                .iter()
+                .flat_map(|(_, payload)| payload.iter().copied())
+                .collect()
+        } else {
+            let size = len.div_ceil(self.data_shards);
+            let padded: Vec<(usize, Vec<u8>)> = shards
+                .iter()
+                .map(|(index, payload)| (*index, pad(payload, size)))
+                .collect();
+            let padded: Vec<(usize, &[u8])> = padded
+                .iter()
+                .map(|(index, payload)| (*index, payload.as_slice()))
+                .collect();
+            let mut data: Vec<u8> = self.codec.reconstruct(&padded).concat();
+            data.truncate(len);
+            data
+        };
+
+        if Block::hash_matches(cid, &data) == Some(false) {
+            let digest = match Hasher::from_code(cid.hash().code()) {
+                Some(hasher) => hasher.digest(&data),
+                None => data,
+            };
+            return Err(BlockstoreError::corrupt(cid, &digest, None));
+        }
+        Ok(Block {
+            cid: *cid,
+            data: data.into(),
+        })
+    }
+}
+
+fn pad(shard: &[u8], size: usize) -> Vec<u8> {
+    let mut padded = shard.to_vec();
+    padded.resize(size, 0);
+    padded
+}
+
+fn malformed(cid: &Cid, index: usize) -> BlockstoreError {
+    BlockstoreError::Backend(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("shard {} of {} is malformed", index, cid),
+    ))
+}
+
+impl<S: Blockstore> Blockstore for ErasureStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        if is_inline(&block.cid) {
+            return Ok(Put::Existing);
+        }
+        let mut put = Put::Existing;
+        let mut errors = Vec::new();
+        for (store, shard) in self.stores.iter().zip(self.encode(block)) {
+            match store.put_block(&shard).await {
+                Ok(shard_put) => put = put.and(shard_put),
+                Err(e) => errors.push(e),
+            }
+        }
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(put),
+        }
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        let mut shards: Vec<Vec<Block>> = vec![Vec::with_capacity(blocks.len()); self.stores.len()];
+        for block in blocks.iter().filter(|block| !is_inline(&block.cid)) {
+            for (batch, shard) in shards.iter_mut().zip(self.encode(block)) {
+                batch.push(shard);
+            }
+        }
+
+        let mut errors = Vec::new();
+        for (store, batch) in self.stores.iter().zip(&shards) {
+            if let Err(e) = store.put_many(batch).await {
+                errors.push(e);
+            }
+        }
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        if is_inline(cid) {
+            return true;
+        }
+        let mut found = 0;
+        for store in &self.stores {
+            if store.has_block(cid).await {
+                found += 1;
+                if found == self.data_shards {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Reads data shards first, turning to the parity shards only for those that can't be read.
+    /// Fails if some shards are left, but too few to reconstruct the block from.
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if let Some(block) = Block::inline(cid) {
+            return Ok(Some(block));
+        }
+        let mut len = None;
+        let mut shards = Vec::with_capacity(self.data_shards);
+        let mut errors = Vec::new();
+        for (index, store) in self.stores.iter().enumerate() {
+            if shards.len() == self.data_shards {
+                break;
+            }
+            let shard = match store.get_block(cid).await {
+                Ok(Some(shard)) => shard,
+                Ok(None) => continue,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            // Shards that disagree with the first about the block's length are as good as lost.
+            match self.parse(index, &shard.data) {
+                Some((shard_len, payload)) if *len.get_or_insert(shard_len) == shard_len => {
+                    shards.push((index, payload))
+                }
+                _ => errors.push(malformed(cid, index)),
+            }
+        }
+
+        match len {
+            Some(len) if shards.len() == self.data_shards => {
+                Ok(Some(self.decode(cid, len, &shards)?))
+            }
+            Some(_) => Err(BlockstoreError::Backend(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is lost: {} of the {} shards needed are left",
+                    cid,
+                    shards.len(),
+                    self.data_shards
+                ),
+            ))),
+            None => match errors.into_iter().next() {
+                Some(e) => Err(e),
+                None => Ok(None),
+            },
+        }
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        // Reads go through the stores in order until they have enough shards.
+        for store in &self.stores[..self.data_shards] {
+            store.prefetch(cids);
+        }
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(Some(cid.hash().size() as u64));
+        }
+        let mut errors = Vec::new();
+        for (index, store) in self.stores.iter().enumerate() {
+            match store.get_block(cid).await {
+                Ok(Some(shard)) => match self.parse(index, &shard.data) {
+                    Some((len, _)) => return Ok(Some(len as u64)),
+                    None => errors.push(malformed(cid, index)),
+                },
+                Ok(None) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(());
+        }
+        let mut found = false;
+        let mut errors = Vec::new();
+        for store in &self.stores {
+            match store.del_block(cid).await {
+                Ok(()) => found = true,
+                Err(BlockstoreError::NotFound(_)) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None if !found => Err(BlockstoreError::NotFound(*cid)),
+            None => Ok(()),
+        }
+    }
+
+    /// Lists the blocks any store has a shard of, each once, whether or not there are enough
+    /// shards left to read them.
+    fn blocks(&self) -> CidStream {
+        merge_listings(self.stores.iter().map(Blockstore::blocks).collect())
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let mut stats = StoreStats::default();
+        for (index, store) in self.stores.iter().enumerate() {
+            let shards = store.stats().await?;
+            stats.blocks = stats.blocks.max(shards.blocks);
+            if index < self.data_shards {
+                stats.bytes += shards
+                    .bytes
+                    .saturating_sub(shards.blocks * HEADER_LEN as u64);
+            }
+            stats.disk_bytes += shards.disk_bytes;
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Codec, make_random_block};
+    use crate::blockstore::FSStore;
+    use crate::memstore::MemStore;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    fn stores(count: usize) -> Vec<MemStore> {
+        (0..count).map(|_| MemStore::new()).collect()
+    }
+
+    async fn make_erasure_store() -> (ErasureStore<MemStore>, ()) {
+        (ErasureStore::new(stores(5), 3), ())
+    }
+
+    crate::conformance::conformance_tests!(make_erasure_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_survive_losing_parity_many_shards() {
+        let store = ErasureStore::new(stores(6), 4);
+        // Sizes that don't split evenly, some smaller than the number of shards.
+        let blocks: Vec<Block> = [0, 1, 3, 1000, 1001].map(make_random_block).into();
+        store.put_many(&blocks).await.unwrap();
+
+        for block in &blocks {
+            store.stores()[0].del_block(&block.cid).await.unwrap();
+            store.stores()[4].del_block(&block.cid).await.unwrap();
+            assert!(store.has_block(&block.cid).await);
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), *block);
+            let size = store.block_size(&block.cid).await.unwrap();
+            assert_eq!(size, Some(block.data.len() as u64));
+
+            store.stores()[2].del_block(&block.cid).await.unwrap();
+            assert!(!store.has_block(&block.cid).await);
+            assert!(store.get_block(&block.cid).await.is_err());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_detect_corrupt_shards() {
+        let store = ErasureStore::new(stores(3), 2);
+        let block = make_random_block(100);
+        store.put_block(&block).await.unwrap();
+
+        // A shard that went bad but still looks well formed.
+        let mut shard = store.stores()[1]
+            .get_block(&block.cid)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut data = shard.data.to_vec();
+        data[HEADER_LEN] ^= 1;
+        shard.data = data.into();
+        store.stores()[1].del_block(&block.cid).await.unwrap();
+        store.stores()[1].put_block(&shard).await.unwrap();
+
+        let result = store.get_block(&block.cid).await;
+        assert!(matches!(result, Err(BlockstoreError::Corrupt(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_serve_inline_blocks_over_fs_store() {
+        let roots: Vec<_> = (0..3).map(|_| tempdir().unwrap()).collect();
+        let mut stores = Vec::new();
+        for root in &roots {
+            stores.push(FSStore::create(PathBuf::from(root.path())).await.unwrap());
+        }
+        let store = ErasureStore::new(stores, 2);
+        let inline = Block::new_inline(&b"hello"[..], Codec::Raw).unwrap();
+
+        assert_eq!(store.put_block(&inline).await.unwrap(), Put::Existing);
+        store.put_many(std::slice::from_ref(&inline)).await.unwrap();
+        assert!(store.has_block(&inline.cid).await);
+        assert_eq!(
+            store.get_block(&inline.cid).await.unwrap(),
+            Some(inline.clone())
+        );
+        assert_eq!(store.block_size(&inline.cid).await.unwrap(), Some(5));
+        store.del_block(&inline.cid).await.unwrap();
+    }
+}
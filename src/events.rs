@@ -0,0 +1,156 @@
+
+use cid::Cid;
+use tokio::sync::broadcast;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, StoreStats};
+
+// How many events a subscriber can fall behind before it starts missing some.
+const EVENT_BUFFER: usize = 1024;
+
+/// A change made to the store through an [`EventStore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockEvent {
+    /// A block was put. Putting a block the store already had counts too.
+    Put { cid: Cid, size: u64 },
+    /// A block was deleted.
+    Delete { cid: Cid, size: u64 },
+}
+
+/// Wraps a [`Blockstore`] so that interested parties can follow the puts and deletes made
+/// through it with [`EventStore::subscribe`], instead of polling the store.
+pub struct EventStore<S> {
+    store: S,
+    sender: broadcast::Sender<BlockEvent>,
+}
+
+impl<S: Blockstore> EventStore<S> {
+    pub fn new(store: S) -> Self {
+        EventStore {
+            store,
+            sender: broadcast::Sender::new(EVENT_BUFFER),
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns a receiver for every event from now on. A receiver that falls more than 1024
+    /// events behind skips the oldest ones, and is told how many it missed with
+    /// [`broadcast::error::RecvError::Lagged`].
+    pub fn subscribe(&self) -> broadcast::Receiver<BlockEvent> {
+        self.sender.subscribe()
+    }
+
+    fn emit(&self, event: BlockEvent) {
+        // Only fails if nobody is listening, which is fine.
+        let _ = self.sender.send(event);
+    }
+}
+
+impl<S: Blockstore> Blockstore for EventStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let put = self.store.put_block(block).await?;
+        self.emit(BlockEvent::Put {
+            cid: block.cid,
+            size: block.data.len() as u64,
+        });
+        Ok(put)
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        self.store.put_many(blocks).await?;
+        for block in blocks {
+            self.emit(BlockEvent::Put {
+                cid: block.cid,
+                size: block.data.len() as u64,
+            });
+        }
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        self.store.has_block(cid).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        self.store.get_block(cid).await
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.store.block_size(cid).await
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        // Deletes don't say how big the block was, so we have to ask first, but only when
+        // someone's going to hear about it.
+        let size = if self.sender.receiver_count() > 0 {
+            self.store.block_size(cid).await?.unwrap_or(0)
+        } else {
+            0
+        };
+
+        self.store.del_block(cid).await?;
+        self.emit(BlockEvent::Delete { cid: *cid, size });
+        Ok(())
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.store.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    async fn make_event_store() -> (EventStore<MemStore>, ()) {
+        (EventStore::new(MemStore::new()), ())
+    }
+
+    crate::conformance::conformance_tests!(make_event_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_notify_subscribers_of_changes() {
+        let store = EventStore::new(MemStore::new());
+        let blocks = [make_random_block(100), make_random_block(200)];
+        store.put_block(&blocks[0]).await.unwrap();
+
+        let mut events = store.subscribe();
+        store.put_many(&blocks).await.unwrap();
+        store.del_block(&blocks[1].cid).await.unwrap();
+        // Failed operations don't produce events.
+        assert!(store.del_block(&blocks[1].cid).await.is_err());
+
+        let expected = [
+            BlockEvent::Put {
+                cid: blocks[0].cid,
+                size: 100,
+            },
+            BlockEvent::Put {
+                cid: blocks[1].cid,
+                size: 200,
+            },
+            BlockEvent::Delete {
+                cid: blocks[1].cid,
+                size: 200,
+            },
+        ];
+        for event in expected {
+            assert_eq!(events.recv().await.unwrap(), event);
+        }
+        assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+    }
+}
@@ -0,0 +1,170 @@
+//! A [`Blockstore`] that fills itself in from trustless gateways, for lazily hydrating a local
+//! cache from the network.
+
+use std::io;
+
+use cid::Cid;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+use crate::remote::HttpStore;
+
+/// Wraps a local store, fetching blocks it doesn't have from a list of gateways, tried in order.
+/// Fetched blocks are verified against their CID, like [`HttpStore`] does, then written to the
+/// local store before being returned.
+///
+/// Only reads fall back; writes, deletes, listing and [`Blockstore::stats`] only see the local
+/// store. Gateways must be reachable over plain HTTP, so public ones need a local proxy.
+pub struct FallbackStore<S> {
+    store: S,
+    gateways: Vec<HttpStore>,
+}
+
+impl<S: Blockstore> FallbackStore<S> {
+    pub fn new(store: S, gateways: Vec<HttpStore>) -> Self {
+        FallbackStore { store, gateways }
+    }
+
+    /// Falls back to the gateways at `endpoints`, `http://host[:port]` URLs.
+    pub fn with_endpoints(store: S, endpoints: &[&str]) -> Result<Self, io::Error> {
+        let gateways = endpoints
+            .iter()
+            .map(|endpoint| HttpStore::new(endpoint))
+            .collect::<Result<_, _>>()?;
+        Ok(FallbackStore::new(store, gateways))
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn gateways(&self) -> &[HttpStore] {
+        &self.gateways
+    }
+
+    // Fetches `cid` from the first gateway that has a valid copy. Gateways that fail or send
+    // the wrong data are skipped; if none of them could be asked, that's an error rather than
+    // a miss.
+    async fn fetch(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        let mut error = None;
+        let mut asked = false;
+        for gateway in &self.gateways {
+            match gateway.get_block(cid).await {
+                Ok(Some(block)) => {
+                    // The block's been verified, so there's no harm in returning it even if
+                    // caching it fails: it'll just get fetched again next time.
+                    let _ = self.store.put_block(&block).await;
+                    return Ok(Some(block));
+                }
+                Ok(None) => asked = true,
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) if !asked => Err(e),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<S: Blockstore> Blockstore for FallbackStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        self.store.put_block(block).await
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        self.store.put_many(blocks).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        if self.store.has_block(cid).await {
+            return true;
+        }
+        for gateway in &self.gateways {
+            if gateway.has_block(cid).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if let Some(block) = self.store.get_block(cid).await? {
+            return Ok(Some(block));
+        }
+        self.fetch(cid).await
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if let Some(size) = self.store.block_size(cid).await? {
+            return Ok(Some(size));
+        }
+        // Fetching the block rather than asking for its size means it gets verified, and
+        // cached for the read that usually follows.
+        let block = self.fetch(cid).await?;
+        Ok(block.map(|block| block.data.len() as u64))
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.store.del_block(cid).await
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.store.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::gateway::TestGateway;
+    use crate::memstore::MemStore;
+
+    async fn make_fallback_store() -> (FallbackStore<MemStore>, ()) {
+        (FallbackStore::new(MemStore::new(), Vec::new()), ())
+    }
+
+    crate::conformance::conformance_tests!(make_fallback_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_fetch_missing_blocks() {
+        let block = make_random_block(100);
+        let tampered = Block {
+            cid: block.cid,
+            data: make_random_block(100).data,
+        };
+        let empty = TestGateway::start(&[]).await;
+        let lying = TestGateway::start(&[tampered]).await;
+        let honest = TestGateway::start(std::slice::from_ref(&block)).await;
+        let endpoints = [&empty, &lying, &honest].map(|gateway| gateway.endpoint.as_str());
+        let store = FallbackStore::with_endpoints(MemStore::new(), &endpoints).unwrap();
+
+        assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+        assert!(store.store().has_block(&block.cid).await);
+
+        let missing = make_random_block(10);
+        assert!(store.get_block(&missing.cid).await.unwrap().is_none());
+        assert!(!store.has_block(&missing.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_fail_when_no_gateway_answers() {
+        let block = make_random_block(100);
+        // Nothing listens on the discard port.
+        let store =
+            FallbackStore::with_endpoints(MemStore::new(), &["http://127.0.0.1:9"]).unwrap();
+        assert!(store.get_block(&block.cid).await.is_err());
+    }
+}
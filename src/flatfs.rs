@@ -0,0 +1,112 @@
+//! The on-disk layout of Kubo's flatfs datastore with the `next-to-last/2` sharding function,
+//! for [`crate::sharding::FlatFs`]. Blocks are keyed by multihash alone: each lives in
+//! `<shard>/<key>.data`, where the key is the unpadded, upper case base32 of the multihash and
+//! the shard is the two characters before its last.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use cid::Cid;
+use multihash::Multihash;
+
+use crate::block::Codec;
+
+/// Name of the file in the root of a flatfs datastore saying how it's sharded.
+pub(crate) const SHARDING_FILE: &str = "SHARDING";
+
+/// Contents of the sharding file for the one sharding function we support.
+pub(crate) const NEXT_TO_LAST_2: &str = "/repo/flatfs/shard/v1/next-to-last/2";
+
+const EXTENSION: &str = ".data";
+const SHARD_LEN: usize = 2;
+
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Where the block with multihash `hash` lives, relative to the root.
+pub(crate) fn block_path(hash: &Multihash<64>) -> PathBuf {
+    let key = encode(&hash.to_bytes());
+    // Keys too short to have a next-to-last pair get padded with underscores, like flatfs does.
+    let padded = format!("{:_>width$}", key, width = SHARD_LEN + 1);
+    let shard = &padded[padded.len() - SHARD_LEN - 1..padded.len() - 1];
+    Path::new(shard).join(format!("{}{}", key, EXTENSION))
+}
+
+/// The block whose file is at `path`, relative to the root, as a raw CIDv1: flatfs doesn't keep
+/// track of codecs. Returns `None` for anything that isn't a block file, such as the sharding
+/// file and whatever else Kubo keeps next to the shards.
+pub(crate) fn cid_from_path(path: &Path) -> Option<Result<Cid, io::Error>> {
+    let mut components = path.components();
+    let (Some(_shard), Some(name), None) =
+        (components.next(), components.next(), components.next())
+    else {
+        return None;
+    };
+    let key = name.as_os_str().to_str()?.strip_suffix(EXTENSION)?;
+
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let cid = decode(key)
+        .ok_or_else(|| invalid(format!("{:?} is not valid base32", key)))
+        .and_then(|bytes| Multihash::from_bytes(&bytes).map_err(|e| invalid(e.to_string())))
+        .map(|hash| Cid::new_v1(Codec::Raw.code(), hash));
+    Some(cid)
+}
+
+fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+    }
+    out
+}
+
+fn decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for char in text.bytes() {
+        let value = ALPHABET.iter().position(|c| *c == char)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_kubo_paths() {
+        // The well known empty directory, as it's laid out in a Kubo repo.
+        let cid = Cid::try_from("QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn").unwrap();
+        let path = block_path(cid.hash());
+        let expected = "X3/CIQFTFEEHEDF6KLBT32BFAGLXEZL4UWFNWM4LFTLMXQBCERZ6CMLX3Y.data";
+        assert_eq!(path, Path::new(expected));
+
+        let listed = cid_from_path(&path).unwrap().unwrap();
+        assert_eq!(listed.hash(), cid.hash());
+        assert_eq!(listed.codec(), Codec::Raw.code());
+    }
+
+    #[test]
+    fn should_ignore_non_block_files() {
+        assert!(cid_from_path(Path::new(SHARDING_FILE)).is_none());
+        assert!(cid_from_path(Path::new("_README")).is_none());
+        assert!(cid_from_path(Path::new("AB/put-123")).is_none());
+        assert!(cid_from_path(Path::new("AB/not!base32.data")).unwrap().is_err());
+    }
+}
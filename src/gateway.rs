@@ -0,0 +1,260 @@
+//! Serves blocks out of any [`Blockstore`] over HTTP, at `GET /ipfs/{cid}`, like the raw block
+//! responses of a trustless gateway. Paths into DAGs and other response formats aren't supported.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cid::Cid;
+use tokio::io::BufStream;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use crate::blockstore::Blockstore;
+use crate::http::{self, Request, Response};
+
+/// Content type of raw block responses.
+pub const RAW_BLOCK_TYPE: &str = "application/vnd.ipld.raw";
+
+const PATH_PREFIX: &str = "/ipfs/";
+
+// Nothing the gateway answers takes a body, so there's no need to read big ones just to say so.
+const MAX_REQUEST_BODY: usize = 64 << 10;
+
+// How long a client gets to send each request, including the wait for it on an idle connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An HTTP server for the blocks in a store.
+///
+/// Blocks are sent as-is, with their CID as the ETag. Since they can never change, they're marked
+/// as immutable, and requests with a matching `If-None-Match` get a `304 Not Modified`.
+pub struct Gateway<S> {
+    store: Arc<S>,
+}
+
+impl<S: Blockstore + 'static> Gateway<S> {
+    pub fn new(store: Arc<S>) -> Self {
+        Gateway { store }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Accepts connections on `listener` until accepting fails, serving each one on a task of its
+    /// own.
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), io::Error> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let gateway = Gateway {
+                store: self.store.clone(),
+            };
+            tokio::spawn(async move {
+                // There's nobody to report errors to: the client just sees the connection close.
+                let _ = gateway.serve_connection(stream).await;
+            });
+        }
+    }
+
+    async fn serve_connection(&self, stream: TcpStream) -> Result<(), io::Error> {
+        let mut stream = BufStream::new(stream);
+        loop {
+            let read = http::read_request(&mut stream, MAX_REQUEST_BODY);
+            let request = match time::timeout(REQUEST_TIMEOUT, read).await {
+                Ok(Ok(Some(request))) => request,
+                Ok(Ok(None)) => return Ok(()),
+                Ok(Err(e)) => {
+                    // Whatever is left of a bad request can't be told apart from the next one,
+                    // so the connection gets closed once the client is told what was wrong.
+                    let status = match e.kind() {
+                        io::ErrorKind::FileTooLarge => 413,
+                        io::ErrorKind::InvalidData => 400,
+                        _ => return Err(e),
+                    };
+                    let mut response = Response::new(status);
+                    response.headers.push(("connection".into(), "close".into()));
+                    http::write_response(&mut stream, &response, false).await?;
+                    return Err(e);
+                }
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out reading request",
+                    ));
+                }
+            };
+            let response = self.handle(&request).await;
+            http::write_response(&mut stream, &response, request.method == "HEAD").await?;
+        }
+    }
+
+    /// Answers a single request.
+    pub async fn handle(&self, request: &Request) -> Response {
+        let head_only = match request.method.as_str() {
+            "GET" => false,
+            "HEAD" => true,
+            _ => {
+                let mut response = Response::new(405);
+                response.headers.push(("allow".into(), "GET, HEAD".into()));
+                return response;
+            }
+        };
+
+        let path = request.target.split(['?', '#']).next().unwrap_or_default();
+        let Some(cid) = path.strip_prefix(PATH_PREFIX) else {
+            return Response::new(404);
+        };
+        let Ok(cid) = Cid::try_from(cid.trim_end_matches('/')) else {
+            return Response::new(400);
+        };
+
+        let etag = format!("\"{}\"", cid);
+        if request.header("if-none-match").is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        }) && self.store.has_block(&cid).await
+        {
+            return with_block_headers(Response::new(304), etag);
+        }
+
+        // HEAD requests get the length a GET would, without reading the block.
+        let found = if head_only {
+            let size = self.store.block_size(&cid).await;
+            size.map(|size| size.map(|size| (size, Vec::new())))
+        } else {
+            let block = self.store.get_block(&cid).await;
+            block.map(|block| block.map(|block| (block.data.len() as u64, block.data.to_vec())))
+        };
+
+        match found {
+            Ok(Some((size, body))) => {
+                let mut response = Response::new(200);
+                response.body = body;
+                response
+                    .headers
+                    .push(("content-length".into(), size.to_string()));
+                response
+                    .headers
+                    .push(("content-type".into(), RAW_BLOCK_TYPE.into()));
+                with_block_headers(response, etag)
+            }
+            Ok(None) => Response::new(404),
+            Err(_) => Response::new(500),
+        }
+    }
+}
+
+fn with_block_headers(mut response: Response, etag: String) -> Response {
+    response.headers.push(("etag".into(), etag));
+    response.headers.push((
+        "cache-control".into(),
+        "public, max-age=29030400, immutable".into(),
+    ));
+    response
+}
+
+// A gateway serving blocks out of a MemStore on a random local port, for tests. The server
+// stops when this is dropped.
+#[cfg(test)]
+pub(crate) struct TestGateway {
+    pub endpoint: String,
+    server: tokio::task::AbortHandle,
+}
+
+#[cfg(test)]
+impl TestGateway {
+    pub async fn start(blocks: &[crate::block::Block]) -> Self {
+        let store = crate::memstore::MemStore::new();
+        store.put_many(blocks).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let gateway = Gateway::new(Arc::new(store));
+        let server = tokio::spawn(async move { gateway.serve(listener).await });
+        TestGateway {
+            endpoint,
+            server: server.abort_handle(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestGateway {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::http::{Client, Endpoint};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_serve_blocks() {
+        let block = make_random_block(1000);
+        let gateway = TestGateway::start(std::slice::from_ref(&block)).await;
+        let client = Client::new(Endpoint::parse(&gateway.endpoint).unwrap());
+        let target = format!("/ipfs/{}", block.cid);
+        let etag = format!("\"{}\"", block.cid);
+
+        let response = client.send("GET", &target, &[], &[]).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, block.data);
+        assert_eq!(response.header("content-length"), Some("1000"));
+        assert_eq!(response.header("etag"), Some(etag.as_str()));
+        assert_eq!(response.header("content-type"), Some(RAW_BLOCK_TYPE));
+
+        let response = client.send("HEAD", &target, &[], &[]).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-length"), Some("1000"));
+        assert!(response.body.is_empty());
+
+        let headers = [("if-none-match".to_string(), etag.clone())];
+        let response = client.send("GET", &target, &headers, &[]).await.unwrap();
+        assert_eq!(response.status, 304);
+        assert!(response.body.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_reject_bad_requests() {
+        let gateway = TestGateway::start(&[]).await;
+        let client = Client::new(Endpoint::parse(&gateway.endpoint).unwrap());
+        let missing = format!("/ipfs/{}", make_random_block(10).cid);
+
+        let status = |method, target: &str| {
+            let target = target.to_string();
+            let client = &client;
+            async move { client.send(method, &target, &[], &[]).await.unwrap().status }
+        };
+        assert_eq!(status("GET", &missing).await, 404);
+        assert_eq!(status("HEAD", &missing).await, 404);
+        assert_eq!(status("GET", "/ipfs/not-a-cid").await, 400);
+        assert_eq!(status("GET", "/ipns/example.com").await, 404);
+        assert_eq!(status("PUT", &missing).await, 405);
+    }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_refuse_oversized_requests() {
+        let gateway = TestGateway::start(&[]).await;
+        let address = gateway.endpoint.strip_prefix("http://").unwrap();
+        let exchange = |request: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            // The gateway closes the connection after answering.
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let huge = "PUT /ipfs/ HTTP/1.1\r\ncontent-length: 18446744073709551615\r\n\r\n";
+        assert!(exchange(huge).await.starts_with("HTTP/1.1 413 "));
+        // The body isn't sent, since the gateway shouldn't be waiting for it.
+        let body = "GET /ipfs/ HTTP/1.1\r\ncontent-length: 5\r\n\r\n";
+        assert!(exchange(body).await.starts_with("HTTP/1.1 413 "));
+        let chunk = "PUT /ipfs/ HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\nfffffffff\r\n";
+        assert!(exchange(chunk).await.starts_with("HTTP/1.1 413 "));
+        let bad_chunk = "PUT /ipfs/ HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\nnope\r\n";
+        assert!(exchange(bad_chunk).await.starts_with("HTTP/1.1 400 "));
+    }
+}
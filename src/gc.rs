@@ -0,0 +1,558 @@
+//! Garbage collection: deleting the blocks no pin keeps, with [`gc`], after looking at what that
+//! would delete with [`gc_plan`] if need be.
+//!
+//! Blocks are told apart by multihash alone, so that a block listed under a different codec
+//! than it's linked to with, as in a [`FlatFs`](crate::sharding::FlatFs) store, still counts as
+//! pinned.
+
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use cid::Cid;
+use multihash::Multihash;
+use tokio::sync::{Mutex as AsyncMutex, RwLock, RwLockReadGuard, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::access::AccessStore;
+use crate::blockstore::{Blockstore, FSStore};
+use crate::dag;
+use crate::memstore::MemStore;
+use crate::pins::{PinMode, PinStore};
+use crate::quota::{EvictionPolicy, QuotaStore};
+use crate::ttl::TtlStore;
+
+/// How many of the blocks it would delete a [`GcPlan`] names.
+pub const PLAN_SAMPLE_SIZE: usize = 10;
+
+/// What [`gc`] would delete, as found by [`gc_plan`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcPlan {
+    pub blocks: u64,
+    pub bytes: u64,
+    /// Some of the blocks, up to [`PLAN_SAMPLE_SIZE`] of them.
+    pub sample: Vec<Cid>,
+}
+
+/// What [`gc`] deleted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Collected {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// How far along a [`gc`] is, as handed to its progress callback after every block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcProgress {
+    /// Going through the pinned DAGs, with `blocks` kept so far.
+    Marking { blocks: u64 },
+    /// Deleting everything else, with `blocks` deleted so far, adding up to `bytes`.
+    Sweeping { blocks: u64, bytes: u64 },
+}
+
+/// Finds out what [`gc`] would delete from `store` with the given pins, without deleting
+/// anything.
+pub async fn gc_plan<S: Blockstore>(
+    store: &S,
+    pins: &[(Cid, PinMode)],
+) -> Result<GcPlan, io::Error> {
+    let mut plan = GcPlan::default();
+    for cid in garbage(store, pins, &mut |_| {}).await? {
+        let Some(size) = store.block_size(&cid).await? else {
+            continue;
+        };
+        plan.blocks += 1;
+        plan.bytes += size;
+        if plan.sample.len() < PLAN_SAMPLE_SIZE {
+            plan.sample.push(cid);
+        }
+    }
+    Ok(plan)
+}
+
+/// Deletes every block in `store` that isn't kept by one of `pins`: a direct pin keeps its own
+/// block, and a recursive one everything reachable from it too. `progress` gets called as it
+/// goes.
+///
+/// Nothing gets deleted if a pinned DAG can't be walked in full, because a block in it is
+/// missing or has links that can't be read, since there's no telling what's under it then.
+/// Blocks put while this runs, and not pinned when it started, may get deleted: to keep them,
+/// put them and collect garbage through a [`PinStore`], with [`PinStore::gc`].
+pub async fn gc<S: Blockstore>(
+    store: &S,
+    pins: &[(Cid, PinMode)],
+    progress: impl FnMut(GcProgress),
+) -> Result<Collected, io::Error> {
+    collect(store, pins, progress, None).await
+}
+
+// Keeps blocks put during a GC cycle from being collected by it, for the puts and the GC that go
+// through the same `PinStore`. Puts note down what they put while a cycle runs, and the cycle
+// spares them. Puts hold the gate's read side while they run, and the cycle takes the write
+// side to start and for every delete, so that it never deletes a block between a put noting it
+// down and the put being done, nor starts in the middle of a put.
+#[derive(Default)]
+pub(crate) struct Fence {
+    gate: RwLock<()>,
+    // What was put since the running cycle started, if there's one.
+    fresh: Mutex<Option<HashSet<Multihash<64>>>>,
+    // Held for the whole of a cycle, so that only one runs at a time.
+    cycle: AsyncMutex<()>,
+}
+
+impl Fence {
+    // Notes down `cids` as fresh if a cycle is running, returning a guard to hold until they've
+    // been put.
+    pub async fn enter<'a>(
+        &self,
+        cids: impl IntoIterator<Item = &'a Cid>,
+    ) -> RwLockReadGuard<'_, ()> {
+        let guard = self.gate.read().await;
+        if let Some(fresh) = self.fresh.lock().unwrap().as_mut() {
+            fresh.extend(cids.into_iter().map(|cid| *cid.hash()));
+        }
+        guard
+    }
+}
+
+// Ends a cycle once dropped.
+struct Cycle<'a>(&'a Fence);
+
+impl Drop for Cycle<'_> {
+    fn drop(&mut self) {
+        *self.0.fresh.lock().unwrap() = None;
+    }
+}
+
+// `gc`, fenced off from puts by `fence` if given, whose cycle must have started.
+async fn collect<S: Blockstore>(
+    store: &S,
+    pins: &[(Cid, PinMode)],
+    mut progress: impl FnMut(GcProgress),
+    fence: Option<&Fence>,
+) -> Result<Collected, io::Error> {
+    let mut collected = Collected::default();
+    for cid in garbage(store, pins, &mut progress).await? {
+        let _gate = match fence {
+            Some(fence) => {
+                let gate = fence.gate.write().await;
+                let fresh = fence.fresh.lock().unwrap();
+                if fresh
+                    .as_ref()
+                    .is_some_and(|fresh| fresh.contains(cid.hash()))
+                {
+                    continue;
+                }
+                Some(gate)
+            }
+            None => None,
+        };
+        let Some(size) = store.block_size(&cid).await? else {
+            continue;
+        };
+        // Someone else may have got there first.
+        if !store.del_block_if_present(&cid).await? {
+            continue;
+        }
+        collected.blocks += 1;
+        collected.bytes += size;
+        progress(GcProgress::Sweeping {
+            blocks: collected.blocks,
+            bytes: collected.bytes,
+        });
+    }
+    Ok(collected)
+}
+
+// Marks everything `pins` keep, then lists the blocks in `store` that aren't.
+async fn garbage<S: Blockstore>(
+    store: &S,
+    pins: &[(Cid, PinMode)],
+    progress: &mut impl FnMut(GcProgress),
+) -> Result<Vec<Cid>, io::Error> {
+    let mut marked: HashSet<Multihash<64>> = HashSet::new();
+    let mut mark = |cid: &Cid, marked: &mut HashSet<Multihash<64>>| {
+        if marked.insert(*cid.hash()) {
+            progress(GcProgress::Marking {
+                blocks: marked.len() as u64,
+            });
+        }
+    };
+    for (root, mode) in pins {
+        match mode {
+            PinMode::Direct => mark(root, &mut marked),
+            PinMode::Recursive => {
+                let mut walk = dag::walk(store, *root);
+                while let Some(block) = walk.next().await {
+                    mark(&block?.cid, &mut marked);
+                }
+            }
+        }
+    }
+
+    let mut garbage = Vec::new();
+    let mut cids = store.blocks();
+    while let Some(cid) = cids.recv().await {
+        let cid = cid?;
+        if !marked.contains(cid.hash()) {
+            garbage.push(cid);
+        }
+    }
+    Ok(garbage)
+}
+
+impl<S: Blockstore> PinStore<S> {
+    /// Finds out what [`PinStore::gc`] would delete, as with [`gc_plan`].
+    pub async fn gc_plan(&self) -> Result<GcPlan, io::Error> {
+        gc_plan(self.store(), &self.gc_roots().await).await
+    }
+
+    /// Deletes every block this store's pins don't keep, as with [`gc`], except for blocks put
+    /// or pinned through this store while it runs. A recursive pin made meanwhile only keeps
+    /// its own block from it, though: blocks under it that were already there, and not pinned
+    /// otherwise, can still go. Only one GC runs at a time, the others waiting their turn.
+    pub async fn gc(&self, progress: impl FnMut(GcProgress)) -> Result<Collected, io::Error> {
+        let fence = self.fence();
+        let _cycle = fence.cycle.lock().await;
+        let cycle = {
+            let _gate = fence.gate.write().await;
+            *fence.fresh.lock().unwrap() = Some(HashSet::new());
+            Cycle(fence)
+        };
+        let pins = self.gc_roots().await;
+        let collected = collect(self.store(), &pins, progress, Some(fence)).await;
+        drop(cycle);
+        collected
+    }
+
+    async fn gc_roots(&self) -> Vec<(Cid, PinMode)> {
+        let pins = self.list_pins().await;
+        pins.into_iter().map(|pin| (pin.cid, pin.mode)).collect()
+    }
+}
+
+/// Upkeep a store does besides garbage collection, which the task started by
+/// [`PinStore::start_gc_task`] has it do on every tick, before collecting garbage. Stores with
+/// none of their own rely on the default, which does nothing, and wrappers pass it on to the
+/// stores they wrap.
+pub trait Upkeep: Blockstore {
+    fn upkeep(&self) -> impl Future<Output = Result<(), io::Error>> + Send {
+        async { Ok(()) }
+    }
+}
+
+impl Upkeep for FSStore {}
+
+impl Upkeep for MemStore {}
+
+/// Sweeps expired blocks, as with [`TtlStore::sweep`].
+impl<S: Upkeep> Upkeep for TtlStore<S> {
+    async fn upkeep(&self) -> Result<(), io::Error> {
+        self.sweep().await?;
+        self.store().upkeep().await
+    }
+}
+
+/// Flushes accesses to the journal, as with [`AccessTracker::flush`].
+///
+/// [`AccessTracker::flush`]: crate::access::AccessTracker::flush
+impl<S: Upkeep> Upkeep for AccessStore<S> {
+    async fn upkeep(&self) -> Result<(), io::Error> {
+        self.tracker().flush().await?;
+        self.store().upkeep().await
+    }
+}
+
+impl<S: Upkeep, P: EvictionPolicy> Upkeep for QuotaStore<S, P> {
+    async fn upkeep(&self) -> Result<(), io::Error> {
+        self.store().upkeep().await
+    }
+}
+
+impl<S: Upkeep> Upkeep for PinStore<S> {
+    async fn upkeep(&self) -> Result<(), io::Error> {
+        self.store().upkeep().await
+    }
+}
+
+/// When the task started by [`PinStore::start_gc_task`] collects garbage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcPolicy {
+    /// Skips collecting garbage while the store holds no more than this many bytes, such as to
+    /// wait until it nears a quota. Upkeep happens regardless.
+    pub min_bytes: u64,
+}
+
+impl<S: Upkeep + 'static> PinStore<S> {
+    /// Spawns a task that, every `interval`, does the store's [`Upkeep`] and then collects
+    /// garbage with [`PinStore::gc`], as `policy` says. The first tick comes right away. A tick
+    /// that fails is just retried on the next one.
+    ///
+    /// The task stops when [`GcTask::stop`] is called, once the store itself is dropped, or
+    /// straight away, in the middle of a cycle if need be, when the handle is dropped.
+    pub fn start_gc_task(self: &Arc<Self>, interval: Duration, policy: GcPolicy) -> GcTask {
+        let store: Weak<Self> = Arc::downgrade(self);
+        let collected = Arc::new(Mutex::new(Collected::default()));
+        let totals = collected.clone();
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    _ = &mut stopped => return,
+                }
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                if let Ok(run) = store.run_gc_tick(policy).await {
+                    let mut totals = totals.lock().unwrap();
+                    totals.blocks += run.blocks;
+                    totals.bytes += run.bytes;
+                }
+            }
+        });
+
+        GcTask {
+            task,
+            stop: Some(stop),
+            collected,
+        }
+    }
+
+    async fn run_gc_tick(&self, policy: GcPolicy) -> Result<Collected, io::Error> {
+        self.upkeep().await?;
+        if self.stats().await?.bytes <= policy.min_bytes {
+            return Ok(Collected::default());
+        }
+        self.gc(|_| {}).await
+    }
+}
+
+/// Handle to the task started by [`PinStore::start_gc_task`]. Dropping it aborts the task.
+pub struct GcTask {
+    task: JoinHandle<()>,
+    stop: Option<oneshot::Sender<()>>,
+    collected: Arc<Mutex<Collected>>,
+}
+
+impl GcTask {
+    /// Everything the task has collected so far.
+    pub fn collected(&self) -> Collected {
+        *self.collected.lock().unwrap()
+    }
+
+    /// Stops the task once any tick it's in the middle of is done, and waits for that.
+    pub async fn stop(mut self) {
+        if let Some(stop) = self.stop.take() {
+            // Only fails if the task is already gone.
+            let _ = stop.send(());
+        }
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for GcTask {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, make_random_block};
+    use crate::blockstore::{BlockstoreError, CidStream, Put, StoreStats};
+    use crate::memstore::MemStore;
+    use crate::ttl::TtlBlockstore;
+    use crate::unixfs;
+    use tokio::sync::{Notify, mpsc};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_plan_and_collect_unpinned_blocks() {
+        let store = PinStore::new(MemStore::new());
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let root = unixfs::import_file(store.store(), data.as_slice(), 4_096)
+            .await
+            .unwrap();
+        let loose: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&loose).await.unwrap();
+        store.pin(&root, PinMode::Recursive).await.unwrap();
+        store
+            .pin_named("kept", &loose[0].cid, PinMode::Direct, None)
+            .await
+            .unwrap();
+        let before = store.stats().await.unwrap();
+
+        let plan = store.gc_plan().await.unwrap();
+        assert_eq!((plan.blocks, plan.bytes), (2, 2_000));
+        let mut sample = plan.sample.clone();
+        sample.sort();
+        let mut expected = vec![loose[1].cid, loose[2].cid];
+        expected.sort();
+        assert_eq!(sample, expected);
+        // Planning leaves the store as it was.
+        assert_eq!(store.stats().await.unwrap(), before);
+
+        let mut events = Vec::new();
+        let collected = store.gc(|event| events.push(event)).await.unwrap();
+        assert_eq!(
+            collected,
+            Collected {
+                blocks: 2,
+                bytes: 2_000
+            }
+        );
+        assert_eq!(
+            events.last(),
+            Some(&GcProgress::Sweeping {
+                blocks: 2,
+                bytes: 2_000
+            })
+        );
+        let marked = before.blocks - 2;
+        assert!(events.contains(&GcProgress::Marking { blocks: marked }));
+        assert!(store.has_block(&loose[0].cid).await);
+        assert!(!store.has_block(&loose[1].cid).await);
+        let mut read = Vec::new();
+        unixfs::export_file(store.store(), root, &mut read, 4)
+            .await
+            .unwrap();
+        assert_eq!(read, data);
+        assert_eq!(store.gc_plan().await.unwrap(), GcPlan::default());
+    }
+
+    // A store whose listings wait for `release` before going ahead, and signal `listing` when
+    // they start.
+    struct GatedStore {
+        store: MemStore,
+        listing: Notify,
+        release: Arc<Notify>,
+    }
+
+    impl Blockstore for GatedStore {
+        async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+            self.store.put_block(block).await
+        }
+
+        async fn has_block(&self, cid: &Cid) -> bool {
+            self.store.has_block(cid).await
+        }
+
+        async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+            self.store.get_block(cid).await
+        }
+
+        async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+            self.store.del_block(cid).await
+        }
+
+        fn blocks(&self) -> CidStream {
+            let mut listed = self.store.blocks();
+            let release = self.release.clone();
+            let (sender, receiver) = mpsc::channel(1);
+            tokio::spawn(async move {
+                release.notified().await;
+                while let Some(cid) = listed.recv().await {
+                    if sender.send(cid).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            self.listing.notify_one();
+            receiver
+        }
+
+        async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+            self.store.stats().await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn should_keep_blocks_put_during_gc() {
+        let release = Arc::new(Notify::new());
+        let store = Arc::new(PinStore::new(GatedStore {
+            store: MemStore::new(),
+            listing: Notify::new(),
+            release: release.clone(),
+        }));
+        let blocks: Vec<Block> = (0..2).map(|_| make_random_block(100)).collect();
+        store.put_many(&blocks).await.unwrap();
+
+        let gc = tokio::spawn({
+            let store = store.clone();
+            async move { store.gc(|_| {}).await }
+        });
+        // Both blocks are garbage by the time the GC lists them, but one gets put again before
+        // it's done.
+        store.store().listing.notified().await;
+        store.put_block(&blocks[0]).await.unwrap();
+        release.notify_one();
+
+        let collected = gc.await.unwrap().unwrap();
+        assert_eq!(collected.blocks, 1);
+        assert!(store.has_block(&blocks[0].cid).await);
+        assert!(!store.has_block(&blocks[1].cid).await);
+        // The next cycle doesn't remember it.
+        store.put_block(&make_random_block(100)).await.unwrap();
+        release.notify_one();
+        assert_eq!(store.gc(|_| {}).await.unwrap().blocks, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_collect_garbage_in_background() {
+        let store = Arc::new(PinStore::new(TtlStore::new(MemStore::new())));
+        let pinned = make_random_block(1_000);
+        let expiring = make_random_block(1_000);
+        store.put_block(&pinned).await.unwrap();
+        store.pin(&pinned.cid, PinMode::Direct).await.unwrap();
+        store
+            .store()
+            .put_block_with_ttl(&expiring, Duration::ZERO)
+            .await
+            .unwrap();
+
+        // Nothing gets collected while the store is small enough, though upkeep goes on.
+        let policy = GcPolicy { min_bytes: 1_500 };
+        let task = store.start_gc_task(Duration::from_millis(10), policy);
+        wait_for(|| async { !store.store().store().has_block(&expiring.cid).await }).await;
+        let garbage: Vec<Block> = (0..2).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&garbage).await.unwrap();
+        wait_for(|| async { task.collected().blocks == 2 }).await;
+        assert_eq!(task.collected().bytes, 2_000);
+        assert!(store.has_block(&pinned.cid).await);
+        task.stop().await;
+
+        // Once stopped, or dropped, the task leaves new garbage alone.
+        let task = store.start_gc_task(Duration::from_millis(10), GcPolicy::default());
+        drop(task);
+        let more = make_random_block(1_000);
+        store.put_block(&more).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(store.has_block(&more.cid).await);
+    }
+
+    async fn wait_for<F: Future<Output = bool>>(condition: impl Fn() -> F) {
+        for _ in 0..500 {
+            if condition().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("gave up waiting");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_not_collect_around_missing_pinned_blocks() {
+        let store = MemStore::new();
+        let block = make_random_block(100);
+        store.put_block(&block).await.unwrap();
+
+        let missing = make_random_block(100).cid;
+        let pins = [(missing, PinMode::Recursive)];
+        let err = gc(&store, &pins, |_| {}).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(store.has_block(&block.cid).await);
+    }
+}
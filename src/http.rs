@@ -0,0 +1,602 @@
+//! A deliberately small HTTP/1.1 implementation: enough to talk to object stores and block
+//! gateways over plain TCP, and to serve blocks back. No TLS, no HTTP/2.
+
+use std::io;
+use std::sync::Mutex;
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream,
+};
+use tokio::net::TcpStream;
+
+/// Requests and responses with heads larger than this are rejected.
+const MAX_HEAD_SIZE: usize = 64 << 10;
+
+/// Lines in message heads and chunk sizes longer than this are rejected.
+const MAX_LINE_SIZE: usize = 8 << 10;
+
+// How many idle connections a client keeps around for reuse.
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Endpoint {
+    /// Parses `http://host[:port]`, ignoring any trailing slash.
+    pub fn parse(url: &str) -> Result<Self, io::Error> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad endpoint {:?}: {}", url, reason),
+            )
+        };
+
+        let authority = match url.split_once("://") {
+            Some(("http", rest)) => rest.trim_end_matches('/'),
+            Some(("https", _)) => return Err(invalid("TLS is not supported")),
+            _ => return Err(invalid("expected an http:// URL")),
+        };
+        if authority.is_empty() || authority.contains('/') {
+            return Err(invalid("expected just a host and optional port"));
+        }
+
+        match authority.rsplit_once(':') {
+            Some((host, port)) => Ok(Endpoint {
+                host: host.to_string(),
+                port: port.parse().map_err(|_| invalid("bad port"))?,
+            }),
+            None => Ok(Endpoint {
+                host: authority.to_string(),
+                port: 80,
+            }),
+        }
+    }
+
+    /// The value for the `Host` header.
+    pub fn authority(&self) -> String {
+        if self.port == 80 {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Path plus query string, e.g. `/bucket/key?uploads`.
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// An HTTP client for a single endpoint, reusing connections across requests.
+pub struct Client {
+    endpoint: Endpoint,
+    idle: Mutex<Vec<BufStream<TcpStream>>>,
+    max_body: usize,
+}
+
+impl Client {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Client {
+            endpoint,
+            idle: Mutex::new(Vec::new()),
+            max_body: usize::MAX,
+        }
+    }
+
+    /// Fails requests whose response bodies are longer than `max_body` with
+    /// [`io::ErrorKind::FileTooLarge`], instead of reading them. There's no limit by default,
+    /// which is only fine for servers that can be trusted.
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Sends a request and reads the whole response. `Host` and `Content-Length` are filled in.
+    pub async fn send(
+        &self,
+        method: &str,
+        target: &str,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) -> Result<Response, io::Error> {
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nhost: {}\r\ncontent-length: {}\r\n",
+            method,
+            target,
+            self.endpoint.authority(),
+            body.len()
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        // A pooled connection may have been closed by the server while idle, which we only find
+        // out about once we try to use it. In that case we try once more on a fresh connection,
+        // unless the server did answer, just with too much.
+        let pooled = self.idle.lock().unwrap().pop();
+        if let Some(connection) = pooled {
+            match self.exchange(connection, method, &head, body).await {
+                Err(e) if e.kind() != io::ErrorKind::FileTooLarge => {}
+                result => return result,
+            }
+        }
+
+        let stream = TcpStream::connect((self.endpoint.host.as_str(), self.endpoint.port)).await?;
+        self.exchange(BufStream::new(stream), method, &head, body)
+            .await
+    }
+
+    async fn exchange(
+        &self,
+        mut connection: BufStream<TcpStream>,
+        method: &str,
+        head: &str,
+        body: &[u8],
+    ) -> Result<Response, io::Error> {
+        connection.write_all(head.as_bytes()).await?;
+        connection.write_all(body).await?;
+        connection.flush().await?;
+
+        let response = read_response(&mut connection, method == "HEAD", self.max_body).await?;
+        let closing = response
+            .header("connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+        if !closing {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE_CONNECTIONS {
+                idle.push(connection);
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+/// Reads a request from a server-side connection. Returns `None` if the client closed the
+/// connection cleanly between requests.
+///
+/// Bodies longer than `max_body` fail with [`io::ErrorKind::FileTooLarge`], which servers should
+/// answer with a 413, and `GET` and `HEAD` requests can't have one at all. Malformed requests
+/// fail with [`io::ErrorKind::InvalidData`].
+pub async fn read_request<R>(reader: &mut R, max_body: usize) -> Result<Option<Request>, io::Error>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let Some(lines) = read_head(reader).await? else {
+        return Ok(None);
+    };
+
+    let mut parts = lines[0].splitn(3, ' ');
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid_data(format!(
+            "malformed request line {:?}",
+            lines[0]
+        )));
+    };
+
+    let headers = parse_headers(&lines[1..])?;
+    let max_body = match method {
+        "GET" | "HEAD" => 0,
+        _ => max_body,
+    };
+    let body = read_body(reader, &headers, max_body).await?;
+    Ok(Some(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+        body,
+    }))
+}
+
+/// Writes `response`, adding its `Content-Length`. For responses to `HEAD` requests, pass
+/// `head_only` so that the length is announced but no body is sent.
+pub async fn write_response<W>(
+    writer: &mut W,
+    response: &Response,
+    head_only: bool,
+) -> Result<(), io::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    if response.header("content-length").is_none() {
+        head.push_str(&format!("content-length: {}\r\n", response.body.len()));
+    }
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    writer.write_all(head.as_bytes()).await?;
+    if !head_only {
+        writer.write_all(&response.body).await?;
+    }
+    writer.flush().await
+}
+
+async fn read_response<R>(
+    reader: &mut R,
+    head_only: bool,
+    max_body: usize,
+) -> Result<Response, io::Error>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let lines = read_head(reader)
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))?;
+
+    let status = lines[0]
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid_data(format!("malformed status line {:?}", lines[0])))?;
+
+    let headers = parse_headers(&lines[1..])?;
+    // Responses to HEAD, and the 1xx/204/304 ones, never have a body even if they say how
+    // long it would have been.
+    let body = if head_only || status == 204 || status == 304 || status < 200 {
+        Vec::new()
+    } else {
+        read_body(reader, &headers, max_body).await?
+    };
+
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+// Reads lines up to the blank line that ends a message head.
+async fn read_head<R>(reader: &mut R) -> Result<Option<Vec<String>>, io::Error>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut lines = Vec::new();
+    let mut total = 0;
+    loop {
+        let mut line = String::new();
+        let read = read_line(reader, &mut line).await?;
+        if read == 0 {
+            return if lines.is_empty() {
+                Ok(None)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated HTTP head",
+                ))
+            };
+        }
+
+        total += read;
+        if total > MAX_HEAD_SIZE {
+            return Err(invalid_data("HTTP head is too large"));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            if lines.is_empty() {
+                // Tolerate stray blank lines between messages.
+                continue;
+            }
+            return Ok(Some(lines));
+        }
+        lines.push(line.to_string());
+    }
+}
+
+fn parse_headers(lines: &[String]) -> Result<Vec<(String, String)>, io::Error> {
+    lines
+        .iter()
+        .map(|line| {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid_data(format!("malformed header {:?}", line)))?;
+            Ok((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+// Reads a line like `AsyncBufReadExt::read_line`, but failing on lines over MAX_LINE_SIZE
+// rather than reading them whole.
+async fn read_line<R>(reader: &mut R, line: &mut String) -> Result<usize, io::Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let read = reader.take(MAX_LINE_SIZE as u64).read_line(line).await?;
+    if read == MAX_LINE_SIZE && !line.ends_with('\n') {
+        return Err(invalid_data("HTTP line is too long"));
+    }
+    Ok(read)
+}
+
+async fn read_body<R>(
+    reader: &mut R,
+    headers: &[(String, String)],
+    max_body: usize,
+) -> Result<Vec<u8>, io::Error>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let chunked = find_header(headers, "transfer-encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        return read_chunked(reader, max_body).await;
+    }
+
+    let len: usize = match find_header(headers, "content-length") {
+        Some(len) => len
+            .parse()
+            .map_err(|_| invalid_data(format!("bad content-length {:?}", len)))?,
+        None => 0,
+    };
+    if len > max_body {
+        return Err(too_large(max_body));
+    }
+
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+async fn read_chunked<R>(reader: &mut R, max_body: usize) -> Result<Vec<u8>, io::Error>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        read_line(reader, &mut line).await?;
+        let size = line.trim().split(';').next().unwrap_or("");
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| invalid_data(format!("bad chunk size {:?}", line)))?;
+
+        if size == 0 {
+            // Skip trailers up to the final blank line.
+            loop {
+                line.clear();
+                if read_line(reader, &mut line).await? == 0 || line.trim().is_empty() {
+                    return Ok(body);
+                }
+            }
+        }
+
+        let start = body.len();
+        let end = start
+            .checked_add(size)
+            .filter(|&end| end <= max_body)
+            .ok_or_else(|| too_large(max_body))?;
+        body.resize(end, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        line.clear();
+        read_line(reader, &mut line).await?;
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Percent-encodes everything except RFC 3986 unreserved characters, and `/` if `keep_slash`.
+pub fn percent_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn too_large(max_body: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::FileTooLarge,
+        format!("HTTP body is over {} bytes", max_body),
+    )
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn should_parse_endpoints() {
+        assert_eq!(
+            Endpoint::parse("http://minio:9000/").unwrap(),
+            Endpoint {
+                host: "minio".to_string(),
+                port: 9000
+            }
+        );
+        assert_eq!(Endpoint::parse("http://example.com").unwrap().port, 80);
+        assert!(Endpoint::parse("https://example.com").is_err());
+        assert!(Endpoint::parse("example.com").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_read_chunked_body() {
+        let raw = b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut reader = BufReader::new(&raw[..]);
+
+        let response = read_response(&mut reader, false, usize::MAX).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello world");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_limit_requests() {
+        async fn error(raw: &[u8]) -> io::ErrorKind {
+            let request = read_request(&mut BufReader::new(raw), 10).await;
+            request.unwrap_err().kind()
+        }
+        let chunked = |chunks: &str| {
+            let head = "PUT / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n";
+            [head, chunks].concat().into_bytes()
+        };
+
+        let huge = b"PUT / HTTP/1.1\r\ncontent-length: 18446744073709551615\r\n\r\n";
+        assert_eq!(error(huge).await, io::ErrorKind::FileTooLarge);
+        let get = b"GET / HTTP/1.1\r\ncontent-length: 1\r\n\r\nx";
+        assert_eq!(error(get).await, io::ErrorKind::FileTooLarge);
+
+        let too_many = chunked("8\r\n12345678\r\n8\r\n12345678\r\n0\r\n\r\n");
+        assert_eq!(error(&too_many).await, io::ErrorKind::FileTooLarge);
+        let too_big = chunked("ffffffffffffffff\r\n");
+        assert_eq!(error(&too_big).await, io::ErrorKind::FileTooLarge);
+        let overflowing = chunked("1ffffffffffffffff\r\n");
+        assert_eq!(error(&overflowing).await, io::ErrorKind::InvalidData);
+
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_SIZE)).into_bytes();
+        assert_eq!(error(&long_line).await, io::ErrorKind::InvalidData);
+
+        let small = b"PUT / HTTP/1.1\r\ncontent-length: 10\r\n\r\n0123456789";
+        let request = read_request(&mut BufReader::new(&small[..]), 10).await;
+        assert_eq!(request.unwrap().unwrap().body, b"0123456789");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_reuse_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            // Only accept a single connection: both requests must go over it.
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            while let Some(request) = read_request(&mut stream, 1 << 10).await.unwrap() {
+                let mut response = Response::new(200);
+                response.body = request.body;
+                write_response(&mut stream, &response, false).await.unwrap();
+            }
+        });
+
+        let client = Client::new(Endpoint::parse(&format!("http://127.0.0.1:{}", port)).unwrap());
+        for body in [&b"first"[..], &b"second"[..]] {
+            let response = client.send("POST", "/echo", &[], body).await.unwrap();
+            assert_eq!(response.body, body);
+        }
+
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_limit_responses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            while let Ok(Some(request)) = read_request(&mut stream, 1 << 10).await {
+                // Only announcing the huge body, which the client shouldn't wait for.
+                let huge = request.target == "/huge";
+                let mut response = Response::new(200);
+                if huge {
+                    let length = u64::MAX.to_string();
+                    response.headers.push(("content-length".into(), length));
+                } else {
+                    response.body = vec![0; 10];
+                }
+                write_response(&mut stream, &response, huge).await.unwrap();
+            }
+        });
+
+        let endpoint = Endpoint::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let client = Client::new(endpoint).with_max_body(10);
+        let response = client.send("GET", "/small", &[], &[]).await;
+        assert_eq!(response.unwrap().body, [0; 10]);
+        let error = client.send("GET", "/huge", &[], &[]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn should_percent_encode() {
+        assert_eq!(percent_encode("a b/c~", false), "a%20b%2Fc~");
+        assert_eq!(percent_encode("a b/c~", true), "a%20b/c~");
+    }
+}
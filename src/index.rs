@@ -0,0 +1,197 @@
+//! The block index [`FSStore::with_index`] keeps on disk, so that finding, sizing and listing
+//! blocks doesn't take going through the shard tree.
+//!
+//! It's an append-only log: a line `+ <cid> <size> <mtime>` whenever a block shows up or
+//! changes, mtimes being in nanoseconds since the epoch, and a line `- <cid>` whenever one goes
+//! away. Loading it replays the log, and it gets rewritten from scratch once most of it is
+//! obsolete. Appends aren't synced, since the index can always be rebuilt from the shard tree: a
+//! log that lost its tail in a crash no longer matches the store's stats, which gets it rebuilt
+//! when the store is next opened with it.
+//!
+//! [`FSStore::with_index`]: crate::blockstore::FSStore::with_index
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, UNIX_EPOCH};
+
+use cid::Cid;
+
+use crate::blockstore::IndexEntry;
+
+// The log gets rewritten once it has more than this many lines per block in the index, plus
+// `LOG_SLACK` lines so that small indexes don't get rewritten all the time.
+const LINES_PER_BLOCK: usize = 2;
+const LOG_SLACK: usize = 1024;
+
+pub(crate) type Entries = HashMap<Cid, IndexEntry>;
+
+pub(crate) struct BlockIndex {
+    path: PathBuf,
+    state: RwLock<State>,
+    // Held for the whole of a rebuild, so that only one runs at a time.
+    rebuilding: Mutex<()>,
+}
+
+struct State {
+    entries: Entries,
+    log: File,
+    lines: usize,
+    // While a rebuild is going through the store, the blocks that changed since it started,
+    // which it may or may not have seen.
+    changed: Option<HashMap<Cid, Option<IndexEntry>>>,
+}
+
+impl BlockIndex {
+    // Replays the log at `path`. Returns `None` if there's no log, or it's not one.
+    pub fn load(path: &Path) -> Result<Option<Entries>, io::Error> {
+        let log = match fs::read_to_string(path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // A last line without its newline got cut short, and is left out.
+        let complete = log.rfind('\n').map_or("", |end| &log[..=end]);
+        let mut entries = HashMap::new();
+        for line in complete.lines() {
+            match parse_line(line) {
+                Some((cid, Some(entry))) => entries.insert(cid, entry),
+                Some((cid, None)) => entries.remove(&cid),
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(entries))
+    }
+
+    // Starts an index of `entries`, replacing whatever log there was at `path`.
+    pub fn create(path: PathBuf, entries: Entries) -> Result<Self, io::Error> {
+        let log = write_log(&path, &entries)?;
+        Ok(BlockIndex {
+            path,
+            state: RwLock::new(State {
+                lines: entries.len(),
+                entries,
+                log,
+                changed: None,
+            }),
+            rebuilding: Mutex::new(()),
+        })
+    }
+
+    pub fn get(&self, key: &Cid) -> Option<IndexEntry> {
+        self.state.read().unwrap().entries.get(key).copied()
+    }
+
+    pub fn contains(&self, key: &Cid) -> bool {
+        self.state.read().unwrap().entries.contains_key(key)
+    }
+
+    pub fn keys(&self) -> Vec<Cid> {
+        self.state.read().unwrap().entries.keys().copied().collect()
+    }
+
+    // Records block `key` as it is now, `None` meaning it's gone. The caller holds the block's
+    // write lock.
+    pub fn set(&self, key: &Cid, entry: Option<IndexEntry>) -> Result<(), io::Error> {
+        let mut state = self.state.write().unwrap();
+        if let Some(changed) = &mut state.changed {
+            changed.insert(*key, entry);
+        }
+        if state.entries.get(key) == entry.as_ref() {
+            return Ok(());
+        }
+
+        let line = match entry {
+            Some(entry) => {
+                state.entries.insert(*key, entry);
+                entry_line(key, &entry)
+            }
+            None => {
+                state.entries.remove(key);
+                format!("- {}\n", key)
+            }
+        };
+        state.log.write_all(line.as_bytes())?;
+        state.lines += 1;
+        if state.lines > state.entries.len() * LINES_PER_BLOCK + LOG_SLACK {
+            state.log = write_log(&self.path, &state.entries)?;
+            state.lines = state.entries.len();
+        }
+        Ok(())
+    }
+
+    // Replaces the index with what `scan` finds in the store, except for blocks that change
+    // while it runs: those keep what they were last set to.
+    pub fn rebuild(
+        &self,
+        scan: impl FnOnce() -> Result<Entries, io::Error>,
+    ) -> Result<(), io::Error> {
+        let _rebuilding = self.rebuilding.lock().unwrap();
+        self.state.write().unwrap().changed = Some(HashMap::new());
+        let scanned = scan();
+
+        let mut state = self.state.write().unwrap();
+        let changed = state.changed.take().unwrap_or_default();
+        let mut entries = scanned?;
+        for (key, entry) in changed {
+            match entry {
+                Some(entry) => entries.insert(key, entry),
+                None => entries.remove(&key),
+            };
+        }
+        state.log = write_log(&self.path, &entries)?;
+        state.lines = entries.len();
+        state.entries = entries;
+        Ok(())
+    }
+}
+
+// How many blocks `entries` has, and how many bytes they add up to.
+pub(crate) fn totals(entries: &Entries) -> (u64, u64) {
+    let bytes = entries.values().map(|entry| entry.size).sum();
+    (entries.len() as u64, bytes)
+}
+
+// Writes a log of just `entries` at `path`, returning it open for appending.
+fn write_log(path: &Path, entries: &Entries) -> Result<File, io::Error> {
+    let temp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    for (cid, entry) in entries {
+        writer.write_all(entry_line(cid, entry).as_bytes())?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?;
+    fs::rename(&temp_path, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+fn entry_line(cid: &Cid, entry: &IndexEntry) -> String {
+    let modified = entry
+        .modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("+ {} {} {}\n", cid, entry.size, modified)
+}
+
+fn parse_line(line: &str) -> Option<(Cid, Option<IndexEntry>)> {
+    let mut fields = line.split(' ');
+    let op = fields.next()?;
+    let cid = Cid::try_from(fields.next()?).ok()?;
+    let entry = match op {
+        "+" => {
+            let size = fields.next()?.parse().ok()?;
+            let nanos: u64 = fields.next()?.parse().ok()?;
+            Some(IndexEntry {
+                size,
+                modified: UNIX_EPOCH + Duration::from_nanos(nanos),
+            })
+        }
+        "-" => None,
+        _ => return None,
+    };
+    fields.next().is_none().then_some((cid, entry))
+}
@@ -0,0 +1,666 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use cid::Cid;
+
+use crate::block::{Block, Codec};
+
+/// A value in the IPLD data model, which is what dag-pb, dag-cbor and dag-json blocks decode to.
+///
+/// dag-pb blocks decode to the map its spec describes: `{"Data": bytes, "Links": [{"Hash": link,
+/// "Name": string, "Tsize": integer}]}`, with `Data`, `Name` and `Tsize` only there when set.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ipld {
+    Null,
+    Bool(bool),
+    /// Wide enough for everything dag-cbor can encode, from `-2^64` to `2^64 - 1`.
+    Integer(i128),
+    Float(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    List(Vec<Ipld>),
+    Map(BTreeMap<String, Ipld>),
+    Link(Cid),
+}
+
+impl Ipld {
+    /// Looks up `key`, if this is a map.
+    pub fn get(&self, key: &str) -> Option<&Ipld> {
+        match self {
+            Ipld::Map(map) => map.get(key),
+            _ => None,
+        }
+    }
+}
+
+/// Types [`Block::decode`] can decode block contents into.
+pub trait Decode: Sized {
+    fn decode(codec: Codec, data: &[u8]) -> Result<Self, io::Error>;
+}
+
+impl Decode for Ipld {
+    /// Raw blocks decode to [`Ipld::Bytes`] of their whole contents.
+    fn decode(codec: Codec, data: &[u8]) -> Result<Self, io::Error> {
+        match codec {
+            Codec::Raw => Ok(Ipld::Bytes(data.to_vec())),
+            Codec::DagPb => decode_dag_pb(data),
+            Codec::DagCbor => decode_dag_cbor(data),
+            Codec::DagJson => decode_dag_json(data),
+        }
+    }
+}
+
+impl Block {
+    /// Decodes the block's contents according to the codec in its CID. Fails with
+    /// [`io::ErrorKind::Unsupported`] for codecs we don't know, and with
+    /// [`io::ErrorKind::InvalidData`] if the contents aren't valid in theirs.
+    pub fn decode<T: Decode>(&self) -> Result<T, io::Error> {
+        let code = self.cid.codec();
+        let codec = Codec::from_code(code).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported codec 0x{:x}", code),
+            )
+        })?;
+        T::decode(codec, &self.data)
+    }
+}
+
+// Values nested deeper than this are rejected rather than risk overflowing the stack.
+const MAX_DEPTH: usize = 256;
+
+fn decode_dag_cbor(data: &[u8]) -> Result<Ipld, io::Error> {
+    let mut reader = CborReader { buf: data };
+    let value = reader.value(0)?;
+    if !reader.buf.is_empty() {
+        return Err(invalid_data("trailing bytes after DAG-CBOR value"));
+    }
+    Ok(value)
+}
+
+const UINT: u8 = 0;
+const NEGINT: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+
+// CBOR tag used by DAG-CBOR for CIDs.
+const CID_TAG: u64 = 42;
+
+struct CborReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> CborReader<'a> {
+    fn take(&mut self, len: u64) -> Result<&'a [u8], io::Error> {
+        if len > self.buf.len() as u64 {
+            return Err(invalid_data("truncated DAG-CBOR"));
+        }
+        let (head, tail) = self.buf.split_at(len as usize);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    // Reads an item header, returning its major type, additional information and argument.
+    fn head(&mut self) -> Result<(u8, u8, u64), io::Error> {
+        let initial = self.take(1)?[0];
+        let info = initial & 0x1f;
+        let argument = match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            _ => {
+                return Err(invalid_data(
+                    "indefinite-length items are not allowed in DAG-CBOR",
+                ));
+            }
+        };
+        Ok((initial >> 5, info, argument))
+    }
+
+    fn text(&mut self, len: u64) -> Result<String, io::Error> {
+        String::from_utf8(self.take(len)?.to_vec()).map_err(invalid_data)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Ipld, io::Error> {
+        if depth > MAX_DEPTH {
+            return Err(invalid_data("DAG-CBOR is nested too deeply"));
+        }
+
+        let (major, info, argument) = self.head()?;
+        let value = match major {
+            UINT => Ipld::Integer(argument as i128),
+            NEGINT => Ipld::Integer(-1 - argument as i128),
+            BYTES => Ipld::Bytes(self.take(argument)?.to_vec()),
+            TEXT => Ipld::String(self.text(argument)?),
+            ARRAY => {
+                let mut list = Vec::new();
+                for _ in 0..argument {
+                    list.push(self.value(depth + 1)?);
+                }
+                Ipld::List(list)
+            }
+            MAP => {
+                let mut map = BTreeMap::new();
+                for _ in 0..argument {
+                    let key = match self.head()? {
+                        (TEXT, _, len) => self.text(len)?,
+                        _ => return Err(invalid_data("DAG-CBOR map keys must be strings")),
+                    };
+                    if map.insert(key, self.value(depth + 1)?).is_some() {
+                        return Err(invalid_data("duplicate key in DAG-CBOR map"));
+                    }
+                }
+                Ipld::Map(map)
+            }
+            TAG if argument == CID_TAG => Ipld::Link(self.cid()?),
+            TAG => {
+                return Err(invalid_data(format!(
+                    "unsupported DAG-CBOR tag {}",
+                    argument
+                )));
+            }
+            // Simple values and floats, of which DAG-CBOR only allows 64-bit ones.
+            _ => match info {
+                20 => Ipld::Bool(false),
+                21 => Ipld::Bool(true),
+                22 => Ipld::Null,
+                27 => Ipld::Float(f64::from_bits(argument)),
+                _ => return Err(invalid_data("unsupported DAG-CBOR simple value or float")),
+            },
+        };
+        Ok(value)
+    }
+
+    fn cid(&mut self) -> Result<Cid, io::Error> {
+        let len = match self.head()? {
+            (BYTES, _, len) => len,
+            _ => return Err(invalid_data("expected a CID")),
+        };
+        match self.take(len)? {
+            // DAG-CBOR prefixes CIDs with the (historical) identity multibase byte.
+            [0x00, cid @ ..] => Cid::try_from(cid).map_err(invalid_data),
+            _ => Err(invalid_data("CID is missing its multibase prefix")),
+        }
+    }
+}
+
+fn decode_dag_pb(data: &[u8]) -> Result<Ipld, io::Error> {
+    let mut node = BTreeMap::new();
+    let mut links = Vec::new();
+    let mut reader = PbReader { buf: data };
+    while let Some(field) = reader.field()? {
+        match field {
+            (1, PbValue::Bytes(data)) => {
+                if node
+                    .insert("Data".to_string(), Ipld::Bytes(data.to_vec()))
+                    .is_some()
+                {
+                    return Err(invalid_data("DAG-PB node has more than one Data field"));
+                }
+            }
+            (2, PbValue::Bytes(link)) => links.push(decode_pb_link(link)?),
+            _ => return Err(invalid_data("unexpected field in DAG-PB node")),
+        }
+    }
+
+    node.insert("Links".to_string(), Ipld::List(links));
+    Ok(Ipld::Map(node))
+}
+
+fn decode_pb_link(data: &[u8]) -> Result<Ipld, io::Error> {
+    let mut link = BTreeMap::new();
+    let mut reader = PbReader { buf: data };
+    while let Some(field) = reader.field()? {
+        let (key, value) = match field {
+            (1, PbValue::Bytes(hash)) => (
+                "Hash",
+                Ipld::Link(Cid::try_from(hash).map_err(invalid_data)?),
+            ),
+            (2, PbValue::Bytes(name)) => (
+                "Name",
+                Ipld::String(String::from_utf8(name.to_vec()).map_err(invalid_data)?),
+            ),
+            (3, PbValue::Varint(size)) => ("Tsize", Ipld::Integer(size as i128)),
+            _ => return Err(invalid_data("unexpected field in DAG-PB link")),
+        };
+        if link.insert(key.to_string(), value).is_some() {
+            return Err(invalid_data(format!(
+                "DAG-PB link has more than one {} field",
+                key
+            )));
+        }
+    }
+
+    if !link.contains_key("Hash") {
+        return Err(invalid_data("DAG-PB link has no Hash"));
+    }
+    Ok(Ipld::Map(link))
+}
+
+pub(crate) enum PbValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Just enough of a protobuf decoder for the two wire types DAG-PB (and UnixFS) use.
+pub(crate) struct PbReader<'a> {
+    pub(crate) buf: &'a [u8],
+}
+
+impl<'a> PbReader<'a> {
+    fn varint(&mut self) -> Result<u64, io::Error> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            let (&byte, rest) = self
+                .buf
+                .split_first()
+                .ok_or_else(|| invalid_data("truncated DAG-PB"))?;
+            self.buf = rest;
+
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("varint is too long"))
+    }
+
+    // Reads the next field, returning its number and value, or `None` at the end of the message.
+    pub(crate) fn field(&mut self) -> Result<Option<(u64, PbValue<'a>)>, io::Error> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => PbValue::Varint(self.varint()?),
+            2 => {
+                let len = self.varint()?;
+                if len > self.buf.len() as u64 {
+                    return Err(invalid_data("truncated DAG-PB"));
+                }
+                let (head, tail) = self.buf.split_at(len as usize);
+                self.buf = tail;
+                PbValue::Bytes(head)
+            }
+            wire_type => {
+                return Err(invalid_data(format!(
+                    "unexpected protobuf wire type {} in DAG-PB",
+                    wire_type
+                )));
+            }
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+fn decode_dag_json(data: &[u8]) -> Result<Ipld, io::Error> {
+    let mut reader = JsonReader { buf: data };
+    let value = reader.value(0)?;
+    if reader.peek().is_some() {
+        return Err(invalid_data("trailing characters after DAG-JSON value"));
+    }
+    Ok(value)
+}
+
+struct JsonReader<'a> {
+    buf: &'a [u8],
+}
+
+impl JsonReader<'_> {
+    // Skips whitespace, then returns the next character without consuming it.
+    fn peek(&mut self) -> Option<u8> {
+        while let [b' ' | b'\t' | b'\n' | b'\r', rest @ ..] = self.buf {
+            self.buf = rest;
+        }
+        self.buf.first().copied()
+    }
+
+    // Consumes the next character if it's `expected`.
+    fn eat(&mut self, expected: u8) -> bool {
+        let matches = self.peek() == Some(expected);
+        if matches {
+            self.buf = &self.buf[1..];
+        }
+        matches
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), io::Error> {
+        match self.eat(expected) {
+            true => Ok(()),
+            false => Err(invalid_data(format!(
+                "expected '{}' in DAG-JSON",
+                expected as char
+            ))),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Ipld, io::Error> {
+        if depth > MAX_DEPTH {
+            return Err(invalid_data("DAG-JSON is nested too deeply"));
+        }
+
+        match self.peek() {
+            Some(b'n') => self.literal("null", Ipld::Null),
+            Some(b't') => self.literal("true", Ipld::Bool(true)),
+            Some(b'f') => self.literal("false", Ipld::Bool(false)),
+            Some(b'"') => Ok(Ipld::String(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(b'[') => {
+                self.expect(b'[')?;
+                let mut list = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        list.push(self.value(depth + 1)?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Ipld::List(list))
+            }
+            Some(b'{') => self.map(depth),
+            _ => Err(invalid_data("expected a value in DAG-JSON")),
+        }
+    }
+
+    fn literal(&mut self, literal: &str, value: Ipld) -> Result<Ipld, io::Error> {
+        let rest = self
+            .buf
+            .strip_prefix(literal.as_bytes())
+            .ok_or_else(|| invalid_data("invalid literal in DAG-JSON"))?;
+        self.buf = rest;
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Ipld, io::Error> {
+        let len = self
+            .buf
+            .iter()
+            .take_while(|c| matches!(c, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+            .count();
+        let (number, rest) = self.buf.split_at(len);
+        self.buf = rest;
+
+        // Only ASCII, so this can't fail.
+        let number = str::from_utf8(number).unwrap();
+        if number.contains(['.', 'e', 'E']) {
+            number.parse().map(Ipld::Float).map_err(invalid_data)
+        } else {
+            number.parse().map(Ipld::Integer).map_err(invalid_data)
+        }
+    }
+
+    fn map(&mut self, depth: usize) -> Result<Ipld, io::Error> {
+        self.expect(b'{')?;
+        let mut map = BTreeMap::new();
+        if !self.eat(b'}') {
+            loop {
+                if self.peek() != Some(b'"') {
+                    return Err(invalid_data("DAG-JSON map keys must be strings"));
+                }
+                let key = self.string()?;
+                self.expect(b':')?;
+                if map.insert(key, self.value(depth + 1)?).is_some() {
+                    return Err(invalid_data("duplicate key in DAG-JSON map"));
+                }
+                if self.eat(b'}') {
+                    break;
+                }
+                self.expect(b',')?;
+            }
+        }
+
+        // Maps with the single key "/" are how DAG-JSON spells links and bytes.
+        if map.len() == 1 {
+            match map.get("/") {
+                Some(Ipld::String(cid)) => {
+                    return Cid::try_from(cid.as_str())
+                        .map(Ipld::Link)
+                        .map_err(invalid_data);
+                }
+                Some(Ipld::Map(inner)) if inner.len() == 1 => {
+                    if let Some(Ipld::String(bytes)) = inner.get("bytes") {
+                        return decode_base64(bytes).map(Ipld::Bytes);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Ipld::Map(map))
+    }
+
+    fn string(&mut self) -> Result<String, io::Error> {
+        self.expect(b'"')?;
+        let mut string = Vec::new();
+        loop {
+            let byte = self.next("unterminated string in DAG-JSON")?;
+            match byte {
+                b'"' => return String::from_utf8(string).map_err(invalid_data),
+                b'\\' => {
+                    let escaped = match self.next("unterminated string in DAG-JSON")? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err(invalid_data("invalid escape in DAG-JSON string")),
+                    };
+                    string.extend_from_slice(escaped.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                0..=0x1f => return Err(invalid_data("control character in DAG-JSON string")),
+                _ => string.push(byte),
+            }
+        }
+    }
+
+    fn next(&mut self, error: &str) -> Result<u8, io::Error> {
+        let (&byte, rest) = self.buf.split_first().ok_or_else(|| invalid_data(error))?;
+        self.buf = rest;
+        Ok(byte)
+    }
+
+    // Decodes what follows a `\u`, including the second escape of a surrogate pair.
+    fn unicode_escape(&mut self) -> Result<char, io::Error> {
+        let first = self.hex_digits()?;
+        let code = if (0xd800..0xdc00).contains(&first) {
+            let rest = self
+                .buf
+                .strip_prefix(b"\\u")
+                .ok_or_else(|| invalid_data("unpaired surrogate in DAG-JSON string"))?;
+            self.buf = rest;
+            let second = self.hex_digits()?;
+            if !(0xdc00..0xe000).contains(&second) {
+                return Err(invalid_data("unpaired surrogate in DAG-JSON string"));
+            }
+            0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+        } else {
+            first
+        };
+        char::from_u32(code).ok_or_else(|| invalid_data("invalid unicode escape in DAG-JSON"))
+    }
+
+    fn hex_digits(&mut self) -> Result<u32, io::Error> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = self.next("truncated unicode escape in DAG-JSON")?;
+            let digit = (digit as char)
+                .to_digit(16)
+                .ok_or_else(|| invalid_data("invalid unicode escape in DAG-JSON"))?;
+            value = value << 4 | digit;
+        }
+        Ok(value)
+    }
+}
+
+// Standard base64, which DAG-JSON writes without padding; we let it slide if it's there.
+fn decode_base64(text: &str) -> Result<Vec<u8>, io::Error> {
+    let mut bytes = Vec::new();
+    let mut bits = 0u32;
+    let mut pending = 0;
+    for digit in text.trim_end_matches('=').bytes() {
+        let value = match digit {
+            b'A'..=b'Z' => digit - b'A',
+            b'a'..=b'z' => digit - b'a' + 26,
+            b'0'..=b'9' => digit - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err(invalid_data("invalid base64 in DAG-JSON bytes")),
+        };
+        bits = bits << 6 | value as u32;
+        pending += 6;
+        if pending >= 8 {
+            pending -= 8;
+            bytes.push((bits >> pending) as u8);
+            bits &= (1 << pending) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Hasher, make_random_block};
+
+    fn block(codec: Codec, data: Vec<u8>) -> Block {
+        Block::new_with_codec(data, codec, Hasher::Sha2_256).unwrap()
+    }
+
+    #[test]
+    fn should_decode_dag_cbor() {
+        let link = make_random_block(100).cid;
+        let cid = link.to_bytes();
+
+        // {"a": [1, -2, true, null, h'0102', 1.5], "l": link}
+        let mut data = vec![
+            0xa2, 0x61, b'a', 0x86, 0x01, 0x21, 0xf5, 0xf6, 0x42, 0x01, 0x02, 0xfb,
+        ];
+        data.extend_from_slice(&1.5f64.to_be_bytes());
+        data.extend_from_slice(&[0x61, b'l', 0xd8, 0x2a, 0x58, cid.len() as u8 + 1, 0x00]);
+        data.extend_from_slice(&cid);
+
+        let value: Ipld = block(Codec::DagCbor, data).decode().unwrap();
+        assert_eq!(
+            value.get("a"),
+            Some(&Ipld::List(vec![
+                Ipld::Integer(1),
+                Ipld::Integer(-2),
+                Ipld::Bool(true),
+                Ipld::Null,
+                Ipld::Bytes(vec![1, 2]),
+                Ipld::Float(1.5),
+            ]))
+        );
+        assert_eq!(value.get("l"), Some(&Ipld::Link(link)));
+    }
+
+    #[test]
+    fn should_reject_invalid_dag_cbor() {
+        let invalid: [&[u8]; 4] = [
+            // Trailing bytes.
+            &[0x01, 0x02],
+            // A map keyed by an integer.
+            &[0xa1, 0x01, 0x01],
+            // An indefinite-length list.
+            &[0x9f, 0xff],
+            // A truncated string.
+            &[0x63, b'a'],
+        ];
+        for data in invalid {
+            let err = block(Codec::DagCbor, data.to_vec())
+                .decode::<Ipld>()
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", data);
+        }
+
+        let deep = [vec![0x81; MAX_DEPTH + 1], vec![0xf6]].concat();
+        assert!(block(Codec::DagCbor, deep).decode::<Ipld>().is_err());
+    }
+
+    #[test]
+    fn should_decode_dag_pb() {
+        let child = make_random_block(100).cid;
+        let hash = child.to_bytes();
+        let mut link = vec![0x0a, hash.len() as u8];
+        link.extend_from_slice(&hash);
+        link.extend_from_slice(&[0x12, 0x03, b'f', b'o', b'o', 0x18, 0xe8, 0x07]);
+
+        let mut data = vec![0x12, link.len() as u8];
+        data.extend_from_slice(&link);
+        data.extend_from_slice(&[0x0a, 0x02, 0x08, 0x01]);
+
+        let value: Ipld = block(Codec::DagPb, data).decode().unwrap();
+        assert_eq!(value.get("Data"), Some(&Ipld::Bytes(vec![0x08, 0x01])));
+        let Some(Ipld::List(links)) = value.get("Links") else {
+            panic!("no links in {:?}", value);
+        };
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].get("Hash"), Some(&Ipld::Link(child)));
+        assert_eq!(links[0].get("Name"), Some(&Ipld::String("foo".to_string())));
+        assert_eq!(links[0].get("Tsize"), Some(&Ipld::Integer(1_000)));
+
+        // A link with no hash.
+        let data = vec![0x12, 0x02, 0x18, 0x01];
+        assert!(block(Codec::DagPb, data).decode::<Ipld>().is_err());
+    }
+
+    #[test]
+    fn should_decode_dag_json() {
+        let link = make_random_block(100).cid;
+        let json = format!(
+            r#"{{"a": [1, -2.5e1, "café 😀", null, false], "b": {{"/": {{"bytes": "AQID"}}}}, "l": {{"/": "{}"}}}}"#,
+            link
+        );
+
+        let value: Ipld = block(Codec::DagJson, json.into_bytes()).decode().unwrap();
+        assert_eq!(
+            value.get("a"),
+            Some(&Ipld::List(vec![
+                Ipld::Integer(1),
+                Ipld::Float(-25.0),
+                Ipld::String("café 😀".to_string()),
+                Ipld::Null,
+                Ipld::Bool(false),
+            ]))
+        );
+        assert_eq!(value.get("b"), Some(&Ipld::Bytes(vec![1, 2, 3])));
+        assert_eq!(value.get("l"), Some(&Ipld::Link(link)));
+
+        for invalid in [r#"{"a": 1,}"#, r#"[1 2]"#, r#""\x""#, "{1: 2}", "nul"] {
+            let data = invalid.as_bytes().to_vec();
+            assert!(
+                block(Codec::DagJson, data).decode::<Ipld>().is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn should_decode_raw_blocks_as_bytes() {
+        let raw = make_random_block(100);
+        assert_eq!(raw.decode::<Ipld>().unwrap(), Ipld::Bytes(raw.data.to_vec()));
+
+        let unknown = Block::with_cid(Cid::new_v1(0x300, *raw.cid.hash()), raw.data).unwrap();
+        let err = unknown.decode::<Ipld>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
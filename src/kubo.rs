@@ -0,0 +1,258 @@
+//! Migrating a Kubo (go-ipfs) repository into an [`FSStore`].
+//!
+//! Kubo keeps blocks in a flatfs datastore under the repo's `blocks/` directory, filed by
+//! multihash alone, and its pins in a LevelDB datastore next to it. The blocks get moved over by
+//! [`import_repo`]; LevelDB we don't read, so pins have to be listed with `ipfs pin ls` and
+//! handed over as parsed by [`parse_pin_ls`].
+
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+
+use cid::Cid;
+use multihash::Multihash;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, FSStore};
+use crate::dag;
+use crate::pins::{PinMode, PinStore};
+use crate::sharding::{FlatFs, ShardingStrategy};
+
+/// What [`import_repo`] got done.
+#[derive(Debug, Default)]
+pub struct Imported {
+    /// How many blocks were imported, whether or not the store already had them.
+    pub blocks: u64,
+    /// The pins that were carried over.
+    pub pins: Vec<(Cid, PinMode)>,
+    /// The pins that weren't, because some of the blocks they cover are missing, corrupt, or
+    /// have links we can't read.
+    pub unpinned: Vec<(Cid, PinMode)>,
+    /// The blocks whose data doesn't match their multihash, which were left out.
+    pub corrupt: Vec<Cid>,
+}
+
+/// Parses the output of `ipfs pin ls`, with or without `--names`. Indirect pins are left out,
+/// since the recursive pins they come from cover them.
+pub fn parse_pin_ls(output: &str) -> Result<Vec<(Cid, PinMode)>, io::Error> {
+    let mut pins = Vec::new();
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed pin listing {:?}", line),
+            )
+        };
+
+        let mut fields = line.split_whitespace();
+        let cid = fields.next().ok_or_else(invalid)?;
+        let mode = match fields.next().ok_or_else(invalid)? {
+            "direct" => PinMode::Direct,
+            "recursive" => PinMode::Recursive,
+            "indirect" => continue,
+            _ => return Err(invalid()),
+        };
+        pins.push((Cid::try_from(cid).map_err(|_| invalid())?, mode));
+    }
+    Ok(pins)
+}
+
+/// Imports the blocks of the Kubo repo at `repo` into `dest`, and carries `pins` over. Blocks
+/// are hard-linked into place where the filesystem allows, and copied otherwise, so the repo is
+/// left as it was either way. Every block gets checked against its multihash first.
+///
+/// Since flatfs doesn't record codecs, the DAGs under `pins` get walked to find out the CIDs
+/// their blocks go by, and they're imported under those. All the other blocks are imported as
+/// raw blocks, the way Kubo lists them too. A pin is only carried over once everything it
+/// covers made it in.
+pub async fn import_repo(
+    repo: &Path,
+    dest: &PinStore<FSStore>,
+    pins: &[(Cid, PinMode)],
+) -> Result<Imported, io::Error> {
+    let source = FSStore::open_read_only(repo.join("blocks")).await?;
+    let source = source.store();
+    if source.sharding().id() != FlatFs.id() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no Kubo blocks directory in {}", repo.display()),
+        ));
+    }
+
+    let mut imported = Imported::default();
+    let mut seen: HashSet<Multihash<64>> = HashSet::new();
+    for (root, mode) in pins {
+        let complete = match mode {
+            PinMode::Direct => match source.get_block(root).await? {
+                Some(block) => import_block(source, dest, &block, &mut seen, &mut imported).await?,
+                None => false,
+            },
+            PinMode::Recursive => {
+                let mut complete = true;
+                let mut walk = dag::walk(source, *root);
+                while let Some(block) = walk.next().await {
+                    complete &= match block {
+                        Ok(block) => {
+                            import_block(source, dest, &block, &mut seen, &mut imported).await?
+                        }
+                        Err(_) => false,
+                    };
+                }
+                complete
+            }
+        };
+
+        if complete {
+            dest.pin(root, *mode).await?;
+            imported.pins.push((*root, *mode));
+        } else {
+            imported.unpinned.push((*root, *mode));
+        }
+    }
+
+    let mut cids = source.blocks();
+    while let Some(cid) = cids.recv().await {
+        let cid = cid?;
+        if seen.contains(cid.hash()) {
+            continue;
+        }
+        if let Some(block) = source.get_block(&cid).await? {
+            import_block(source, dest, &block, &mut seen, &mut imported).await?;
+        }
+    }
+
+    Ok(imported)
+}
+
+// Imports `block`, unless it's already been seen under some CID. Returns whether it's in `dest`
+// now, which it isn't if it's corrupt.
+async fn import_block(
+    source: &FSStore,
+    dest: &PinStore<FSStore>,
+    block: &Block,
+    seen: &mut HashSet<Multihash<64>>,
+    imported: &mut Imported,
+) -> Result<bool, io::Error> {
+    if !seen.insert(*block.cid.hash()) {
+        return Ok(!imported
+            .corrupt
+            .iter()
+            .any(|cid| cid.hash() == block.cid.hash()));
+    }
+    if Block::hash_matches(&block.cid, &block.data) == Some(false) {
+        imported.corrupt.push(block.cid);
+        return Ok(false);
+    }
+
+    let source_path = source.block_path(&block.cid);
+    dest.store().link_block(block, source_path).await?;
+    imported.blocks += 1;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Codec, make_random_block};
+    use crate::flatfs;
+    use crate::unixfs;
+    use std::fs;
+    use tempfile::tempdir;
+
+    // Lays out `blocks` the way Kubo would in the repo at `repo`.
+    fn make_kubo_repo(repo: &Path, blocks: &[Block]) {
+        let dir = repo.join("blocks");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(flatfs::SHARDING_FILE),
+            format!("{}\n", flatfs::NEXT_TO_LAST_2),
+        )
+        .unwrap();
+        for block in blocks {
+            let path = dir.join(flatfs::block_path(block.cid.hash()));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, &block.data).unwrap();
+        }
+    }
+
+    #[test]
+    fn should_parse_pin_ls_output() {
+        let a = make_random_block(10).cid;
+        let b = make_random_block(10).cid;
+        let output = format!("{} recursive\n{} indirect\n{} direct my-file\n", a, b, b);
+        let pins = parse_pin_ls(&output).unwrap();
+        assert_eq!(pins, vec![(a, PinMode::Recursive), (b, PinMode::Direct)]);
+
+        assert!(parse_pin_ls(&format!("{} sideways", a)).is_err());
+        assert!(parse_pin_ls("not-a-cid recursive").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_import_kubo_repo() {
+        // A file made of a DAG-PB root and raw leaves, plus a loose block and a corrupt one.
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let built = crate::memstore::MemStore::new();
+        let root = unixfs::import_file(&built, data.as_slice(), 4_096)
+            .await
+            .unwrap();
+        let mut blocks = Vec::new();
+        let mut walk = dag::walk(&built, root);
+        while let Some(block) = walk.next().await {
+            blocks.push(block.unwrap());
+        }
+        assert_eq!(root.codec(), Codec::DagPb.code());
+        let loose = make_random_block(100);
+        let corrupt = make_random_block(100);
+        let repo = tempdir().unwrap();
+        make_kubo_repo(repo.path(), &blocks);
+        make_kubo_repo(repo.path(), std::slice::from_ref(&loose));
+        let corrupt_path = repo
+            .path()
+            .join("blocks")
+            .join(flatfs::block_path(corrupt.cid.hash()));
+        fs::create_dir_all(corrupt_path.parent().unwrap()).unwrap();
+        fs::write(&corrupt_path, b"not what it says").unwrap();
+
+        let dest_root = tempdir().unwrap();
+        let dest = FSStore::create(dest_root.path().to_path_buf())
+            .await
+            .unwrap();
+        let dest = PinStore::for_fs_store(dest).await.unwrap();
+        let missing = make_random_block(100).cid;
+        let pins = [(root, PinMode::Recursive), (missing, PinMode::Direct)];
+        let imported = import_repo(repo.path(), &dest, &pins).await.unwrap();
+
+        assert_eq!(imported.blocks, blocks.len() as u64 + 1);
+        assert_eq!(imported.pins, vec![(root, PinMode::Recursive)]);
+        assert_eq!(imported.unpinned, vec![(missing, PinMode::Direct)]);
+        assert_eq!(imported.corrupt.len(), 1);
+        assert_eq!(imported.corrupt[0].hash(), corrupt.cid.hash());
+
+        // The pinned DAG keeps its codecs, and reads back as the file it was.
+        assert_eq!(dest.is_pinned(&root).await, Some(PinMode::Recursive));
+        let mut read = Vec::new();
+        unixfs::export_file(dest.store(), root, &mut read, 4)
+            .await
+            .unwrap();
+        assert_eq!(read, data);
+        assert!(dest.has_block(&loose.cid).await);
+        assert_eq!(dest.stats().await.unwrap().blocks, blocks.len() as u64 + 1);
+        // The repo itself is left alone, and shares its files with the store.
+        assert!(
+            repo.path()
+                .join("blocks")
+                .join(flatfs::block_path(loose.cid.hash()))
+                .exists()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let path = dest.store().block_path(&loose.cid);
+            assert_eq!(fs::metadata(path).unwrap().nlink(), 2);
+        }
+
+        let not_kubo = tempdir().unwrap();
+        fs::create_dir(not_kubo.path().join("blocks")).unwrap();
+        assert!(import_repo(not_kubo.path(), &dest, &[]).await.is_err());
+    }
+}
@@ -1,2 +1,56 @@
+pub mod access;
+mod blake3;
 pub mod block;
-pub mod blockstore;
\ No newline at end of file
+pub mod blocking;
+pub mod blockstore;
+pub mod bloom;
+pub mod car;
+pub mod carstore;
+#[cfg(test)]
+mod conformance;
+pub mod dag;
+pub mod diff;
+pub mod dynamic;
+pub mod encrypted;
+pub mod erasure;
+pub mod events;
+pub mod fallback;
+mod flatfs;
+pub mod gateway;
+pub mod gc;
+pub mod http;
+mod index;
+pub mod ipld;
+pub mod kubo;
+pub mod memstore;
+pub mod meta;
+pub mod migrate;
+pub mod mirrored;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod mmap;
+pub mod overlay;
+mod packs;
+pub mod pins;
+pub mod quota;
+pub mod readonly;
+mod reed_solomon;
+pub mod remote;
+pub mod replicated;
+pub mod retry;
+pub mod s3;
+pub mod scrub;
+pub mod sharded;
+pub mod sharding;
+mod sha3;
+pub mod snapshot;
+pub mod stream;
+pub mod sync;
+pub mod tiered;
+pub mod timeout;
+pub mod ttl;
+pub mod txn;
+pub mod union;
+pub mod unixfs;
+pub mod writeback;
+mod xchacha;
\ No newline at end of file
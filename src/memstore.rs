@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::RwLock;
+
+use crate::block::{Block, is_inline, to_v1};
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+use cid::Cid;
+use tokio::sync::mpsc;
+
+/// An in-memory [`Blockstore`]. Handy for tests, and when built with [`MemStore::bounded`] it
+/// doubles as a small cache: once the byte cap is reached, the oldest blocks get evicted to
+/// make room for new ones.
+pub struct MemStore {
+    inner: RwLock<Inner>,
+    capacity: Option<usize>,
+}
+
+struct Inner {
+    blocks: HashMap<Cid, (u64, Block)>,
+    // Metadata attached with `MetaBlockstore::put_block_with_meta`, by the same keys.
+    meta: HashMap<Cid, Vec<u8>>,
+    // Tags given with `MetaBlockstore::tag`, both ways round.
+    tags: HashMap<Cid, Vec<String>>,
+    tagged: HashMap<String, HashSet<Cid>>,
+    // Insertion order, so we know what to evict first when we're over capacity.
+    order: BTreeMap<u64, Cid>,
+    next_seq: u64,
+    bytes: usize,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        Self::with_cap(None)
+    }
+
+    /// Creates a store that holds at most `capacity` bytes of block data.
+    pub fn bounded(capacity: usize) -> Self {
+        Self::with_cap(Some(capacity))
+    }
+
+    fn with_cap(capacity: Option<usize>) -> Self {
+        MemStore {
+            inner: RwLock::new(Inner {
+                blocks: HashMap::new(),
+                meta: HashMap::new(),
+                tags: HashMap::new(),
+                tagged: HashMap::new(),
+                order: BTreeMap::new(),
+                next_seq: 0,
+                bytes: 0,
+            }),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total bytes of block data currently held.
+    pub fn size(&self) -> usize {
+        self.inner.read().unwrap().bytes
+    }
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemStore {
+    fn check_fits(&self, size: usize) -> Result<(), BlockstoreError> {
+        match self.capacity {
+            Some(capacity) if size > capacity => Err(BlockstoreError::QuotaExceeded {
+                size: size as u64,
+                max_bytes: capacity as u64,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Puts all of `blocks` under a single lock, so readers see either none or all of them.
+    pub(crate) fn put_atomically(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        let mut inner = self.inner.write().unwrap();
+        // Evicting some of the blocks to make room for the rest wouldn't be all-or-nothing, so
+        // they must fit together, counting the ones already here as they're held.
+        let mut sizes = HashMap::new();
+        for block in blocks {
+            let cid = to_v1(&block.cid);
+            let size = match inner.blocks.get(&cid) {
+                Some((_, held)) => held.data.len(),
+                None => block.data.len(),
+            };
+            sizes.insert(cid, size);
+        }
+        self.check_fits(sizes.values().sum())?;
+
+        // The ones already here then go to the back of the line, so that making room for the
+        // rest doesn't evict them.
+        for cid in sizes.keys() {
+            inner.refresh(cid);
+        }
+        for block in blocks {
+            inner.insert(block, self.capacity);
+        }
+        Ok(())
+    }
+
+    // Puts `block` and attaches `meta` to it under a single lock, so readers never see the
+    // block with its old metadata.
+    pub(crate) fn put_with_meta(&self, block: &Block, meta: &[u8]) -> Result<Put, BlockstoreError> {
+        self.check_fits(block.data.len())?;
+        let mut inner = self.inner.write().unwrap();
+        let put = inner.insert(block, self.capacity);
+        inner.meta.insert(to_v1(&block.cid), meta.to_vec());
+        Ok(put)
+    }
+
+    pub(crate) fn meta(&self, cid: &Cid) -> Option<Vec<u8>> {
+        self.inner.read().unwrap().meta.get(&to_v1(cid)).cloned()
+    }
+
+    pub(crate) fn tag(&self, cid: &Cid, tag: &str) -> Result<(), BlockstoreError> {
+        let cid = to_v1(cid);
+        let mut inner = self.inner.write().unwrap();
+        if !inner.blocks.contains_key(&cid) {
+            return Err(BlockstoreError::NotFound(cid));
+        }
+        let tags = inner.tags.entry(cid).or_default();
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+            inner.tagged.entry(tag.to_string()).or_default().insert(cid);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn untag(&self, cid: &Cid, tag: &str) {
+        let cid = to_v1(cid);
+        let mut inner = self.inner.write().unwrap();
+        if let Some(tags) = inner.tags.get_mut(&cid) {
+            tags.retain(|t| t != tag);
+            if tags.is_empty() {
+                inner.tags.remove(&cid);
+            }
+        }
+        inner.untag(&cid, tag);
+    }
+
+    pub(crate) fn tags(&self, cid: &Cid) -> Vec<String> {
+        let inner = self.inner.read().unwrap();
+        inner.tags.get(&to_v1(cid)).cloned().unwrap_or_default()
+    }
+
+    pub(crate) fn tagged(&self, tag: &str) -> Vec<Cid> {
+        let inner = self.inner.read().unwrap();
+        inner.tagged.get(tag).map(|cids| cids.iter().copied().collect()).unwrap_or_default()
+    }
+}
+
+impl Inner {
+    // Adds `block` unless it's already there, evicting the oldest blocks until it fits within
+    // `capacity`, which it must be no larger than.
+    fn insert(&mut self, block: &Block, capacity: Option<usize>) -> Put {
+        // CIDv0s are kept under their v1 equivalent, so both versions find the block.
+        let cid = to_v1(&block.cid);
+        if is_inline(&cid) || self.blocks.contains_key(&cid) {
+            return Put::Existing;
+        }
+
+        let size = block.data.len();
+        if let Some(capacity) = capacity {
+            while self.bytes + size > capacity {
+                // Can't be empty: the block fits in an empty store.
+                let (_, oldest) = self.order.pop_first().unwrap();
+                self.remove(&oldest);
+            }
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.insert(seq, cid);
+        let data = block.data.clone();
+        self.blocks.insert(cid, (seq, Block { cid, data }));
+        self.bytes += size;
+        Put::Written { bytes: size as u64 }
+    }
+
+    // Makes `cid`, if it's here, the last block to be evicted, as if it had just been put.
+    fn refresh(&mut self, cid: &Cid) {
+        let Some((seq, _)) = self.blocks.get_mut(cid) else {
+            return;
+        };
+        self.order.remove(seq);
+        *seq = self.next_seq;
+        self.order.insert(self.next_seq, *cid);
+        self.next_seq += 1;
+    }
+
+    // Takes `cid` off the blocks with `tag`, forgetting about the tag once none have it.
+    fn untag(&mut self, cid: &Cid, tag: &str) {
+        if let Some(cids) = self.tagged.get_mut(tag) {
+            cids.remove(cid);
+            if cids.is_empty() {
+                self.tagged.remove(tag);
+            }
+        }
+    }
+
+    fn remove(&mut self, cid: &Cid) -> Option<Block> {
+        let (seq, block) = self.blocks.remove(cid)?;
+        self.order.remove(&seq);
+        self.meta.remove(cid);
+        for tag in self.tags.remove(cid).unwrap_or_default() {
+            self.untag(cid, &tag);
+        }
+        self.bytes -= block.data.len();
+        Some(block)
+    }
+}
+
+/// Like with [`FSStore`](crate::blockstore::FSStore), blocks with identity CIDs are served from
+/// the CID and always there, without being held, listed or counted.
+impl Blockstore for MemStore {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        if is_inline(&block.cid) {
+            return Ok(Put::Existing);
+        }
+        self.check_fits(block.data.len())?;
+        Ok(self.inner.write().unwrap().insert(block, self.capacity))
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        is_inline(cid) || self.inner.read().unwrap().blocks.contains_key(&to_v1(cid))
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if let Some(block) = Block::inline(cid) {
+            return Ok(Some(block));
+        }
+        Ok(self
+            .inner
+            .read()
+            .unwrap()
+            .blocks
+            .get(&to_v1(cid))
+            .map(|(_, block)| Block {
+                cid: *cid,
+                data: block.data.clone(),
+            }))
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(Some(cid.hash().size() as u64));
+        }
+        Ok(self
+            .inner
+            .read()
+            .unwrap()
+            .blocks
+            .get(&to_v1(cid))
+            .map(|(_, block)| block.data.len() as u64))
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(());
+        }
+        match self.inner.write().unwrap().remove(&to_v1(cid)) {
+            Some(_) => Ok(()),
+            None => Err(BlockstoreError::NotFound(*cid)),
+        }
+    }
+
+    fn blocks(&self) -> CidStream {
+        // We list a snapshot so the lock isn't held while the consumer takes its time.
+        let cids: Vec<Cid> = self.inner.read().unwrap().blocks.keys().copied().collect();
+        let (sender, receiver) = mpsc::channel(cids.len().max(1));
+        for cid in cids {
+            // Can't fail: the channel has room for everything, and we hold the receiver.
+            sender.try_send(Ok(cid)).unwrap();
+        }
+        receiver
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let inner = self.inner.read().unwrap();
+        Ok(StoreStats {
+            blocks: inner.blocks.len() as u64,
+            bytes: inner.bytes as u64,
+            disk_bytes: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::block::{make_random_block, to_v0, Codec, Hasher};
+
+    async fn make_mem_store() -> (MemStore, ()) {
+        (MemStore::new(), ())
+    }
+
+    crate::conformance::conformance_tests!(make_mem_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_get_stored_block() {
+        let store = MemStore::new();
+        let block = make_random_block(1_000);
+
+        assert!(store.get_block(&block.cid).await.unwrap().is_none());
+        store.put_block(&block).await.unwrap();
+
+        assert!(store.has_block(&block.cid).await);
+        assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+        assert_eq!(store.block_size(&block.cid).await.unwrap(), Some(1_000));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_resolve_v0_and_v1_to_same_block() {
+        let store = MemStore::new();
+        let data = make_random_block(1_000).data;
+        let v1 = Block::new_with_codec(data.clone(), Codec::DagPb, Hasher::Sha2_256)
+            .unwrap()
+            .cid;
+        let v0 = to_v0(&v1).unwrap();
+
+        store.put_block(&Block::with_cid(v0, data).unwrap()).await.unwrap();
+
+        assert!(store.has_block(&v1).await);
+        assert_eq!(store.get_block(&v1).await.unwrap().unwrap().cid, v1);
+        assert_eq!(store.get_block(&v0).await.unwrap().unwrap().cid, v0);
+        assert_eq!(store.block_size(&v1).await.unwrap(), Some(1_000));
+        store.del_block(&v1).await.unwrap();
+        assert!(!store.has_block(&v0).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_put_many_blocks() {
+        let store = MemStore::new();
+        let blocks: Vec<Block> = (0..5).map(|_| make_random_block(1_000)).collect();
+
+        store.put_many(&blocks).await.unwrap();
+
+        assert_eq!(store.len(), 5);
+        for block in &blocks {
+            assert!(store.has_block(&block.cid).await);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_list_stored_blocks() {
+        let store = MemStore::new();
+        let blocks: Vec<Block> = (0..5).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks).await.unwrap();
+
+        let mut listed = Vec::new();
+        let mut stream = store.blocks();
+        while let Some(cid) = stream.recv().await {
+            listed.push(cid.unwrap());
+        }
+
+        listed.sort();
+        let mut expected: Vec<Cid> = blocks.iter().map(|b| b.cid).collect();
+        expected.sort();
+        assert_eq!(listed, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_delete_block() {
+        let store = MemStore::new();
+        let block = make_random_block(1_000);
+
+        store.put_block(&block).await.unwrap();
+        store.del_block(&block.cid).await.unwrap();
+
+        assert!(!store.has_block(&block.cid).await);
+        assert!(store.is_empty());
+        assert_eq!(store.size(), 0);
+        assert_eq!(store.stats().await.unwrap(), StoreStats::default());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_evict_oldest_blocks_when_over_capacity() {
+        let store = MemStore::bounded(2_500);
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+
+        for block in &blocks {
+            store.put_block(block).await.unwrap();
+        }
+
+        assert!(!store.has_block(&blocks[0].cid).await);
+        assert!(store.has_block(&blocks[1].cid).await);
+        assert!(store.has_block(&blocks[2].cid).await);
+        assert_eq!(store.size(), 2_000);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_reject_blocks_larger_than_capacity() {
+        let store = MemStore::bounded(500);
+        let block = make_random_block(1_000);
+
+        let err = store.put_block(&block).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(store.is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+//! Small application metadata, like the peer a block came from or the batch it was imported in,
+//! attached to blocks without changing their CIDs, and tags for finding blocks again by.
+
+use std::io;
+
+use cid::Cid;
+use tokio::sync::mpsc;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, FSStore, Put};
+use crate::memstore::MemStore;
+
+/// The most bytes of metadata a block can have.
+pub const MAX_META_SIZE: usize = 64 << 10;
+
+/// The longest a tag can be, in bytes.
+pub const MAX_TAG_LEN: usize = 255;
+
+/// Extension of [`Blockstore`] for stores that can keep metadata and tags beside blocks. Both
+/// stay with their block until the block is deleted: a plain `put_block` of a block that's
+/// already there leaves them alone.
+///
+/// A block can have any number of tags, and every tag any number of blocks, so that, say, all
+/// the blocks from one import can be tagged with it, and then listed or deleted together. Tags
+/// are up to [`MAX_TAG_LEN`] bytes, can't be empty or start with a dot, and can't contain
+/// slashes, backslashes, line breaks or NULs.
+///
+/// [`FSStore`] keeps each block's metadata in a file of its own under
+/// [`META_DIR`](crate::blockstore::META_DIR), and its tags both in a list beside that and as
+/// entries under [`TAGS_DIR`](crate::blockstore::TAGS_DIR). [`MemStore`] keeps it all in
+/// memory.
+pub trait MetaBlockstore: Blockstore {
+    /// Stores `block`, with `meta` replacing whatever metadata it already had. Fails with
+    /// [`io::ErrorKind::InvalidInput`] if `meta` is over [`MAX_META_SIZE`].
+    fn put_block_with_meta(
+        &self,
+        block: &Block,
+        meta: &[u8],
+    ) -> impl Future<Output = Result<Put, BlockstoreError>> + Send;
+
+    /// Returns the metadata of block `cid`, or `None` if it has none or isn't in the store.
+    fn get_meta(
+        &self,
+        cid: &Cid,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, BlockstoreError>> + Send;
+
+    /// Gives block `cid` the tag `tag`, if it doesn't have it yet. Fails with
+    /// [`BlockstoreError::NotFound`] if the block isn't in the store.
+    fn tag(&self, cid: &Cid, tag: &str)
+    -> impl Future<Output = Result<(), BlockstoreError>> + Send;
+
+    /// Takes the tag `tag` off block `cid`, if it has it.
+    fn untag(
+        &self,
+        cid: &Cid,
+        tag: &str,
+    ) -> impl Future<Output = Result<(), BlockstoreError>> + Send;
+
+    /// Returns the tags of block `cid`, in the order they were given.
+    fn tags(&self, cid: &Cid) -> impl Future<Output = Result<Vec<String>, BlockstoreError>> + Send;
+
+    /// Lists the blocks with the tag `tag`, in no particular order. Blocks tagged or untagged
+    /// while this runs may or may not be listed.
+    fn list_by_tag(&self, tag: &str) -> CidStream;
+}
+
+fn check_size(meta: &[u8]) -> Result<(), BlockstoreError> {
+    if meta.len() > MAX_META_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} bytes of metadata, over {}", meta.len(), MAX_META_SIZE),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn check_tag(tag: &str) -> Result<(), BlockstoreError> {
+    let valid = !tag.is_empty()
+        && tag.len() <= MAX_TAG_LEN
+        && !tag.starts_with('.')
+        && !tag.contains(['/', '\\', '\n', '\r', '\0']);
+    if !valid {
+        return Err(
+            io::Error::new(io::ErrorKind::InvalidInput, format!("bad tag {:?}", tag)).into(),
+        );
+    }
+    Ok(())
+}
+
+// Lists nothing but the error that `tag` isn't valid, if it isn't.
+fn bad_tag(tag: &str) -> Option<CidStream> {
+    let e = check_tag(tag).err()?;
+    let (sender, receiver) = mpsc::channel(1);
+    // Can't fail: the channel has room, and we hold the receiver.
+    sender.try_send(Err(e)).unwrap();
+    Some(receiver)
+}
+
+/// The block goes in first, and the metadata after it, so a crash in between can leave the block
+/// without it but never the other way around.
+impl MetaBlockstore for FSStore {
+    async fn put_block_with_meta(
+        &self,
+        block: &Block,
+        meta: &[u8],
+    ) -> Result<Put, BlockstoreError> {
+        check_size(meta)?;
+        let put = self.put_block(block).await?;
+        self.put_meta(&block.cid, meta).await?;
+        Ok(put)
+    }
+
+    async fn get_meta(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreError> {
+        self.read_meta(cid).await
+    }
+
+    async fn tag(&self, cid: &Cid, tag: &str) -> Result<(), BlockstoreError> {
+        check_tag(tag)?;
+        self.tag_block(cid, tag).await
+    }
+
+    async fn untag(&self, cid: &Cid, tag: &str) -> Result<(), BlockstoreError> {
+        check_tag(tag)?;
+        self.untag_block(cid, tag).await
+    }
+
+    async fn tags(&self, cid: &Cid) -> Result<Vec<String>, BlockstoreError> {
+        self.block_tags(cid).await
+    }
+
+    fn list_by_tag(&self, tag: &str) -> CidStream {
+        bad_tag(tag).unwrap_or_else(|| self.list_tagged(tag))
+    }
+}
+
+impl MetaBlockstore for MemStore {
+    async fn put_block_with_meta(
+        &self,
+        block: &Block,
+        meta: &[u8],
+    ) -> Result<Put, BlockstoreError> {
+        check_size(meta)?;
+        self.put_with_meta(block, meta)
+    }
+
+    async fn get_meta(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreError> {
+        Ok(self.meta(cid))
+    }
+
+    async fn tag(&self, cid: &Cid, tag: &str) -> Result<(), BlockstoreError> {
+        check_tag(tag)?;
+        MemStore::tag(self, cid, tag)
+    }
+
+    async fn untag(&self, cid: &Cid, tag: &str) -> Result<(), BlockstoreError> {
+        check_tag(tag)?;
+        MemStore::untag(self, cid, tag);
+        Ok(())
+    }
+
+    async fn tags(&self, cid: &Cid) -> Result<Vec<String>, BlockstoreError> {
+        Ok(MemStore::tags(self, cid))
+    }
+
+    fn list_by_tag(&self, tag: &str) -> CidStream {
+        if let Some(bad) = bad_tag(tag) {
+            return bad;
+        }
+        let cids = self.tagged(tag);
+        let (sender, receiver) = mpsc::channel(cids.len().max(1));
+        for cid in cids {
+            // Can't fail: the channel has room for everything, and we hold the receiver.
+            sender.try_send(Ok(cid)).unwrap();
+        }
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::blockstore::{META_DIR, TAGS_DIR};
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    async fn check_meta(store: &impl MetaBlockstore) {
+        let block = make_random_block(100);
+        let plain = make_random_block(100);
+
+        let put = store.put_block_with_meta(&block, b"peer=a").await.unwrap();
+        assert_eq!(put, Put::Written { bytes: 100 });
+        store.put_block(&plain).await.unwrap();
+        assert_eq!(
+            store.get_meta(&block.cid).await.unwrap().unwrap(),
+            b"peer=a"
+        );
+        assert_eq!(store.get_meta(&plain.cid).await.unwrap(), None);
+
+        // Putting the block again only replaces its metadata if there's new metadata to put.
+        store.put_block(&block).await.unwrap();
+        assert_eq!(
+            store.get_meta(&block.cid).await.unwrap().unwrap(),
+            b"peer=a"
+        );
+        let put = store.put_block_with_meta(&block, b"peer=b").await.unwrap();
+        assert_eq!(put, Put::Existing);
+        assert_eq!(
+            store.get_meta(&block.cid).await.unwrap().unwrap(),
+            b"peer=b"
+        );
+        assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+
+        let err = store
+            .put_block_with_meta(&plain, &[0; MAX_META_SIZE + 1])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Deleting the block takes its metadata with it.
+        store.del_block(&block.cid).await.unwrap();
+        store.put_block(&block).await.unwrap();
+        assert_eq!(store.get_meta(&block.cid).await.unwrap(), None);
+    }
+
+    async fn check_tags(store: &impl MetaBlockstore) {
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(100)).collect();
+        store.put_many(&blocks).await.unwrap();
+        for block in &blocks[..2] {
+            store.tag(&block.cid, "import:x").await.unwrap();
+        }
+        store.tag(&blocks[1].cid, "peer=a").await.unwrap();
+        store.tag(&blocks[1].cid, "peer=a").await.unwrap();
+        store.tag(&blocks[2].cid, "import:y").await.unwrap();
+
+        assert_eq!(
+            store.tags(&blocks[1].cid).await.unwrap(),
+            ["import:x", "peer=a"]
+        );
+        assert_eq!(
+            listed(store, "import:x").await,
+            HashSet::from([blocks[0].cid, blocks[1].cid])
+        );
+        assert!(listed(store, "nobody").await.is_empty());
+
+        // Deleting everything from one import.
+        let mut cids = store.list_by_tag("import:x");
+        while let Some(cid) = cids.recv().await {
+            store.del_block(&cid.unwrap()).await.unwrap();
+        }
+        assert!(listed(store, "import:x").await.is_empty());
+        assert!(listed(store, "peer=a").await.is_empty());
+        assert!(store.tags(&blocks[0].cid).await.unwrap().is_empty());
+        assert!(store.has_block(&blocks[2].cid).await);
+
+        store.untag(&blocks[2].cid, "import:y").await.unwrap();
+        assert!(listed(store, "import:y").await.is_empty());
+        assert!(store.tags(&blocks[2].cid).await.unwrap().is_empty());
+
+        let err = store.tag(&blocks[0].cid, "import:x").await.unwrap_err();
+        assert!(matches!(err, BlockstoreError::NotFound(_)));
+        for bad in ["", ".hidden", "a/b", "a\nb"] {
+            let err = store.tag(&blocks[2].cid, bad).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            let err = store.list_by_tag(bad).recv().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    async fn listed(store: &impl MetaBlockstore, tag: &str) -> HashSet<Cid> {
+        let mut cids = store.list_by_tag(tag);
+        let mut listed = HashSet::new();
+        while let Some(cid) = cids.recv().await {
+            listed.insert(cid.unwrap());
+        }
+        listed
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_meta_in_mem_store() {
+        check_meta(&MemStore::new()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_tag_blocks_in_mem_store() {
+        check_tags(&MemStore::new()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_tag_blocks_in_fs_store() {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        check_tags(&store).await;
+
+        // Tags outlive the store, and leave nothing behind once they're gone.
+        let block = make_random_block(100);
+        store.put_block(&block).await.unwrap();
+        store.tag(&block.cid, "kept").await.unwrap();
+        drop(store);
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(listed(&store, "kept").await, HashSet::from([block.cid]));
+        store.del_block(&block.cid).await.unwrap();
+        let tags_dir = root.path().join(TAGS_DIR);
+        assert_eq!(std::fs::read_dir(tags_dir).unwrap().count(), 0);
+        let meta_dir = root.path().join(META_DIR);
+        assert_eq!(std::fs::read_dir(meta_dir).unwrap().count(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_meta_in_sidecar_files() {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        check_meta(&store).await;
+
+        let block = make_random_block(100);
+        store.put_block_with_meta(&block, b"batch=7").await.unwrap();
+        let stats = store.stats().await.unwrap();
+        drop(store);
+
+        // Metadata files are neither listed nor counted as blocks.
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        assert_eq!(
+            store.get_meta(&block.cid).await.unwrap().unwrap(),
+            b"batch=7"
+        );
+        assert_eq!(store.stats().await.unwrap().blocks, stats.blocks);
+        let mut cids = store.blocks();
+        let mut listed = 0;
+        while let Some(cid) = cids.recv().await {
+            cid.unwrap();
+            listed += 1;
+        }
+        assert_eq!(listed, stats.blocks);
+
+        // Nor are they left behind once their blocks are gone.
+        store.del_block(&block.cid).await.unwrap();
+        let mut cids = store.blocks();
+        while let Some(cid) = cids.recv().await {
+            store.del_block(&cid.unwrap()).await.unwrap();
+        }
+        let meta_dir = root.path().join(META_DIR);
+        assert_eq!(std::fs::read_dir(meta_dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,349 @@
+//! Operation counters and latency histograms for any [`Blockstore`], rendered in the Prometheus
+//! text exposition format so they can be scraped, e.g. by serving [`render`]'s output over
+//! [`crate::http`].
+
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use cid::Cid;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, StoreStats};
+
+// Upper bounds of the latency histogram buckets, in seconds. Anything slower only shows up in the
+// implicit `+Inf` bucket.
+const BUCKETS: [f64; 14] = [
+    0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5,
+    1.0,
+];
+
+#[derive(Clone, Copy)]
+enum Op {
+    Put,
+    PutMany,
+    Has,
+    Get,
+    Delete,
+}
+
+const OPS: [Op; 5] = [Op::Put, Op::PutMany, Op::Has, Op::Get, Op::Delete];
+
+impl Op {
+    fn name(self) -> &'static str {
+        match self {
+            Op::Put => "put",
+            Op::PutMany => "put_many",
+            Op::Has => "has",
+            Op::Get => "get",
+            Op::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Per bucket rather than cumulative, with the `+Inf` bucket last.
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS.partition_point(|bound| *bound < seconds);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+// Names and descriptions of the counters, which all get a `blockstore_` prefix and a `_total`
+// suffix.
+const COUNTERS: [(&str, &str); 8] = [
+    ("puts", "Blocks put."),
+    ("gets", "Blocks fetched."),
+    ("deletes", "Blocks deleted."),
+    ("hits", "Lookups that found their block."),
+    ("misses", "Lookups that didn't find their block."),
+    ("bytes_written", "Bytes of block data put."),
+    ("bytes_read", "Bytes of block data fetched."),
+    ("errors", "Operations that failed."),
+];
+
+/// What a [`MetricsStore`] has counted so far.
+pub struct Metrics {
+    store: String,
+    puts: AtomicU64,
+    gets: AtomicU64,
+    deletes: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bytes_written: AtomicU64,
+    bytes_read: AtomicU64,
+    errors: AtomicU64,
+    latency: [Histogram; OPS.len()],
+}
+
+impl Metrics {
+    fn new(store: String) -> Self {
+        Metrics {
+            store,
+            puts: AtomicU64::default(),
+            gets: AtomicU64::default(),
+            deletes: AtomicU64::default(),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+            bytes_written: AtomicU64::default(),
+            bytes_read: AtomicU64::default(),
+            errors: AtomicU64::default(),
+            latency: Default::default(),
+        }
+    }
+
+    // In the same order as `COUNTERS`.
+    fn counters(&self) -> [&AtomicU64; COUNTERS.len()] {
+        [
+            &self.puts,
+            &self.gets,
+            &self.deletes,
+            &self.hits,
+            &self.misses,
+            &self.bytes_written,
+            &self.bytes_read,
+            &self.errors,
+        ]
+    }
+
+    fn lookup(&self, found: bool) {
+        let counter = if found { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Records how long `op` took since `start`, and whether it failed.
+    fn finish<T>(&self, op: Op, start: Instant, result: &Result<T, BlockstoreError>) {
+        self.latency[op as usize].observe(start.elapsed());
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Renders `metrics` in the Prometheus text exposition format. Each store's series are labelled
+/// with the name it was given in [`MetricsStore::new`], so metrics from several layers of
+/// wrappers can be rendered together.
+pub fn render(metrics: &[&Metrics]) -> String {
+    let mut out = String::new();
+    for (i, (name, help)) in COUNTERS.into_iter().enumerate() {
+        let _ = writeln!(out, "# HELP blockstore_{}_total {}", name, help);
+        let _ = writeln!(out, "# TYPE blockstore_{}_total counter", name);
+        for m in metrics {
+            let value = m.counters()[i].load(Ordering::Relaxed);
+            let labels = format!("store=\"{}\"", escape(&m.store));
+            let _ = writeln!(out, "blockstore_{}_total{{{}}} {}", name, labels, value);
+        }
+    }
+
+    let name = "blockstore_operation_duration_seconds";
+    let _ = writeln!(out, "# HELP {} How long operations took.", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for m in metrics {
+        for op in OPS {
+            let histogram = &m.latency[op as usize];
+            let labels = format!("store=\"{}\",op=\"{}\"", escape(&m.store), op.name());
+            let mut count = 0;
+            for (i, bucket) in histogram.counts.iter().enumerate() {
+                count += bucket.load(Ordering::Relaxed);
+                let bound = BUCKETS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, labels, bound, count
+                );
+            }
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+        }
+    }
+    out
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Wraps a [`Blockstore`], counting what goes through it and timing every operation. Wrap each
+/// layer of a stack of stores that's of interest, e.g. both a cache and the `FSStore` behind it,
+/// to see where time goes.
+pub struct MetricsStore<S> {
+    store: S,
+    metrics: Metrics,
+}
+
+impl<S: Blockstore> MetricsStore<S> {
+    /// Wraps `store`, whose metrics get labelled `store="<name>"`.
+    pub fn new(store: S, name: impl Into<String>) -> Self {
+        MetricsStore {
+            store,
+            metrics: Metrics::new(name.into()),
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
+impl<S: Blockstore> Blockstore for MetricsStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let start = Instant::now();
+        let result = self.store.put_block(block).await;
+        self.metrics.finish(Op::Put, start, &result);
+        if let Ok(put) = result {
+            self.metrics.puts.fetch_add(1, Ordering::Relaxed);
+            if put.is_written() {
+                let len = block.data.len() as u64;
+                self.metrics.bytes_written.fetch_add(len, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        let start = Instant::now();
+        let result = self.store.put_many(blocks).await;
+        self.metrics.finish(Op::PutMany, start, &result);
+        if result.is_ok() {
+            let len: usize = blocks.iter().map(|block| block.data.len()).sum();
+            self.metrics
+                .puts
+                .fetch_add(blocks.len() as u64, Ordering::Relaxed);
+            self.metrics
+                .bytes_written
+                .fetch_add(len as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        let start = Instant::now();
+        let found = self.store.has_block(cid).await;
+        self.metrics.finish(Op::Has, start, &Ok(()));
+        self.metrics.lookup(found);
+        found
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        let start = Instant::now();
+        let result = self.store.get_block(cid).await;
+        self.metrics.finish(Op::Get, start, &result);
+        self.metrics.gets.fetch_add(1, Ordering::Relaxed);
+        match &result {
+            Ok(Some(block)) => {
+                self.metrics.lookup(true);
+                let len = block.data.len() as u64;
+                self.metrics.bytes_read.fetch_add(len, Ordering::Relaxed);
+            }
+            Ok(None) => self.metrics.lookup(false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.metrics.lookup(false),
+            Err(_) => {}
+        }
+        result
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.store.block_size(cid).await
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        let start = Instant::now();
+        let result = self.store.del_block(cid).await;
+        self.metrics.finish(Op::Delete, start, &result);
+        if result.is_ok() {
+            self.metrics.deletes.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.store.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+
+    async fn make_metrics_store() -> (MetricsStore<MemStore>, ()) {
+        (MetricsStore::new(MemStore::new(), "mem"), ())
+    }
+
+    crate::conformance::conformance_tests!(make_metrics_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_count_operations() {
+        let store = MetricsStore::new(MemStore::new(), "mem");
+        let block = make_random_block(100);
+
+        store.put_block(&block).await.unwrap();
+        store.get_block(&block.cid).await.unwrap();
+        store.get_block(&make_random_block(100).cid).await.unwrap();
+        store.del_block(&block.cid).await.unwrap();
+        assert!(store.del_block(&block.cid).await.is_err());
+
+        let text = render(&[store.metrics()]);
+        for line in [
+            "blockstore_puts_total{store=\"mem\"} 1",
+            "blockstore_gets_total{store=\"mem\"} 2",
+            "blockstore_deletes_total{store=\"mem\"} 1",
+            "blockstore_hits_total{store=\"mem\"} 1",
+            "blockstore_misses_total{store=\"mem\"} 1",
+            "blockstore_bytes_written_total{store=\"mem\"} 100",
+            "blockstore_bytes_read_total{store=\"mem\"} 100",
+            "blockstore_errors_total{store=\"mem\"} 1",
+            "blockstore_operation_duration_seconds_bucket{store=\"mem\",op=\"get\",le=\"+Inf\"} 2",
+            "blockstore_operation_duration_seconds_count{store=\"mem\",op=\"delete\"} 2",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in:\n{}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_render_each_layer_separately() {
+        let inner = MetricsStore::new(MemStore::new(), "inner");
+        let store = MetricsStore::new(inner, "outer \"quoted\"");
+        store.put_block(&make_random_block(100)).await.unwrap();
+
+        let text = render(&[store.metrics(), store.store().metrics()]);
+        assert!(text.contains("blockstore_puts_total{store=\"outer \\\"quoted\\\"\"} 1"));
+        assert!(text.contains("blockstore_puts_total{store=\"inner\"} 1"));
+        assert_eq!(
+            text.matches("# TYPE blockstore_puts_total counter").count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Versioning of [`FSStore`]'s on-disk format. Every store records the version it's laid out as
+//! in [`VERSION_FILE`], which [`FSStore::create`] checks so that it never goes ahead with a
+//! layout it doesn't understand. Stores at an older version are upgraded in place by [`migrate`].
+//!
+//! [`FSStore`]: crate::blockstore::FSStore
+//! [`FSStore::create`]: crate::blockstore::FSStore::create
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{fs, io};
+
+use cid::Version;
+use tokio::task::spawn_blocking;
+
+use crate::sharding::{self, FlatFs, Prefix, ShardingStrategy};
+
+/// Name of the file in a store's root holding its version.
+pub const VERSION_FILE: &str = ".version";
+
+/// The version stores get created at, and the only one [`FSStore`] opens.
+///
+/// [`FSStore`]: crate::blockstore::FSStore
+pub const CURRENT_VERSION: u32 = 1;
+
+type Migration = fn(&Path) -> Result<(), io::Error>;
+
+// `MIGRATIONS[n]` upgrades a store from version `n` to `n + 1`. Each must be safe to run again
+// after being interrupted, since the version only gets bumped once it's done.
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [v0_to_v1];
+
+/// Upgrades the store at `root` to [`CURRENT_VERSION`], returning the version it was at. The
+/// store must not be open while this runs.
+pub async fn migrate(root: PathBuf) -> Result<u32, io::Error> {
+    spawn_blocking(move || {
+        let from = match read_version(&root)? {
+            Some(version) if version > CURRENT_VERSION => return Err(too_new(&root, version)),
+            Some(version) => version,
+            None if is_unversioned_current(&root)? => CURRENT_VERSION,
+            None => 0,
+        };
+
+        for version in from..CURRENT_VERSION {
+            MIGRATIONS[version as usize](&root)?;
+            write_version(&root, version + 1)?;
+        }
+        if from == CURRENT_VERSION {
+            write_version(&root, CURRENT_VERSION)?;
+        }
+        Ok(from)
+    })
+    .await?
+}
+
+// Fails unless the store at `root` is at the current version. Stores that don't record one yet
+// but are laid out like the current version get stamped with it, unless `read_only` is set.
+pub(crate) fn check_version(root: &Path, read_only: bool) -> Result<(), io::Error> {
+    let version = match read_version(root)? {
+        Some(version) => version,
+        None if is_unversioned_current(root)? => {
+            if !read_only {
+                write_version(root, CURRENT_VERSION)?;
+            }
+            return Ok(());
+        }
+        None => 0,
+    };
+
+    if version > CURRENT_VERSION {
+        return Err(too_new(root, version));
+    }
+    if version < CURRENT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "store at {:?} is at version {}, and needs migrating to version {}",
+                root, version, CURRENT_VERSION
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn read_version(root: &Path) -> Result<Option<u32>, io::Error> {
+    match fs::read_to_string(root.join(VERSION_FILE)) {
+        Ok(contents) => contents.trim().parse().map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {} in {:?}: {}", VERSION_FILE, root, e),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_version(root: &Path, version: u32) -> Result<(), io::Error> {
+    fs::write(root.join(VERSION_FILE), format!("{}\n", version))
+}
+
+// Whether the store at `root` can be at the current version without saying so: it's either
+// brand new, or a Kubo `blocks/` directory, whose layout we've never laid out differently.
+fn is_unversioned_current(root: &Path) -> Result<bool, io::Error> {
+    if fs::read_dir(root)?.next().is_none() {
+        return Ok(true);
+    }
+    Ok(sharding::read_id(root)? == Some(FlatFs.id()))
+}
+
+fn too_new(root: &Path, version: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "store at {:?} is at version {}, but only version {} is supported",
+            root, version, CURRENT_VERSION
+        ),
+    )
+}
+
+// Version 0 stores predate both CIDv0s being filed under their v1 equivalent and the sharding
+// strategy being recorded. Moves any blocks still filed under a CIDv0, then records the default
+// strategy if the store doesn't have one.
+fn v0_to_v1(root: &Path) -> Result<(), io::Error> {
+    let sharding: Arc<dyn ShardingStrategy> = match sharding::detect(root)? {
+        Some(sharding) => sharding,
+        None => {
+            sharding::write_id(root, &Prefix::default())?;
+            Arc::new(Prefix::default())
+        }
+    };
+
+    let mut files = Vec::new();
+    list_files(root, Path::new(""), &mut files)?;
+    for relative in files {
+        // Anything that isn't a block is left for the store to complain about.
+        let Some(Ok(cid)) = sharding.cid_from_path(&relative) else {
+            continue;
+        };
+        let target = sharding.block_path(&cid);
+        if cid.version() != Version::V0 || target == relative {
+            continue;
+        }
+
+        let target = root.join(target);
+        if target.exists() {
+            fs::remove_file(root.join(&relative))?;
+        } else {
+            fs::create_dir_all(target.parent().unwrap())?;
+            fs::rename(root.join(&relative), &target)?;
+        }
+        remove_empty_parents(root, &relative)?;
+    }
+    Ok(())
+}
+
+// Collects the paths, relative to `root`, of all files under `dir` that aren't dot-prefixed.
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    for entry in fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let relative = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            list_files(root, &relative, files)?;
+        } else {
+            files.push(relative);
+        }
+    }
+    Ok(())
+}
+
+// Removes the directories leading to `relative` that moving it out of left empty.
+fn remove_empty_parents(root: &Path, relative: &Path) -> Result<(), io::Error> {
+    for dir in relative.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() || fs::read_dir(root.join(dir))?.next().is_some() {
+            break;
+        }
+        fs::remove_dir(root.join(dir))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, Codec, Hasher, to_v0};
+    use crate::blockstore::{Blockstore, FSStore};
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_migrate_unversioned_store() {
+        let root = tempdir().unwrap();
+        let block =
+            Block::new_with_codec(b"legacy".to_vec(), Codec::DagPb, Hasher::Sha2_256).unwrap();
+        let v0 = to_v0(&block.cid).unwrap();
+        // How stores used to file blocks put under a CIDv0.
+        let legacy_path = FSStore::block_path_raw(15, &v0);
+        let legacy_dir = root.path().join(legacy_path.iter().next().unwrap());
+        let legacy_path = root.path().join(legacy_path);
+        fs::create_dir_all(legacy_path.parent().unwrap()).unwrap();
+        fs::write(&legacy_path, &block.data).unwrap();
+
+        let result = FSStore::create(root.path().to_path_buf()).await;
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::Unsupported);
+
+        assert_eq!(migrate(root.path().to_path_buf()).await.unwrap(), 0);
+        assert_eq!(
+            migrate(root.path().to_path_buf()).await.unwrap(),
+            CURRENT_VERSION
+        );
+        assert!(!legacy_dir.exists());
+
+        let store = FSStore::create(root.path().to_path_buf()).await.unwrap();
+        assert_eq!(
+            store.get_block(&v0).await.unwrap().unwrap().data,
+            block.data
+        );
+        assert_eq!(store.stats().await.unwrap().blocks, 1);
+        let mut cids = store.blocks();
+        assert_eq!(cids.recv().await.unwrap().unwrap(), block.cid);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_refuse_newer_versions() {
+        let root = tempdir().unwrap();
+        drop(FSStore::create(root.path().to_path_buf()).await.unwrap());
+        assert_eq!(read_version(root.path()).unwrap(), Some(CURRENT_VERSION));
+
+        write_version(root.path(), CURRENT_VERSION + 1).unwrap();
+        let result = FSStore::create(root.path().to_path_buf()).await;
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::Unsupported);
+        let result = FSStore::open_read_only(root.path().to_path_buf()).await;
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::Unsupported);
+        assert!(migrate(root.path().to_path_buf()).await.is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! Mirroring blocks across several equivalent stores, so that losing one of them loses nothing.
+
+use std::collections::HashSet;
+
+use cid::Cid;
+use tokio::sync::mpsc;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+
+/// Writes every block to all of its stores, and reads from whichever has it first, trying them
+/// in order. Writes succeed once the write quorum has them, which by default is every store;
+/// lowering it lets writes carry on with stores down. Reads only fail if every store does.
+///
+/// Stores that missed writes while they were down aren't repaired. Since reads fall through to
+/// the next store, those blocks stay readable for as long as another copy survives. To mirror
+/// stores of different types, box them up as [`DynBlockstore`]s.
+///
+/// [`DynBlockstore`]: crate::dynamic::DynBlockstore
+pub struct MirroredStore<S> {
+    stores: Vec<S>,
+    write_quorum: usize,
+}
+
+impl<S: Blockstore> MirroredStore<S> {
+    /// Mirrors blocks across `stores`, which must not be empty.
+    pub fn new(stores: Vec<S>) -> Self {
+        assert!(!stores.is_empty(), "a mirror needs at least one store");
+        MirroredStore {
+            write_quorum: stores.len(),
+            stores,
+        }
+    }
+
+    /// Considers writes done once `write_quorum` stores have them. Must be between one and the
+    /// number of stores.
+    pub fn with_write_quorum(mut self, write_quorum: usize) -> Self {
+        assert!(
+            (1..=self.stores.len()).contains(&write_quorum),
+            "write quorum must be between 1 and {}",
+            self.stores.len()
+        );
+        self.write_quorum = write_quorum;
+        self
+    }
+
+    pub fn stores(&self) -> &[S] {
+        &self.stores
+    }
+
+    // Whether enough writes went through, failing with the first error otherwise. Every store
+    // either succeeded or failed, so there are errors whenever the quorum isn't met.
+    fn check_quorum(
+        &self,
+        succeeded: usize,
+        mut errors: Vec<BlockstoreError>,
+    ) -> Result<(), BlockstoreError> {
+        if succeeded < self.write_quorum {
+            return Err(errors.swap_remove(0));
+        }
+        Ok(())
+    }
+
+    // Reads miss unless every store failed, in which case they fail with the first error.
+    fn miss<T>(&self, mut errors: Vec<BlockstoreError>) -> Result<Option<T>, BlockstoreError> {
+        if errors.len() == self.stores.len() {
+            return Err(errors.swap_remove(0));
+        }
+        Ok(None)
+    }
+}
+
+impl<S: Blockstore> Blockstore for MirroredStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let mut put = Put::Existing;
+        let mut succeeded = 0;
+        let mut errors = Vec::new();
+        for store in &self.stores {
+            match store.put_block(block).await {
+                Ok(copy_put) => {
+                    put = put.and(copy_put);
+                    succeeded += 1;
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        self.check_quorum(succeeded, errors)?;
+        Ok(put)
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        let mut succeeded = 0;
+        let mut errors = Vec::new();
+        for store in &self.stores {
+            match store.put_many(blocks).await {
+                Ok(()) => succeeded += 1,
+                Err(e) => errors.push(e),
+            }
+        }
+        self.check_quorum(succeeded, errors)
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        for store in &self.stores {
+            if store.has_block(cid).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        let mut errors = Vec::new();
+        for store in &self.stores {
+            match store.get_block(cid).await {
+                Ok(Some(block)) => return Ok(Some(block)),
+                Ok(None) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+        self.miss(errors)
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        // Reads go to the first store unless it doesn't have the block.
+        self.stores[0].prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        let mut errors = Vec::new();
+        for store in &self.stores {
+            match store.block_size(cid).await {
+                Ok(Some(size)) => return Ok(Some(size)),
+                Ok(None) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+        self.miss(errors)
+    }
+
+    /// Deletes the block from every store. Stores that don't have it count towards the quorum,
+    /// but if none of them do, the block is reported missing.
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        let mut found = false;
+        let mut succeeded = 0;
+        let mut errors = Vec::new();
+        for store in &self.stores {
+            match store.del_block(cid).await {
+                Ok(()) => {
+                    found = true;
+                    succeeded += 1;
+                }
+                Err(BlockstoreError::NotFound(_)) => succeeded += 1,
+                Err(e) => errors.push(e),
+            }
+        }
+        self.check_quorum(succeeded, errors)?;
+        if !found {
+            return Err(BlockstoreError::NotFound(*cid));
+        }
+        Ok(())
+    }
+
+    /// Lists the blocks in any of the stores, each once. Stores whose listing fails are skipped,
+    /// unless they all fail.
+    fn blocks(&self) -> CidStream {
+        merge_listings(self.stores.iter().map(Blockstore::blocks).collect())
+    }
+
+    /// The stats of the first store that reports them.
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let mut errors = Vec::new();
+        for store in &self.stores {
+            match store.stats().await {
+                Ok(stats) => return Ok(stats),
+                Err(e) => errors.push(e),
+            }
+        }
+        Err(errors.swap_remove(0))
+    }
+}
+
+// Lists the blocks in any of `streams`, each once. Listings that fail are skipped, unless they
+// all do.
+pub(crate) fn merge_listings(streams: Vec<CidStream>) -> CidStream {
+    let (sender, receiver) = mpsc::channel(1024);
+
+    tokio::spawn(async move {
+        let listings = streams.len();
+        let mut seen = HashSet::new();
+        let mut errors = Vec::new();
+        for mut cids in streams {
+            while let Some(cid) = cids.recv().await {
+                match cid {
+                    Ok(cid) => {
+                        if seen.insert(cid) && sender.send(Ok(cid)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        errors.push(e);
+                        break;
+                    }
+                }
+            }
+        }
+        if !errors.is_empty() && errors.len() == listings {
+            let _ = sender.send(Err(errors.swap_remove(0))).await;
+        }
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use crate::remote::HttpStore;
+
+    async fn make_mirrored_store() -> (MirroredStore<MemStore>, ()) {
+        (
+            MirroredStore::new(vec![MemStore::new(), MemStore::new()]),
+            (),
+        )
+    }
+
+    crate::conformance::conformance_tests!(make_mirrored_store);
+
+    // A mirror whose first store is down: nothing listens on the discard port.
+    fn make_degraded_mirror() -> MirroredStore<Box<dyn crate::dynamic::DynBlockstore>> {
+        MirroredStore::new(vec![
+            Box::new(HttpStore::new("http://127.0.0.1:9").unwrap()),
+            Box::new(MemStore::new()),
+        ])
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_survive_a_store_being_down() {
+        let store = make_degraded_mirror().with_write_quorum(1);
+        let block = make_random_block(100);
+
+        // Only the copy that got written counts.
+        assert_eq!(store.put_block(&block).await.unwrap(), Put::Written { bytes: 100 });
+        assert!(store.has_block(&block.cid).await);
+        assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+        let mut cids = store.blocks();
+        assert_eq!(cids.recv().await.unwrap().unwrap(), block.cid);
+        assert!(cids.recv().await.is_none());
+        assert_eq!(store.stats().await.unwrap().blocks, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_count_every_copy_written() {
+        let store = MirroredStore::new(vec![MemStore::new(), MemStore::new()]);
+        let block = make_random_block(100);
+        store.stores()[0].put_block(&block).await.unwrap();
+
+        assert_eq!(store.put_block(&block).await.unwrap(), Put::Written { bytes: 100 });
+        let other = make_random_block(100);
+        assert_eq!(store.put_block(&other).await.unwrap().bytes_written(), 200);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_fail_writes_without_quorum() {
+        let store = make_degraded_mirror();
+        let block = make_random_block(100);
+
+        assert!(store.put_block(&block).await.is_err());
+        // The store that's up still took the write.
+        assert!(store.stores()[1].has_block(&block.cid).await);
+    }
+}
@@ -0,0 +1,100 @@
+//! Read-only memory maps of block files, for [`crate::blockstore::ReadMode::Mmap`].
+
+use std::io;
+use std::path::Path;
+
+use bytes::Bytes;
+
+/// Maps the file at `path` into memory, returning its contents as [`Bytes`] that unmap it once
+/// the last reference goes away. Block files are never written to once they're in place (puts
+/// go through a temporary file and a rename), so the mapping stays valid even if the block is
+/// deleted or replaced in the meantime.
+#[cfg(unix)]
+pub fn map_file(path: &Path) -> Result<Bytes, io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len() as usize;
+    // mmap refuses empty mappings.
+    if len == 0 {
+        return Ok(Bytes::new());
+    }
+
+    // SAFETY: we map a file we hold open read-only and privately, and only ever read through the
+    // mapping, which `Mmap` unmaps exactly once.
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Bytes::from_owner(Mmap { ptr, len }))
+}
+
+/// Without mmap, we read the file like any other.
+#[cfg(not(unix))]
+pub fn map_file(path: &Path) -> Result<Bytes, io::Error> {
+    std::fs::read(path).map(Bytes::from)
+}
+
+#[cfg(unix)]
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only, so it can be read from any thread.
+#[cfg(unix)]
+unsafe impl Send for Mmap {}
+#[cfg(unix)]
+unsafe impl Sync for Mmap {}
+
+#[cfg(unix)]
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len` mapped, readable bytes for as long as we're alive.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: the mapping came from a successful mmap of this length, and nothing borrows
+        // from it any more.
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn should_map_file_contents() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("block");
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        let mapped = map_file(&path).unwrap();
+        // The mapping outlives the file, and slices share it.
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mapped, data);
+        assert_eq!(mapped.slice(1_000..1_010), data[1_000..1_010]);
+
+        fs::write(&path, b"").unwrap();
+        assert!(map_file(&path).unwrap().is_empty());
+    }
+}
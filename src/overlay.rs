@@ -0,0 +1,280 @@
+use std::collections::HashSet;
+use std::io;
+use std::sync::Mutex;
+
+use cid::Cid;
+use tokio::sync::mpsc;
+
+use crate::block::{Block, is_inline};
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, StoreStats};
+use crate::memstore::MemStore;
+
+/// Stages writes and deletes in a scratch store in front of a base store that's left alone
+/// until [`OverlayStore::commit`]. Reads see the base with the staged changes applied;
+/// [`OverlayStore::discard`] throws the changes away instead.
+///
+/// Deleting a block that's only in the base leaves a tombstone that hides it, kept in memory.
+pub struct OverlayStore<B, L = MemStore> {
+    base: B,
+    scratch: L,
+    tombstones: Mutex<HashSet<Cid>>,
+}
+
+impl<B: Blockstore> OverlayStore<B, MemStore> {
+    /// Stages changes to `base` in memory.
+    pub fn new(base: B) -> Self {
+        Self::with_scratch(base, MemStore::new())
+    }
+}
+
+impl<B: Blockstore, L: Blockstore> OverlayStore<B, L> {
+    /// Stages changes to `base` in `scratch`, which should start out empty: whatever's in it
+    /// counts as staged.
+    pub fn with_scratch(base: B, scratch: L) -> Self {
+        OverlayStore {
+            base,
+            scratch,
+            tombstones: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn base(&self) -> &B {
+        &self.base
+    }
+
+    pub fn scratch(&self) -> &L {
+        &self.scratch
+    }
+
+    /// Applies every staged change to the base, leaving nothing staged. If this fails partway,
+    /// whatever wasn't applied yet stays staged, so it's safe to call again.
+    pub async fn commit(&self) -> Result<(), io::Error> {
+        let mut staged = Vec::new();
+        let mut cids = self.scratch.blocks();
+        while let Some(cid) = cids.recv().await {
+            staged.push(cid?);
+        }
+
+        // Blocks are only dropped from the scratch store once they're safely in the base, so
+        // they stay readable throughout.
+        for cid in staged {
+            let Some(block) = self.scratch.get_block(&cid).await? else {
+                continue;
+            };
+            self.base.put_block(&block).await?;
+            forget(self.scratch.del_block(&cid).await)?;
+        }
+
+        let tombstones: Vec<Cid> = self.tombstones.lock().unwrap().iter().copied().collect();
+        for cid in tombstones {
+            forget(self.base.del_block(&cid).await)?;
+            self.tombstones.lock().unwrap().remove(&cid);
+        }
+
+        Ok(())
+    }
+
+    /// Drops every staged change, leaving the base as it was.
+    pub async fn discard(&self) -> Result<(), io::Error> {
+        let mut staged = Vec::new();
+        let mut cids = self.scratch.blocks();
+        while let Some(cid) = cids.recv().await {
+            staged.push(cid?);
+        }
+
+        for cid in staged {
+            forget(self.scratch.del_block(&cid).await)?;
+        }
+        self.tombstones.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn is_deleted(&self, cid: &Cid) -> bool {
+        self.tombstones.lock().unwrap().contains(cid)
+    }
+}
+
+// Treats deleting a block that's already gone as success.
+fn forget(result: Result<(), BlockstoreError>) -> Result<(), BlockstoreError> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl<B: Blockstore, L: Blockstore> Blockstore for OverlayStore<B, L> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let put = self.scratch.put_block(block).await?;
+        self.tombstones.lock().unwrap().remove(&block.cid);
+        Ok(put)
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        self.scratch.put_many(blocks).await?;
+        let mut tombstones = self.tombstones.lock().unwrap();
+        for block in blocks {
+            tombstones.remove(&block.cid);
+        }
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        !self.is_deleted(cid)
+            && (self.scratch.has_block(cid).await || self.base.has_block(cid).await)
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if self.is_deleted(cid) {
+            return Ok(None);
+        }
+        match self.scratch.get_block(cid).await? {
+            Some(block) => Ok(Some(block)),
+            None => self.base.get_block(cid).await,
+        }
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.scratch.prefetch(cids);
+        self.base.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if self.is_deleted(cid) {
+            return Ok(None);
+        }
+        match self.scratch.block_size(cid).await? {
+            Some(size) => Ok(Some(size)),
+            None => self.base.block_size(cid).await,
+        }
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        // Blocks with identity CIDs can't be hidden: every store has them.
+        if is_inline(cid) {
+            return Ok(());
+        }
+        if !self.is_deleted(cid) {
+            let mut found = self.scratch.del_block_if_present(cid).await?;
+            if self.base.has_block(cid).await {
+                self.tombstones.lock().unwrap().insert(*cid);
+                found = true;
+            }
+            if found {
+                return Ok(());
+            }
+        }
+
+        Err(BlockstoreError::NotFound(*cid))
+    }
+
+    /// Lists the staged blocks, then those of the base that haven't been deleted or staged.
+    fn blocks(&self) -> CidStream {
+        let (sender, receiver) = mpsc::channel(1024);
+        let streams = [self.scratch.blocks(), self.base.blocks()];
+        let mut seen = self.tombstones.lock().unwrap().clone();
+
+        tokio::spawn(async move {
+            for mut cids in streams {
+                while let Some(cid) = cids.recv().await {
+                    if let Ok(cid) = &cid
+                        && !seen.insert(*cid)
+                    {
+                        continue;
+                    }
+                    if sender.send(cid).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        receiver
+    }
+
+    /// Counts blocks and bytes as reads see them, which takes a walk over both stores. Disk
+    /// usage is what both stores take up, deleted blocks included.
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let mut stats = StoreStats::default();
+        let mut cids = self.blocks();
+        while let Some(cid) = cids.recv().await {
+            if let Some(size) = self.block_size(&cid?).await? {
+                stats.blocks += 1;
+                stats.bytes += size;
+            }
+        }
+
+        stats.disk_bytes =
+            self.base.stats().await?.disk_bytes + self.scratch.stats().await?.disk_bytes;
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+
+    async fn make_overlay_store() -> (OverlayStore<MemStore>, ()) {
+        (OverlayStore::new(MemStore::new()), ())
+    }
+
+    crate::conformance::conformance_tests!(make_overlay_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_stage_changes_until_commit() {
+        let base = MemStore::new();
+        let existing = make_random_block(1_000);
+        base.put_block(&existing).await.unwrap();
+        let store = OverlayStore::new(base);
+
+        let added = make_random_block(1_000);
+        store.put_block(&added).await.unwrap();
+        store.del_block(&existing.cid).await.unwrap();
+
+        assert!(store.has_block(&added.cid).await);
+        assert!(!store.has_block(&existing.cid).await);
+        assert_eq!(store.get_block(&existing.cid).await.unwrap(), None);
+        assert!(!store.base().has_block(&added.cid).await);
+        assert!(store.base().has_block(&existing.cid).await);
+
+        store.commit().await.unwrap();
+
+        assert!(store.base().has_block(&added.cid).await);
+        assert!(!store.base().has_block(&existing.cid).await);
+        assert!(store.scratch().is_empty());
+        assert!(store.has_block(&added.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_leave_base_alone_on_discard() {
+        let base = MemStore::new();
+        let existing = make_random_block(1_000);
+        base.put_block(&existing).await.unwrap();
+        let store = OverlayStore::new(base);
+
+        let added = make_random_block(1_000);
+        store.put_block(&added).await.unwrap();
+        store.del_block(&existing.cid).await.unwrap();
+        store.discard().await.unwrap();
+
+        assert!(!store.has_block(&added.cid).await);
+        assert!(store.has_block(&existing.cid).await);
+        assert_eq!(store.base().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_undelete_block_when_put_again() {
+        let base = MemStore::new();
+        let block = make_random_block(1_000);
+        base.put_block(&block).await.unwrap();
+        let store = OverlayStore::new(base);
+
+        store.del_block(&block.cid).await.unwrap();
+        let err = store.del_block(&block.cid).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        store.put_block(&block).await.unwrap();
+        store.commit().await.unwrap();
+        assert!(store.base().has_block(&block.cid).await);
+    }
+}
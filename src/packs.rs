@@ -0,0 +1,522 @@
+//! Pack files, which [`FSStore::compact`] moves small blocks into so that each stops taking up a
+//! filesystem block of its own.
+//!
+//! A pack is a CARv1 file without roots, written once and never changed, with an index of where
+//! each block's data is in it next to it. Packs only count once the manifest lists them, which
+//! it does once they're complete and synced, so compaction getting interrupted leaves nothing
+//! behind but strays for the next one to clean up. Blocks deleted from a pack get listed in its
+//! tombstone file, until compaction finds enough of the pack deleted to rewrite it.
+//!
+//! [`FSStore::compact`]: crate::blockstore::FSStore::compact
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use cid::Cid;
+
+use crate::blockstore::{StoreStats, disk_usage};
+use crate::car::{self, CarIndex};
+use crate::mmap::map_file;
+
+// Lists the packs that count, one ID per line.
+const MANIFEST_FILE: &str = "manifest";
+
+// Packs get blocks added until they're this big, and then compaction starts another one.
+const MAX_PACK_SIZE: u64 = 64 << 20;
+
+// The packs in one store's packs directory, and the index of the blocks in them.
+pub(crate) struct Packs {
+    dir: PathBuf,
+    state: RwLock<State>,
+    // Held for the whole of a compaction, so that only one runs at a time.
+    compacting: Mutex<()>,
+}
+
+#[derive(Default)]
+struct State {
+    packs: BTreeMap<u64, Pack>,
+    index: HashMap<Cid, Location>,
+    // Blocks being copied into packs that aren't installed yet, so that deleting one in the
+    // meantime tombstones its copy too.
+    pending: HashMap<Cid, Pending>,
+    next_id: u64,
+}
+
+struct Pack {
+    data: Bytes,
+    // Bytes of blocks in the pack, dead or alive.
+    block_bytes: u64,
+    // Bytes of blocks in the pack that are deleted, or live on in a newer pack.
+    dead_bytes: u64,
+    // What the pack's files took up on disk when it was loaded or written, which is what gets
+    // taken back off the store's stats when it's removed.
+    disk_bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Location {
+    pack: u64,
+    range: Range<usize>,
+}
+
+#[derive(Clone, Copy)]
+struct Pending {
+    pack: u64,
+    // The pack the block is being moved out of, if it isn't a loose block being packed.
+    from: Option<u64>,
+}
+
+impl Packs {
+    // Loads the packs in `dir`, if there are any, returning them along with the stats of the
+    // blocks they hold. Packs the manifest doesn't list are left alone.
+    pub fn open(dir: PathBuf) -> Result<(Self, StoreStats), io::Error> {
+        let mut state = State::default();
+        let mut stats = StoreStats::default();
+        for id in read_manifest(&dir)? {
+            let paths = PackPaths::new(&dir, id);
+            let data = map_file(&paths.car)?;
+            let entries = car::read_index(&paths.index, data.len())?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("pack {:?} doesn't match its index", paths.car),
+                )
+            })?;
+            let dead = read_tombstones(&paths.tombstones)?;
+
+            let mut pack = Pack {
+                data,
+                block_bytes: block_bytes(&entries),
+                dead_bytes: 0,
+                disk_bytes: paths.disk_usage()?,
+            };
+            for (cid, range) in entries {
+                if dead.contains(&cid) {
+                    pack.dead_bytes += range.len() as u64;
+                    continue;
+                }
+                stats.blocks += 1;
+                stats.bytes += range.len() as u64;
+                state.index.insert(cid, Location { pack: id, range });
+            }
+            stats.disk_bytes += pack.disk_bytes;
+            state.packs.insert(id, pack);
+            state.next_id = id + 1;
+        }
+
+        let packs = Packs {
+            dir,
+            state: RwLock::new(state),
+            compacting: Mutex::new(()),
+        };
+        Ok((packs, stats))
+    }
+
+    pub fn contains(&self, key: &Cid) -> bool {
+        self.state.read().unwrap().index.contains_key(key)
+    }
+
+    // The data of block `key`, if it's packed.
+    pub fn read(&self, key: &Cid) -> Option<Bytes> {
+        let state = self.state.read().unwrap();
+        let location = state.index.get(key)?;
+        Some(
+            state.packs[&location.pack]
+                .data
+                .slice(location.range.clone()),
+        )
+    }
+
+    pub fn size(&self, key: &Cid) -> Option<u64> {
+        let state = self.state.read().unwrap();
+        state
+            .index
+            .get(key)
+            .map(|location| location.range.len() as u64)
+    }
+
+    // When the pack holding block `key` was written, if it's packed.
+    pub fn modified(&self, key: &Cid) -> Option<SystemTime> {
+        let pack = self.state.read().unwrap().index.get(key)?.pack;
+        let metadata = fs::metadata(PackPaths::new(&self.dir, pack).car).ok()?;
+        metadata.modified().ok()
+    }
+
+    pub fn keys(&self) -> Vec<Cid> {
+        self.state.read().unwrap().index.keys().copied().collect()
+    }
+
+    // Deletes block `key` from its pack, and from the pack it's being copied into if any,
+    // tombstoning it in either. Returns its size, if it was packed. The caller holds the block's
+    // write lock.
+    pub fn delete(&self, key: &Cid, sync: bool) -> Result<Option<u64>, io::Error> {
+        let mut state = self.state.write().unwrap();
+        if let Some(pending) = state.pending.remove(key) {
+            self.tombstone(pending.pack, key, sync)?;
+        }
+        let Some(location) = state.index.remove(key) else {
+            return Ok(None);
+        };
+        let size = location.range.len() as u64;
+        if let Err(e) = self.tombstone(location.pack, key, sync) {
+            state.index.insert(*key, location);
+            return Err(e);
+        }
+        state.packs.get_mut(&location.pack).unwrap().dead_bytes += size;
+        Ok(Some(size))
+    }
+
+    fn tombstone(&self, pack: u64, key: &Cid, sync: bool) -> Result<(), io::Error> {
+        let path = PackPaths::new(&self.dir, pack).tombstones;
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(format!("{}\n", key).as_bytes())?;
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Starts a compaction, which has the packs to itself until the returned packer is done.
+    pub fn packer(&self) -> Result<Packer<'_>, io::Error> {
+        let compacting = self.compacting.lock().unwrap();
+        self.remove_strays()?;
+        Ok(Packer {
+            packs: self,
+            _compacting: compacting,
+            writer: None,
+            written: Vec::new(),
+        })
+    }
+
+    // Removes whatever a compaction that never finished left in the packs directory.
+    fn remove_strays(&self) -> Result<(), io::Error> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let state = self.state.read().unwrap();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let id = name
+                .split('.')
+                .next()
+                .and_then(|id| u64::from_str_radix(id, 16).ok());
+            let live = match id {
+                Some(id) => state.packs.contains_key(&id) && !name.ends_with(".tmp"),
+                None => name == MANIFEST_FILE,
+            };
+            if !live {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    // The packs that have at least half of their blocks' bytes dead.
+    pub fn sparse(&self) -> Vec<u64> {
+        let state = self.state.read().unwrap();
+        state
+            .packs
+            .iter()
+            .filter(|(_, pack)| pack.dead_bytes > 0 && pack.dead_bytes * 2 >= pack.block_bytes)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    // The blocks still live in `pack`.
+    pub fn live_blocks(&self, pack: u64) -> Vec<(Cid, Bytes)> {
+        let state = self.state.read().unwrap();
+        let data = &state.packs[&pack].data;
+        state
+            .index
+            .iter()
+            .filter(|(_, location)| location.pack == pack)
+            .map(|(cid, location)| (*cid, data.slice(location.range.clone())))
+            .collect()
+    }
+
+    // Packs the block `key`, which the pack it's being copied into has now, once it's no longer
+    // loose: it's gone from the index if it was deleted while it was being copied. The caller
+    // holds the block's write lock.
+    pub fn install_loose(&self, key: &Cid, location: Location) -> bool {
+        let mut state = self.state.write().unwrap();
+        let pack = location.pack;
+        if state.pending.remove(key).is_none() {
+            state.packs.get_mut(&pack).unwrap().dead_bytes += location.range.len() as u64;
+            return false;
+        }
+        state.index.insert(*key, location);
+        true
+    }
+}
+
+// Writes the packs for a compaction, and then swaps them in.
+pub(crate) struct Packer<'a> {
+    packs: &'a Packs,
+    _compacting: MutexGuard<'a, ()>,
+    writer: Option<PackWriter>,
+    written: Vec<(u64, CarIndex)>,
+}
+
+// What installing a compaction's packs did.
+pub(crate) struct Installed {
+    // The loose blocks in the new packs, for `Packs::install_loose`.
+    pub loose: Vec<(Cid, Location)>,
+    // Disk usage of the packs added and removed.
+    pub added_bytes: u64,
+    pub removed_bytes: u64,
+}
+
+impl Packer<'_> {
+    // Copies block `key` into the pack being written, out of the pack `from` if it's moving
+    // rather than a loose block being packed. A moving block that's no longer in `from` got
+    // deleted in the meantime, and is left out. The caller holds the block's write lock.
+    pub fn add(&mut self, key: &Cid, data: &[u8], from: Option<u64>) -> Result<(), io::Error> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => self.writer.insert(self.start()?),
+        };
+        {
+            let mut state = self.packs.state.write().unwrap();
+            if let Some(from) = from
+                && state
+                    .index
+                    .get(key)
+                    .is_none_or(|location| location.pack != from)
+            {
+                return Ok(());
+            }
+            let pending = Pending {
+                pack: writer.id,
+                from,
+            };
+            state.pending.insert(*key, pending);
+        }
+        writer.append(key, data)?;
+
+        if writer.len >= MAX_PACK_SIZE {
+            let writer = self.writer.take().unwrap();
+            self.written.push(writer.finish()?);
+        }
+        Ok(())
+    }
+
+    fn start(&self) -> Result<PackWriter, io::Error> {
+        let id = {
+            let mut state = self.packs.state.write().unwrap();
+            state.next_id += 1;
+            state.next_id - 1
+        };
+        fs::create_dir_all(&self.packs.dir)?;
+        PackWriter::create(&self.packs.dir, id)
+    }
+
+    // Makes the packs written so far count, in place of the `replaced` ones, where the blocks
+    // moving out of them live on. All of it happens under one write of the manifest, so a crash
+    // leaves either the old packs or the new ones.
+    pub fn install(mut self, replaced: &[u64]) -> Result<Installed, io::Error> {
+        if let Some(writer) = self.writer.take() {
+            self.written.push(writer.finish()?);
+        }
+        let dir = &self.packs.dir;
+        let mut installed = Installed {
+            loose: Vec::new(),
+            added_bytes: 0,
+            removed_bytes: 0,
+        };
+        if self.written.is_empty() && replaced.is_empty() {
+            return Ok(installed);
+        }
+
+        let mut new_packs = Vec::new();
+        for (id, entries) in self.written.drain(..) {
+            let paths = PackPaths::new(dir, id);
+            let pack = Pack {
+                data: map_file(&paths.car)?,
+                block_bytes: block_bytes(&entries),
+                dead_bytes: 0,
+                disk_bytes: paths.disk_usage()?,
+            };
+            new_packs.push((id, pack, entries));
+        }
+        sync_dir(dir)?;
+
+        // Deletes wait for the whole swap, so none of them can tombstone a block in a pack
+        // that's about to stop counting instead of the one taking over.
+        let mut state = self.packs.state.write().unwrap();
+        let mut ids: Vec<u64> = state
+            .packs
+            .keys()
+            .chain(new_packs.iter().map(|(id, _, _)| id))
+            .copied()
+            .filter(|id| !replaced.contains(id))
+            .collect();
+        ids.sort_unstable();
+        write_manifest(dir, &ids)?;
+
+        for (id, mut pack, entries) in new_packs {
+            for (key, range) in entries {
+                let location = Location { pack: id, range };
+                match state.pending.get(&key).copied() {
+                    Some(pending) if pending.pack == id && pending.from.is_some() => {
+                        state.pending.remove(&key);
+                        state.index.insert(key, location);
+                    }
+                    Some(pending) if pending.pack == id => installed.loose.push((key, location)),
+                    _ => pack.dead_bytes += location.range.len() as u64,
+                }
+            }
+            installed.added_bytes += pack.disk_bytes;
+            state.packs.insert(id, pack);
+        }
+        for id in replaced {
+            if let Some(pack) = state.packs.remove(id) {
+                installed.removed_bytes += pack.disk_bytes;
+            }
+        }
+        drop(state);
+
+        // Readers still holding data from the replaced packs keep their mappings.
+        for id in replaced {
+            PackPaths::new(dir, *id).remove()?;
+        }
+        Ok(installed)
+    }
+}
+
+struct PackWriter {
+    id: u64,
+    path: PathBuf,
+    file: BufWriter<File>,
+    len: u64,
+    entries: CarIndex,
+}
+
+impl PackWriter {
+    fn create(dir: &Path, id: u64) -> Result<Self, io::Error> {
+        let path = PackPaths::new(dir, id).car;
+        let mut file = BufWriter::new(File::create(&path)?);
+        let header = car::header_frame(&[]);
+        file.write_all(&header)?;
+        Ok(PackWriter {
+            id,
+            path,
+            file,
+            len: header.len() as u64,
+            entries: Vec::new(),
+        })
+    }
+
+    fn append(&mut self, cid: &Cid, data: &[u8]) -> Result<(), io::Error> {
+        let prefix = car::block_frame_prefix(cid, data.len());
+        self.file.write_all(&prefix)?;
+        self.file.write_all(data)?;
+        let start = (self.len + prefix.len() as u64) as usize;
+        self.entries.push((*cid, start..start + data.len()));
+        self.len = (start + data.len()) as u64;
+        Ok(())
+    }
+
+    // Syncs the pack, then writes its index: a pack with an index is always complete.
+    fn finish(self) -> Result<(u64, CarIndex), io::Error> {
+        self.file
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        let paths = PackPaths::new(self.path.parent().unwrap(), self.id);
+        car::write_index(&paths.index, self.len as usize, &self.entries)?;
+        Ok((self.id, self.entries))
+    }
+}
+
+struct PackPaths {
+    car: PathBuf,
+    index: PathBuf,
+    tombstones: PathBuf,
+}
+
+impl PackPaths {
+    fn new(dir: &Path, id: u64) -> Self {
+        PackPaths {
+            car: dir.join(format!("{:016x}.car", id)),
+            index: dir.join(format!("{:016x}.idx", id)),
+            tombstones: dir.join(format!("{:016x}.dead", id)),
+        }
+    }
+
+    // Tombstones are left out, since they grow with every delete and aren't worth a stat each.
+    fn disk_usage(&self) -> Result<u64, io::Error> {
+        Ok(disk_usage(&fs::metadata(&self.car)?) + disk_usage(&fs::metadata(&self.index)?))
+    }
+
+    // The index goes first, since a pack without one is just a stray.
+    fn remove(&self) -> Result<(), io::Error> {
+        for path in [&self.index, &self.car, &self.tombstones] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn block_bytes(entries: &CarIndex) -> u64 {
+    entries.iter().map(|(_, range)| range.len() as u64).sum()
+}
+
+fn read_manifest(dir: &Path) -> Result<Vec<u64>, io::Error> {
+    let path = dir.join(MANIFEST_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    contents
+        .lines()
+        .map(|line| {
+            u64::from_str_radix(line, 16).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed pack ID {:?} in {:?}", line, path),
+                )
+            })
+        })
+        .collect()
+}
+
+fn write_manifest(dir: &Path, ids: &[u64]) -> Result<(), io::Error> {
+    let path = dir.join(MANIFEST_FILE);
+    let temp_path = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    let contents: String = ids.iter().map(|id| format!("{:016x}\n", id)).collect();
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path)?;
+    sync_dir(dir)
+}
+
+fn sync_dir(dir: &Path) -> Result<(), io::Error> {
+    File::open(dir)?.sync_all()
+}
+
+// A crash can leave the last tombstone half-written, but only for a delete that never returned.
+fn read_tombstones(path: &Path) -> Result<HashSet<Cid>, io::Error> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter_map(|line| Cid::try_from(line).ok())
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cid::Cid;
+use tokio::sync::Mutex;
+
+use crate::block::Block;
+use crate::gc::Fence;
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, FSStore, StoreStats};
+
+/// Name of the pin file inside an [`FSStore`]'s root. Dot-prefixed so it's never taken for a
+/// block.
+pub const PINS_FILE: &str = ".pins";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PinMode {
+    /// Keeps just the pinned block.
+    Direct,
+    /// Keeps the pinned block and everything reachable from it.
+    Recursive,
+}
+
+impl PinMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PinMode::Direct => "direct",
+            PinMode::Recursive => "recursive",
+        }
+    }
+}
+
+/// A pin, as listed by [`PinStore::list_pins`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin {
+    pub cid: Cid,
+    pub mode: PinMode,
+    /// The name it was pinned under with [`PinStore::pin_named`], or `None` for a pin on the
+    /// CID itself.
+    pub name: Option<String>,
+    /// When it was made. Pins from a file that didn't record that show the epoch.
+    pub created: SystemTime,
+    /// Whatever the named pin was given to keep along with it.
+    pub label: Option<Vec<u8>>,
+}
+
+// Everything that's pinned, cheap enough to clone for rolling back a change.
+#[derive(Debug, Clone, Default)]
+struct Pins {
+    // Pins on CIDs themselves, with no names or labels.
+    by_cid: HashMap<Cid, Pin>,
+    named: HashMap<String, Pin>,
+}
+
+/// Wraps a [`Blockstore`] with a record of which blocks are pinned, i.e. must survive garbage
+/// collection. A CID is pinned at most once: pinning it recursively supersedes a direct pin, and
+/// pinning directly something that's already pinned recursively is a no-op.
+///
+/// Pins can also be given names, such as `dataset-v3`, to keep track of what they're for. Every
+/// name pins one CID, which any number of names can share. Those are kept apart from the pins
+/// on CIDs themselves: a CID is pinned as long as any of them is there.
+///
+/// Pins are kept in memory, and optionally persisted to a file that gets rewritten on every
+/// change. That's fine for the hundreds-to-thousands of pins these are meant for.
+pub struct PinStore<S> {
+    store: S,
+    pins: Mutex<Pins>,
+    path: Option<PathBuf>,
+    fence: Fence,
+}
+
+impl<S: Blockstore> PinStore<S> {
+    /// Wraps `store` with pins that only live as long as this instance.
+    pub fn new(store: S) -> Self {
+        PinStore {
+            store,
+            pins: Mutex::new(Pins::default()),
+            path: None,
+            fence: Fence::default(),
+        }
+    }
+
+    /// Wraps `store` with pins persisted to `path`, loading any that are already there.
+    pub async fn open(store: S, path: PathBuf) -> Result<Self, io::Error> {
+        let pins = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => parse_pins(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Pins::default(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(PinStore {
+            store,
+            pins: Mutex::new(pins),
+            path: Some(path),
+            fence: Fence::default(),
+        })
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub(crate) fn fence(&self) -> &Fence {
+        &self.fence
+    }
+
+    pub async fn pin(&self, cid: &Cid, mode: PinMode) -> Result<(), io::Error> {
+        let _fence = self.fence.enter([cid]).await;
+        let mut pins = self.pins.lock().await;
+        let current = pins.by_cid.get(cid).map(|pin| pin.mode);
+        if current >= Some(mode) {
+            return Ok(());
+        }
+
+        let previous = pins.clone();
+        let pin = pins.by_cid.entry(*cid).or_insert_with(|| Pin {
+            cid: *cid,
+            mode,
+            name: None,
+            created: SystemTime::now(),
+            label: None,
+        });
+        pin.mode = mode;
+        self.persist_or_restore(&mut pins, previous).await
+    }
+
+    /// Pins `cid` under `name`, along with `label` if given. A pin that already goes by `name`
+    /// is replaced, which makes for moving a name on to a new version of what it pins. Names
+    /// can't be empty or hold line breaks.
+    pub async fn pin_named(
+        &self,
+        name: &str,
+        cid: &Cid,
+        mode: PinMode,
+        label: Option<Vec<u8>>,
+    ) -> Result<(), io::Error> {
+        if name.is_empty() || name.contains(['\n', '\r']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid pin name {:?}", name),
+            ));
+        }
+
+        let _fence = self.fence.enter([cid]).await;
+        let mut pins = self.pins.lock().await;
+        let previous = pins.clone();
+        let pin = Pin {
+            cid: *cid,
+            mode,
+            name: Some(name.to_string()),
+            created: SystemTime::now(),
+            label,
+        };
+        pins.named.insert(name.to_string(), pin);
+        self.persist_or_restore(&mut pins, previous).await
+    }
+
+    /// Removes the pin on `cid`, whatever its mode. Returns whether there was one. Named pins on
+    /// `cid` are left alone, and only go with [`PinStore::unpin_named`].
+    pub async fn unpin(&self, cid: &Cid) -> Result<bool, io::Error> {
+        let mut pins = self.pins.lock().await;
+        if !pins.by_cid.contains_key(cid) {
+            return Ok(false);
+        }
+
+        let previous = pins.clone();
+        pins.by_cid.remove(cid);
+        self.persist_or_restore(&mut pins, previous).await?;
+        Ok(true)
+    }
+
+    /// Removes the pin named `name`. Returns whether there was one.
+    pub async fn unpin_named(&self, name: &str) -> Result<bool, io::Error> {
+        let mut pins = self.pins.lock().await;
+        if !pins.named.contains_key(name) {
+            return Ok(false);
+        }
+
+        let previous = pins.clone();
+        pins.named.remove(name);
+        self.persist_or_restore(&mut pins, previous).await?;
+        Ok(true)
+    }
+
+    /// Returns how `cid` is pinned, if at all, counting its named pins: a CID pinned both ways
+    /// is pinned recursively. This only knows about pins on `cid` itself, not whether it's
+    /// reachable from some recursively pinned block.
+    pub async fn is_pinned(&self, cid: &Cid) -> Option<PinMode> {
+        let pins = self.pins.lock().await;
+        let named = pins.named.values().filter(|pin| pin.cid == *cid);
+        pins.by_cid
+            .get(cid)
+            .into_iter()
+            .chain(named)
+            .map(|pin| pin.mode)
+            .max()
+    }
+
+    /// Returns the pin named `name`, if there's one.
+    pub async fn named_pin(&self, name: &str) -> Option<Pin> {
+        self.pins.lock().await.named.get(name).cloned()
+    }
+
+    /// Lists every pin, first those on CIDs themselves by CID, and then the named ones by name.
+    pub async fn list_pins(&self) -> Vec<Pin> {
+        let pins = self.pins.lock().await;
+        let mut by_cid: Vec<Pin> = pins.by_cid.values().cloned().collect();
+        by_cid.sort_by_key(|pin| pin.cid);
+        let mut named: Vec<Pin> = pins.named.values().cloned().collect();
+        named.sort_by(|a, b| a.name.cmp(&b.name));
+        by_cid.extend(named);
+        by_cid
+    }
+
+    // Persists `pins` as changed, putting them back to `previous` if that fails.
+    async fn persist_or_restore(&self, pins: &mut Pins, previous: Pins) -> Result<(), io::Error> {
+        if let Err(e) = self.persist(pins).await {
+            *pins = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Rewrites the pin file so it reflects `pins`. We write to a temporary file and rename it
+    // over the old one, so a crash mid-write leaves the previous pin set intact.
+    async fn persist(&self, pins: &Pins) -> Result<(), io::Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut contents = String::new();
+        for pin in pins.by_cid.values() {
+            let created = nanos(pin.created);
+            contents.push_str(&format!("{} {} {}\n", pin.mode.as_str(), pin.cid, created));
+        }
+        for (name, pin) in &pins.named {
+            let label = match &pin.label {
+                Some(label) => label.iter().map(|byte| format!("{:02x}", byte)).collect(),
+                None => "-".to_string(),
+            };
+            contents.push_str(&format!(
+                "named {} {} {} {} {}\n",
+                pin.mode.as_str(),
+                pin.cid,
+                nanos(pin.created),
+                label,
+                name
+            ));
+        }
+
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, contents).await?;
+        tokio::fs::rename(&temp_path, path).await
+    }
+}
+
+impl PinStore<FSStore> {
+    /// Wraps an [`FSStore`], persisting pins in a file inside its root.
+    pub async fn for_fs_store(store: FSStore) -> Result<Self, io::Error> {
+        let path = store.root().join(PINS_FILE);
+        Self::open(store, path).await
+    }
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+// Reads back what `PinStore::persist` wrote. Lines are `<mode> <cid> <created>` for pins on
+// CIDs themselves, without the creation time in files from before those were recorded, and
+// `named <mode> <cid> <created> <label> <name>` for named ones, the label being in hex or `-`
+// for none.
+fn parse_pins(contents: &str) -> Result<Pins, io::Error> {
+    let mut pins = Pins::default();
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed pin entry {:?}", line),
+            )
+        };
+        let mode = |mode| match mode {
+            "direct" => Ok(PinMode::Direct),
+            "recursive" => Ok(PinMode::Recursive),
+            _ => Err(invalid()),
+        };
+        let cid = |cid| Cid::try_from(cid).map_err(|_| invalid());
+        let created = |nanos: &str| {
+            let nanos: u64 = nanos.parse().map_err(|_| invalid())?;
+            Ok::<_, io::Error>(UNIX_EPOCH + Duration::from_nanos(nanos))
+        };
+
+        if let Some(named) = line.strip_prefix("named ") {
+            let fields: Vec<&str> = named.splitn(5, ' ').collect();
+            let [pin_mode, pin_cid, pin_created, label, name] = fields[..] else {
+                return Err(invalid());
+            };
+            let label = match label {
+                "-" => None,
+                hex => Some(parse_hex(hex).ok_or_else(invalid)?),
+            };
+            let pin = Pin {
+                cid: cid(pin_cid)?,
+                mode: mode(pin_mode)?,
+                name: Some(name.to_string()),
+                created: created(pin_created)?,
+                label,
+            };
+            pins.named.insert(name.to_string(), pin);
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (pin_mode, pin_cid, pin_created) = match fields[..] {
+            [pin_mode, pin_cid] => (pin_mode, pin_cid, UNIX_EPOCH),
+            [pin_mode, pin_cid, pin_created] => (pin_mode, pin_cid, created(pin_created)?),
+            _ => return Err(invalid()),
+        };
+        let cid = cid(pin_cid)?;
+        let pin = Pin {
+            cid,
+            mode: mode(pin_mode)?,
+            name: None,
+            created: pin_created,
+            label: None,
+        };
+        pins.by_cid.insert(cid, pin);
+    }
+    Ok(pins)
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl<S: Blockstore> Blockstore for PinStore<S> {
+    /// Puts made while [`PinStore::gc`] runs are kept from it.
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let _fence = self.fence.enter([&block.cid]).await;
+        self.store.put_block(block).await
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        let _fence = self.fence.enter(blocks.iter().map(|block| &block.cid)).await;
+        self.store.put_many(blocks).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        self.store.has_block(cid).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        self.store.get_block(cid).await
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.store.block_size(cid).await
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.store.del_block(cid).await
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.store.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_pin_and_unpin() {
+        let store = PinStore::new(MemStore::new());
+        let block = make_random_block(100);
+
+        assert_eq!(store.is_pinned(&block.cid).await, None);
+        store.pin(&block.cid, PinMode::Direct).await.unwrap();
+        assert_eq!(store.is_pinned(&block.cid).await, Some(PinMode::Direct));
+
+        assert!(store.unpin(&block.cid).await.unwrap());
+        assert!(!store.unpin(&block.cid).await.unwrap());
+        assert_eq!(store.is_pinned(&block.cid).await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_strongest_pin_mode() {
+        let store = PinStore::new(MemStore::new());
+        let block = make_random_block(100);
+
+        store.pin(&block.cid, PinMode::Direct).await.unwrap();
+        store.pin(&block.cid, PinMode::Recursive).await.unwrap();
+        store.pin(&block.cid, PinMode::Direct).await.unwrap();
+
+        let pins = store.list_pins().await;
+        assert_eq!(pins.len(), 1);
+        assert_eq!((pins[0].cid, pins[0].mode), (block.cid, PinMode::Recursive));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_persist_pins_for_fs_store() {
+        let root = tempdir().unwrap();
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(100)).collect();
+
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = PinStore::for_fs_store(store).await.unwrap();
+        store.pin(&blocks[0].cid, PinMode::Direct).await.unwrap();
+        store.pin(&blocks[1].cid, PinMode::Recursive).await.unwrap();
+        store.pin(&blocks[2].cid, PinMode::Direct).await.unwrap();
+        store.unpin(&blocks[2].cid).await.unwrap();
+        let label = Some(b"first cut\n\0".to_vec());
+        let name = "dataset v1";
+        store
+            .pin_named(name, &blocks[2].cid, PinMode::Recursive, label)
+            .await
+            .unwrap();
+        let expected = store.list_pins().await;
+        drop(store);
+
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = PinStore::for_fs_store(store).await.unwrap();
+        assert_eq!(store.list_pins().await, expected);
+        assert_eq!(expected.len(), 3);
+
+        // The pin file mustn't be mistaken for a block.
+        let mut cids = store.blocks();
+        assert!(cids.recv().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_named_pins() {
+        let store = PinStore::new(MemStore::new());
+        let v1 = make_random_block(100).cid;
+        let v2 = make_random_block(100).cid;
+
+        let before = SystemTime::now();
+        store
+            .pin_named("dataset", &v1, PinMode::Recursive, Some(b"v1".to_vec()))
+            .await
+            .unwrap();
+        store.pin(&v1, PinMode::Direct).await.unwrap();
+        // Named pins count towards the mode, and outlive unpinning the CID itself.
+        assert_eq!(store.is_pinned(&v1).await, Some(PinMode::Recursive));
+        assert!(store.unpin(&v1).await.unwrap());
+        assert_eq!(store.is_pinned(&v1).await, Some(PinMode::Recursive));
+        let pin = store.named_pin("dataset").await.unwrap();
+        assert_eq!(pin.cid, v1);
+        assert_eq!(pin.label.as_deref(), Some(&b"v1"[..]));
+        assert!(pin.created >= before);
+
+        // Pinning under the same name again moves the name on.
+        store
+            .pin_named("dataset", &v2, PinMode::Direct, None)
+            .await
+            .unwrap();
+        store
+            .pin_named("backup", &v2, PinMode::Recursive, None)
+            .await
+            .unwrap();
+        assert_eq!(store.is_pinned(&v1).await, None);
+        assert_eq!(store.is_pinned(&v2).await, Some(PinMode::Recursive));
+        let names: Vec<Option<String>> = store
+            .list_pins()
+            .await
+            .into_iter()
+            .map(|pin| pin.name)
+            .collect();
+        assert_eq!(
+            names,
+            [Some("backup".to_string()), Some("dataset".to_string())]
+        );
+
+        assert!(store.unpin_named("backup").await.unwrap());
+        assert!(!store.unpin_named("backup").await.unwrap());
+        assert_eq!(store.is_pinned(&v2).await, Some(PinMode::Direct));
+        let err = store
+            .pin_named("two\nlines", &v1, PinMode::Direct, None)
+            .await;
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn should_read_pin_files_without_creation_times() {
+        let cid = make_random_block(100).cid;
+        let pins = parse_pins(&format!("recursive {}\n", cid)).unwrap();
+        assert_eq!(pins.by_cid[&cid].mode, PinMode::Recursive);
+        assert_eq!(pins.by_cid[&cid].created, UNIX_EPOCH);
+        assert!(parse_pins(&format!("named direct {} 0 xyz name\n", cid)).is_err());
+    }
+}
@@ -0,0 +1,786 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use cid::Cid;
+
+use crate::access::AccessTracker;
+use crate::block::{Block, to_v1};
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, StoreStats};
+
+/// Decides which block to evict when a [`QuotaStore`] runs out of room. The store tells the
+/// policy about every block that comes, goes, or gets read, and asks it for victims.
+pub trait EvictionPolicy: Send {
+    fn on_insert(&mut self, cid: &Cid, size: u64);
+    fn on_access(&mut self, cid: &Cid);
+    fn on_remove(&mut self, cid: &Cid);
+    /// Picks the next block to evict, and forgets about it. Returns `None` if there's nothing
+    /// left to evict.
+    fn victim(&mut self) -> Option<Cid>;
+}
+
+/// Evicts the least recently put or read block first.
+#[derive(Default)]
+pub struct Lru {
+    ticks: HashMap<Cid, u64>,
+    by_tick: BTreeMap<u64, Cid>,
+    next_tick: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, cid: &Cid) {
+        if let Some(tick) = self.ticks.remove(cid) {
+            self.by_tick.remove(&tick);
+        }
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.ticks.insert(*cid, tick);
+        self.by_tick.insert(tick, *cid);
+    }
+}
+
+impl EvictionPolicy for Lru {
+    fn on_insert(&mut self, cid: &Cid, _size: u64) {
+        self.touch(cid);
+    }
+
+    fn on_access(&mut self, cid: &Cid) {
+        if self.ticks.contains_key(cid) {
+            self.touch(cid);
+        }
+    }
+
+    fn on_remove(&mut self, cid: &Cid) {
+        if let Some(tick) = self.ticks.remove(cid) {
+            self.by_tick.remove(&tick);
+        }
+    }
+
+    fn victim(&mut self) -> Option<Cid> {
+        let (_, cid) = self.by_tick.pop_first()?;
+        self.ticks.remove(&cid);
+        Some(cid)
+    }
+}
+
+/// Evicts the way S3-FIFO does, so that a one-off pass over many blocks (say, exporting a whole
+/// DAG) doesn't flush out the ones that keep getting read, as it would with [`Lru`].
+///
+/// New blocks go into a small queue, and are the first to be evicted unless they get read again
+/// while in it, in which case they move on to the main queue instead. That one is evicted from
+/// in the order blocks got there, except that every read since buys a block another trip
+/// through it, up to three. Blocks evicted from the small queue are remembered for a while, and
+/// go straight into the main one if they come back.
+#[derive(Default)]
+pub struct S3Fifo {
+    entries: HashMap<Cid, Entry>,
+    small: Queue,
+    main: Queue,
+    ghosts: HashMap<Cid, u64>,
+    ghosts_by_tick: BTreeMap<u64, Cid>,
+    next_tick: u64,
+}
+
+struct Entry {
+    main: bool,
+    tick: u64,
+    size: u64,
+    reads: u8,
+}
+
+#[derive(Default)]
+struct Queue {
+    by_tick: BTreeMap<u64, Cid>,
+    bytes: u64,
+}
+
+// How many reads a block in the main queue can bank.
+const MAX_READS: u8 = 3;
+
+impl S3Fifo {
+    fn tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+
+    fn queue(&mut self, main: bool) -> &mut Queue {
+        if main { &mut self.main } else { &mut self.small }
+    }
+
+    // Puts `cid` at the back of the queue `entry` says.
+    fn enqueue(&mut self, cid: Cid, mut entry: Entry) {
+        entry.tick = self.tick();
+        let queue = self.queue(entry.main);
+        queue.by_tick.insert(entry.tick, cid);
+        queue.bytes += entry.size;
+        self.entries.insert(cid, entry);
+    }
+
+    fn dequeue(&mut self, cid: &Cid) -> Option<Entry> {
+        let entry = self.entries.remove(cid)?;
+        let queue = self.queue(entry.main);
+        queue.by_tick.remove(&entry.tick);
+        queue.bytes -= entry.size;
+        Some(entry)
+    }
+
+    // Remembers an evicted block, forgetting the oldest ones so there are never more ghosts
+    // than blocks.
+    fn haunt(&mut self, cid: Cid) {
+        let tick = self.tick();
+        self.ghosts.insert(cid, tick);
+        self.ghosts_by_tick.insert(tick, cid);
+        while self.ghosts.len() > self.entries.len() {
+            let Some((_, ghost)) = self.ghosts_by_tick.pop_first() else {
+                break;
+            };
+            self.ghosts.remove(&ghost);
+        }
+    }
+}
+
+impl EvictionPolicy for S3Fifo {
+    fn on_insert(&mut self, cid: &Cid, size: u64) {
+        self.dequeue(cid);
+        let main = match self.ghosts.remove(cid) {
+            Some(tick) => {
+                self.ghosts_by_tick.remove(&tick);
+                true
+            }
+            None => false,
+        };
+        let entry = Entry {
+            main,
+            tick: 0,
+            size,
+            reads: 0,
+        };
+        self.enqueue(*cid, entry);
+    }
+
+    fn on_access(&mut self, cid: &Cid) {
+        if let Some(entry) = self.entries.get_mut(cid) {
+            entry.reads = (entry.reads + 1).min(MAX_READS);
+        }
+    }
+
+    fn on_remove(&mut self, cid: &Cid) {
+        self.dequeue(cid);
+    }
+
+    fn victim(&mut self) -> Option<Cid> {
+        loop {
+            // The small queue gets about a tenth of the room.
+            let total = self.small.bytes + self.main.bytes;
+            let from_small = self.main.by_tick.is_empty() || self.small.bytes * 10 > total;
+            let (_, &cid) = self.queue(!from_small).by_tick.first_key_value()?;
+            let mut entry = self.dequeue(&cid).unwrap();
+            if entry.reads == 0 {
+                if from_small {
+                    self.haunt(cid);
+                }
+                return Some(cid);
+            }
+
+            if from_small {
+                entry.main = true;
+                entry.reads = 0;
+            } else {
+                entry.reads -= 1;
+            }
+            self.enqueue(cid, entry);
+        }
+    }
+}
+
+/// Wraps a [`Blockstore`], capping the total bytes it holds. When a put would go over the cap,
+/// blocks chosen by the [`EvictionPolicy`] get deleted until the new one fits.
+///
+/// The quota only knows about blocks that were in the store when it was wrapped, or that went
+/// through the wrapper since; anything written to the underlying store directly goes unnoticed.
+///
+/// If the store runs out of disk space before the quota is reached, failing a put with
+/// [`BlockstoreError::OutOfSpace`], just enough blocks get evicted to make up for the shortfall,
+/// and the put is tried once more.
+pub struct QuotaStore<S, P = Lru> {
+    store: S,
+    max_bytes: u64,
+    state: Mutex<State<P>>,
+    misses: Option<Mutex<Misses>>,
+    tracker: Option<Arc<AccessTracker>>,
+}
+
+// Blocks are known by their CIDv1 here, as with the misses, so that putting a block under its
+// CIDv0 and then its CIDv1 only counts it once.
+struct State<P> {
+    policy: P,
+    sizes: HashMap<Cid, u64>,
+    used: u64,
+}
+
+// The CIDs the store was last found not to have, oldest first.
+struct Misses {
+    capacity: usize,
+    cids: HashSet<Cid>,
+    // Can still hold CIDs that have since been forgotten, but never more than `capacity`.
+    order: VecDeque<Cid>,
+    // Bumped by every put, so that a miss that raced with one doesn't get remembered.
+    generation: u64,
+}
+
+impl<S: Blockstore> QuotaStore<S, Lru> {
+    pub async fn lru(store: S, max_bytes: u64) -> Result<Self, io::Error> {
+        Self::new(store, max_bytes, Lru::default()).await
+    }
+}
+
+impl<S: Blockstore> QuotaStore<S, S3Fifo> {
+    pub async fn s3_fifo(store: S, max_bytes: u64) -> Result<Self, io::Error> {
+        Self::new(store, max_bytes, S3Fifo::default()).await
+    }
+}
+
+impl<S: Blockstore, P: EvictionPolicy> QuotaStore<S, P> {
+    /// Wraps `store`, first going through the blocks already in it so they count against the
+    /// quota. Those are handed to the policy in whatever order the store lists them.
+    pub async fn new(store: S, max_bytes: u64, mut policy: P) -> Result<Self, io::Error> {
+        let mut sizes = HashMap::new();
+        let mut used = 0;
+        let mut cids = store.blocks();
+        while let Some(cid) = cids.recv().await {
+            let cid = cid?;
+            let key = to_v1(&cid);
+            if sizes.contains_key(&key) {
+                continue;
+            }
+            if let Some(size) = store.block_size(&cid).await? {
+                policy.on_insert(&key, size);
+                sizes.insert(key, size);
+                used += size;
+            }
+        }
+
+        Ok(QuotaStore {
+            store,
+            max_bytes,
+            state: Mutex::new(State {
+                policy,
+                sizes,
+                used,
+            }),
+            misses: None,
+            tracker: None,
+        })
+    }
+
+    /// Remembers up to `capacity` CIDs the store was found not to have, so that asking for them
+    /// again doesn't go all the way to it. Putting a block through the wrapper takes it off the
+    /// list; one written to the underlying store directly stays hidden until it falls off.
+    pub fn with_negative_cache(mut self, capacity: usize) -> Self {
+        self.misses = Some(Mutex::new(Misses {
+            capacity,
+            cids: HashSet::new(),
+            order: VecDeque::new(),
+            generation: 0,
+        }));
+        self
+    }
+
+    /// Records the blocks put, read and evicted through the wrapper in `tracker`. The blocks it
+    /// already knows about are first handed to the policy as accessed, least recently accessed
+    /// first, so that eviction takes up where it left off before a restart rather than going
+    /// by the order the store lists blocks in.
+    pub fn with_access_tracker(mut self, tracker: Arc<AccessTracker>) -> Self {
+        let state = self.state.get_mut().unwrap();
+        for (cid, _) in tracker.by_last_access() {
+            state.policy.on_access(&to_v1(&cid));
+        }
+        self.tracker = Some(tracker);
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Bytes currently counted against the quota.
+    pub fn used(&self) -> u64 {
+        self.state.lock().unwrap().used
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    // Returns `None` if `cid` is known to be missing, and otherwise the generation to pass to
+    // `remember_miss` if it turns out to be.
+    fn check_misses(&self, cid: &Cid) -> Option<u64> {
+        let Some(misses) = &self.misses else {
+            return Some(0);
+        };
+        let misses = misses.lock().unwrap();
+        (!misses.cids.contains(&to_v1(cid))).then_some(misses.generation)
+    }
+
+    fn remember_miss(&self, cid: &Cid, generation: u64) {
+        let Some(misses) = &self.misses else {
+            return;
+        };
+        let key = to_v1(cid);
+        let mut misses = misses.lock().unwrap();
+        if misses.generation != generation || misses.capacity == 0 || !misses.cids.insert(key) {
+            return;
+        }
+        misses.order.push_back(key);
+        while misses.order.len() > misses.capacity {
+            let oldest = misses.order.pop_front().unwrap();
+            misses.cids.remove(&oldest);
+        }
+    }
+
+    fn forget_miss(&self, cid: &Cid) {
+        if let Some(misses) = &self.misses {
+            let mut misses = misses.lock().unwrap();
+            misses.cids.remove(&to_v1(cid));
+            misses.generation += 1;
+        }
+    }
+
+    fn record_access(&self, cid: &Cid) {
+        if let Some(tracker) = &self.tracker {
+            tracker.record(cid);
+        }
+    }
+
+    // Deletes blocks taken off the books, with a block that's already gone counting as deleted.
+    async fn delete_victims(&self, victims: &[Cid]) -> Result<(), BlockstoreError> {
+        for victim in victims {
+            self.store.del_block_if_present(victim).await?;
+            if let Some(tracker) = &self.tracker {
+                tracker.forget(victim);
+            }
+        }
+        Ok(())
+    }
+
+    async fn put(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let key = to_v1(&block.cid);
+        let size = block.data.len() as u64;
+        if size > self.max_bytes {
+            return Err(BlockstoreError::QuotaExceeded {
+                size,
+                max_bytes: self.max_bytes,
+            });
+        }
+
+        // Pick victims and reserve room for the new block in one go, so that concurrent puts
+        // can't both claim the same free space. The actual I/O happens without the lock.
+        let victims = {
+            let mut state = self.state.lock().unwrap();
+            if state.sizes.contains_key(&key) {
+                state.policy.on_access(&key);
+                None
+            } else {
+                Some(Self::reserve(&mut state, &key, size, self.max_bytes))
+            }
+        };
+        let Some(victims) = victims else {
+            return self.put_evicting(block).await;
+        };
+
+        let result = async {
+            self.delete_victims(&victims).await?;
+            self.put_evicting(block).await
+        }
+        .await;
+
+        if result.is_err() {
+            let mut state = self.state.lock().unwrap();
+            if state.sizes.remove(&key).is_some() {
+                state.policy.on_remove(&key);
+                state.used -= size;
+            }
+        }
+        result
+    }
+
+    // Puts `block` in the store, and if the disk under it is too full for that, evicts blocks
+    // to make up the difference and tries once more.
+    async fn put_evicting(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let (needed, available) = match self.store.put_block(block).await {
+            Err(BlockstoreError::OutOfSpace { needed, available }) => (needed, available),
+            result => return result,
+        };
+        let victims = self.evict(&to_v1(&block.cid), needed.saturating_sub(available));
+        if victims.is_empty() {
+            return Err(BlockstoreError::OutOfSpace { needed, available });
+        }
+        self.delete_victims(&victims).await?;
+        self.store.put_block(block).await
+    }
+
+    // Takes blocks other than `cid`, a CIDv1, off the books until they add up to `bytes`,
+    // returning them for deleting. Nothing is evicted if all of them together don't add up to
+    // that.
+    fn evict(&self, cid: &Cid, bytes: u64) -> Vec<Cid> {
+        let mut state = self.state.lock().unwrap();
+        let own = state.sizes.get(cid).copied().unwrap_or(0);
+        if state.used - own < bytes {
+            return Vec::new();
+        }
+        let mut victims = Vec::new();
+        let mut freed = 0;
+        while freed < bytes {
+            let Some(victim) = state.policy.victim() else {
+                break;
+            };
+            if victim == *cid {
+                // The block being put goes back to the policy, and nothing after it is evicted.
+                if let Some(&size) = state.sizes.get(cid) {
+                    state.policy.on_insert(cid, size);
+                }
+                break;
+            }
+            let size = state.sizes.remove(&victim).unwrap_or(0);
+            state.used -= size;
+            freed += size;
+            victims.push(victim);
+        }
+        victims
+    }
+
+    // Accounts for a new block, returning the blocks that must go to make room for it.
+    fn reserve(state: &mut State<P>, cid: &Cid, size: u64, max_bytes: u64) -> Vec<Cid> {
+        let mut victims = Vec::new();
+        while state.used + size > max_bytes {
+            // Can't run dry: we've checked that the block fits in an empty store.
+            let victim = state.policy.victim().unwrap();
+            state.used -= state.sizes.remove(&victim).unwrap_or(0);
+            victims.push(victim);
+        }
+
+        state.policy.on_insert(cid, size);
+        state.sizes.insert(*cid, size);
+        state.used += size;
+        victims
+    }
+}
+
+impl<S: Blockstore, P: EvictionPolicy> Blockstore for QuotaStore<S, P> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        // Only once the put is done, so that no read can find the block missing after this.
+        let result = self.put(block).await;
+        self.forget_miss(&block.cid);
+        if result.is_ok() {
+            self.record_access(&block.cid);
+        }
+        result
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        let Some(generation) = self.check_misses(cid) else {
+            return false;
+        };
+        let has = self.store.has_block(cid).await;
+        if !has {
+            self.remember_miss(cid, generation);
+        }
+        has
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        let Some(generation) = self.check_misses(cid) else {
+            return Ok(None);
+        };
+        let block = self.store.get_block(cid).await?;
+        match &block {
+            Some(_) => {
+                self.state.lock().unwrap().policy.on_access(&to_v1(cid));
+                self.record_access(cid);
+            }
+            None => self.remember_miss(cid, generation),
+        }
+        Ok(block)
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        let Some(generation) = self.check_misses(cid) else {
+            return Ok(None);
+        };
+        let size = self.store.block_size(cid).await?;
+        if size.is_none() {
+            self.remember_miss(cid, generation);
+        }
+        Ok(size)
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.store.del_block(cid).await?;
+        if let Some(tracker) = &self.tracker {
+            tracker.forget(cid);
+        }
+
+        let key = to_v1(cid);
+        let mut state = self.state.lock().unwrap();
+        if let Some(size) = state.sizes.remove(&key) {
+            state.policy.on_remove(&key);
+            state.used -= size;
+        }
+        Ok(())
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.store.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Codec, Hasher, make_random_block, to_v0};
+    use crate::memstore::MemStore;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_evict_least_recently_used_block() {
+        let store = QuotaStore::lru(MemStore::new(), 3_000).await.unwrap();
+        let blocks: Vec<Block> = (0..4).map(|_| make_random_block(1_000)).collect();
+
+        for block in &blocks[..3] {
+            store.put_block(block).await.unwrap();
+        }
+        // Reading the oldest block makes the second one the least recently used.
+        store.get_block(&blocks[0].cid).await.unwrap();
+        store.put_block(&blocks[3]).await.unwrap();
+
+        assert!(store.has_block(&blocks[0].cid).await);
+        assert!(!store.has_block(&blocks[1].cid).await);
+        assert!(store.has_block(&blocks[2].cid).await);
+        assert!(store.has_block(&blocks[3].cid).await);
+        assert_eq!(store.used(), 3_000);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_evict_by_tracked_accesses() {
+        let inner = MemStore::new();
+        let blocks: Vec<Block> = (0..4).map(|_| make_random_block(1_000)).collect();
+        inner.put_many(&blocks[..3]).await.unwrap();
+        // As if from before a restart: the last block was put longest ago, then the others.
+        let tracker = Arc::new(AccessTracker::new());
+        for block in [&blocks[2], &blocks[0], &blocks[1]] {
+            tracker.record(&block.cid);
+        }
+
+        let store = QuotaStore::lru(inner, 3_000)
+            .await
+            .unwrap()
+            .with_access_tracker(tracker.clone());
+        store.put_block(&blocks[3]).await.unwrap();
+
+        assert!(!store.has_block(&blocks[2].cid).await);
+        assert!(store.has_block(&blocks[0].cid).await);
+        assert_eq!(tracker.access_info(&blocks[2].cid), None);
+        assert_eq!(tracker.access_info(&blocks[3].cid).unwrap().count, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_hot_blocks_through_scans() {
+        let hot: Vec<Block> = (0..5).map(|_| make_random_block(100)).collect();
+        let scan: Vec<Block> = (0..20).map(|_| make_random_block(100)).collect();
+        let lru = QuotaStore::lru(MemStore::new(), 1_000).await.unwrap();
+        let s3_fifo = QuotaStore::s3_fifo(MemStore::new(), 1_000).await.unwrap();
+
+        for block in &hot {
+            lru.put_block(block).await.unwrap();
+            s3_fifo.put_block(block).await.unwrap();
+            lru.get_block(&block.cid).await.unwrap();
+            s3_fifo.get_block(&block.cid).await.unwrap();
+        }
+        for block in &scan {
+            lru.put_block(block).await.unwrap();
+            s3_fifo.put_block(block).await.unwrap();
+        }
+
+        for block in &hot {
+            assert!(!lru.has_block(&block.cid).await);
+            assert!(s3_fifo.has_block(&block.cid).await);
+        }
+        assert_eq!(s3_fifo.used(), 1_000);
+        assert_eq!(s3_fifo.store().len(), 10);
+
+        // A scanned block that comes back soon after being evicted is taken to be hot too.
+        let comeback = &scan[14];
+        assert!(!s3_fifo.has_block(&comeback.cid).await);
+        s3_fifo.put_block(comeback).await.unwrap();
+        for _ in 0..20 {
+            s3_fifo.put_block(&make_random_block(100)).await.unwrap();
+        }
+        assert!(s3_fifo.has_block(&comeback.cid).await);
+        for block in &hot {
+            assert!(s3_fifo.has_block(&block.cid).await);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_remember_misses_until_put() {
+        let store = QuotaStore::lru(MemStore::new(), 10_000)
+            .await
+            .unwrap()
+            .with_negative_cache(2);
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(100)).collect();
+
+        // Once known to be missing, blocks stay that way even if they turn up underneath.
+        assert!(!store.has_block(&blocks[0].cid).await);
+        assert_eq!(store.get_block(&blocks[1].cid).await.unwrap(), None);
+        store.store().put_many(&blocks[..2]).await.unwrap();
+        assert!(!store.has_block(&blocks[0].cid).await);
+        assert_eq!(store.block_size(&blocks[1].cid).await.unwrap(), None);
+
+        // Until they're put through the wrapper.
+        store.put_block(&blocks[0]).await.unwrap();
+        assert!(store.has_block(&blocks[0].cid).await);
+
+        // Or get pushed out by newer misses.
+        assert!(!store.has_block(&blocks[2].cid).await);
+        assert!(!store.has_block(&make_random_block(100).cid).await);
+        assert!(store.has_block(&blocks[1].cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_count_existing_blocks() {
+        let inner = MemStore::new();
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+        inner.put_many(&blocks[..2]).await.unwrap();
+
+        let store = QuotaStore::lru(inner, 2_000).await.unwrap();
+        assert_eq!(store.used(), 2_000);
+
+        store.put_block(&blocks[2]).await.unwrap();
+        assert_eq!(store.store().len(), 2);
+        assert_eq!(store.used(), 2_000);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_count_v0_and_v1_once() {
+        let store = QuotaStore::lru(MemStore::new(), 10_000)
+            .await
+            .unwrap()
+            .with_negative_cache(10);
+        let block =
+            Block::new_with_codec(vec![7; 1_000], Codec::DagPb, Hasher::Sha2_256).unwrap();
+        let v0 = Block {
+            cid: to_v0(&block.cid).unwrap(),
+            data: block.data.clone(),
+        };
+
+        // A miss under one CID is taken back by a put under the other.
+        assert!(!store.has_block(&v0.cid).await);
+        store.put_block(&block).await.unwrap();
+        assert!(store.has_block(&v0.cid).await);
+
+        store.put_block(&v0).await.unwrap();
+        assert_eq!(store.used(), 1_000);
+        store.del_block(&v0.cid).await.unwrap();
+        assert_eq!(store.used(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_reject_block_larger_than_quota() {
+        let store = QuotaStore::lru(MemStore::new(), 500).await.unwrap();
+        let block = make_random_block(1_000);
+
+        let err = store.put_block(&block).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(matches!(
+            err,
+            BlockstoreError::QuotaExceeded {
+                size: 1_000,
+                max_bytes: 500
+            }
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_release_quota_on_delete() {
+        let store = QuotaStore::lru(MemStore::new(), 2_000).await.unwrap();
+        let block = make_random_block(1_000);
+
+        store.put_block(&block).await.unwrap();
+        store.put_block(&block).await.unwrap();
+        assert_eq!(store.used(), 1_000);
+
+        store.del_block(&block.cid).await.unwrap();
+        assert_eq!(store.used(), 0);
+    }
+
+    // A store on a disk of `disk_bytes`, which runs out of space before any quota does.
+    struct SmallDisk {
+        store: MemStore,
+        disk_bytes: u64,
+    }
+
+    impl Blockstore for SmallDisk {
+        async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+            let used = self.store.stats().await?.bytes;
+            let needed = block.data.len() as u64;
+            if !self.store.has_block(&block.cid).await && used + needed > self.disk_bytes {
+                let available = self.disk_bytes - used;
+                return Err(BlockstoreError::OutOfSpace { needed, available });
+            }
+            self.store.put_block(block).await
+        }
+
+        async fn has_block(&self, cid: &Cid) -> bool {
+            self.store.has_block(cid).await
+        }
+
+        async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+            self.store.get_block(cid).await
+        }
+
+        async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+            self.store.del_block(cid).await
+        }
+
+        fn blocks(&self) -> CidStream {
+            self.store.blocks()
+        }
+
+        async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+            self.store.stats().await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_evict_when_disk_fills_up() {
+        let disk = SmallDisk {
+            store: MemStore::new(),
+            disk_bytes: 2_500,
+        };
+        let store = QuotaStore::lru(disk, 10_000).await.unwrap();
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+
+        store.put_block(&blocks[0]).await.unwrap();
+        store.put_block(&blocks[1]).await.unwrap();
+        // The quota has room for the third block, but the disk only does once one goes.
+        store.put_block(&blocks[2]).await.unwrap();
+        assert!(!store.has_block(&blocks[0].cid).await);
+        assert!(store.has_block(&blocks[1].cid).await);
+        assert!(store.has_block(&blocks[2].cid).await);
+        assert_eq!(store.used(), 2_000);
+
+        // A block the disk can't take however much gets evicted fails straight away, without
+        // evicting anything.
+        let err = store.put_block(&make_random_block(3_000)).await.unwrap_err();
+        assert!(matches!(err, BlockstoreError::OutOfSpace { .. }));
+        assert_eq!(store.used(), 2_000);
+        assert!(store.has_block(&blocks[1].cid).await);
+    }
+}
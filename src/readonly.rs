@@ -0,0 +1,126 @@
+
+use cid::Cid;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, StoreStats};
+
+/// Wraps a [`Blockstore`] so that nothing can modify it through the wrapper: reads are
+/// forwarded, while puts and deletes fail with [`BlockstoreError::ReadOnly`].
+pub struct ReadOnlyStore<S> {
+    store: S,
+}
+
+impl<S: Blockstore> ReadOnlyStore<S> {
+    pub fn new(store: S) -> Self {
+        ReadOnlyStore { store }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+impl<S: Blockstore> Blockstore for ReadOnlyStore<S> {
+    async fn put_block(&self, _block: &Block) -> Result<Put, BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    async fn put_many(&self, _blocks: &[Block]) -> Result<(), BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        self.store.has_block(cid).await
+    }
+
+    async fn has_many(&self, cids: &[Cid]) -> Vec<bool> {
+        self.store.has_many(cids).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        self.store.get_block(cid).await
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, BlockstoreError> {
+        self.store.get_many(cids).await
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.store.block_size(cid).await
+    }
+
+    async fn del_block(&self, _cid: &Cid) -> Result<(), BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    async fn del_many(&self, _cids: &[Cid]) -> Result<(), BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.store.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::block::make_random_block;
+    use crate::blockstore::FSStore;
+    use crate::memstore::MemStore;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_reject_writes() {
+        let inner = MemStore::new();
+        let block = make_random_block(100);
+        inner.put_block(&block).await.unwrap();
+        let store = ReadOnlyStore::new(inner);
+
+        for err in [
+            store.put_block(&make_random_block(100)).await.unwrap_err(),
+            store.del_block(&block.cid).await.unwrap_err(),
+        ] {
+            assert_eq!(err.kind(), io::ErrorKind::ReadOnlyFilesystem);
+            assert!(matches!(err, BlockstoreError::ReadOnly));
+        }
+        assert_eq!(store.store().len(), 1);
+        assert!(store.has_block(&block.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_open_existing_fs_store_read_only() {
+        let root = tempdir().unwrap();
+        let block = make_random_block(100);
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        store.put_block(&block).await.unwrap();
+        drop(store);
+
+        let store = FSStore::open_read_only(PathBuf::from(root.path()))
+            .await
+            .unwrap();
+        let retrieved = store.get_block(&block.cid).await.unwrap().unwrap();
+        assert_eq!(retrieved.data, block.data);
+        assert_eq!(store.stats().await.unwrap().blocks, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_not_create_missing_root_when_read_only() {
+        let parent = tempdir().unwrap();
+        let root = parent.path().join("missing");
+
+        let err = FSStore::open_read_only(root.clone()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!root.exists());
+    }
+}
@@ -0,0 +1,181 @@
+//! Reed–Solomon erasure coding over GF(2^8), for [`crate::erasure::ErasureStore`].
+//!
+//! The code is systematic: the data shards are kept as they are, and the parity shards come from
+//! a Cauchy matrix. Every square submatrix of a Cauchy matrix is invertible, so any `data_shards`
+//! of the shards are enough to get the data back.
+
+// Exponentials (doubled up, so that sums of two logarithms can index it directly) and
+// logarithms of the field with the polynomial x^8 + x^4 + x^3 + x^2 + 1 and generator 2.
+const TABLES: ([u8; 512], [u8; 256]) = tables();
+const EXP: [u8; 512] = TABLES.0;
+const LOG: [u8; 256] = TABLES.1;
+
+const fn tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0; 512];
+    let mut log = [0; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    (exp, log)
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+}
+
+fn inv(a: u8) -> u8 {
+    debug_assert!(a != 0, "zero has no inverse");
+    EXP[255 - LOG[a as usize] as usize]
+}
+
+pub(crate) struct ReedSolomon {
+    data_shards: usize,
+    // Row `i` holds the coefficients parity shard `i` is computed with.
+    parity_rows: Vec<Vec<u8>>,
+}
+
+impl ReedSolomon {
+    /// There can be at most 256 shards in all.
+    pub fn new(data_shards: usize, parity_shards: usize) -> Self {
+        assert!(data_shards > 0 && data_shards + parity_shards <= 256);
+        let parity_rows = (0..parity_shards)
+            .map(|i| {
+                (0..data_shards)
+                    .map(|j| inv((data_shards + i) as u8 ^ j as u8))
+                    .collect()
+            })
+            .collect();
+        ReedSolomon {
+            data_shards,
+            parity_rows,
+        }
+    }
+
+    /// Computes the parity shards for `data`, which must be `data_shards` shards of the same
+    /// length.
+    pub fn encode(&self, data: &[&[u8]]) -> Vec<Vec<u8>> {
+        assert_eq!(data.len(), self.data_shards);
+        self.parity_rows
+            .iter()
+            .map(|row| combine(row, data))
+            .collect()
+    }
+
+    /// Recovers the data shards from `data_shards` shards of the same length, each given with
+    /// its index among all shards, data shards first.
+    pub fn reconstruct(&self, shards: &[(usize, &[u8])]) -> Vec<Vec<u8>> {
+        assert_eq!(shards.len(), self.data_shards);
+        let rows: Vec<Vec<u8>> = shards.iter().map(|(index, _)| self.row(*index)).collect();
+        let payloads: Vec<&[u8]> = shards.iter().map(|(_, shard)| *shard).collect();
+        invert(rows)
+            .iter()
+            .map(|row| combine(row, &payloads))
+            .collect()
+    }
+
+    // The coefficients taking the data shards to shard `index`.
+    fn row(&self, index: usize) -> Vec<u8> {
+        match index.checked_sub(self.data_shards) {
+            Some(parity) => self.parity_rows[parity].clone(),
+            None => (0..self.data_shards).map(|j| (j == index) as u8).collect(),
+        }
+    }
+}
+
+// Sums up `shards`, each multiplied by its coefficient in `row`.
+fn combine(row: &[u8], shards: &[&[u8]]) -> Vec<u8> {
+    let mut out = vec![0; shards.first().map_or(0, |shard| shard.len())];
+    for (coefficient, shard) in row.iter().zip(shards) {
+        for (out, byte) in out.iter_mut().zip(*shard) {
+            *out ^= mul(*coefficient, *byte);
+        }
+    }
+    out
+}
+
+// Inverts a square matrix by Gauss–Jordan elimination. Only ever called on rows of the encoding
+// matrix, which are guaranteed to be independent.
+fn invert(mut matrix: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n)
+        .map(|i| (0..n).map(|j| (i == j) as u8).collect())
+        .collect();
+
+    for column in 0..n {
+        let pivot = (column..n)
+            .find(|row| matrix[*row][column] != 0)
+            .expect("singular matrix");
+        matrix.swap(column, pivot);
+        inverse.swap(column, pivot);
+
+        let scale = inv(matrix[column][column]);
+        for j in 0..n {
+            matrix[column][j] = mul(matrix[column][j], scale);
+            inverse[column][j] = mul(inverse[column][j], scale);
+        }
+        for row in 0..n {
+            let factor = matrix[row][column];
+            if row == column || factor == 0 {
+                continue;
+            }
+            for j in 0..n {
+                matrix[row][j] ^= mul(factor, matrix[column][j]);
+                inverse[row][j] ^= mul(factor, inverse[column][j]);
+            }
+        }
+    }
+    inverse
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_invert_field_elements() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1, "{}", a);
+        }
+        assert_eq!(mul(0x53, 0xca), mul(0xca, 0x53));
+    }
+
+    #[test]
+    fn should_reconstruct_from_any_data_shards() {
+        let codec = ReedSolomon::new(4, 2);
+        let data: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| (0..16).map(|j| i * 16 + j).collect())
+            .collect();
+        let refs: Vec<&[u8]> = data.iter().map(Vec::as_slice).collect();
+        let mut shards = data.clone();
+        shards.extend(codec.encode(&refs));
+
+        // Lose every possible pair of shards.
+        for lost in 0..6 {
+            for also_lost in lost + 1..6 {
+                let kept: Vec<(usize, &[u8])> = (0..6)
+                    .filter(|i| *i != lost && *i != also_lost)
+                    .map(|i| (i, shards[i].as_slice()))
+                    .collect();
+                assert_eq!(
+                    codec.reconstruct(&kept),
+                    data,
+                    "lost {} and {}",
+                    lost,
+                    also_lost
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! A [`Blockstore`] reading blocks from a remote trustless gateway, such as a [`Gateway`].
+//!
+//! [`Gateway`]: crate::gateway::Gateway
+
+use std::io;
+
+use cid::Cid;
+use tokio::sync::mpsc;
+
+use crate::block::{Block, Hasher, is_inline};
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+use crate::gateway::RAW_BLOCK_TYPE;
+use crate::http::{self, Endpoint, Response};
+
+/// Fetches blocks from `GET /ipfs/{cid}` at a remote endpoint, verifying that whatever comes
+/// back hashes to the CID that was asked for: the remote doesn't need to be trusted. Connections
+/// are kept open and reused across requests.
+///
+/// Gateways are read-only, so putting and deleting blocks fails with
+/// [`BlockstoreError::ReadOnly`]. They can't be listed either, and neither listing nor
+/// [`Blockstore::stats`] are supported. Blocks with identity CIDs are served from the CID without
+/// asking the gateway.
+pub struct HttpStore {
+    http: http::Client,
+}
+
+/// How large the blocks an [`HttpStore`] reads can be unless told otherwise. Anything larger
+/// fails to read rather than being buffered, so a hostile gateway can't exhaust our memory.
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 4 << 20;
+
+impl HttpStore {
+    /// Reads from the gateway at `endpoint`, an `http://host[:port]` URL. No requests are made
+    /// until the store is used.
+    pub fn new(endpoint: &str) -> Result<Self, io::Error> {
+        let http = http::Client::new(Endpoint::parse(endpoint)?);
+        Ok(HttpStore {
+            http: http.with_max_body(DEFAULT_MAX_BLOCK_SIZE),
+        })
+    }
+
+    /// Fails to read blocks larger than `max_block_size` instead of [`DEFAULT_MAX_BLOCK_SIZE`].
+    pub fn with_max_block_size(mut self, max_block_size: usize) -> Self {
+        self.http = self.http.with_max_body(max_block_size);
+        self
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        self.http.endpoint()
+    }
+
+    async fn request(&self, method: &str, cid: &Cid) -> Result<Option<Response>, io::Error> {
+        let target = format!("/ipfs/{}?format=raw", cid);
+        let headers = [("accept".to_string(), RAW_BLOCK_TYPE.to_string())];
+        let response = self.http.send(method, &target, &headers, &[]).await?;
+        match response.status {
+            404 => Ok(None),
+            _ if response.is_success() => Ok(Some(response)),
+            status => Err(io::Error::other(format!(
+                "gateway request for {} failed with {}",
+                cid, status
+            ))),
+        }
+    }
+}
+
+impl Blockstore for HttpStore {
+    async fn put_block(&self, _block: &Block) -> Result<Put, BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        is_inline(cid) || matches!(self.request("HEAD", cid).await, Ok(Some(_)))
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if let Some(block) = Block::inline(cid) {
+            return Ok(Some(block));
+        }
+        // Fail before fetching anything we'd have no way to check.
+        let Some(hasher) = Hasher::from_code(cid.hash().code()) else {
+            return Err(unsupported(&format!(
+                "verifying hash function {:#x}",
+                cid.hash().code()
+            )));
+        };
+        let Some(response) = self.request("GET", cid).await? else {
+            return Ok(None);
+        };
+
+        if Block::hash_matches(cid, &response.body) == Some(false) {
+            let digest = hasher.digest(&response.body);
+            return Err(BlockstoreError::corrupt(cid, &digest, None));
+        }
+        Ok(Some(Block {
+            cid: *cid,
+            data: response.body.into(),
+        }))
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(Some(cid.hash().size() as u64));
+        }
+        let Some(response) = self.request("HEAD", cid).await? else {
+            return Ok(None);
+        };
+        let size = response
+            .header("content-length")
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("gateway didn't say how large {} is", cid),
+                )
+            })?;
+        Ok(Some(size))
+    }
+
+    async fn del_block(&self, _cid: &Cid) -> Result<(), BlockstoreError> {
+        Err(BlockstoreError::ReadOnly)
+    }
+
+    fn blocks(&self) -> CidStream {
+        let (sender, receiver) = mpsc::channel(1);
+        let _ = sender.try_send(Err(unsupported("listing blocks")));
+        receiver
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        Err(unsupported("store stats"))
+    }
+}
+
+fn unsupported(what: &str) -> BlockstoreError {
+    BlockstoreError::Backend(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is not supported by HTTP gateways", what),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Codec, make_random_block};
+    use crate::gateway::TestGateway;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_read_remote_blocks() {
+        let block = make_random_block(1000);
+        let missing = make_random_block(10);
+        let gateway = TestGateway::start(std::slice::from_ref(&block)).await;
+        let store = HttpStore::new(&gateway.endpoint).unwrap();
+
+        assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+        assert!(store.has_block(&block.cid).await);
+        assert_eq!(store.block_size(&block.cid).await.unwrap(), Some(1000));
+
+        assert!(store.get_block(&missing.cid).await.unwrap().is_none());
+        assert!(!store.has_block(&missing.cid).await);
+        assert_eq!(store.block_size(&missing.cid).await.unwrap(), None);
+
+        // Identity CIDs are answered without asking the gateway.
+        drop(gateway);
+        let inline = Block::new_inline(&b"inline"[..], Codec::Raw).unwrap();
+        assert_eq!(store.get_block(&inline.cid).await.unwrap().unwrap(), inline);
+        assert!(store.has_block(&inline.cid).await);
+        assert_eq!(store.block_size(&inline.cid).await.unwrap(), Some(6));
+
+        let result = store.put_block(&missing).await;
+        assert!(matches!(result, Err(BlockstoreError::ReadOnly)));
+        let result = store.blocks().recv().await.unwrap();
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_refuse_blocks_over_max_size() {
+        let block = make_random_block(1000);
+        let gateway = TestGateway::start(std::slice::from_ref(&block)).await;
+        let store = HttpStore::new(&gateway.endpoint)
+            .unwrap()
+            .with_max_block_size(999);
+
+        let error = store.get_block(&block.cid).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+        // Sizes are only announced, so they can still be asked for.
+        assert_eq!(store.block_size(&block.cid).await.unwrap(), Some(1000));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_reject_tampered_blocks() {
+        let block = make_random_block(100);
+        let tampered = Block {
+            cid: block.cid,
+            data: make_random_block(100).data,
+        };
+        let gateway = TestGateway::start(&[tampered]).await;
+        let store = HttpStore::new(&gateway.endpoint).unwrap();
+
+        match store.get_block(&block.cid).await {
+            Err(BlockstoreError::Corrupt(corruption)) => assert_eq!(corruption.expected, block.cid),
+            result => panic!("expected a corrupt block, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Asynchronous replication of one store's writes to another, for keeping a warm standby copy.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cid::Cid;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+
+/// How many writes can be waiting to be replicated by default.
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// Writes to a primary store, and replicates them to a secondary one in the background. Writes
+/// are acknowledged as soon as the primary has them; once the replication queue is full, they
+/// wait for it to make room. Reads only ever go to the primary.
+///
+/// Only blocks the primary actually writes get replicated: blocks it already had when it was
+/// wrapped aren't copied over. Replication keeps going after the store is dropped, until the
+/// queue is empty.
+pub struct ReplicatedStore<P, S> {
+    primary: P,
+    secondary: Arc<S>,
+    queue: mpsc::Sender<Replication>,
+    state: Arc<Mutex<State>>,
+}
+
+/// How far the secondary is behind, as reported by [`ReplicatedStore::lag`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplicationLag {
+    /// Writes acknowledged but not yet replicated.
+    pub pending: usize,
+    /// How long the oldest of those has been waiting.
+    pub oldest: Duration,
+}
+
+enum Replication {
+    Put(Block),
+    Del(Cid),
+    Sync(oneshot::Sender<()>),
+}
+
+#[derive(Default)]
+struct State {
+    // When each pending write was queued, oldest first. Writes are replicated in order, so the
+    // front is always the one being worked on.
+    queued: VecDeque<Instant>,
+    // The first replication failure since the last sync.
+    error: Option<BlockstoreError>,
+}
+
+impl<P: Blockstore, S: Blockstore + 'static> ReplicatedStore<P, S> {
+    /// Replicates `primary` to `secondary`, spawning the task that does so.
+    pub fn new(primary: P, secondary: S) -> Self {
+        Self::with_queue_size(primary, secondary, DEFAULT_QUEUE_SIZE)
+    }
+
+    /// Like [`ReplicatedStore::new`], with room for `queue_size` writes waiting to be
+    /// replicated.
+    pub fn with_queue_size(primary: P, secondary: S, queue_size: usize) -> Self {
+        let (queue, receiver) = mpsc::channel(queue_size);
+        let secondary = Arc::new(secondary);
+        let state = Arc::new(Mutex::new(State::default()));
+        tokio::spawn(replicate(secondary.clone(), receiver, state.clone()));
+
+        ReplicatedStore {
+            primary,
+            secondary,
+            queue,
+            state,
+        }
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+
+    /// Waits for every write acknowledged so far to be replicated. Fails with the first error
+    /// replication ran into since the last sync, if any: the write that caused it is lost on the
+    /// secondary.
+    pub async fn sync(&self) -> Result<(), BlockstoreError> {
+        let (done, synced) = oneshot::channel();
+        self.queue
+            .send(Replication::Sync(done))
+            .await
+            .map_err(|_| stopped())?;
+        synced.await.map_err(|_| stopped())?;
+
+        match self.state.lock().unwrap().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub fn lag(&self) -> ReplicationLag {
+        let state = self.state.lock().unwrap();
+        ReplicationLag {
+            pending: state.queued.len(),
+            oldest: state
+                .queued
+                .front()
+                .map(Instant::elapsed)
+                .unwrap_or_default(),
+        }
+    }
+
+    async fn enqueue(&self, replication: Replication) -> Result<(), BlockstoreError> {
+        // Counted before sending, so that the task never finishes a write it hasn't seen queued.
+        self.state.lock().unwrap().queued.push_back(Instant::now());
+        if self.queue.send(replication).await.is_err() {
+            self.state.lock().unwrap().queued.pop_back();
+            return Err(stopped());
+        }
+        Ok(())
+    }
+}
+
+async fn replicate<S: Blockstore>(
+    secondary: Arc<S>,
+    mut receiver: mpsc::Receiver<Replication>,
+    state: Arc<Mutex<State>>,
+) {
+    while let Some(replication) = receiver.recv().await {
+        let result = match replication {
+            Replication::Put(block) => secondary.put_block(&block).await.map(|_| ()),
+            Replication::Del(cid) => match secondary.del_block(&cid).await {
+                Err(BlockstoreError::NotFound(_)) => Ok(()),
+                result => result,
+            },
+            Replication::Sync(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+
+        let mut state = state.lock().unwrap();
+        state.queued.pop_front();
+        if let Err(e) = result {
+            state.error.get_or_insert(e);
+        }
+    }
+}
+
+fn stopped() -> BlockstoreError {
+    BlockstoreError::Backend(io::Error::other("replication task stopped"))
+}
+
+impl<P: Blockstore, S: Blockstore + 'static> Blockstore for ReplicatedStore<P, S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let put = self.primary.put_block(block).await?;
+        if put.is_written() {
+            self.enqueue(Replication::Put(block.clone())).await?;
+        }
+        Ok(put)
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        // Batches don't say which blocks were new, so they all get replicated.
+        self.primary.put_many(blocks).await?;
+        for block in blocks {
+            self.enqueue(Replication::Put(block.clone())).await?;
+        }
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        self.primary.has_block(cid).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        self.primary.get_block(cid).await
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.primary.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.primary.block_size(cid).await
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.primary.del_block(cid).await?;
+        self.enqueue(Replication::Del(*cid)).await
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.primary.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.primary.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use crate::readonly::ReadOnlyStore;
+
+    async fn make_replicated_store() -> (ReplicatedStore<MemStore, MemStore>, ()) {
+        (ReplicatedStore::new(MemStore::new(), MemStore::new()), ())
+    }
+
+    crate::conformance::conformance_tests!(make_replicated_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_replicate_writes() {
+        let store = ReplicatedStore::with_queue_size(MemStore::new(), MemStore::new(), 2);
+        let blocks: Vec<Block> = (0..10).map(|_| make_random_block(100)).collect();
+
+        for block in &blocks {
+            store.put_block(block).await.unwrap();
+        }
+        store.del_block(&blocks[0].cid).await.unwrap();
+        store.sync().await.unwrap();
+
+        assert_eq!(store.lag(), ReplicationLag::default());
+        assert!(!store.secondary().has_block(&blocks[0].cid).await);
+        for block in &blocks[1..] {
+            assert!(store.secondary().has_block(&block.cid).await);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_report_replication_failures() {
+        let store = ReplicatedStore::new(MemStore::new(), ReadOnlyStore::new(MemStore::new()));
+        let block = make_random_block(100);
+
+        // The write itself succeeds, since the primary took it.
+        store.put_block(&block).await.unwrap();
+        let result = store.sync().await;
+        assert!(matches!(result, Err(BlockstoreError::ReadOnly)));
+        store.sync().await.unwrap();
+    }
+}
@@ -0,0 +1,348 @@
+//! Retrying operations that fail for reasons that may go away, like a dropped connection to a
+//! remote backend.
+
+use std::io;
+use std::time::Duration;
+
+use cid::Cid;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+
+/// How a [`RetryStore`] goes about retrying. The delay before each retry doubles from
+/// `initial_delay` up to `max_delay`, and is then cut short by a random fraction of up to
+/// `jitter` of it, so that clients which failed together don't all come back at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times an operation gets tried in all, the first time included.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Between 0, for waiting out every delay in full, and 1, for waiting anywhere from not
+    /// at all to the full delay.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    /// Four attempts, retried after 100ms, 200ms and 400ms, less up to half of that.
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // How long to wait before retrying after `attempt` attempts.
+    fn delay(&self, attempt: u32) -> Duration {
+        let doubled = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let delay = doubled.min(self.max_delay);
+        delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * rand::random::<f64>())
+    }
+}
+
+/// Whether `error` is worth retrying: timeouts and the I/O errors of connections that went
+/// wrong. Missing or corrupt blocks, full and read-only stores and the like will fail the same
+/// way again, and aren't.
+pub fn is_transient(error: &BlockstoreError) -> bool {
+    match error {
+        BlockstoreError::Timeout { .. } => true,
+        BlockstoreError::Backend(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+/// Wraps a [`Blockstore`] so that operations which fail with a transient error, as told by
+/// [`is_transient`] or the function given to [`RetryStore::with_classifier`], get tried again
+/// as the [`RetryPolicy`] says. Once it runs out of attempts, the last error is returned.
+///
+/// Puts are safe to repeat, since a block is the same block however many times it's put. A
+/// delete that finds the block gone on a retry counts as done, since an earlier attempt may have
+/// gone through before failing; the flip side is that deleting a block that was never there
+/// goes unreported when the first attempt fails transiently. Checks can't fail, and listings
+/// can't be resumed, so neither is retried.
+pub struct RetryStore<S> {
+    store: S,
+    policy: RetryPolicy,
+    classifier: fn(&BlockstoreError) -> bool,
+}
+
+impl<S: Blockstore> RetryStore<S> {
+    pub fn new(store: S, policy: RetryPolicy) -> Self {
+        assert!(
+            policy.max_attempts > 0,
+            "an operation has to be tried at least once"
+        );
+        RetryStore {
+            store,
+            policy,
+            classifier: is_transient,
+        }
+    }
+
+    /// Retries the errors `classifier` returns true for, instead of those [`is_transient`]
+    /// does.
+    pub fn with_classifier(mut self, classifier: fn(&BlockstoreError) -> bool) -> Self {
+        self.classifier = classifier;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    async fn retry<T, F: Future<Output = Result<T, BlockstoreError>>>(
+        &self,
+        mut operation: impl FnMut() -> F,
+    ) -> Result<T, BlockstoreError> {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(e) if attempt < self.policy.max_attempts && (self.classifier)(&e) => {
+                    tokio::time::sleep(self.policy.delay(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Like `retry`, but for deletes, which are done once a retry finds nothing to delete.
+    async fn retry_delete<F: Future<Output = Result<(), BlockstoreError>>>(
+        &self,
+        mut operation: impl FnMut() -> F,
+    ) -> Result<(), BlockstoreError> {
+        let mut retried = false;
+        self.retry(|| {
+            let delete = operation();
+            let retry = retried;
+            retried = true;
+            async move {
+                match delete.await {
+                    Err(BlockstoreError::NotFound(_)) if retry => Ok(()),
+                    result => result,
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl<S: Blockstore> Blockstore for RetryStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        self.retry(|| self.store.put_block(block)).await
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        self.retry(|| self.store.put_many(blocks)).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        self.store.has_block(cid).await
+    }
+
+    async fn has_many(&self, cids: &[Cid]) -> Vec<bool> {
+        self.store.has_many(cids).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        self.retry(|| self.store.get_block(cid)).await
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, BlockstoreError> {
+        self.retry(|| self.store.get_many(cids)).await
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.retry(|| self.store.block_size(cid)).await
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.retry_delete(|| self.store.del_block(cid)).await
+    }
+
+    async fn del_many(&self, cids: &[Cid]) -> Result<(), BlockstoreError> {
+        self.retry_delete(|| self.store.del_many(cids)).await
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.retry(|| self.store.stats()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    async fn make_retry_store() -> (RetryStore<MemStore>, ()) {
+        (RetryStore::new(MemStore::new(), fast_policy()), ())
+    }
+
+    crate::conformance::conformance_tests!(make_retry_store);
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            jitter: 0.5,
+        }
+    }
+
+    // A store whose operations each fail with `kind` the first `failures` times, after doing
+    // what they were asked to.
+    struct FlakyStore {
+        store: MemStore,
+        failures: u32,
+        kind: io::ErrorKind,
+        calls: AtomicU32,
+    }
+
+    impl FlakyStore {
+        fn new(failures: u32, kind: io::ErrorKind) -> Self {
+            FlakyStore {
+                store: MemStore::new(),
+                failures,
+                kind,
+                calls: AtomicU32::new(0),
+            }
+        }
+
+        fn fail<T>(&self, result: Result<T, BlockstoreError>) -> Result<T, BlockstoreError> {
+            if self.calls.fetch_add(1, Ordering::Relaxed) < self.failures {
+                return Err(io::Error::from(self.kind).into());
+            }
+            result
+        }
+    }
+
+    impl Blockstore for FlakyStore {
+        async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+            let put = self.store.put_block(block).await;
+            self.fail(put)
+        }
+
+        async fn has_block(&self, cid: &Cid) -> bool {
+            self.store.has_block(cid).await
+        }
+
+        async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+            let block = self.store.get_block(cid).await;
+            self.fail(block)
+        }
+
+        async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+            let deleted = self.store.del_block(cid).await;
+            self.fail(deleted)
+        }
+
+        fn blocks(&self) -> CidStream {
+            self.store.blocks()
+        }
+
+        async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+            self.store.stats().await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_retry_transient_failures() {
+        let store = RetryStore::new(
+            FlakyStore::new(2, io::ErrorKind::ConnectionReset),
+            fast_policy(),
+        );
+        let block = make_random_block(100);
+        store.put_block(&block).await.unwrap();
+        assert_eq!(store.store().calls.load(Ordering::Relaxed), 3);
+
+        // The first attempt deleted the block, so the retry finding it gone is fine.
+        let store = RetryStore::new(
+            FlakyStore::new(1, io::ErrorKind::ConnectionReset),
+            fast_policy(),
+        );
+        store.store().store.put_block(&block).await.unwrap();
+        store.del_block(&block.cid).await.unwrap();
+        assert!(!store.has_block(&block.cid).await);
+
+        // Once attempts run out, the last error comes back.
+        let store = RetryStore::new(
+            FlakyStore::new(5, io::ErrorKind::ConnectionReset),
+            fast_policy(),
+        );
+        let err = store.get_block(&block.cid).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(store.store().calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_only_retry_what_classifier_allows() {
+        let store = RetryStore::new(
+            FlakyStore::new(1, io::ErrorKind::PermissionDenied),
+            fast_policy(),
+        );
+        let block = make_random_block(100);
+        let err = store.put_block(&block).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(store.store().calls.load(Ordering::Relaxed), 1);
+
+        let store = store.with_classifier(|e| e.kind() == io::ErrorKind::PermissionDenied);
+        store.store().calls.store(0, Ordering::Relaxed);
+        store.put_block(&block).await.unwrap();
+        assert_eq!(store.store().calls.load(Ordering::Relaxed), 2);
+        // Missing blocks aren't transient, and fail straight away.
+        assert!(!is_transient(&BlockstoreError::NotFound(block.cid)));
+    }
+
+    #[test]
+    fn should_back_off_exponentially() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1_000),
+            jitter: 0.0,
+        };
+        let delays: Vec<Duration> = (1..=6).map(|attempt| policy.delay(attempt)).collect();
+        let expected = [100, 200, 400, 800, 1_000, 1_000].map(Duration::from_millis);
+        assert_eq!(delays, expected);
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let delay = jittered.delay(2);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+}
@@ -0,0 +1,1026 @@
+use std::fmt::Write as _;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cid::Cid;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::block::{Block, is_inline, to_v1};
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, StoreStats};
+use crate::http::{self, Endpoint, Response};
+
+pub const DEFAULT_REGION: &str = "us-east-1";
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(100);
+pub const DEFAULT_MULTIPART_THRESHOLD: usize = 16 << 20;
+/// S3 won't take parts smaller than 5 MiB, other than the last one.
+pub const DEFAULT_PART_SIZE: usize = 8 << 20;
+pub const DEFAULT_UPLOAD_CONCURRENCY: usize = 4;
+
+#[derive(Clone)]
+pub struct S3Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+/// A [`Blockstore`] backed by an S3-compatible object store, with one object per block under
+/// `{prefix}{cid}` in a single bucket. Buckets are addressed path-style (`/bucket/key`), which is
+/// what MinIO and most self-hosted implementations expect.
+///
+/// Only plain HTTP endpoints are supported. Blocks above the multipart threshold are uploaded in
+/// parts, several at a time. Requests that fail with a connection error or a 5xx/429 response are
+/// retried with exponential backoff.
+///
+/// Listing and [`Blockstore::stats`] page through the whole prefix, so they cost one request per
+/// thousand blocks.
+pub struct S3Store {
+    requester: Requester,
+    bucket: String,
+    prefix: String,
+    multipart_threshold: usize,
+    part_size: usize,
+    upload_concurrency: usize,
+}
+
+// Everything needed to send signed requests, cheap to clone into spawned tasks.
+#[derive(Clone)]
+struct Requester {
+    http: Arc<http::Client>,
+    region: String,
+    credentials: S3Credentials,
+    max_retries: u32,
+    retry_delay: Duration,
+}
+
+impl S3Store {
+    /// Connects to `bucket` at `endpoint`, an `http://host[:port]` URL. No requests are made
+    /// until the store is used.
+    pub fn new(
+        endpoint: &str,
+        bucket: &str,
+        credentials: S3Credentials,
+    ) -> Result<Self, io::Error> {
+        Ok(S3Store {
+            requester: Requester {
+                http: Arc::new(http::Client::new(Endpoint::parse(endpoint)?)),
+                region: DEFAULT_REGION.to_string(),
+                credentials,
+                max_retries: DEFAULT_MAX_RETRIES,
+                retry_delay: DEFAULT_RETRY_DELAY,
+            },
+            bucket: bucket.to_string(),
+            prefix: String::new(),
+            multipart_threshold: DEFAULT_MULTIPART_THRESHOLD,
+            part_size: DEFAULT_PART_SIZE,
+            upload_concurrency: DEFAULT_UPLOAD_CONCURRENCY,
+        })
+    }
+
+    /// Keeps blocks under `prefix` (e.g. `blocks/`), so several stores can share a bucket.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Sets the region requests are signed for. MinIO accepts the default, `us-east-1`.
+    pub fn with_region(mut self, region: &str) -> Self {
+        self.requester.region = region.to_string();
+        self
+    }
+
+    /// Retries failed requests up to `max_retries` times, waiting `delay` before the first retry
+    /// and doubling that each time after.
+    pub fn with_retries(mut self, max_retries: u32, delay: Duration) -> Self {
+        self.requester.max_retries = max_retries;
+        self.requester.retry_delay = delay;
+        self
+    }
+
+    /// Uploads blocks larger than `threshold` bytes in `part_size` parts, at most `concurrency`
+    /// at a time.
+    pub fn with_multipart(
+        mut self,
+        threshold: usize,
+        part_size: usize,
+        concurrency: usize,
+    ) -> Self {
+        assert!(part_size > 0 && concurrency > 0);
+        self.multipart_threshold = threshold;
+        self.part_size = part_size;
+        self.upload_concurrency = concurrency;
+        self
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn object_path(&self, cid: &Cid) -> String {
+        format!(
+            "/{}/{}",
+            self.bucket,
+            http::percent_encode(&format!("{}{}", self.prefix, to_v1(cid)), true)
+        )
+    }
+
+    async fn head(&self, cid: &Cid) -> Result<Option<u64>, io::Error> {
+        let response = self
+            .requester
+            .send("HEAD", &self.object_path(cid), &[], &[])
+            .await?;
+        match response.status {
+            404 => Ok(None),
+            _ if response.is_success() => response
+                .header("content-length")
+                .and_then(|len| len.parse().ok())
+                .map(Some)
+                .ok_or_else(|| invalid_data("HEAD response without a content-length")),
+            _ => Err(status_error(&response)),
+        }
+    }
+
+    async fn put_multipart(&self, block: &Block) -> Result<(), io::Error> {
+        let path = self.object_path(&block.cid);
+        let response = self
+            .requester
+            .send("POST", &path, &[("uploads", "")], &[])
+            .await?;
+        if !response.is_success() {
+            return Err(status_error(&response));
+        }
+        let body = String::from_utf8_lossy(&response.body);
+        let upload_id = xml_values(&body, "UploadId")
+            .next()
+            .ok_or_else(|| invalid_data("no UploadId in multipart upload response"))?;
+
+        let result = self.upload_parts(&path, &upload_id, &block.data).await;
+        let result = match result {
+            Ok(etags) => self.complete_multipart(&path, &upload_id, &etags).await,
+            Err(e) => Err(e),
+        };
+
+        if result.is_err() {
+            // Best effort: otherwise the parts linger (and get billed) until a lifecycle rule
+            // cleans them up.
+            let _ = self
+                .requester
+                .send("DELETE", &path, &[("uploadId", &upload_id)], &[])
+                .await;
+        }
+        result
+    }
+
+    // Uploads `data` in parts, returning their ETags in part order.
+    async fn upload_parts(
+        &self,
+        path: &str,
+        upload_id: &str,
+        data: &[u8],
+    ) -> Result<Vec<String>, io::Error> {
+        let mut etags = vec![String::new(); data.len().div_ceil(self.part_size)];
+        let mut uploads = JoinSet::new();
+
+        for (index, chunk) in data.chunks(self.part_size).enumerate() {
+            if uploads.len() >= self.upload_concurrency {
+                let (index, etag) = uploads.join_next().await.unwrap()??;
+                etags[index] = etag;
+            }
+
+            let requester = self.requester.clone();
+            let path = path.to_string();
+            let upload_id = upload_id.to_string();
+            let chunk = chunk.to_vec();
+            uploads.spawn(async move {
+                let part_number = (index + 1).to_string();
+                let query = [
+                    ("partNumber", part_number.as_str()),
+                    ("uploadId", &upload_id),
+                ];
+                let response = requester.send("PUT", &path, &query, &chunk).await?;
+                if !response.is_success() {
+                    return Err(status_error(&response));
+                }
+                let etag = response
+                    .header("etag")
+                    .ok_or_else(|| invalid_data("part upload response without an ETag"))?;
+                Ok((index, etag.to_string()))
+            });
+        }
+
+        while let Some(result) = uploads.join_next().await {
+            let (index, etag) = result??;
+            etags[index] = etag;
+        }
+        Ok(etags)
+    }
+
+    async fn complete_multipart(
+        &self,
+        path: &str,
+        upload_id: &str,
+        etags: &[String],
+    ) -> Result<(), io::Error> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (index, etag) in etags.iter().enumerate() {
+            write!(
+                body,
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                index + 1,
+                xml_escape(etag)
+            )
+            .unwrap();
+        }
+        body.push_str("</CompleteMultipartUpload>");
+
+        let response = self
+            .requester
+            .send("POST", path, &[("uploadId", upload_id)], body.as_bytes())
+            .await?;
+        // S3 may report a failed completion with a 200 and an error document.
+        if !response.is_success() || String::from_utf8_lossy(&response.body).contains("<Error>") {
+            return Err(status_error(&response));
+        }
+        Ok(())
+    }
+
+    // Lists one page of objects under our prefix, starting at `token`. Returns the blocks found
+    // along with their sizes, and the token for the next page if there is one.
+    async fn list_page(
+        requester: &Requester,
+        bucket: &str,
+        prefix: &str,
+        token: Option<&str>,
+    ) -> Result<ListPage, io::Error> {
+        let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+        if let Some(token) = token {
+            query.push(("continuation-token", token));
+        }
+
+        let response = requester
+            .send("GET", &format!("/{}", bucket), &query, &[])
+            .await?;
+        if !response.is_success() {
+            return Err(status_error(&response));
+        }
+        let body = String::from_utf8_lossy(&response.body);
+
+        let entries = xml_values(&body, "Contents")
+            .map(|contents| parse_listed(&contents, prefix))
+            .collect();
+        let truncated = xml_values(&body, "IsTruncated").next().as_deref() == Some("true");
+        let next = xml_values(&body, "NextContinuationToken")
+            .next()
+            .filter(|_| truncated);
+        Ok((entries, next))
+    }
+}
+
+type ListPage = (Vec<Result<(Cid, u64), io::Error>>, Option<String>);
+
+fn parse_listed(contents: &str, prefix: &str) -> Result<(Cid, u64), io::Error> {
+    let key = xml_values(contents, "Key")
+        .next()
+        .ok_or_else(|| invalid_data("listed object without a key"))?;
+    let size = xml_values(contents, "Size")
+        .next()
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| invalid_data(format!("listed object {:?} without a size", key)))?;
+
+    let cid = key
+        .strip_prefix(prefix)
+        .and_then(|cid| Cid::try_from(cid).ok())
+        .ok_or_else(|| invalid_data(format!("object {:?} is not a block", key)))?;
+    Ok((cid, size))
+}
+
+impl Requester {
+    // Signs and sends a request, retrying on connection errors and on responses that say the
+    // server is (temporarily) unable to cope.
+    async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<Response, io::Error> {
+        let query = canonical_query(query);
+        let target = if query.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}?{}", path, query)
+        };
+        let payload_hash = hex(&Sha256::digest(body));
+
+        let mut attempt = 0;
+        loop {
+            let amz_date = amz_date(SystemTime::now());
+            let mut headers = vec![
+                ("host".to_string(), self.http.endpoint().authority()),
+                ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+                ("x-amz-date".to_string(), amz_date.clone()),
+            ];
+            let authorization = authorization(
+                &self.credentials,
+                &self.region,
+                "s3",
+                &amz_date,
+                method,
+                path,
+                &query,
+                &headers,
+                &payload_hash,
+            );
+            // The client supplies its own host header.
+            headers.remove(0);
+            headers.push(("authorization".to_string(), authorization));
+
+            let result = self.http.send(method, &target, &headers, body).await;
+            let retriable = match &result {
+                Ok(response) => matches!(response.status, 429 | 500 | 502 | 503 | 504),
+                Err(_) => true,
+            };
+            if !retriable || attempt >= self.max_retries {
+                return result;
+            }
+
+            tokio::time::sleep(self.retry_delay * 2u32.saturating_pow(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Blocks with identity CIDs are served from the CID and always there, without ever being
+/// uploaded.
+impl Blockstore for S3Store {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        if is_inline(&block.cid) {
+            return Ok(Put::Existing);
+        }
+        // A HEAD is a lot cheaper than uploading the block again.
+        if self.head(&block.cid).await?.is_some() {
+            return Ok(Put::Existing);
+        }
+        if block.data.len() > self.multipart_threshold {
+            self.put_multipart(block).await?;
+            return Ok(Put::Written {
+                bytes: block.data.len() as u64,
+            });
+        }
+
+        let response = self
+            .requester
+            .send("PUT", &self.object_path(&block.cid), &[], &block.data)
+            .await?;
+        if !response.is_success() {
+            return Err(status_error(&response).into());
+        }
+        Ok(Put::Written {
+            bytes: block.data.len() as u64,
+        })
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        is_inline(cid) || matches!(self.head(cid).await, Ok(Some(_)))
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if let Some(block) = Block::inline(cid) {
+            return Ok(Some(block));
+        }
+        let response = self
+            .requester
+            .send("GET", &self.object_path(cid), &[], &[])
+            .await?;
+        match response.status {
+            404 => Ok(None),
+            _ if response.is_success() => Ok(Some(Block {
+                cid: *cid,
+                data: response.body.into(),
+            })),
+            _ => Err(status_error(&response).into()),
+        }
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(Some(cid.hash().size() as u64));
+        }
+        Ok(self.head(cid).await?)
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        if is_inline(cid) {
+            return Ok(());
+        }
+        // S3 happily deletes objects that aren't there, so we have to check first to report
+        // missing blocks like the other backends do.
+        if self.head(cid).await?.is_none() {
+            return Err(BlockstoreError::NotFound(*cid));
+        }
+
+        let response = self
+            .requester
+            .send("DELETE", &self.object_path(cid), &[], &[])
+            .await?;
+        if !response.is_success() {
+            return Err(status_error(&response).into());
+        }
+        Ok(())
+    }
+
+    fn blocks(&self) -> CidStream {
+        let (sender, receiver) = mpsc::channel(1024);
+        let requester = self.requester.clone();
+        let bucket = self.bucket.clone();
+        let prefix = self.prefix.clone();
+
+        tokio::spawn(async move {
+            let mut token = None;
+            loop {
+                let page = Self::list_page(&requester, &bucket, &prefix, token.as_deref()).await;
+                let (entries, next) = match page {
+                    Ok(page) => page,
+                    Err(e) => {
+                        let _ = sender.send(Err(e.into())).await;
+                        return;
+                    }
+                };
+
+                for entry in entries {
+                    if sender.send(entry.map(|(cid, _)| cid).map_err(Into::into)).await.is_err() {
+                        // Nobody's listening anymore.
+                        return;
+                    }
+                }
+
+                match next {
+                    Some(next) => token = Some(next),
+                    None => return,
+                }
+            }
+        });
+
+        receiver
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let mut stats = StoreStats::default();
+        let mut token = None;
+        loop {
+            let (entries, next) = Self::list_page(
+                &self.requester,
+                &self.bucket,
+                &self.prefix,
+                token.as_deref(),
+            )
+            .await?;
+            for entry in entries {
+                let (_, size) = entry?;
+                stats.blocks += 1;
+                stats.bytes += size;
+            }
+
+            match next {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+
+        // Objects are billed by their size, which is as close to "disk usage" as we get.
+        stats.disk_bytes = stats.bytes;
+        Ok(stats)
+    }
+}
+
+// Builds the AWS Signature Version 4 `Authorization` header for a request. `headers` are the
+// (lowercase) headers to sign, which must include `host` and `x-amz-date`.
+#[allow(clippy::too_many_arguments)]
+fn authorization(
+    credentials: &S3Credentials,
+    region: &str,
+    service: &str,
+    amz_date: &str,
+    method: &str,
+    path: &str,
+    canonical_query: &str,
+    headers: &[(String, String)],
+    payload_hash: &str,
+) -> String {
+    let mut headers: Vec<_> = headers.iter().collect();
+    headers.sort();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, path, canonical_query, canonical_headers, signed_headers, payload_hash
+    );
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = hmac_sha256(
+        format!("AWS4{}", credentials.secret_key).as_bytes(),
+        date.as_bytes(),
+    );
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    let key = hmac_sha256(&key, b"aws4_request");
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key, scope, signed_headers, signature
+    )
+}
+
+fn canonical_query(query: &[(&str, &str)]) -> String {
+    let mut pairs: Vec<_> = query
+        .iter()
+        .map(|(key, value)| {
+            (
+                http::percent_encode(key, false),
+                http::percent_encode(value, false),
+            )
+        })
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut padded = [0u8; 64];
+    if key.len() > padded.len() {
+        padded[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        padded[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(padded.map(|byte| byte ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(padded.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        write!(hex, "{:02x}", byte).unwrap();
+        hex
+    })
+}
+
+// Formats `time` as `YYYYMMDDTHHMMSSZ`, in UTC.
+fn amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs = secs % 86_400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+// Converts days since the Unix epoch into a (proleptic Gregorian) date, after Howard Hinnant's
+// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+// Returns the (unescaped) contents of every `<tag>...</tag>` element in `xml`, in order. This is
+// only meant for the flat, well-known documents S3 sends back.
+fn xml_values<'a>(xml: &'a str, tag: &str) -> impl Iterator<Item = String> + 'a {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let len = rest[start..].find(&close)?;
+        let value = xml_unescape(&rest[start..start + len]);
+        rest = &rest[start + len + close.len()..];
+        Some(value)
+    })
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#34;", "\"")
+        .replace("&amp;", "&")
+}
+
+fn status_error(response: &Response) -> io::Error {
+    let body = String::from_utf8_lossy(&response.body);
+    let code = xml_values(&body, "Code").next().unwrap_or_default();
+    let message = xml_values(&body, "Message").next().unwrap_or_default();
+    let error = format!(
+        "S3 request failed with {}: {} {}",
+        response.status, code, message
+    );
+
+    match response.status {
+        403 => io::Error::new(io::ErrorKind::PermissionDenied, error),
+        404 => io::Error::new(io::ErrorKind::NotFound, error),
+        _ => io::Error::other(error),
+    }
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::Mutex;
+    use tokio::io::BufStream;
+    use tokio::net::TcpListener;
+    use tokio::task::AbortHandle;
+
+    /// A tiny in-memory imitation of the S3 API, just enough for [`S3Store`].
+    #[derive(Default)]
+    struct MockS3 {
+        objects: BTreeMap<String, Vec<u8>>,
+        uploads: HashMap<String, BTreeMap<usize, Vec<u8>>>,
+        completed_uploads: usize,
+        unauthorized: usize,
+        // Answer this many requests with a 503 before behaving.
+        failures: usize,
+    }
+
+    struct MockGuard {
+        server: AbortHandle,
+        state: Arc<Mutex<MockS3>>,
+    }
+
+    impl Drop for MockGuard {
+        fn drop(&mut self) {
+            self.server.abort();
+        }
+    }
+
+    async fn start_mock_s3() -> (String, MockGuard) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockS3::default()));
+
+        let server_state = state.clone();
+        let server = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufStream::new(stream);
+                    while let Ok(Some(request)) = http::read_request(&mut stream, 1 << 30).await {
+                        let response = state.lock().unwrap().handle(&request);
+                        let head_only = request.method == "HEAD";
+                        if http::write_response(&mut stream, &response, head_only)
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        (
+            endpoint,
+            MockGuard {
+                server: server.abort_handle(),
+                state,
+            },
+        )
+    }
+
+    impl MockS3 {
+        fn handle(&mut self, request: &http::Request) -> Response {
+            if !request
+                .header("authorization")
+                .is_some_and(|auth| auth.starts_with("AWS4-HMAC-SHA256 Credential=test/"))
+            {
+                self.unauthorized += 1;
+                return Response::new(403);
+            }
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Response::new(503);
+            }
+
+            let (path, query) = request
+                .target
+                .split_once('?')
+                .unwrap_or((&request.target, ""));
+            let query: HashMap<String, String> = query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (percent_decode(key), percent_decode(value))
+                })
+                .collect();
+            let path = percent_decode(path);
+            let (bucket, key) = match path[1..].split_once('/') {
+                Some((bucket, key)) => (bucket, Some(key.to_string())),
+                None => (&path[1..], None),
+            };
+            assert_eq!(bucket, "bucket");
+
+            let mut response = Response::new(200);
+            match (request.method.as_str(), key) {
+                ("GET", None) => response.body = self.list(&query).into_bytes(),
+                ("POST", Some(_)) if query.contains_key("uploads") => {
+                    let upload_id = format!("upload-{}", self.uploads.len());
+                    self.uploads.insert(upload_id.clone(), BTreeMap::new());
+                    response.body = format!(
+                        "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                        upload_id
+                    )
+                    .into_bytes();
+                }
+                ("PUT", Some(_)) if query.contains_key("uploadId") => {
+                    let part: usize = query["partNumber"].parse().unwrap();
+                    self.uploads
+                        .get_mut(&query["uploadId"])
+                        .unwrap()
+                        .insert(part, request.body.clone());
+                    response
+                        .headers
+                        .push(("etag".to_string(), format!("\"etag-{}\"", part)));
+                }
+                ("POST", Some(key)) if query.contains_key("uploadId") => {
+                    let parts = self.uploads.remove(&query["uploadId"]).unwrap();
+                    let body = String::from_utf8(request.body.clone()).unwrap();
+                    let mut data = Vec::new();
+                    for (number, etag) in
+                        xml_values(&body, "PartNumber").zip(xml_values(&body, "ETag"))
+                    {
+                        let number: usize = number.parse().unwrap();
+                        assert_eq!(etag, format!("\"etag-{}\"", number));
+                        data.extend_from_slice(&parts[&number]);
+                    }
+                    self.objects.insert(key, data);
+                    self.completed_uploads += 1;
+                }
+                ("DELETE", Some(_)) if query.contains_key("uploadId") => {
+                    self.uploads.remove(&query["uploadId"]);
+                    response.status = 204;
+                }
+                ("PUT", Some(key)) => {
+                    self.objects.insert(key, request.body.clone());
+                }
+                ("GET", Some(key)) => match self.objects.get(&key) {
+                    Some(data) => response.body = data.clone(),
+                    None => response.status = 404,
+                },
+                ("HEAD", Some(key)) => match self.objects.get(&key) {
+                    Some(data) => response
+                        .headers
+                        .push(("content-length".to_string(), data.len().to_string())),
+                    None => response.status = 404,
+                },
+                ("DELETE", Some(key)) => {
+                    self.objects.remove(&key);
+                    response.status = 204;
+                }
+                _ => response.status = 405,
+            }
+            response
+        }
+
+        // Lists two keys per page, to make sure the store follows continuation tokens.
+        fn list(&self, query: &HashMap<String, String>) -> String {
+            let prefix = query.get("prefix").cloned().unwrap_or_default();
+            let after = query.get("continuation-token").cloned().unwrap_or_default();
+            let mut keys = self
+                .objects
+                .iter()
+                .filter(|(key, _)| key.starts_with(&prefix) && **key > after);
+
+            let mut body = String::from("<ListBucketResult>");
+            let mut last = None;
+            for (key, data) in keys.by_ref().take(2) {
+                body.push_str(&format!(
+                    "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                    key,
+                    data.len()
+                ));
+                last = Some(key.clone());
+            }
+            if keys.next().is_some() {
+                body.push_str(&format!(
+                    "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+                    last.unwrap()
+                ));
+            } else {
+                body.push_str("<IsTruncated>false</IsTruncated>");
+            }
+            body.push_str("</ListBucketResult>");
+            body
+        }
+    }
+
+    fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                decoded.push(u8::from_str_radix(&value[i + 1..i + 3], 16).unwrap());
+                i += 3;
+            } else {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    fn credentials() -> S3Credentials {
+        S3Credentials {
+            access_key: "test".to_string(),
+            secret_key: "secret".to_string(),
+        }
+    }
+
+    async fn make_s3_store() -> (S3Store, MockGuard) {
+        let (endpoint, guard) = start_mock_s3().await;
+        let store = S3Store::new(&endpoint, "bucket", credentials())
+            .unwrap()
+            .with_prefix("blocks/");
+        (store, guard)
+    }
+
+    crate::conformance::conformance_tests!(make_s3_store);
+
+    #[test]
+    fn should_sign_like_aws() {
+        // The worked example from the AWS Signature Version 4 documentation.
+        let credentials = S3Credentials {
+            access_key: "AKIDEXAMPLE".to_string(),
+            secret_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+        };
+        let headers = [
+            (
+                "content-type".to_string(),
+                "application/x-www-form-urlencoded; charset=utf-8".to_string(),
+            ),
+            ("host".to_string(), "iam.amazonaws.com".to_string()),
+            ("x-amz-date".to_string(), "20150830T123600Z".to_string()),
+        ];
+
+        let authorization = authorization(
+            &credentials,
+            "us-east-1",
+            "iam",
+            &amz_date(UNIX_EPOCH + Duration::from_secs(1_440_938_160)),
+            "GET",
+            "/",
+            &canonical_query(&[("Version", "2010-05-08"), ("Action", "ListUsers")]),
+            &headers,
+            &hex(&Sha256::digest(b"")),
+        );
+
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_upload_large_blocks_in_parts() {
+        let (store, guard) = make_s3_store().await;
+        let store = store.with_multipart(1_000, 300, 2);
+        let small = make_random_block(1_000);
+        let large = make_random_block(1_001);
+
+        store.put_block(&small).await.unwrap();
+        store.put_block(&large).await.unwrap();
+
+        assert_eq!(guard.state.lock().unwrap().completed_uploads, 1);
+        assert_eq!(
+            store.get_block(&large.cid).await.unwrap().unwrap().data,
+            large.data
+        );
+        assert_eq!(
+            store.get_block(&small.cid).await.unwrap().unwrap().data,
+            small.data
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_retry_failed_requests() {
+        let (store, guard) = make_s3_store().await;
+        let store = store.with_retries(2, Duration::from_millis(1));
+        let block = make_random_block(100);
+
+        guard.state.lock().unwrap().failures = 2;
+        store.put_block(&block).await.unwrap();
+        assert!(store.has_block(&block.cid).await);
+
+        guard.state.lock().unwrap().failures = 3;
+        let err = store.get_block(&block.cid).await.unwrap_err();
+        assert!(err.to_string().contains("503"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_prefixes_apart() {
+        let (endpoint, guard) = start_mock_s3().await;
+        let first = S3Store::new(&endpoint, "bucket", credentials())
+            .unwrap()
+            .with_prefix("first/");
+        let second = S3Store::new(&endpoint, "bucket", credentials())
+            .unwrap()
+            .with_prefix("second/");
+        let block = make_random_block(100);
+
+        first.put_block(&block).await.unwrap();
+
+        assert!(first.has_block(&block.cid).await);
+        assert!(!second.has_block(&block.cid).await);
+        assert_eq!(second.stats().await.unwrap().blocks, 0);
+        assert!(
+            guard
+                .state
+                .lock()
+                .unwrap()
+                .objects
+                .contains_key(&format!("first/{}", block.cid))
+        );
+    }
+
+    #[test]
+    fn should_format_amz_dates() {
+        assert_eq!(amz_date(UNIX_EPOCH), "19700101T000000Z");
+        assert_eq!(
+            amz_date(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "20000229T000000Z"
+        );
+    }
+}
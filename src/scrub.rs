@@ -0,0 +1,236 @@
+use std::io;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use bytes::Bytes;
+use cid::Cid;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, FSStore};
+
+/// What [`FSStore::scrub`] does with blocks it finds corrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScrubAction {
+    /// Leave them be, just report them.
+    #[default]
+    Report,
+    /// Delete them.
+    Delete,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Blocks whose contents were hashed and compared against their CID.
+    pub checked: u64,
+    /// Blocks using a hash function we don't support, which couldn't be checked.
+    pub unverifiable: u64,
+    /// Blocks whose contents didn't match their CID, whatever happened to them next.
+    pub corrupted: Vec<Cid>,
+    /// Corrupted blocks that were replaced by a good copy.
+    pub repaired: Vec<Cid>,
+    /// Corrupted blocks that were deleted.
+    pub deleted: Vec<Cid>,
+}
+
+impl FSStore {
+    /// Reads every block in the store and checks that it still hashes to the CID its path says
+    /// it has. Blocks that don't are reported, and dealt with according to `action`.
+    pub async fn scrub(&self, action: ScrubAction) -> Result<ScrubReport, io::Error> {
+        self.scrub_with(None::<&FSStore>, action).await
+    }
+
+    /// Like [`FSStore::scrub`], but first tries to replace corrupted blocks with a good copy
+    /// from `source`. Only if that has no copy, or a corrupted one too, does `action` apply.
+    pub async fn scrub_and_repair<S: Blockstore>(
+        &self,
+        source: &S,
+        action: ScrubAction,
+    ) -> Result<ScrubReport, io::Error> {
+        self.scrub_with(Some(source), action).await
+    }
+
+    async fn scrub_with<S: Blockstore>(
+        &self,
+        source: Option<&S>,
+        action: ScrubAction,
+    ) -> Result<ScrubReport, io::Error> {
+        let mut report = ScrubReport::default();
+        let mut cids = self.blocks();
+        while let Some(cid) = cids.recv().await {
+            let cid = cid?;
+            let data = match tokio::fs::read(self.block_path(&cid)).await {
+                Ok(data) => Bytes::from(data),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => match self.packed_block(&cid) {
+                    Some(data) => data,
+                    // Deleted since it was listed.
+                    None => continue,
+                },
+                Err(e) => return Err(e),
+            };
+
+            match Block::hash_matches(&cid, &data) {
+                Some(true) => {
+                    report.checked += 1;
+                    continue;
+                }
+                Some(false) => report.checked += 1,
+                None => {
+                    report.unverifiable += 1;
+                    continue;
+                }
+            }
+            report.corrupted.push(cid);
+
+            if let Some(source) = source
+                && self.repair(&cid, source).await?
+            {
+                report.repaired.push(cid);
+                continue;
+            }
+            if action == ScrubAction::Delete {
+                self.del_block(&cid).await?;
+                report.deleted.push(cid);
+            }
+        }
+
+        Ok(report)
+    }
+
+    // Replaces a corrupted block with the copy in `source`, if it has a good one.
+    async fn repair<S: Blockstore>(&self, cid: &Cid, source: &S) -> Result<bool, io::Error> {
+        let Some(block) = source.get_block(cid).await? else {
+            return Ok(false);
+        };
+        if Block::hash_matches(cid, &block.data) != Some(true) {
+            return Ok(false);
+        }
+
+        // Puts never overwrite a block that's already there, so the bad copy has to go first.
+        self.del_block(cid).await?;
+        self.put_block(&block).await?;
+        Ok(true)
+    }
+
+    /// Spawns a task that calls [`FSStore::scrub`] every `interval`, handing the outcome of each
+    /// run to the returned [`Scrubber`]. The task stops when that is dropped, or once the store
+    /// itself is.
+    pub fn start_scrubber(self: &Arc<Self>, interval: Duration, action: ScrubAction) -> Scrubber {
+        let store: Weak<Self> = Arc::downgrade(self);
+        let (sender, reports) = mpsc::channel(SCRUB_REPORTS_BUFFER);
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                // If nobody's been collecting reports, we'd rather drop new ones than stall.
+                let _ = sender.try_send(store.scrub(action).await);
+            }
+        });
+
+        Scrubber { task, reports }
+    }
+}
+
+const SCRUB_REPORTS_BUFFER: usize = 16;
+
+/// Handle to the task started by [`FSStore::start_scrubber`].
+pub struct Scrubber {
+    task: JoinHandle<()>,
+    reports: mpsc::Receiver<Result<ScrubReport, io::Error>>,
+}
+
+impl Scrubber {
+    /// Waits for the next scrub to finish, returning its report.
+    pub async fn next_report(&mut self) -> Option<Result<ScrubReport, io::Error>> {
+        self.reports.recv().await
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_report_corrupted_blocks() {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks).await.unwrap();
+        fs::write(store.block_path(&blocks[1].cid), b"bit rot").unwrap();
+
+        let report = store.scrub(ScrubAction::Report).await.unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.corrupted, vec![blocks[1].cid]);
+        assert!(report.deleted.is_empty());
+        assert!(store.has_block(&blocks[1].cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_delete_corrupted_blocks() {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let block = make_random_block(1_000);
+        store.put_block(&block).await.unwrap();
+        fs::write(store.block_path(&block.cid), b"bit rot").unwrap();
+
+        let report = store.scrub(ScrubAction::Delete).await.unwrap();
+        assert_eq!(report.deleted, vec![block.cid]);
+        assert!(!store.has_block(&block.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_repair_corrupted_blocks_from_source() {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let blocks: Vec<Block> = (0..2).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks).await.unwrap();
+        for block in &blocks {
+            fs::write(store.block_path(&block.cid), b"bit rot").unwrap();
+        }
+        // Only the first block has a backup to repair it from.
+        let backup = MemStore::new();
+        backup.put_block(&blocks[0]).await.unwrap();
+
+        let report = store
+            .scrub_and_repair(&backup, ScrubAction::Delete)
+            .await
+            .unwrap();
+        assert_eq!(report.corrupted.len(), 2);
+        assert_eq!(report.repaired, vec![blocks[0].cid]);
+        assert_eq!(report.deleted, vec![blocks[1].cid]);
+
+        let repaired = fs::read(store.block_path(&blocks[0].cid)).unwrap();
+        assert_eq!(repaired, blocks[0].data);
+        assert_eq!(store.stats().await.unwrap().blocks, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_scrub_in_background() {
+        let root = tempdir().unwrap();
+        let store = Arc::new(FSStore::create(PathBuf::from(root.path())).await.unwrap());
+        let block = make_random_block(1_000);
+        store.put_block(&block).await.unwrap();
+        fs::write(store.block_path(&block.cid), b"bit rot").unwrap();
+
+        let mut scrubber = store.start_scrubber(Duration::from_millis(10), ScrubAction::Delete);
+        let report = scrubber.next_report().await.unwrap().unwrap();
+        assert_eq!(report.deleted, vec![block.cid]);
+        let report = scrubber.next_report().await.unwrap().unwrap();
+        assert_eq!(report, ScrubReport::default());
+    }
+}
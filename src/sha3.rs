@@ -0,0 +1,141 @@
+//! SHA3-256 (FIPS 202), for [`crate::block::Hasher::Sha3_256`].
+
+const ROUND_CONSTANTS: [u64; 24] = [
+    0x0000_0000_0000_0001,
+    0x0000_0000_0000_8082,
+    0x8000_0000_0000_808a,
+    0x8000_0000_8000_8000,
+    0x0000_0000_0000_808b,
+    0x0000_0000_8000_0001,
+    0x8000_0000_8000_8081,
+    0x8000_0000_0000_8009,
+    0x0000_0000_0000_008a,
+    0x0000_0000_0000_0088,
+    0x0000_0000_8000_8009,
+    0x0000_0000_8000_000a,
+    0x0000_0000_8000_808b,
+    0x8000_0000_0000_008b,
+    0x8000_0000_0000_8089,
+    0x8000_0000_0000_8003,
+    0x8000_0000_0000_8002,
+    0x8000_0000_0000_0080,
+    0x0000_0000_0000_800a,
+    0x8000_0000_8000_000a,
+    0x8000_0000_8000_8081,
+    0x8000_0000_0000_8080,
+    0x0000_0000_8000_0001,
+    0x8000_0000_8000_8008,
+];
+
+// Rotation amounts and destination lanes for the combined rho and pi steps, following lane 1
+// around the cycle that visits all 24 lanes other than lane 0.
+const ROTATIONS: [u32; 24] = [
+    1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+];
+const LANES: [usize; 24] = [
+    10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+];
+
+// 1600 bits of state minus twice the output size.
+const RATE: usize = 136;
+
+pub fn sha3_256(data: &[u8]) -> [u8; 32] {
+    let mut state = [0u64; 25];
+
+    let mut chunks = data.chunks_exact(RATE);
+    for chunk in &mut chunks {
+        absorb(&mut state, chunk);
+        keccak_f(&mut state);
+    }
+
+    let rest = chunks.remainder();
+    let mut last = [0u8; RATE];
+    last[..rest.len()].copy_from_slice(rest);
+    // The SHA3 domain separation bits, then the start and end of the pad10*1 padding.
+    last[rest.len()] ^= 0x06;
+    last[RATE - 1] ^= 0x80;
+    absorb(&mut state, &last);
+    keccak_f(&mut state);
+
+    let mut digest = [0u8; 32];
+    for (bytes, lane) in digest.chunks_mut(8).zip(state) {
+        bytes.copy_from_slice(&lane.to_le_bytes());
+    }
+    digest
+}
+
+fn absorb(state: &mut [u64; 25], block: &[u8]) {
+    for (lane, bytes) in state.iter_mut().zip(block.chunks(8)) {
+        *lane ^= u64::from_le_bytes(bytes.try_into().unwrap());
+    }
+}
+
+fn keccak_f(state: &mut [u64; 25]) {
+    for round_constant in ROUND_CONSTANTS {
+        // Theta: mix each column's parity into its neighbours.
+        let mut parity = [0u64; 5];
+        for (x, parity) in parity.iter_mut().enumerate() {
+            *parity = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+        }
+        for x in 0..5 {
+            let mix = parity[(x + 4) % 5] ^ parity[(x + 1) % 5].rotate_left(1);
+            for y in 0..5 {
+                state[x + 5 * y] ^= mix;
+            }
+        }
+
+        // Rho and pi: rotate each lane and move it to its new position.
+        let mut carried = state[1];
+        for (lane, rotation) in LANES.into_iter().zip(ROTATIONS) {
+            let displaced = state[lane];
+            state[lane] = carried.rotate_left(rotation);
+            carried = displaced;
+        }
+
+        // Chi: the only non-linear step, row by row.
+        for y in 0..5 {
+            let row: [u64; 5] = state[5 * y..5 * y + 5].try_into().unwrap();
+            for x in 0..5 {
+                state[5 * y + x] = row[x] ^ (!row[(x + 1) % 5] & row[(x + 2) % 5]);
+            }
+        }
+
+        // Iota.
+        state[0] ^= round_constant;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn should_match_known_digests() {
+        assert_eq!(
+            hex(&sha3_256(b"")),
+            "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a"
+        );
+        assert_eq!(
+            hex(&sha3_256(b"abc")),
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"
+        );
+    }
+
+    #[test]
+    fn should_hash_across_block_boundaries() {
+        let data: Vec<u8> = (0..1_000).map(|i| (i % 251) as u8).collect();
+        // Exactly one block's worth, which needs a whole extra block for the padding.
+        assert_eq!(
+            hex(&sha3_256(&data[..RATE])),
+            "cf3ccff92480a29160c2d38317c430e14749bfee1788106957dfe73f8c4930e5"
+        );
+        assert_eq!(
+            hex(&sha3_256(&data)),
+            "48e66a01861d0eadaacdb7a6ae7db6b9ac79242ecced4154a9fbb33c4e3cc571"
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! Spreading blocks over several stores, such as to make use of more than one disk.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use cid::Cid;
+use tokio::sync::{Mutex as AsyncMutex, RwLock as AsyncRwLock};
+use tokio::task::JoinHandle;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+use crate::mirrored::merge_listings;
+
+/// Keeps each block in just one of several stores, the shards, picked by rendezvous hashing on
+/// the block's multihash: the shard that scores highest for a block gets it. Adding a shard
+/// only moves the blocks that now score highest on it, which [`ShardedStore::add_shard`] does
+/// in the background.
+///
+/// Which shard a block goes to only depends on the block and on the shard's position, so the
+/// shards must always be given in the same order, with new ones added at the end.
+pub struct ShardedStore<S> {
+    layout: RwLock<Layout<S>>,
+    // The last generation of the layout that every block is known to be on the right shard
+    // for. Until the current one is, blocks missing from their shard get looked for on the
+    // others.
+    settled: AtomicU64,
+    // Moving a block takes the write side, and deleting one the read side, so that a block
+    // getting deleted can't be moved back in.
+    moves: AsyncRwLock<()>,
+    // Held for the whole of a rebalance, so that only one runs at a time.
+    rebalancing: AsyncMutex<()>,
+}
+
+struct Layout<S> {
+    shards: Vec<Arc<S>>,
+    // Bumped for every shard added.
+    generation: u64,
+}
+
+impl<S: Blockstore> ShardedStore<S> {
+    /// Spreads blocks over `shards`, of which there must be at least one. Blocks already in
+    /// them are taken to be on the right shard; if they might not be, as after a rebalance got
+    /// interrupted, call [`ShardedStore::rebalance`].
+    pub fn new(shards: Vec<S>) -> Self {
+        assert!(!shards.is_empty(), "need at least one shard");
+        ShardedStore {
+            layout: RwLock::new(Layout {
+                shards: shards.into_iter().map(Arc::new).collect(),
+                generation: 0,
+            }),
+            settled: AtomicU64::new(0),
+            moves: AsyncRwLock::new(()),
+            rebalancing: AsyncMutex::new(()),
+        }
+    }
+
+    pub fn shards(&self) -> Vec<Arc<S>> {
+        self.layout.read().unwrap().shards.clone()
+    }
+
+    /// The position of the shard `cid` belongs on.
+    pub fn shard_for(&self, cid: &Cid) -> usize {
+        pick(cid, self.layout.read().unwrap().shards.len())
+    }
+
+    /// Moves every block that isn't on the shard it belongs on to that shard, returning how
+    /// many were moved. Waits for any rebalance already running to be done or give up first.
+    pub async fn rebalance(&self) -> Result<u64, io::Error> {
+        self.move_misplaced(&AtomicU64::new(0)).await
+    }
+
+    async fn move_misplaced(&self, moved: &AtomicU64) -> Result<u64, io::Error> {
+        let _rebalancing = self.rebalancing.lock().await;
+        let (shards, generation) = {
+            let layout = self.layout.read().unwrap();
+            (layout.shards.clone(), layout.generation)
+        };
+        for (index, shard) in shards.iter().enumerate() {
+            let mut cids = shard.blocks();
+            while let Some(cid) = cids.recv().await {
+                let cid = cid?;
+                let owner = pick(&cid, shards.len());
+                if owner == index {
+                    continue;
+                }
+
+                let _move = self.moves.write().await;
+                // A shard added since makes this layout out of date, and a rebalance for the new
+                // one is waiting to redo all of this anyway.
+                if self.layout.read().unwrap().generation != generation {
+                    return Ok(moved.load(Ordering::Relaxed));
+                }
+                // Someone else may have deleted it since.
+                let Some(block) = shard.get_block(&cid).await? else {
+                    continue;
+                };
+                shards[owner].put_block(&block).await?;
+                shard.del_block_if_present(&cid).await?;
+                moved.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.settled.fetch_max(generation, Ordering::Relaxed);
+        Ok(moved.load(Ordering::Relaxed))
+    }
+
+    // The shard `cid` belongs on, and if some blocks might not be on theirs yet, the others.
+    fn route(&self, cid: &Cid) -> (Arc<S>, Vec<Arc<S>>) {
+        let layout = self.layout.read().unwrap();
+        let owner = pick(cid, layout.shards.len());
+        let mut others = Vec::new();
+        if self.settled.load(Ordering::Relaxed) < layout.generation {
+            let shards = layout.shards.iter().enumerate();
+            others.extend(
+                shards
+                    .filter(|(i, _)| *i != owner)
+                    .map(|(_, shard)| shard.clone()),
+            );
+        }
+        (layout.shards[owner].clone(), others)
+    }
+
+    // Whether `cid` is on a shard other than the one it belongs on, as it can only be while
+    // blocks are being moved.
+    async fn on_other_shard(&self, cid: &Cid) -> bool {
+        for shard in self.route(cid).1 {
+            if shard.has_block(cid).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Splits `cids` up by the shard they belong on, keeping track of where each one came from.
+    fn split(&self, cids: &[Cid]) -> Vec<(Arc<S>, Vec<usize>, Vec<Cid>)> {
+        let layout = self.layout.read().unwrap();
+        let mut split: HashMap<usize, (Vec<usize>, Vec<Cid>)> = HashMap::new();
+        for (i, cid) in cids.iter().enumerate() {
+            let (positions, cids) = split.entry(pick(cid, layout.shards.len())).or_default();
+            positions.push(i);
+            cids.push(*cid);
+        }
+        split
+            .into_iter()
+            .map(|(shard, (positions, cids))| (layout.shards[shard].clone(), positions, cids))
+            .collect()
+    }
+}
+
+impl<S: Blockstore + 'static> ShardedStore<S> {
+    /// Adds `shard` after the others, and spawns a task that moves the blocks that now belong
+    /// on it there. Blocks can be read, put and deleted as usual while that goes on. The task
+    /// stops when the returned handle is dropped, leaving the rest of the blocks where they
+    /// were until the next rebalance: they can still be read, only more slowly.
+    pub fn add_shard(self: &Arc<Self>, shard: S) -> Rebalance {
+        {
+            let mut layout = self.layout.write().unwrap();
+            layout.shards.push(Arc::new(shard));
+            layout.generation += 1;
+        }
+
+        let moved = Arc::new(AtomicU64::new(0));
+        let store = self.clone();
+        let task = tokio::spawn({
+            let moved = moved.clone();
+            async move { store.move_misplaced(&moved).await }
+        });
+        Rebalance { task, moved }
+    }
+}
+
+/// Handle to the task started by [`ShardedStore::add_shard`]. Dropping it aborts the task.
+pub struct Rebalance {
+    task: JoinHandle<Result<u64, io::Error>>,
+    moved: Arc<AtomicU64>,
+}
+
+impl Rebalance {
+    /// How many blocks the task has moved so far.
+    pub fn moved(&self) -> u64 {
+        self.moved.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the task to be done, returning how many blocks it moved.
+    pub async fn wait(mut self) -> Result<u64, io::Error> {
+        (&mut self.task).await?
+    }
+}
+
+impl Drop for Rebalance {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Rendezvous hashing: the shard that scores highest for `cid`. Scores have to come out the
+// same in every build, since they decide where blocks live, so they're FNV-1a, which is
+// simple enough to spell out, followed by a finalizer to spread the bits.
+fn pick(cid: &Cid, shards: usize) -> usize {
+    let key = cid.hash().to_bytes();
+    let score = |shard: usize| {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in (shard as u64).to_le_bytes().iter().chain(&key) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        hash ^ (hash >> 31)
+    };
+    (0..shards).max_by_key(|shard| score(*shard)).unwrap()
+}
+
+impl<S: Blockstore> Blockstore for ShardedStore<S> {
+    /// While blocks are being moved, a block that's still on the shard it's moving from counts
+    /// as already there, and is left for the move to take care of.
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        if self.on_other_shard(&block.cid).await {
+            return Ok(Put::Existing);
+        }
+        let (owner, _) = self.route(&block.cid);
+        owner.put_block(block).await
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        let mut missing = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            if !self.on_other_shard(&block.cid).await {
+                missing.push(block);
+            }
+        }
+        let cids: Vec<Cid> = missing.iter().map(|block| block.cid).collect();
+        for (shard, positions, _) in self.split(&cids) {
+            let batch: Vec<Block> = positions.iter().map(|i| missing[*i].clone()).collect();
+            shard.put_many(&batch).await?;
+        }
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        let (owner, _) = self.route(cid);
+        owner.has_block(cid).await || self.on_other_shard(cid).await
+    }
+
+    async fn has_many(&self, cids: &[Cid]) -> Vec<bool> {
+        let mut found = vec![false; cids.len()];
+        for (shard, positions, batch) in self.split(cids) {
+            for (i, has) in positions.into_iter().zip(shard.has_many(&batch).await) {
+                found[i] = has;
+            }
+        }
+        // Anything not found on its shard might not have been moved there yet.
+        for (i, cid) in cids.iter().enumerate() {
+            if !found[i] {
+                found[i] = self.on_other_shard(cid).await;
+            }
+        }
+        found
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        let (owner, others) = self.route(cid);
+        if let Some(block) = owner.get_block(cid).await? {
+            return Ok(Some(block));
+        }
+        for shard in others {
+            if let Some(block) = shard.get_block(cid).await? {
+                return Ok(Some(block));
+            }
+        }
+        Ok(None)
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        for (shard, _, batch) in self.split(cids) {
+            shard.prefetch(&batch);
+        }
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        let (owner, others) = self.route(cid);
+        if let Some(size) = owner.block_size(cid).await? {
+            return Ok(Some(size));
+        }
+        for shard in others {
+            if let Some(size) = shard.block_size(cid).await? {
+                return Ok(Some(size));
+            }
+        }
+        Ok(None)
+    }
+
+    /// While blocks are being moved, deletes the block from every shard it's on, as it can be
+    /// on the one it's moving from as well as the one it belongs on.
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        let _moves = self.moves.read().await;
+        let (owner, others) = self.route(cid);
+        let mut found = owner.del_block_if_present(cid).await?;
+        for shard in others {
+            found |= shard.del_block_if_present(cid).await?;
+        }
+        match found {
+            true => Ok(()),
+            false => Err(BlockstoreError::NotFound(*cid)),
+        }
+    }
+
+    /// Lists the blocks on every shard, each once, even if it's on two while being moved.
+    fn blocks(&self) -> CidStream {
+        merge_listings(self.shards().iter().map(|shard| shard.blocks()).collect())
+    }
+
+    /// Adds up the stats of all shards. A block that's in the middle of being moved can get
+    /// counted twice.
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let mut stats = StoreStats::default();
+        for shard in self.shards() {
+            let shard = shard.stats().await?;
+            stats.blocks += shard.blocks;
+            stats.bytes += shard.bytes;
+            stats.disk_bytes += shard.disk_bytes;
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+
+    fn shards(count: usize) -> Vec<MemStore> {
+        (0..count).map(|_| MemStore::new()).collect()
+    }
+
+    async fn make_sharded_store() -> (ShardedStore<MemStore>, ()) {
+        (ShardedStore::new(shards(3)), ())
+    }
+
+    crate::conformance::conformance_tests!(make_sharded_store);
+
+    // Checks that every block is on just the shard it belongs on.
+    async fn assert_placed(store: &ShardedStore<MemStore>, blocks: &[Block]) {
+        for block in blocks {
+            let owner = store.shard_for(&block.cid);
+            for (index, shard) in store.shards().iter().enumerate() {
+                assert_eq!(shard.has_block(&block.cid).await, index == owner);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_spread_blocks_over_shards() {
+        let store = ShardedStore::new(shards(4));
+        let blocks: Vec<Block> = (0..400).map(|_| make_random_block(10)).collect();
+        store.put_many(&blocks[..200]).await.unwrap();
+        for block in &blocks[200..] {
+            store.put_block(block).await.unwrap();
+        }
+
+        assert_placed(&store, &blocks).await;
+        for shard in store.shards() {
+            assert!(shard.len() > 50, "only {} blocks on a shard", shard.len());
+        }
+        let stats = store.stats().await.unwrap();
+        assert_eq!((stats.blocks, stats.bytes), (400, 4_000));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_rebalance_onto_added_shard() {
+        let store = Arc::new(ShardedStore::new(shards(2)));
+        let blocks: Vec<Block> = (0..300).map(|_| make_random_block(10)).collect();
+        store.put_many(&blocks).await.unwrap();
+        let before: Vec<usize> = blocks.iter().map(|b| store.shard_for(&b.cid)).collect();
+
+        let moved = store.add_shard(MemStore::new()).wait().await.unwrap();
+        assert_placed(&store, &blocks).await;
+        // Blocks only ever move to the new shard.
+        for (block, before) in blocks.iter().zip(before) {
+            let after = store.shard_for(&block.cid);
+            assert!(after == before || after == 2);
+        }
+        assert_eq!(moved, store.shards()[2].len() as u64);
+        assert!(moved > 50, "only {} blocks moved", moved);
+        assert_eq!(store.stats().await.unwrap().blocks, 300);
+        assert_eq!(store.rebalance().await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_find_blocks_not_moved_yet() {
+        let store = Arc::new(ShardedStore::new(shards(2)));
+        let blocks: Vec<Block> = (0..300).map(|_| make_random_block(10)).collect();
+        store.put_many(&blocks).await.unwrap();
+
+        // Aborting the rebalance before it gets going leaves blocks on the shards they used to
+        // belong on.
+        let paused = store.rebalancing.lock().await;
+        drop(store.add_shard(MemStore::new()));
+        drop(paused);
+        assert_eq!(store.shards()[2].len(), 0);
+        for block in &blocks {
+            assert!(store.has_block(&block.cid).await);
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), *block);
+        }
+        let cids: Vec<Cid> = blocks.iter().map(|block| block.cid).collect();
+        assert!(store.has_many(&cids).await.into_iter().all(|found| found));
+        store.del_block(&blocks[0].cid).await.unwrap();
+        assert!(!store.has_block(&blocks[0].cid).await);
+        // Putting a block that's yet to be moved finds it where it is.
+        let mut unmoved = None;
+        for block in &blocks[1..] {
+            let owner = &store.shards()[store.shard_for(&block.cid)];
+            if !owner.has_block(&block.cid).await {
+                unmoved = Some(block);
+                break;
+            }
+        }
+        let unmoved = unmoved.unwrap();
+        assert_eq!(store.put_block(unmoved).await.unwrap(), Put::Existing);
+        assert!(!store.shards()[2].has_block(&unmoved.cid).await);
+
+        store.rebalance().await.unwrap();
+        assert_placed(&store, &blocks[1..]).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_rebalance_onto_shards_added_together() {
+        let store = Arc::new(ShardedStore::new(shards(2)));
+        let blocks: Vec<Block> = (0..300).map(|_| make_random_block(10)).collect();
+        store.put_many(&blocks).await.unwrap();
+
+        let first = store.add_shard(MemStore::new());
+        let second = store.add_shard(MemStore::new());
+        first.wait().await.unwrap();
+        second.wait().await.unwrap();
+        assert_placed(&store, &blocks).await;
+        for block in &blocks {
+            assert!(store.has_block(&block.cid).await);
+        }
+    }
+}
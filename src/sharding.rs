@@ -0,0 +1,287 @@
+//! How [`FSStore`] spreads blocks over directories, so that no single one ends up with more
+//! entries than the filesystem handles well.
+
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{fs, io};
+
+use cid::Cid;
+
+use crate::block::{Codec, to_v1};
+use crate::blockstore::FSStore;
+use crate::flatfs;
+
+/// Name of the file in an [`FSStore`]'s root recording the [`ShardingStrategy::id`] it was
+/// created with. Stores using [`FlatFs`] record it in Kubo's `SHARDING` file instead.
+pub const SHARDING_FILE: &str = ".sharding";
+
+/// Decides where [`FSStore`] keeps each block under its root.
+pub trait ShardingStrategy: Send + Sync + Debug {
+    /// Identifies the strategy and its parameters, e.g. `prefix/15`. This is what gets persisted,
+    /// so that reopening a store uses the layout it was created with.
+    fn id(&self) -> String;
+
+    /// Where the block `cid` lives, relative to the store's root.
+    fn block_path(&self, cid: &Cid) -> PathBuf;
+
+    /// The block whose file is at `path`, relative to the store's root. Returns `None` for files
+    /// that aren't meant to be blocks, and an error for ones that should be but aren't valid.
+    /// Dot-prefixed entries are always skipped before this gets asked.
+    fn cid_from_path(&self, path: &Path) -> Option<Result<Cid, io::Error>>;
+
+    /// The CID a block is filed under. CIDs that map to the same file must normalize to the
+    /// same CID, which keys anything the store keeps per block file. By default, CIDv0s are
+    /// filed under their v1 equivalent.
+    fn normalize(&self, cid: &Cid) -> Cid {
+        to_v1(cid)
+    }
+}
+
+/// Each block is a file named after its CIDv1, chopped into nested directories of
+/// `chars_per_level` characters. The default, with 15 characters per level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    pub chars_per_level: usize,
+}
+
+impl Default for Prefix {
+    fn default() -> Self {
+        Prefix {
+            chars_per_level: DEFAULT_CHARS_PER_LEVEL,
+        }
+    }
+}
+
+const DEFAULT_CHARS_PER_LEVEL: usize = 15;
+
+impl ShardingStrategy for Prefix {
+    fn id(&self) -> String {
+        format!("prefix/{}", self.chars_per_level)
+    }
+
+    fn block_path(&self, cid: &Cid) -> PathBuf {
+        FSStore::block_path_raw(self.chars_per_level, &to_v1(cid))
+    }
+
+    fn cid_from_path(&self, path: &Path) -> Option<Result<Cid, io::Error>> {
+        let name: String = path.iter().map(|part| part.to_string_lossy()).collect();
+        Some(parse_cid(&name))
+    }
+}
+
+/// Each block is a file named after its CIDv1, in a directory named after the CID's last
+/// `chars` characters. Those are spread more evenly than the first few, which are the same for
+/// every CID with the same version, codec and hash function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Suffix {
+    pub chars: usize,
+}
+
+impl ShardingStrategy for Suffix {
+    fn id(&self) -> String {
+        format!("suffix/{}", self.chars)
+    }
+
+    fn block_path(&self, cid: &Cid) -> PathBuf {
+        let name = to_v1(cid).to_string();
+        let shard = &name[name.len().saturating_sub(self.chars)..];
+        Path::new(shard).join(&name)
+    }
+
+    fn cid_from_path(&self, path: &Path) -> Option<Result<Cid, io::Error>> {
+        Some(file_cid(path, 2))
+    }
+}
+
+/// Each block is a file named after its CIDv1, two directories deep, with `fanout` directories
+/// at each level. Which ones a block goes in is picked by hashing its CID.
+///
+/// `fanout` must not be zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TwoLevel {
+    pub fanout: u32,
+}
+
+impl ShardingStrategy for TwoLevel {
+    fn id(&self) -> String {
+        format!("two-level/{}", self.fanout)
+    }
+
+    fn block_path(&self, cid: &Cid) -> PathBuf {
+        let cid = to_v1(cid);
+        let hash = fnv1a(&cid.to_bytes());
+        let fanout = self.fanout as u64;
+        let width = format!("{:x}", self.fanout.saturating_sub(1)).len();
+        let first = format!("{:0width$x}", hash % fanout, width = width);
+        let second = format!("{:0width$x}", (hash / fanout) % fanout, width = width);
+        [first, second, cid.to_string()].iter().collect()
+    }
+
+    fn cid_from_path(&self, path: &Path) -> Option<Result<Cid, io::Error>> {
+        Some(file_cid(path, 3))
+    }
+}
+
+/// Every block is a file named after its CIDv1, right in the root. Only sensible for small
+/// stores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flat;
+
+impl ShardingStrategy for Flat {
+    fn id(&self) -> String {
+        "flat".to_string()
+    }
+
+    fn block_path(&self, cid: &Cid) -> PathBuf {
+        PathBuf::from(to_v1(cid).to_string())
+    }
+
+    fn cid_from_path(&self, path: &Path) -> Option<Result<Cid, io::Error>> {
+        Some(file_cid(path, 1))
+    }
+}
+
+/// The layout of a Kubo repo's `blocks/` directory: flatfs with the `next-to-last/2` sharding
+/// function. Files are named after multihashes alone, so any CID with a block's multihash finds
+/// it, and blocks are listed as raw CIDv1s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlatFs;
+
+impl ShardingStrategy for FlatFs {
+    fn id(&self) -> String {
+        flatfs::NEXT_TO_LAST_2.to_string()
+    }
+
+    fn block_path(&self, cid: &Cid) -> PathBuf {
+        flatfs::block_path(cid.hash())
+    }
+
+    fn cid_from_path(&self, path: &Path) -> Option<Result<Cid, io::Error>> {
+        flatfs::cid_from_path(path)
+    }
+
+    fn normalize(&self, cid: &Cid) -> Cid {
+        Cid::new_v1(Codec::Raw.code(), *cid.hash())
+    }
+}
+
+/// Looks up a built-in strategy by its [`ShardingStrategy::id`].
+pub fn from_id(id: &str) -> Option<Arc<dyn ShardingStrategy>> {
+    if id == flatfs::NEXT_TO_LAST_2 {
+        return Some(Arc::new(FlatFs));
+    }
+    if id == "flat" {
+        return Some(Arc::new(Flat));
+    }
+
+    let (name, param) = id.split_once('/')?;
+    let param: usize = param.parse().ok().filter(|param| *param > 0)?;
+    match name {
+        "prefix" => Some(Arc::new(Prefix {
+            chars_per_level: param,
+        })),
+        "suffix" => Some(Arc::new(Suffix { chars: param })),
+        "two-level" => Some(Arc::new(TwoLevel {
+            fanout: param.try_into().ok()?,
+        })),
+        _ => None,
+    }
+}
+
+// Reads the id of the strategy the store at `root` was created with, if it recorded one.
+pub(crate) fn read_id(root: &Path) -> Result<Option<String>, io::Error> {
+    for file in [SHARDING_FILE, flatfs::SHARDING_FILE] {
+        match fs::read_to_string(root.join(file)) {
+            Ok(id) => return Ok(Some(id.trim().to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+// Works out the strategy of the store at `root` from its config file, if it has one.
+pub(crate) fn detect(root: &Path) -> Result<Option<Arc<dyn ShardingStrategy>>, io::Error> {
+    let Some(id) = read_id(root)? else {
+        return Ok(None);
+    };
+    from_id(&id).map(Some).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported sharding strategy {:?}", id),
+        )
+    })
+}
+
+// Records `sharding` as the strategy of the store at `root`.
+pub(crate) fn write_id(root: &Path, sharding: &dyn ShardingStrategy) -> Result<(), io::Error> {
+    let id = sharding.id();
+    let file = if id == flatfs::NEXT_TO_LAST_2 {
+        flatfs::SHARDING_FILE
+    } else {
+        SHARDING_FILE
+    };
+    fs::write(root.join(file), format!("{}\n", id))
+}
+
+fn parse_cid(name: &str) -> Result<Cid, io::Error> {
+    Cid::try_from(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Parses the file name at the end of `path`, which should be `depth` components long.
+fn file_cid(path: &Path, depth: usize) -> Result<Cid, io::Error> {
+    if path.iter().count() != depth {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected blocks {} levels deep", depth),
+        ));
+    }
+    parse_cid(&path.file_name().unwrap().to_string_lossy())
+}
+
+// 64-bit FNV-1a. Unlike std's hashers, it's guaranteed to stay the same, which matters for
+// anything that ends up on disk.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+
+    #[test]
+    fn should_round_trip_block_paths() {
+        let strategies: [Arc<dyn ShardingStrategy>; 5] = [
+            Arc::new(Prefix::default()),
+            Arc::new(Suffix { chars: 3 }),
+            Arc::new(TwoLevel { fanout: 256 }),
+            Arc::new(Flat),
+            Arc::new(FlatFs),
+        ];
+        let cid = make_random_block(100).cid;
+
+        for sharding in strategies {
+            let path = sharding.block_path(&cid);
+            let listed = sharding.cid_from_path(&path).unwrap().unwrap();
+            assert_eq!(listed, cid, "{:?} put it at {:?}", sharding, path);
+            assert_eq!(from_id(&sharding.id()).unwrap().id(), sharding.id());
+        }
+    }
+
+    #[test]
+    fn should_spread_two_level_shards() {
+        let sharding = TwoLevel { fanout: 16 };
+        let path = sharding.block_path(&make_random_block(100).cid);
+        let dirs: Vec<_> = path
+            .iter()
+            .take(2)
+            .map(|dir| dir.to_str().unwrap())
+            .collect();
+        assert!(dirs.iter().all(|dir| dir.len() == 1), "{:?}", path);
+        assert!(from_id("two-level/0").is_none());
+    }
+}
@@ -0,0 +1,439 @@
+//! Consistent snapshots of a store that keeps taking writes while they're made, and restoring
+//! stores from them.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+
+use cid::Cid;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, FSStore, Put, StoreStats};
+use crate::car::{self, CarReader};
+use crate::overlay::OverlayStore;
+
+// How many bytes worth of blocks a restore hands to the store in one go.
+const RESTORE_BATCH_BYTES: usize = 4 << 20;
+
+/// What a snapshot holds, for taking the next one incrementally with
+/// [`SnapshotStore::snapshot_since`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    /// Every block in the store when the snapshot was taken, whether it's in the snapshot's
+    /// archive or in an earlier one.
+    pub cids: HashSet<Cid>,
+    /// For incremental snapshots, the blocks deleted since the snapshot they were taken since.
+    pub deleted: Vec<Cid>,
+}
+
+impl Manifest {
+    /// Writes the manifest out as text, one CID per line, with deleted blocks marked by a `-`.
+    pub async fn write<W: AsyncWrite>(&self, writer: W) -> Result<(), io::Error> {
+        let writer = BufWriter::new(writer);
+        tokio::pin!(writer);
+        for cid in &self.cids {
+            writer.write_all(format!("{}\n", cid).as_bytes()).await?;
+        }
+        for cid in &self.deleted {
+            writer.write_all(format!("-{}\n", cid).as_bytes()).await?;
+        }
+        writer.flush().await
+    }
+
+    /// Reads a manifest written by [`Manifest::write`].
+    pub async fn read<R: AsyncRead>(reader: R) -> Result<Manifest, io::Error> {
+        let reader = BufReader::new(reader);
+        tokio::pin!(reader);
+        let mut manifest = Manifest::default();
+        let mut lines = reader.lines();
+        while let Some(line) = lines.next_line().await? {
+            let (deleted, cid) = match line.strip_prefix('-') {
+                Some(cid) => (true, cid),
+                None => (false, line.as_str()),
+            };
+            let cid = Cid::try_from(cid).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad CID {:?} in manifest: {}", cid, e),
+                )
+            })?;
+            match deleted {
+                true => manifest.deleted.push(cid),
+                false => {
+                    manifest.cids.insert(cid);
+                }
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// Wraps a [`Blockstore`] that [`SnapshotStore::snapshot`] can archive as a whole, without
+/// stopping writes and without them leaking into the archive.
+///
+/// While a snapshot is being written, puts and deletes are staged in an in-memory
+/// [`OverlayStore`] in front of the backing store, which is left alone until the snapshot is
+/// done and then gets the staged changes applied. Reads see the changes right away throughout.
+pub struct SnapshotStore<S> {
+    overlay: OverlayStore<S>,
+    // Whether writes go to the overlay, leaving the backing store as it was when the snapshot
+    // started. Writers hold it for reading while they write, so that switching has to wait
+    // for the writes already in flight.
+    frozen: RwLock<bool>,
+    // Snapshots are taken one at a time.
+    snapshotting: Mutex<()>,
+}
+
+impl<S: Blockstore> SnapshotStore<S> {
+    pub fn new(store: S) -> Self {
+        SnapshotStore {
+            overlay: OverlayStore::new(store),
+            frozen: RwLock::new(false),
+            snapshotting: Mutex::new(()),
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        self.overlay.base()
+    }
+
+    /// Writes every block in the store as of when this was called to `dest`, as a CARv1 file
+    /// with no roots, and returns the snapshot's manifest. Writes made in the meantime are
+    /// applied to the backing store once the archive is written. If applying them fails, they
+    /// stay staged, and get applied before the next snapshot starts.
+    pub async fn snapshot<W: AsyncWrite>(&self, dest: W) -> Result<Manifest, io::Error> {
+        self.export(&Manifest::default(), dest).await
+    }
+
+    /// Like [`SnapshotStore::snapshot`], but only archives the blocks that aren't in `since`,
+    /// the manifest of an earlier snapshot. The new manifest lists the blocks in `since` that
+    /// are gone by now as deleted.
+    pub async fn snapshot_since<W: AsyncWrite>(
+        &self,
+        since: &Manifest,
+        dest: W,
+    ) -> Result<Manifest, io::Error> {
+        self.export(since, dest).await
+    }
+
+    async fn export<W: AsyncWrite>(
+        &self,
+        since: &Manifest,
+        dest: W,
+    ) -> Result<Manifest, io::Error> {
+        let _snapshotting = self.snapshotting.lock().await;
+        self.freeze().await?;
+        let exported = async {
+            let mut manifest = Manifest::default();
+            let mut added = Vec::new();
+            let mut cids = self.overlay.base().blocks();
+            while let Some(cid) = cids.recv().await {
+                let cid = cid?;
+                if manifest.cids.insert(cid) && !since.cids.contains(&cid) {
+                    added.push(cid);
+                }
+            }
+            manifest.deleted = since.cids.difference(&manifest.cids).copied().collect();
+
+            car::export_car(self.overlay.base(), &[], added, dest).await?;
+            Ok::<_, io::Error>(manifest)
+        }
+        .await;
+        let thawed = self.thaw().await;
+        let manifest = exported?;
+        thawed?;
+        Ok(manifest)
+    }
+
+    async fn freeze(&self) -> Result<(), io::Error> {
+        let mut frozen = self.frozen.write().await;
+        // Left over from a snapshot that couldn't apply its writes, which this one shouldn't
+        // miss.
+        if *frozen {
+            self.overlay.commit().await?;
+        }
+        *frozen = true;
+        Ok(())
+    }
+
+    async fn thaw(&self) -> Result<(), io::Error> {
+        let mut frozen = self.frozen.write().await;
+        self.overlay.commit().await?;
+        *frozen = false;
+        Ok(())
+    }
+}
+
+/// What [`restore`] came up with.
+pub struct Restored {
+    pub store: FSStore,
+    /// How many blocks were restored.
+    pub blocks: u64,
+    /// The blocks whose data in the archive doesn't match their CIDs, which were left out.
+    pub corrupt: Vec<Cid>,
+}
+
+/// Rebuilds an [`FSStore`] at `dest_root`, creating it if needed, from an archive written by
+/// [`SnapshotStore::snapshot`], or by [`SnapshotStore::snapshot_since`] with [`restore_since`]
+/// doing the rest. Every block gets checked against its CID on the way in, and blocks that
+/// don't match are reported rather than stored. An archive that isn't a well-formed CAR file
+/// fails the restore, though, leaving whatever was restored before that in place.
+pub async fn restore<R: AsyncRead>(archive: R, dest_root: PathBuf) -> Result<Restored, io::Error> {
+    let store = FSStore::create(dest_root).await?;
+    let mut reader = CarReader::new(archive).await?;
+    let mut restored = Restored {
+        store,
+        blocks: 0,
+        corrupt: Vec::new(),
+    };
+
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    while let Some((cid, data)) = reader.next_block().await? {
+        let Some(block) = Block::with_cid(cid, data) else {
+            restored.corrupt.push(cid);
+            continue;
+        };
+        batch_bytes += block.data.len();
+        batch.push(block);
+        if batch_bytes >= RESTORE_BATCH_BYTES {
+            restored.store.put_many(&batch).await?;
+            restored.blocks += batch.len() as u64;
+            batch.clear();
+            batch_bytes = 0;
+        }
+    }
+    restored.store.put_many(&batch).await?;
+    restored.blocks += batch.len() as u64;
+
+    Ok(restored)
+}
+
+/// Applies an incremental snapshot, with its archive and `manifest`, to the store at
+/// `dest_root`, which should have been restored up to the snapshot it was taken since: the
+/// archive's blocks get restored as with [`restore`], and the blocks the manifest lists as
+/// deleted are deleted.
+pub async fn restore_since<R: AsyncRead>(
+    archive: R,
+    manifest: &Manifest,
+    dest_root: PathBuf,
+) -> Result<Restored, io::Error> {
+    let restored = restore(archive, dest_root).await?;
+    for cid in &manifest.deleted {
+        match restored.store.del_block(cid).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(restored)
+}
+
+impl<S: Blockstore> Blockstore for SnapshotStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let frozen = self.frozen.read().await;
+        if !*frozen {
+            return self.overlay.base().put_block(block).await;
+        }
+        // The overlay would count blocks the backing store has as new.
+        if self.overlay.has_block(&block.cid).await {
+            return Ok(Put::Existing);
+        }
+        self.overlay.put_block(block).await
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        let frozen = self.frozen.read().await;
+        match *frozen {
+            true => self.overlay.put_many(blocks).await,
+            false => self.overlay.base().put_many(blocks).await,
+        }
+    }
+
+    // Reads go through the overlay either way: with nothing staged, that's the backing store.
+    async fn has_block(&self, cid: &Cid) -> bool {
+        self.overlay.has_block(cid).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        self.overlay.get_block(cid).await
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.overlay.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.overlay.block_size(cid).await
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        let frozen = self.frozen.read().await;
+        match *frozen {
+            true => self.overlay.del_block(cid).await,
+            false => self.overlay.base().del_block(cid).await,
+        }
+    }
+
+    fn blocks(&self) -> CidStream {
+        self.overlay.blocks()
+    }
+
+    /// The backing store's stats, or, during a snapshot, the overlay's, which takes a walk over
+    /// the store.
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let frozen = self.frozen.read().await;
+        match *frozen {
+            true => self.overlay.stats().await,
+            false => self.overlay.base().stats().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+
+    async fn make_snapshot_store() -> (SnapshotStore<MemStore>, ()) {
+        (SnapshotStore::new(MemStore::new()), ())
+    }
+
+    crate::conformance::conformance_tests!(make_snapshot_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn should_snapshot_while_writes_continue() {
+        let store = Arc::new(SnapshotStore::new(MemStore::new()));
+        let blocks: Vec<Block> = (0..32).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks).await.unwrap();
+
+        // A pipe this small stalls the snapshot until we read from it.
+        let (writer, mut reader) = tokio::io::duplex(64);
+        let snapshot = tokio::spawn({
+            let store = store.clone();
+            async move { store.snapshot(writer).await }
+        });
+        let mut archive = vec![0; 1];
+        reader.read_exact(&mut archive).await.unwrap();
+
+        // The snapshot has started, so these shouldn't make it in.
+        let added = make_random_block(1_000);
+        store.put_block(&added).await.unwrap();
+        store.del_block(&blocks[0].cid).await.unwrap();
+        assert!(store.has_block(&added.cid).await);
+        assert!(!store.has_block(&blocks[0].cid).await);
+        assert!(store.store().has_block(&blocks[0].cid).await);
+
+        reader.read_to_end(&mut archive).await.unwrap();
+        let manifest = snapshot.await.unwrap().unwrap();
+        assert_eq!(manifest.cids, blocks.iter().map(|b| b.cid).collect());
+        let restored = MemStore::new();
+        car::import_car(&restored, archive.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(restored.len(), blocks.len());
+        for block in &blocks {
+            assert!(restored.has_block(&block.cid).await);
+        }
+
+        // Once it's done, the writes made in the meantime are applied.
+        assert!(store.store().has_block(&added.cid).await);
+        assert!(!store.store().has_block(&blocks[0].cid).await);
+        assert_eq!(store.stats().await.unwrap().blocks, blocks.len() as u64);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_snapshot_incrementally() {
+        let store = SnapshotStore::new(MemStore::new());
+        let blocks: Vec<Block> = (0..4).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks[..3]).await.unwrap();
+        let full = store.snapshot(tokio::io::sink()).await.unwrap();
+
+        store.del_block(&blocks[0].cid).await.unwrap();
+        store.put_block(&blocks[3]).await.unwrap();
+        let mut archive = Vec::new();
+        let manifest = store.snapshot_since(&full, &mut archive).await.unwrap();
+
+        let restored = MemStore::new();
+        car::import_car(&restored, archive.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(restored.len(), 1);
+        assert!(restored.has_block(&blocks[3].cid).await);
+        assert_eq!(manifest.deleted, vec![blocks[0].cid]);
+        assert_eq!(manifest.cids, blocks[1..].iter().map(|b| b.cid).collect());
+
+        // Manifests make it through being written out.
+        let mut written = Vec::new();
+        manifest.write(&mut written).await.unwrap();
+        assert_eq!(Manifest::read(written.as_slice()).await.unwrap(), manifest);
+        assert!(Manifest::read(&b"not-a-cid\n"[..]).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_restore_snapshots() {
+        let store = SnapshotStore::new(MemStore::new());
+        let blocks: Vec<Block> = (0..4).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks[..3]).await.unwrap();
+        let mut archive = Vec::new();
+        let full = store.snapshot(&mut archive).await.unwrap();
+        store.del_block(&blocks[0].cid).await.unwrap();
+        store.put_block(&blocks[3]).await.unwrap();
+        let mut increment = Vec::new();
+        let manifest = store.snapshot_since(&full, &mut increment).await.unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let restored = restore(archive.as_slice(), dest.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!((restored.blocks, restored.corrupt.len()), (3, 0));
+        let restored = restore_since(increment.as_slice(), &manifest, dest.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(restored.blocks, 1);
+
+        // What's on disk makes a store like the one snapshotted.
+        let reopened = FSStore::create(dest.path().to_path_buf()).await.unwrap();
+        assert_eq!(reopened.stats().await.unwrap().blocks, 3);
+        assert!(!reopened.has_block(&blocks[0].cid).await);
+        for block in &blocks[1..] {
+            assert_eq!(
+                reopened.get_block(&block.cid).await.unwrap().unwrap(),
+                *block
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_report_corrupt_blocks_when_restoring() {
+        let store = SnapshotStore::new(MemStore::new());
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(1_000)).collect();
+        store.put_many(&blocks).await.unwrap();
+        let mut archive = Vec::new();
+        store.snapshot(&mut archive).await.unwrap();
+
+        // The last byte of the archive belongs to the last block in it.
+        *archive.last_mut().unwrap() ^= 0xff;
+        let dest = tempfile::tempdir().unwrap();
+        let restored = restore(archive.as_slice(), dest.path().to_path_buf())
+            .await
+            .unwrap();
+
+        assert_eq!(restored.blocks, 2);
+        assert_eq!(restored.corrupt.len(), 1);
+        let corrupt = restored.corrupt[0];
+        assert!(blocks.iter().any(|block| block.cid == corrupt));
+        assert!(!restored.store.has_block(&corrupt).await);
+
+        archive.truncate(archive.len() - 10);
+        assert!(
+            restore(archive.as_slice(), dest.path().to_path_buf())
+                .await
+                .is_err()
+        );
+    }
+}
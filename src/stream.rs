@@ -0,0 +1,245 @@
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use cid::Cid;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, ReadBuf};
+
+use crate::block::Hasher;
+use crate::blockstore::{Blockstore, BlockstoreError, FSStore, VerifyMode};
+
+/// A [`Blockstore`] that can hand out a block's contents as a stream, for blocks too large to
+/// comfortably hold in memory.
+pub trait StreamingBlockstore: Blockstore {
+    type Reader: AsyncRead + Unpin + Send;
+
+    /// Opens the block `cid` for reading, or returns `None` if the store doesn't have it.
+    fn get_block_stream(
+        &self,
+        cid: &Cid,
+    ) -> impl Future<Output = Result<Option<Self::Reader>, BlockstoreError>> + Send;
+
+    /// Reads `len` bytes of the block `cid` starting at `offset`, or fewer if the block ends
+    /// first. Returns `None` if the store doesn't have the block. Part of a block can't be
+    /// checked against its CID, so ranges never are.
+    fn get_block_range(
+        &self,
+        cid: &Cid,
+        offset: u64,
+        len: u64,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, BlockstoreError>> + Send;
+}
+
+/// Reads a block's contents, optionally hashing them on the way through. A verified reader that
+/// reaches the end of data that doesn't match the CID fails with [`BlockstoreError::Corrupt`],
+/// wrapped in an [`io::Error`] of kind [`io::ErrorKind::InvalidData`].
+///
+/// Only SHA2 hashes are computed incrementally so far; blocks hashed with anything else are passed
+/// through unchecked.
+pub struct BlockReader<R> {
+    inner: R,
+    cid: Cid,
+    hasher: Option<StreamHasher>,
+}
+
+enum StreamHasher {
+    Sha2_256(Sha256),
+    Sha2_512(Sha512),
+}
+
+impl<R> BlockReader<R> {
+    /// Passes the contents of `cid` read from `inner` through as they are.
+    pub fn new(inner: R, cid: Cid) -> Self {
+        BlockReader {
+            inner,
+            cid,
+            hasher: None,
+        }
+    }
+
+    /// Checks the contents of `cid` read from `inner` against it as they're read.
+    pub fn verified(inner: R, cid: Cid) -> Self {
+        let hasher = match Hasher::from_code(cid.hash().code()) {
+            Some(Hasher::Sha2_256) => Some(StreamHasher::Sha2_256(Sha256::new())),
+            Some(Hasher::Sha2_512) => Some(StreamHasher::Sha2_512(Sha512::new())),
+            _ => None,
+        };
+        BlockReader { inner, cid, hasher }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            Some(StreamHasher::Sha2_256(hasher)) => hasher.update(data),
+            Some(StreamHasher::Sha2_512(hasher)) => hasher.update(data),
+            None => {}
+        }
+    }
+
+    // Called at the end of the data, to check it against the CID.
+    fn finish(&mut self) -> Result<(), io::Error> {
+        let digest = match self.hasher.take() {
+            Some(StreamHasher::Sha2_256(hasher)) => hasher.finalize().to_vec(),
+            Some(StreamHasher::Sha2_512(hasher)) => hasher.finalize().to_vec(),
+            None => return Ok(()),
+        };
+        if digest != self.cid.hash().digest() {
+            return Err(BlockstoreError::corrupt(&self.cid, &digest, None).into());
+        }
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for BlockReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let wanted = buf.remaining();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        let read = &buf.filled()[before..];
+        if read.is_empty() && wanted > 0 {
+            return Poll::Ready(this.finish());
+        }
+        this.update(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl StreamingBlockstore for FSStore {
+    type Reader = BlockReader<File>;
+
+    /// Opens the block's file. Blocks are verified unless the store's [`VerifyMode`] is
+    /// [`VerifyMode::Off`], but aren't quarantined even under [`VerifyMode::Quarantine`]: by
+    /// the time a mismatch shows up, the reader is all that's left.
+    async fn get_block_stream(&self, cid: &Cid) -> Result<Option<Self::Reader>, BlockstoreError> {
+        let file = match File::open(self.block_path(cid)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(match self.verify_mode() {
+            VerifyMode::Off => BlockReader::new(file, *cid),
+            VerifyMode::Verify | VerifyMode::Quarantine => BlockReader::verified(file, *cid),
+        }))
+    }
+
+    async fn get_block_range(
+        &self,
+        cid: &Cid,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>, BlockstoreError> {
+        let mut file = match File::open(self.block_path(cid)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut data = Vec::new();
+        file.take(len).read_to_end(&mut data).await?;
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, make_random_block};
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_stream_block_contents() {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let block = make_random_block(1 << 20);
+        store.put_block(&block).await.unwrap();
+
+        let mut reader = store.get_block_stream(&block.cid).await.unwrap().unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, block.data);
+
+        let missing = make_random_block(100);
+        assert!(
+            store
+                .get_block_stream(&missing.cid)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_detect_corruption_at_end_of_stream() {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(PathBuf::from(root.path()))
+            .await
+            .unwrap()
+            .with_verify_mode(VerifyMode::Verify);
+        let block = make_random_block(100_000);
+        store.put_block(&block).await.unwrap();
+        let mut corrupted = block.data.to_vec();
+        corrupted[50_000] ^= 1;
+        fs::write(store.block_path(&block.cid), corrupted).unwrap();
+
+        let mut reader = store.get_block_stream(&block.cid).await.unwrap().unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match BlockstoreError::from(err) {
+            BlockstoreError::Corrupt(corruption) => assert_eq!(corruption.expected, block.cid),
+            err => panic!("expected corruption, got {:?}", err),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_verify_sha2_512_blocks() {
+        let block = Block::new_with_hasher(vec![7; 10_000], Hasher::Sha2_512).unwrap();
+
+        let mut good = BlockReader::verified(&block.data[..], block.cid);
+        good.read_to_end(&mut Vec::new()).await.unwrap();
+
+        let mut bad = BlockReader::verified(&block.data[1..], block.cid);
+        assert!(bad.read_to_end(&mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_read_block_ranges() {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let block = make_random_block(10_000);
+        store.put_block(&block).await.unwrap();
+
+        let range = store.get_block_range(&block.cid, 1_000, 500).await.unwrap();
+        assert_eq!(range.as_deref(), Some(&block.data[1_000..1_500]));
+        let tail = store.get_block_range(&block.cid, 9_900, 500).await.unwrap();
+        assert_eq!(tail.as_deref(), Some(&block.data[9_900..]));
+        let past_end = store
+            .get_block_range(&block.cid, 20_000, 500)
+            .await
+            .unwrap();
+        assert_eq!(past_end, Some(Vec::new()));
+
+        let missing = make_random_block(100);
+        assert!(
+            store
+                .get_block_range(&missing.cid, 0, 10)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Making one store hold everything another one does, such as to back it up.
+
+use std::io;
+
+use cid::Cid;
+
+use crate::blockstore::{Blockstore, CidStream};
+use crate::dag::next_finished;
+
+// How many listed CIDs get checked against the other store at a time.
+const SYNC_BATCH: usize = 256;
+
+/// How many blocks [`sync`] copies at a time unless told otherwise.
+pub const DEFAULT_SYNC_CONCURRENCY: usize = 16;
+
+/// How [`sync`] goes about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncOptions {
+    /// How many blocks get copied at a time. Must be at least 1.
+    pub concurrency: usize,
+    /// Whether to delete the blocks in the destination that aren't in the source.
+    pub delete_extra: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            concurrency: DEFAULT_SYNC_CONCURRENCY,
+            delete_extra: false,
+        }
+    }
+}
+
+/// What [`sync`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Synced {
+    /// Blocks copied, adding up to `bytes`.
+    pub copied: u64,
+    pub bytes: u64,
+    pub deleted: u64,
+}
+
+/// How far along a [`sync`] is, as handed to its progress callback after every batch of blocks
+/// checked and every block copied or deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncProgress {
+    /// Going through the source, with `checked` blocks listed so far, of which `copied` were
+    /// missing from the destination and have been copied, adding up to `bytes`.
+    Copying {
+        checked: u64,
+        copied: u64,
+        bytes: u64,
+    },
+    /// Going through the destination, with `checked` blocks listed so far and `deleted` of them
+    /// deleted for not being in the source.
+    Deleting { checked: u64, deleted: u64 },
+}
+
+/// Copies every block in `src` that `dst` doesn't have over to it, then, if
+/// [`SyncOptions::delete_extra`] is set, deletes every block in `dst` that isn't in `src`.
+/// `progress` gets called as it goes. Blocks put into or deleted from `src` while this runs may
+/// or may not make it to `dst`.
+pub async fn sync(
+    src: &impl Blockstore,
+    dst: &impl Blockstore,
+    options: SyncOptions,
+    mut progress: impl FnMut(SyncProgress),
+) -> Result<Synced, io::Error> {
+    assert!(options.concurrency > 0, "concurrency must be at least 1");
+
+    let mut synced = Synced::default();
+    let mut checked = 0;
+    let mut cids = src.blocks();
+    while let Some(batch) = next_batch(&mut cids).await? {
+        checked += batch.len() as u64;
+        let found = dst.has_many(&batch).await;
+        let mut missing = batch
+            .into_iter()
+            .zip(found)
+            .filter_map(|(cid, found)| (!found).then_some(cid));
+        progress(SyncProgress::Copying {
+            checked,
+            copied: synced.copied,
+            bytes: synced.bytes,
+        });
+
+        let mut in_flight = Vec::new();
+        loop {
+            while in_flight.len() < options.concurrency
+                && let Some(cid) = missing.next()
+            {
+                in_flight.push(Box::pin(copy_block(src, dst, cid)));
+            }
+            let Some(result) = next_finished(&mut in_flight).await else {
+                break;
+            };
+            let Some(bytes) = result? else {
+                continue;
+            };
+            synced.copied += 1;
+            synced.bytes += bytes;
+            progress(SyncProgress::Copying {
+                checked,
+                copied: synced.copied,
+                bytes: synced.bytes,
+            });
+        }
+    }
+
+    if !options.delete_extra {
+        return Ok(synced);
+    }
+
+    // Like with GC, the extra blocks all get listed before any are deleted, so as not to delete
+    // from under the listing.
+    let mut checked = 0;
+    let mut extra = Vec::new();
+    let mut cids = dst.blocks();
+    while let Some(batch) = next_batch(&mut cids).await? {
+        checked += batch.len() as u64;
+        let found = src.has_many(&batch).await;
+        extra.extend(
+            batch
+                .into_iter()
+                .zip(found)
+                .filter_map(|(cid, found)| (!found).then_some(cid)),
+        );
+        progress(SyncProgress::Deleting {
+            checked,
+            deleted: 0,
+        });
+    }
+    for cid in extra {
+        if !dst.del_block_if_present(&cid).await? {
+            continue;
+        }
+        synced.deleted += 1;
+        progress(SyncProgress::Deleting {
+            checked,
+            deleted: synced.deleted,
+        });
+    }
+    Ok(synced)
+}
+
+// Waits for the next CID in `cids`, then takes as many more as are ready, up to a batch.
+// Returns `None` once the listing is done.
+async fn next_batch(cids: &mut CidStream) -> Result<Option<Vec<Cid>>, io::Error> {
+    let Some(cid) = cids.recv().await else {
+        return Ok(None);
+    };
+    let mut batch = vec![cid?];
+    while batch.len() < SYNC_BATCH
+        && let Ok(cid) = cids.try_recv()
+    {
+        batch.push(cid?);
+    }
+    Ok(Some(batch))
+}
+
+// Copies a block from `src` to `dst`, returning its size, or `None` if it's no longer in `src`.
+async fn copy_block(
+    src: &impl Blockstore,
+    dst: &impl Blockstore,
+    cid: Cid,
+) -> Result<Option<u64>, io::Error> {
+    let Some(block) = src.get_block(&cid).await? else {
+        return Ok(None);
+    };
+    dst.put_block(&block).await?;
+    Ok(Some(block.data.len() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, make_random_block};
+    use crate::diff::{Difference, diff};
+    use crate::memstore::MemStore;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_copy_only_missing_blocks() {
+        let src = MemStore::new();
+        let dst = MemStore::new();
+        let blocks: Vec<Block> = (0..1_000).map(|_| make_random_block(10)).collect();
+        src.put_many(&blocks).await.unwrap();
+        dst.put_many(&blocks[..400]).await.unwrap();
+        let extra = make_random_block(10);
+        dst.put_block(&extra).await.unwrap();
+
+        let mut last = None;
+        let synced = sync(&src, &dst, SyncOptions::default(), |p| last = Some(p))
+            .await
+            .unwrap();
+        assert_eq!(
+            synced,
+            Synced {
+                copied: 600,
+                bytes: 6_000,
+                deleted: 0,
+            }
+        );
+        let finished = SyncProgress::Copying {
+            checked: 1_000,
+            copied: 600,
+            bytes: 6_000,
+        };
+        assert_eq!(last, Some(finished));
+        assert!(dst.has_block(&extra.cid).await);
+        assert_eq!(
+            diff(&src, &dst).collect().await.unwrap(),
+            [Difference::OnlyInB(extra.cid)]
+        );
+
+        let again = sync(&src, &dst, SyncOptions::default(), |_| {})
+            .await
+            .unwrap();
+        assert_eq!(again, Synced::default());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_delete_extra_blocks_if_asked() {
+        let src = MemStore::new();
+        let dst = MemStore::new();
+        let blocks: Vec<Block> = (0..10).map(|_| make_random_block(10)).collect();
+        src.put_many(&blocks[..5]).await.unwrap();
+        dst.put_many(&blocks[3..]).await.unwrap();
+
+        let options = SyncOptions {
+            concurrency: 1,
+            delete_extra: true,
+        };
+        let mut last = None;
+        let synced = sync(&src, &dst, options, |p| last = Some(p)).await.unwrap();
+        assert_eq!(
+            synced,
+            Synced {
+                copied: 3,
+                bytes: 30,
+                deleted: 5,
+            }
+        );
+        let finished = SyncProgress::Deleting {
+            checked: 10,
+            deleted: 5,
+        };
+        assert_eq!(last, Some(finished));
+        assert!(diff(&src, &dst).next().await.is_none());
+    }
+}
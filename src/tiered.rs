@@ -0,0 +1,339 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use cid::Cid;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::access::AccessTracker;
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, StoreStats};
+
+/// Puts a fast store in front of a slow one. Blocks are always written to the hot store, and
+/// moved to the cold one by [`TieredStore::migrate`] once they haven't been read or written for
+/// a while; [`TieredStore::start_migrator`] does that periodically. Reading a block that's
+/// only in the cold store pulls it back into the hot one.
+///
+/// A block normally lives in exactly one tier, but can briefly be in both while it's moving.
+/// Only accesses through the wrapper count: blocks that were in the hot store when it was
+/// wrapped are taken to have been accessed right then, unless they're known to the
+/// [`AccessTracker`] given to [`TieredStore::with_access_tracker`].
+pub struct TieredStore<H, C> {
+    hot: H,
+    cold: C,
+    cold_after: Duration,
+    created: Instant,
+    accessed: Mutex<HashMap<Cid, Instant>>,
+    tracker: Option<Arc<AccessTracker>>,
+}
+
+impl<H: Blockstore, C: Blockstore> TieredStore<H, C> {
+    /// Layers `hot` over `cold`, with blocks becoming eligible for migration once they've gone
+    /// untouched for `cold_after`.
+    pub fn new(hot: H, cold: C, cold_after: Duration) -> Self {
+        TieredStore {
+            hot,
+            cold,
+            cold_after,
+            created: Instant::now(),
+            accessed: Mutex::new(HashMap::new()),
+            tracker: None,
+        }
+    }
+
+    /// Keeps access times in `tracker` rather than just in memory, so that blocks which went
+    /// cold before a restart can be migrated without waiting `cold_after` all over again.
+    pub fn with_access_tracker(mut self, tracker: Arc<AccessTracker>) -> Self {
+        self.tracker = Some(tracker);
+        self
+    }
+
+    pub fn hot(&self) -> &H {
+        &self.hot
+    }
+
+    pub fn cold(&self) -> &C {
+        &self.cold
+    }
+
+    /// Moves every block in the hot store that's gone cold into the cold store, returning how
+    /// many were moved.
+    pub async fn migrate(&self) -> Result<usize, io::Error> {
+        let mut candidates = Vec::new();
+        let mut cids = self.hot.blocks();
+        while let Some(cid) = cids.recv().await {
+            let cid = cid?;
+            if self.is_cold(&cid) {
+                candidates.push(cid);
+            }
+        }
+
+        let mut migrated = 0;
+        for cid in candidates {
+            let Some(block) = self.hot.get_block(&cid).await? else {
+                continue;
+            };
+            self.cold.put_block(&block).await?;
+
+            // The block may have been read while we were copying it, in which case it stays
+            // hot (and the cold copy just goes unused until next time).
+            if !self.is_cold(&cid) {
+                continue;
+            }
+            if self.hot.del_block_if_present(&cid).await? {
+                migrated += 1;
+            }
+            self.accessed.lock().unwrap().remove(&cid);
+        }
+
+        Ok(migrated)
+    }
+
+    fn is_cold(&self, cid: &Cid) -> bool {
+        let idle = match &self.tracker {
+            Some(tracker) => tracker
+                .access_info(cid)
+                .map(|info| info.last_access.elapsed().unwrap_or_default()),
+            None => self.accessed.lock().unwrap().get(cid).map(Instant::elapsed),
+        };
+        idle.unwrap_or_else(|| self.created.elapsed()) >= self.cold_after
+    }
+
+    fn touch(&self, cid: &Cid) {
+        match &self.tracker {
+            Some(tracker) => tracker.record(cid),
+            None => {
+                self.accessed.lock().unwrap().insert(*cid, Instant::now());
+            }
+        }
+    }
+
+    // Copies a block that was found in the cold store back into the hot one. This is best
+    // effort: if it fails, the block just stays cold.
+    async fn promote(&self, block: &Block) {
+        if self.hot.put_block(block).await.is_err() {
+            return;
+        }
+        self.touch(&block.cid);
+        let _ = self.cold.del_block(&block.cid).await;
+    }
+}
+
+impl<H: Blockstore + 'static, C: Blockstore + 'static> TieredStore<H, C> {
+    /// Spawns a task that calls [`TieredStore::migrate`] every `interval`. The task stops when
+    /// the returned handle is dropped, or once the store itself is.
+    pub fn start_migrator(self: &Arc<Self>, interval: Duration) -> Migrator {
+        let store: Weak<Self> = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                // A failed migration will just be retried on the next tick.
+                let _ = store.migrate().await;
+            }
+        });
+
+        Migrator { task }
+    }
+}
+
+/// Handle to the task started by [`TieredStore::start_migrator`].
+pub struct Migrator {
+    task: JoinHandle<()>,
+}
+
+impl Drop for Migrator {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl<H: Blockstore, C: Blockstore> Blockstore for TieredStore<H, C> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        let put = self.hot.put_block(block).await?;
+        self.touch(&block.cid);
+        Ok(put)
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        self.hot.put_many(blocks).await?;
+        for block in blocks {
+            self.touch(&block.cid);
+        }
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        self.hot.has_block(cid).await || self.cold.has_block(cid).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if let Some(block) = self.hot.get_block(cid).await? {
+            self.touch(cid);
+            return Ok(Some(block));
+        }
+
+        let block = self.cold.get_block(cid).await?;
+        if let Some(block) = &block {
+            self.promote(block).await;
+        }
+        Ok(block)
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.hot.prefetch(cids);
+        self.cold.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        match self.hot.block_size(cid).await? {
+            Some(size) => Ok(Some(size)),
+            None => self.cold.block_size(cid).await,
+        }
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        let in_hot = self.hot.del_block_if_present(cid).await?;
+        let found = self.cold.del_block_if_present(cid).await? || in_hot;
+        self.accessed.lock().unwrap().remove(cid);
+        if let Some(tracker) = &self.tracker {
+            tracker.forget(cid);
+        }
+
+        if !found {
+            return Err(BlockstoreError::NotFound(*cid));
+        }
+        Ok(())
+    }
+
+    /// Lists the hot blocks, then the cold ones, skipping any that are mid-migration and so in
+    /// both.
+    fn blocks(&self) -> CidStream {
+        let (sender, receiver) = mpsc::channel(1024);
+        let streams = [self.hot.blocks(), self.cold.blocks()];
+
+        tokio::spawn(async move {
+            let mut seen = HashSet::new();
+            for mut cids in streams {
+                while let Some(cid) = cids.recv().await {
+                    if let Ok(cid) = &cid
+                        && !seen.insert(*cid)
+                    {
+                        continue;
+                    }
+                    if sender.send(cid).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        receiver
+    }
+
+    /// Adds up the stats of both tiers, so blocks that are mid-migration count twice.
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let hot = self.hot.stats().await?;
+        let cold = self.cold.stats().await?;
+        Ok(StoreStats {
+            blocks: hot.blocks + cold.blocks,
+            bytes: hot.bytes + cold.bytes,
+            disk_bytes: hot.disk_bytes + cold.disk_bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+
+    async fn make_tiered_store() -> (TieredStore<MemStore, MemStore>, ()) {
+        let store = TieredStore::new(MemStore::new(), MemStore::new(), Duration::from_secs(3_600));
+        (store, ())
+    }
+
+    crate::conformance::conformance_tests!(make_tiered_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_migrate_cold_blocks_and_pull_them_back() {
+        let store = TieredStore::new(MemStore::new(), MemStore::new(), Duration::ZERO);
+        let block = make_random_block(1_000);
+        store.put_block(&block).await.unwrap();
+
+        assert_eq!(store.migrate().await.unwrap(), 1);
+        assert!(!store.hot().has_block(&block.cid).await);
+        assert!(store.cold().has_block(&block.cid).await);
+
+        let retrieved = store.get_block(&block.cid).await.unwrap().unwrap();
+        assert_eq!(retrieved.data, block.data);
+        assert!(store.hot().has_block(&block.cid).await);
+        assert!(!store.cold().has_block(&block.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_keep_recently_used_blocks_hot() {
+        let hot = MemStore::new();
+        let old = make_random_block(1_000);
+        hot.put_block(&old).await.unwrap();
+        let store = TieredStore::new(hot, MemStore::new(), Duration::from_millis(50));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let recent = make_random_block(1_000);
+        store.put_block(&recent).await.unwrap();
+
+        assert_eq!(store.migrate().await.unwrap(), 1);
+        assert!(store.cold().has_block(&old.cid).await);
+        assert!(store.hot().has_block(&recent.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_go_by_tracked_accesses() {
+        let hot = MemStore::new();
+        let old = make_random_block(1_000);
+        let recent = make_random_block(1_000);
+        hot.put_many(&[old.clone(), recent.clone()]).await.unwrap();
+        // As if from before a restart, with only one block read since.
+        let tracker = Arc::new(AccessTracker::new());
+        tracker.record(&old.cid);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tracker.record(&recent.cid);
+
+        let store = TieredStore::new(hot, MemStore::new(), Duration::from_millis(50))
+            .with_access_tracker(tracker.clone());
+        assert_eq!(store.migrate().await.unwrap(), 1);
+        assert!(store.cold().has_block(&old.cid).await);
+        assert!(store.hot().has_block(&recent.cid).await);
+
+        store.get_block(&old.cid).await.unwrap().unwrap();
+        assert_eq!(tracker.access_info(&old.cid).unwrap().count, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_migrate_in_background() {
+        let store = Arc::new(TieredStore::new(
+            MemStore::new(),
+            MemStore::new(),
+            Duration::ZERO,
+        ));
+        let block = make_random_block(1_000);
+        store.put_block(&block).await.unwrap();
+
+        let _migrator = store.start_migrator(Duration::from_millis(10));
+        for _ in 0..100 {
+            if store.cold().has_block(&block.cid).await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(store.cold().has_block(&block.cid).await);
+        assert!(!store.hot().has_block(&block.cid).await);
+    }
+}
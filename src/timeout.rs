@@ -0,0 +1,263 @@
+//! Bounding how long operations on a store can take, for backends that can hang, like roots on
+//! network filesystems.
+
+use std::time::Duration;
+
+use cid::Cid;
+use tokio::sync::mpsc;
+use tokio::time;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+
+/// The kinds of operation a [`TimeoutStore`] has separate timeouts for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Checking for, getting and sizing blocks, listing them, and stats.
+    Read,
+    /// Putting blocks.
+    Write,
+    /// Deleting blocks.
+    Delete,
+}
+
+/// Wraps a [`Blockstore`] so that operations which take longer than their timeout fail with
+/// [`BlockstoreError::Timeout`], rather than keeping the caller waiting. An operation that times
+/// out is dropped, but whatever the store already did towards it stays done: a timed-out put may
+/// still have stored the block.
+///
+/// Batch operations get one timeout for the whole batch, and listing gets one for every block
+/// listed. Since `has_block` and `has_many` have no way to fail, checks that time out report the
+/// blocks as missing.
+pub struct TimeoutStore<S> {
+    store: S,
+    read: Duration,
+    write: Duration,
+    delete: Duration,
+}
+
+impl<S: Blockstore> TimeoutStore<S> {
+    /// Wraps `store` with `timeout` for every kind of operation.
+    pub fn new(store: S, timeout: Duration) -> Self {
+        TimeoutStore {
+            store,
+            read: timeout,
+            write: timeout,
+            delete: timeout,
+        }
+    }
+
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read = timeout;
+        self
+    }
+
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write = timeout;
+        self
+    }
+
+    pub fn with_delete_timeout(mut self, timeout: Duration) -> Self {
+        self.delete = timeout;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn timeout(&self, operation: Operation) -> Duration {
+        match operation {
+            Operation::Read => self.read,
+            Operation::Write => self.write,
+            Operation::Delete => self.delete,
+        }
+    }
+
+    async fn limit<T>(
+        &self,
+        operation: Operation,
+        future: impl Future<Output = Result<T, BlockstoreError>>,
+    ) -> Result<T, BlockstoreError> {
+        let after = self.timeout(operation);
+        match time::timeout(after, future).await {
+            Ok(result) => result,
+            Err(_) => Err(BlockstoreError::Timeout { operation, after }),
+        }
+    }
+}
+
+impl<S: Blockstore> Blockstore for TimeoutStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        self.limit(Operation::Write, self.store.put_block(block))
+            .await
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        self.limit(Operation::Write, self.store.put_many(blocks))
+            .await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        time::timeout(self.read, self.store.has_block(cid))
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn has_many(&self, cids: &[Cid]) -> Vec<bool> {
+        time::timeout(self.read, self.store.has_many(cids))
+            .await
+            .unwrap_or_else(|_| vec![false; cids.len()])
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        self.limit(Operation::Read, self.store.get_block(cid)).await
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, BlockstoreError> {
+        self.limit(Operation::Read, self.store.get_many(cids)).await
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        self.limit(Operation::Read, self.store.block_size(cid))
+            .await
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.limit(Operation::Delete, self.store.del_block(cid))
+            .await
+    }
+
+    async fn del_many(&self, cids: &[Cid]) -> Result<(), BlockstoreError> {
+        self.limit(Operation::Delete, self.store.del_many(cids))
+            .await
+    }
+
+    /// Ends the listing with a [`BlockstoreError::Timeout`] if the next block takes too long.
+    fn blocks(&self) -> CidStream {
+        let mut listed = self.store.blocks();
+        let after = self.read;
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let next = match time::timeout(after, listed.recv()).await {
+                    Ok(Some(next)) => next,
+                    Ok(None) => return,
+                    Err(_) => Err(BlockstoreError::Timeout {
+                        operation: Operation::Read,
+                        after,
+                    }),
+                };
+                let failed = next.is_err();
+                if sender.send(next).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        receiver
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.limit(Operation::Read, self.store.stats()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use std::future::pending;
+    use std::io;
+
+    async fn make_timeout_store() -> (TimeoutStore<MemStore>, ()) {
+        (
+            TimeoutStore::new(MemStore::new(), Duration::from_secs(10)),
+            (),
+        )
+    }
+
+    crate::conformance::conformance_tests!(make_timeout_store);
+
+    // A store whose puts, deletes and listings never finish.
+    struct StuckStore(MemStore);
+
+    impl Blockstore for StuckStore {
+        async fn put_block(&self, _block: &Block) -> Result<Put, BlockstoreError> {
+            pending().await
+        }
+
+        async fn has_block(&self, cid: &Cid) -> bool {
+            self.0.has_block(cid).await
+        }
+
+        async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+            self.0.get_block(cid).await
+        }
+
+        async fn del_block(&self, _cid: &Cid) -> Result<(), BlockstoreError> {
+            pending().await
+        }
+
+        fn blocks(&self) -> CidStream {
+            // The sender stays alive with the task, which never finishes.
+            let (sender, receiver) = mpsc::channel(1);
+            tokio::spawn(async move {
+                let _sender = sender;
+                pending::<()>().await
+            });
+            receiver
+        }
+
+        async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+            self.0.stats().await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_time_out_stuck_operations() {
+        let inner = MemStore::new();
+        let block = make_random_block(100);
+        inner.put_block(&block).await.unwrap();
+        let store = TimeoutStore::new(StuckStore(inner), Duration::from_secs(10))
+            .with_write_timeout(Duration::from_millis(20))
+            .with_delete_timeout(Duration::from_millis(30))
+            .with_read_timeout(Duration::from_millis(40));
+
+        let err = store.put_block(&make_random_block(100)).await.unwrap_err();
+        assert!(matches!(
+            err,
+            BlockstoreError::Timeout {
+                operation: Operation::Write,
+                after,
+            } if after == Duration::from_millis(20)
+        ));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err = store.del_block(&block.cid).await.unwrap_err();
+        assert!(matches!(
+            err,
+            BlockstoreError::Timeout {
+                operation: Operation::Delete,
+                ..
+            }
+        ));
+
+        // Operations that don't get stuck aren't affected.
+        assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), block);
+        assert!(store.has_block(&block.cid).await);
+
+        let mut cids = store.blocks();
+        assert!(matches!(
+            cids.recv().await,
+            Some(Err(BlockstoreError::Timeout {
+                operation: Operation::Read,
+                ..
+            }))
+        ));
+        assert!(cids.recv().await.is_none());
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cid::Cid;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::block::{Block, to_v1};
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, FSStore, StoreStats};
+
+/// Name of the expiry journal inside an [`FSStore`]'s root.
+pub const EXPIRY_FILE: &str = ".expiry";
+
+/// Extension of [`Blockstore`] for stores that can expire blocks.
+pub trait TtlBlockstore: Blockstore {
+    /// Stores `block` so that it goes away once `ttl` has elapsed. Putting a block with a TTL
+    /// never shortens its life: if it's already there with a later expiry, or with none at all,
+    /// that stays. A TTL too long for the clock to reach, such as [`Duration::MAX`], makes the
+    /// block permanent like a plain put does.
+    fn put_block_with_ttl(
+        &self,
+        block: &Block,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<Put, BlockstoreError>> + Send;
+}
+
+/// Wraps a [`Blockstore`] with per-block expiry times. Expired blocks read as missing right
+/// away, and are physically deleted by [`TtlStore::sweep`], which [`TtlStore::start_sweeper`]
+/// runs periodically. A plain `put_block` makes a block permanent again.
+///
+/// Expiries can be persisted to an append-only journal, which gets compacted every time it's
+/// opened.
+pub struct TtlStore<S> {
+    store: S,
+    expiries: Mutex<Expiries>,
+}
+
+struct Expiries {
+    // Keyed by CIDv1, so a block's expiry applies under its v0 CID too.
+    by_cid: HashMap<Cid, SystemTime>,
+    journal: Option<File>,
+}
+
+impl Expiries {
+    fn get(&self, cid: &Cid) -> Option<SystemTime> {
+        self.by_cid.get(&to_v1(cid)).copied()
+    }
+
+    async fn set(&mut self, cid: &Cid, expiry: SystemTime) -> Result<(), io::Error> {
+        let cid = to_v1(cid);
+        self.log(&cid, unix_nanos(expiry)).await?;
+        self.by_cid.insert(cid, expiry);
+        Ok(())
+    }
+
+    async fn clear(&mut self, cid: &Cid) -> Result<(), io::Error> {
+        let cid = to_v1(cid);
+        if self.by_cid.contains_key(&cid) {
+            // Zero is never a real expiry, so we use it to mean "cleared".
+            self.log(&cid, 0).await?;
+            self.by_cid.remove(&cid);
+        }
+        Ok(())
+    }
+
+    fn is_expired(&self, cid: &Cid, now: SystemTime) -> bool {
+        self.get(cid).is_some_and(|expiry| expiry <= now)
+    }
+
+    async fn log(&mut self, cid: &Cid, nanos: u128) -> Result<(), io::Error> {
+        let Some(journal) = &mut self.journal else {
+            return Ok(());
+        };
+        // Tokio files finish writes in the background, so it takes a flush to see them through.
+        let entry = format!("{} {}\n", nanos, cid);
+        journal.write_all(entry.as_bytes()).await?;
+        journal.flush().await
+    }
+}
+
+impl<S: Blockstore> TtlStore<S> {
+    /// Wraps `store`, keeping expiries in memory only.
+    pub fn new(store: S) -> Self {
+        TtlStore {
+            store,
+            expiries: Mutex::new(Expiries {
+                by_cid: HashMap::new(),
+                journal: None,
+            }),
+        }
+    }
+
+    /// Wraps `store`, persisting expiries to the journal at `path`.
+    pub async fn open(store: S, path: PathBuf) -> Result<Self, io::Error> {
+        let by_cid = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => replay_journal(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        // Compact: rewrite the journal with just the live entries, then append from there on.
+        let mut contents = String::new();
+        for (cid, expiry) in &by_cid {
+            contents.push_str(&format!("{} {}\n", unix_nanos(*expiry), cid));
+        }
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, contents).await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+
+        let journal = OpenOptions::new().append(true).open(&path).await?;
+        Ok(TtlStore {
+            store,
+            expiries: Mutex::new(Expiries {
+                by_cid,
+                journal: Some(journal),
+            }),
+        })
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns when `cid` expires, or `None` if it doesn't.
+    pub async fn expiry(&self, cid: &Cid) -> Option<SystemTime> {
+        self.expiries.lock().await.get(cid)
+    }
+
+    /// Deletes every block whose expiry has passed, returning how many were deleted.
+    pub async fn sweep(&self) -> Result<usize, io::Error> {
+        let now = SystemTime::now();
+        let expired: Vec<Cid> = {
+            let expiries = self.expiries.lock().await;
+            expiries
+                .by_cid
+                .iter()
+                .filter(|(_, expiry)| **expiry <= now)
+                .map(|(cid, _)| *cid)
+                .collect()
+        };
+
+        let mut swept = 0;
+        for cid in expired {
+            // The block may have been made permanent since we looked, so we check again, and
+            // hold the lock while deleting so that it can't happen until we're done.
+            let mut expiries = self.expiries.lock().await;
+            if !expiries.is_expired(&cid, now) {
+                continue;
+            }
+
+            if self.store.del_block_if_present(&cid).await? {
+                swept += 1;
+            }
+            expiries.clear(&cid).await?;
+        }
+
+        Ok(swept)
+    }
+}
+
+impl<S: Blockstore + 'static> TtlStore<S> {
+    /// Spawns a task that calls [`TtlStore::sweep`] every `interval`. The task stops when the
+    /// returned handle is dropped, or once the store itself is.
+    pub fn start_sweeper(self: &Arc<Self>, interval: Duration) -> Sweeper {
+        let store: Weak<Self> = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                // A failed sweep will just be retried on the next tick.
+                let _ = store.sweep().await;
+            }
+        });
+
+        Sweeper { task }
+    }
+}
+
+impl TtlStore<FSStore> {
+    /// Wraps an [`FSStore`], persisting expiries in a journal inside its root.
+    pub async fn for_fs_store(store: FSStore) -> Result<Self, io::Error> {
+        let path = store.root().join(EXPIRY_FILE);
+        Self::open(store, path).await
+    }
+}
+
+/// Handle to the task started by [`TtlStore::start_sweeper`].
+pub struct Sweeper {
+    task: JoinHandle<()>,
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn replay_journal(contents: &str) -> Result<HashMap<Cid, SystemTime>, io::Error> {
+    // A crash can leave the last line half-written. Its put never returned, so we can safely
+    // pretend it didn't happen.
+    let complete = &contents[..contents.rfind('\n').map_or(0, |end| end + 1)];
+
+    let mut by_cid = HashMap::new();
+    for line in complete.lines().filter(|line| !line.is_empty()) {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed expiry entry {:?}", line),
+            )
+        };
+
+        let (nanos, cid) = line.split_once(' ').ok_or_else(invalid)?;
+        let nanos: u128 = nanos.parse().map_err(|_| invalid())?;
+        // Older journals have entries under CIDv0s too.
+        let cid = to_v1(&Cid::try_from(cid).map_err(|_| invalid())?);
+        if nanos == 0 {
+            by_cid.remove(&cid);
+        } else {
+            by_cid.insert(cid, from_unix_nanos(nanos).ok_or_else(invalid)?);
+        }
+    }
+    Ok(by_cid)
+}
+
+// Expiries are persisted as nanoseconds since the epoch, so they survive a round trip exactly.
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().max(1)
+}
+
+fn from_unix_nanos(nanos: u128) -> Option<SystemTime> {
+    let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+    let since_epoch = Duration::new(secs, (nanos % 1_000_000_000) as u32);
+    UNIX_EPOCH.checked_add(since_epoch)
+}
+
+impl<S: Blockstore> TtlBlockstore for TtlStore<S> {
+    async fn put_block_with_ttl(&self, block: &Block, ttl: Duration) -> Result<Put, BlockstoreError> {
+        let Some(expiry) = SystemTime::now().checked_add(ttl) else {
+            return self.put_block(block).await;
+        };
+        {
+            let mut expiries = self.expiries.lock().await;
+            match expiries.get(&block.cid) {
+                Some(current) if current >= expiry => {}
+                Some(_) => expiries.set(&block.cid, expiry).await?,
+                // Already stored without an expiry, so it stays permanent.
+                None if self.store.has_block(&block.cid).await => {}
+                None => expiries.set(&block.cid, expiry).await?,
+            }
+        }
+
+        self.store.put_block(block).await
+    }
+}
+
+impl<S: Blockstore> Blockstore for TtlStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        // Clearing the expiry first means a concurrent sweep can't delete the block after
+        // we've written it.
+        self.expiries.lock().await.clear(&block.cid).await?;
+        self.store.put_block(block).await
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        {
+            let mut expiries = self.expiries.lock().await;
+            for block in blocks {
+                expiries.clear(&block.cid).await?;
+            }
+        }
+        self.store.put_many(blocks).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        if self.expiries.lock().await.is_expired(cid, SystemTime::now()) {
+            return false;
+        }
+        self.store.has_block(cid).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        if self.expiries.lock().await.is_expired(cid, SystemTime::now()) {
+            return Ok(None);
+        }
+        self.store.get_block(cid).await
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        if self.expiries.lock().await.is_expired(cid, SystemTime::now()) {
+            return Ok(None);
+        }
+        self.store.block_size(cid).await
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.store.del_block(cid).await?;
+        Ok(self.expiries.lock().await.clear(cid).await?)
+    }
+
+    /// Lists everything in the underlying store, including expired blocks that haven't been
+    /// swept yet.
+    fn blocks(&self) -> CidStream {
+        self.store.blocks()
+    }
+
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.store.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Codec, Hasher, make_random_block};
+    use crate::memstore::MemStore;
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_hide_and_sweep_expired_blocks() {
+        let store = TtlStore::new(MemStore::new());
+        let expiring = make_random_block(100);
+        let permanent = make_random_block(100);
+
+        store.put_block_with_ttl(&expiring, Duration::ZERO).await.unwrap();
+        store.put_block(&permanent).await.unwrap();
+
+        assert!(!store.has_block(&expiring.cid).await);
+        assert!(store.get_block(&expiring.cid).await.unwrap().is_none());
+        assert!(store.store().has_block(&expiring.cid).await);
+
+        assert_eq!(store.sweep().await.unwrap(), 1);
+        assert!(!store.store().has_block(&expiring.cid).await);
+        assert!(store.has_block(&permanent.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_never_shorten_block_life() {
+        let store = TtlStore::new(MemStore::new());
+        let permanent = make_random_block(100);
+        let long_lived = make_random_block(100);
+
+        store.put_block(&permanent).await.unwrap();
+        store.put_block_with_ttl(&permanent, Duration::ZERO).await.unwrap();
+        assert_eq!(store.expiry(&permanent.cid).await, None);
+
+        let hour = Duration::from_secs(3600);
+        store.put_block_with_ttl(&long_lived, hour).await.unwrap();
+        let expiry = store.expiry(&long_lived.cid).await.unwrap();
+        store.put_block_with_ttl(&long_lived, Duration::ZERO).await.unwrap();
+        assert_eq!(store.expiry(&long_lived.cid).await, Some(expiry));
+
+        // A plain put makes it permanent.
+        store.put_block(&long_lived).await.unwrap();
+        assert_eq!(store.expiry(&long_lived.cid).await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_persist_expiries() {
+        let root = tempdir().unwrap();
+        let blocks: Vec<Block> = (0..3).map(|_| make_random_block(100)).collect();
+        let hour = Duration::from_secs(3600);
+
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = TtlStore::for_fs_store(store).await.unwrap();
+        for block in &blocks {
+            store.put_block_with_ttl(block, hour).await.unwrap();
+        }
+        store.put_block(&blocks[2]).await.unwrap();
+        let expiry = store.expiry(&blocks[0].cid).await.unwrap();
+        // Every expiry is in the journal as soon as it's set, not just once the store goes.
+        let journal = std::fs::read_to_string(root.path().join(EXPIRY_FILE)).unwrap();
+        assert_eq!(journal.lines().count(), 4);
+        drop(store);
+
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = TtlStore::for_fs_store(store).await.unwrap();
+        assert_eq!(store.expiry(&blocks[0].cid).await, Some(expiry));
+        assert!(store.expiry(&blocks[1].cid).await.is_some());
+        assert_eq!(store.expiry(&blocks[2].cid).await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_handle_far_off_expiries() {
+        let root = tempdir().unwrap();
+        let forever = make_random_block(100);
+        let millennium = make_random_block(100);
+
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = TtlStore::for_fs_store(store).await.unwrap();
+        store.put_block_with_ttl(&forever, Duration::MAX).await.unwrap();
+        assert_eq!(store.expiry(&forever.cid).await, None);
+        assert!(store.has_block(&forever.cid).await);
+        // Past what nanoseconds since the epoch fit in 64 bits.
+        let ttl = Duration::from_secs(1_000 * 365 * 24 * 3600);
+        store.put_block_with_ttl(&millennium, ttl).await.unwrap();
+        let expiry = store.expiry(&millennium.cid).await.unwrap();
+        drop(store);
+
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        let store = TtlStore::for_fs_store(store).await.unwrap();
+        assert_eq!(store.expiry(&millennium.cid).await, Some(expiry));
+        assert!(store.has_block(&forever.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_expire_blocks_under_v0_and_v1() {
+        let store = TtlStore::new(MemStore::new());
+        let block = Block::new_with_codec(vec![1; 100], Codec::DagPb, Hasher::Sha2_256).unwrap();
+        let v1 = Block {
+            cid: to_v1(&block.cid),
+            data: block.data.clone(),
+        };
+
+        store.put_block_with_ttl(&block, Duration::ZERO).await.unwrap();
+        assert!(!store.has_block(&v1.cid).await);
+        assert!(store.expiry(&v1.cid).await.is_some());
+        store.put_block(&v1).await.unwrap();
+        assert!(store.has_block(&block.cid).await);
+        assert_eq!(store.expiry(&block.cid).await, None);
+
+        // Now permanent under either CID, so neither can give it an expiry again.
+        store.put_block_with_ttl(&block, Duration::ZERO).await.unwrap();
+        assert_eq!(store.expiry(&v1.cid).await, None);
+        assert_eq!(store.sweep().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn should_ignore_torn_journal_entry() {
+        let cid = make_random_block(100).cid;
+        let journal = format!("1000 {}\n2000 {}", cid, cid);
+
+        let by_cid = replay_journal(&journal[..journal.len() - 3]).unwrap();
+        assert_eq!(by_cid[&cid], UNIX_EPOCH + Duration::from_nanos(1000));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_sweep_in_background() {
+        let store = Arc::new(TtlStore::new(MemStore::new()));
+        let block = make_random_block(100);
+        store.put_block_with_ttl(&block, Duration::ZERO).await.unwrap();
+
+        let _sweeper = store.start_sweeper(Duration::from_millis(10));
+        for _ in 0..100 {
+            if !store.store().has_block(&block.cid).await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("block was never swept");
+    }
+}
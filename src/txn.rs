@@ -0,0 +1,290 @@
+//! Puts that span several blocks and survive crashes as a whole, so that a parent can be stored
+//! together with its children without the store ever coming back with dangling links. Whether
+//! concurrent readers see them show up all at once depends on the store: [`MemStore`] commits
+//! do, [`FSStore`] ones show up a block at a time, but never a parent before its children.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use cid::Cid;
+
+use crate::block::{Block, to_v1};
+use crate::blockstore::{Blockstore, BlockstoreError, FSStore};
+use crate::dag::links;
+use crate::memstore::MemStore;
+
+/// A [`Blockstore`] that can group puts into a [`Transaction`].
+pub trait TransactionalBlockstore: Blockstore {
+    type Transaction<'a>: Transaction
+    where
+        Self: 'a;
+
+    /// Starts a new transaction. Nothing put into it shows up in the store until it's committed.
+    fn begin(&self) -> impl Future<Output = Result<Self::Transaction<'_>, BlockstoreError>> + Send;
+}
+
+/// A group of puts, as returned by [`TransactionalBlockstore::begin`]. Dropping a transaction
+/// without committing it rolls it back.
+pub trait Transaction: Send {
+    fn put(&mut self, block: &Block) -> impl Future<Output = Result<(), BlockstoreError>> + Send;
+
+    /// Makes every block put into the transaction visible. Should the process die while this
+    /// runs, the store comes back with either all of them or none. Readers may or may not see
+    /// the blocks show up one by one while this runs, depending on the store.
+    fn commit(self) -> impl Future<Output = Result<(), BlockstoreError>> + Send;
+}
+
+impl TransactionalBlockstore for MemStore {
+    type Transaction<'a> = MemTransaction<'a>;
+
+    async fn begin(&self) -> Result<MemTransaction<'_>, BlockstoreError> {
+        Ok(MemTransaction {
+            store: self,
+            blocks: Vec::new(),
+        })
+    }
+}
+
+/// A [`MemStore`] transaction, which holds on to its blocks until they're all inserted under a
+/// single lock.
+pub struct MemTransaction<'a> {
+    store: &'a MemStore,
+    blocks: Vec<Block>,
+}
+
+impl Transaction for MemTransaction<'_> {
+    async fn put(&mut self, block: &Block) -> Result<(), BlockstoreError> {
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    async fn commit(self) -> Result<(), BlockstoreError> {
+        self.store.put_atomically(&self.blocks)
+    }
+}
+
+impl TransactionalBlockstore for FSStore {
+    type Transaction<'a> = FSTransaction<'a>;
+
+    async fn begin(&self) -> Result<FSTransaction<'_>, BlockstoreError> {
+        Ok(FSTransaction {
+            dir: Some(self.create_staging_dir().await?),
+            store: self,
+            links: HashMap::new(),
+            order: Vec::new(),
+        })
+    }
+}
+
+/// An [`FSStore`] transaction. Blocks are written to a staging directory in the store's root as
+/// they're put, and renamed into the shard tree on commit.
+///
+/// Renaming many files can't be done atomically, so concurrent readers may see a commit in
+/// progress, but never a block before the blocks it links to: those are moved first.
+pub struct FSTransaction<'a> {
+    store: &'a FSStore,
+    // Taken by `commit`, which leaves nothing for `drop` to clean up.
+    dir: Option<PathBuf>,
+    // What each staged block links to, normalized to v1, and the order they were staged in.
+    links: HashMap<Cid, Vec<Cid>>,
+    order: Vec<Cid>,
+}
+
+impl Transaction for FSTransaction<'_> {
+    async fn put(&mut self, block: &Block) -> Result<(), BlockstoreError> {
+        let cid = to_v1(&block.cid);
+        if self.links.contains_key(&cid) {
+            return Ok(());
+        }
+
+        self.store
+            .stage_block(self.dir.as_ref().unwrap(), block)
+            .await?;
+        // Blocks we can't decode can't have links we know about either.
+        let links = links(block).unwrap_or_default();
+        self.links.insert(cid, links.iter().map(to_v1).collect());
+        self.order.push(cid);
+        Ok(())
+    }
+
+    async fn commit(mut self) -> Result<(), BlockstoreError> {
+        let dir = self.dir.take().unwrap();
+        let order = self.children_first();
+        self.store.commit_staged(dir, order).await
+    }
+}
+
+impl FSTransaction<'_> {
+    // Orders the staged blocks so that each comes after the staged blocks it links to.
+    fn children_first(&self) -> Vec<Cid> {
+        let mut visited = HashSet::new();
+        let mut order = Vec::with_capacity(self.order.len());
+        for cid in &self.order {
+            self.visit(cid, &mut visited, &mut order);
+        }
+        order
+    }
+
+    fn visit(&self, cid: &Cid, visited: &mut HashSet<Cid>, order: &mut Vec<Cid>) {
+        // Content addressing rules out cycles, so this always bottoms out.
+        let Some(links) = self.links.get(cid) else {
+            return;
+        };
+        if !visited.insert(*cid) {
+            return;
+        }
+        for link in links {
+            self.visit(link, visited, order);
+        }
+        order.push(*cid);
+    }
+}
+
+impl Drop for FSTransaction<'_> {
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.take() {
+            // Best effort: whatever's left over gets rolled back next time the store is opened.
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::block::{Codec, Hasher, make_random_block};
+    use std::fs;
+    use tempfile::{TempDir, tempdir};
+
+    async fn make_fs_store() -> (FSStore, TempDir) {
+        let root = tempdir().unwrap();
+        let store = FSStore::create(PathBuf::from(root.path())).await.unwrap();
+        (store, root)
+    }
+
+    // A dag-cbor block holding a list of links.
+    fn node(children: &[Cid]) -> Block {
+        let mut data = vec![0x80 | children.len() as u8];
+        for child in children {
+            let cid = child.to_bytes();
+            data.extend_from_slice(&[0xd8, 0x2a, 0x58, cid.len() as u8 + 1, 0x00]);
+            data.extend_from_slice(&cid);
+        }
+        Block::new_with_codec(data, Codec::DagCbor, Hasher::Sha2_256).unwrap()
+    }
+
+    fn staging_dirs(store: &FSStore) -> usize {
+        fs::read_dir(store.root())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with(".txn-")
+            })
+            .count()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_show_blocks_only_once_committed() {
+        let (store, _root) = make_fs_store().await;
+        let children: Vec<Block> = (0..3).map(|_| make_random_block(100)).collect();
+        let cids: Vec<Cid> = children.iter().map(|child| child.cid).collect();
+        let parent = node(&cids);
+
+        let mut txn = store.begin().await.unwrap();
+        // Parent first, so committing in put order would briefly leave its links dangling.
+        txn.put(&parent).await.unwrap();
+        for child in &children {
+            txn.put(child).await.unwrap();
+        }
+        assert_eq!(txn.children_first().last(), Some(&parent.cid));
+        assert!(!store.has_block(&parent.cid).await);
+        assert_eq!(store.stats().await.unwrap().blocks, 0);
+
+        txn.commit().await.unwrap();
+        assert_eq!(store.get_block(&parent.cid).await.unwrap().unwrap(), parent);
+        for child in &children {
+            assert!(store.has_block(&child.cid).await);
+        }
+        assert_eq!(store.stats().await.unwrap().blocks, 4);
+        assert_eq!(staging_dirs(&store), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_roll_back_dropped_transaction() {
+        let (store, _root) = make_fs_store().await;
+        let block = make_random_block(100);
+
+        let mut txn = store.begin().await.unwrap();
+        txn.put(&block).await.unwrap();
+        drop(txn);
+
+        assert!(!store.has_block(&block.cid).await);
+        assert_eq!(staging_dirs(&store), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_recover_transactions_on_open() {
+        let (store, root) = make_fs_store().await;
+        let committed = make_random_block(100);
+        let abandoned = make_random_block(100);
+
+        // One transaction that crashed right after writing its commit file, one that never got
+        // that far.
+        let mut txn = store.begin().await.unwrap();
+        txn.put(&committed).await.unwrap();
+        let dir = txn.dir.take().unwrap();
+        fs::write(dir.join("COMMIT"), format!("{}\n", committed.cid)).unwrap();
+        drop(txn);
+        let mut txn = store.begin().await.unwrap();
+        txn.put(&abandoned).await.unwrap();
+        std::mem::forget(txn);
+        drop(store);
+
+        let store = FSStore::create(root.path().to_path_buf()).await.unwrap();
+        assert!(store.has_block(&committed.cid).await);
+        assert!(!store.has_block(&abandoned.cid).await);
+        assert_eq!(store.stats().await.unwrap().blocks, 1);
+        assert_eq!(staging_dirs(&store), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_commit_mem_transaction_at_once() {
+        let store = MemStore::bounded(250);
+        let blocks = [make_random_block(100), make_random_block(100)];
+
+        let mut txn = store.begin().await.unwrap();
+        for block in &blocks {
+            txn.put(block).await.unwrap();
+        }
+        assert!(store.is_empty());
+        txn.commit().await.unwrap();
+        assert_eq!(store.len(), 2);
+
+        // Too big to fit as a whole, so none of it goes in.
+        let mut txn = store.begin().await.unwrap();
+        for _ in 0..3 {
+            txn.put(&make_random_block(100)).await.unwrap();
+        }
+        let err = txn.commit().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(store.len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_not_evict_blocks_of_mem_transaction() {
+        let store = MemStore::bounded(300);
+        let blocks: Vec<Block> = (0..4).map(|_| make_random_block(100)).collect();
+        store.put_many(&blocks[..3]).await.unwrap();
+
+        // The block that's already there is the oldest, so it would be next to go.
+        let mut txn = store.begin().await.unwrap();
+        txn.put(&blocks[0]).await.unwrap();
+        txn.put(&blocks[3]).await.unwrap();
+        txn.commit().await.unwrap();
+        assert!(store.has_block(&blocks[0].cid).await);
+        assert!(store.has_block(&blocks[3].cid).await);
+        assert!(!store.has_block(&blocks[1].cid).await);
+        assert_eq!(store.len(), 3);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+
+use cid::Cid;
+use tokio::sync::mpsc;
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, Put, CidStream, StoreStats};
+
+/// Reads through an ordered list of stores, returning a block from the first one that has it.
+/// Writes, deletes included, only ever go to the first store, so the rest can be read-only
+/// bases: deleting a block that's also in one of those leaves it readable.
+pub struct UnionStore<S> {
+    stores: Vec<S>,
+}
+
+impl<S: Blockstore> UnionStore<S> {
+    /// Layers `stores`, first to last. Panics if there are none.
+    pub fn new(stores: Vec<S>) -> Self {
+        assert!(!stores.is_empty(), "a union needs at least one store");
+        UnionStore { stores }
+    }
+
+    pub fn stores(&self) -> &[S] {
+        &self.stores
+    }
+
+    fn writable(&self) -> &S {
+        &self.stores[0]
+    }
+}
+
+impl<S: Blockstore> Blockstore for UnionStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        self.writable().put_block(block).await
+    }
+
+    async fn put_many(&self, blocks: &[Block]) -> Result<(), BlockstoreError> {
+        self.writable().put_many(blocks).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        for store in &self.stores {
+            if store.has_block(cid).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        for store in &self.stores {
+            if let Some(block) = store.get_block(cid).await? {
+                return Ok(Some(block));
+            }
+        }
+        Ok(None)
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        for store in &self.stores {
+            store.prefetch(cids);
+        }
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        for store in &self.stores {
+            if let Some(size) = store.block_size(cid).await? {
+                return Ok(Some(size));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        self.writable().del_block(cid).await
+    }
+
+    /// Lists the blocks of every store in order, each one only the first time it shows up.
+    fn blocks(&self) -> CidStream {
+        let (sender, receiver) = mpsc::channel(1024);
+        let streams: Vec<CidStream> = self.stores.iter().map(|store| store.blocks()).collect();
+
+        tokio::spawn(async move {
+            let mut seen = HashSet::new();
+            for mut cids in streams {
+                while let Some(cid) = cids.recv().await {
+                    if let Ok(cid) = &cid
+                        && !seen.insert(*cid)
+                    {
+                        continue;
+                    }
+                    if sender.send(cid).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        receiver
+    }
+
+    /// Adds up the stats of every store, so blocks held by several of them count once for each.
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        let mut total = StoreStats::default();
+        for store in &self.stores {
+            let stats = store.stats().await?;
+            total.blocks += stats.blocks;
+            total.bytes += stats.bytes;
+            total.disk_bytes += stats.disk_bytes;
+        }
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+
+    async fn make_union_store() -> (UnionStore<MemStore>, ()) {
+        (UnionStore::new(vec![MemStore::new(), MemStore::new()]), ())
+    }
+
+    crate::conformance::conformance_tests!(make_union_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_read_through_to_base_stores() {
+        let base = MemStore::new();
+        let block = make_random_block(1_000);
+        base.put_block(&block).await.unwrap();
+        let store = UnionStore::new(vec![MemStore::new(), base]);
+
+        assert!(store.has_block(&block.cid).await);
+        assert_eq!(store.block_size(&block.cid).await.unwrap(), Some(1_000));
+        assert_eq!(
+            store.get_block(&block.cid).await.unwrap().unwrap().data,
+            block.data
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_only_write_to_first_store() {
+        let base = MemStore::new();
+        let shadowed = make_random_block(1_000);
+        base.put_block(&shadowed).await.unwrap();
+        let store = UnionStore::new(vec![MemStore::new(), base]);
+
+        let block = make_random_block(1_000);
+        store.put_block(&block).await.unwrap();
+        assert!(store.stores()[0].has_block(&block.cid).await);
+        assert!(!store.stores()[1].has_block(&block.cid).await);
+
+        let err = store.del_block(&shadowed.cid).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(store.has_block(&shadowed.cid).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_list_shared_blocks_once() {
+        let block = make_random_block(1_000);
+        let stores = vec![MemStore::new(), MemStore::new()];
+        for store in &stores {
+            store.put_block(&block).await.unwrap();
+        }
+        let store = UnionStore::new(stores);
+
+        let mut cids = store.blocks();
+        assert_eq!(cids.recv().await.unwrap().unwrap(), block.cid);
+        assert!(cids.recv().await.is_none());
+    }
+}
@@ -0,0 +1,492 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::future::{self, Future};
+use std::io;
+use std::pin::Pin;
+use std::task::Poll;
+
+use cid::Cid;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+
+use crate::block::{Block, Codec, Hasher};
+use crate::blockstore::Blockstore;
+use crate::car::write_varint;
+use crate::ipld::{Ipld, PbReader, PbValue};
+
+/// The chunk size [`import_file`] is usually called with, the same as Kubo's default.
+pub const DEFAULT_CHUNK_SIZE: usize = 256 << 10;
+
+/// How many links a node gets before the tree grows another level, the same as Kubo's default.
+pub const MAX_LINKS: usize = 174;
+
+/// How many blocks [`export_file`] is usually called to fetch ahead of the one being written.
+pub const DEFAULT_READ_AHEAD: usize = 8;
+
+// The UnixFS types of nodes holding file data.
+const RAW: u64 = 0;
+const FILE: u64 = 2;
+
+/// Reads a file from `reader`, splits it into `chunk_size` chunks, and stores it in `store` as a
+/// UnixFS DAG, returning its root. Chunks are stored as raw blocks, under a balanced tree of
+/// dag-pb nodes with up to [`MAX_LINKS`] links each; a file that fits in a single chunk is just
+/// that chunk. This is the layout `ipfs add --cid-version=1 --raw-leaves` produces.
+///
+/// Blocks are stored as the file is read, so memory use stays around a chunk plus a node per
+/// level of the tree.
+pub async fn import_file<R: AsyncRead>(
+    store: &impl Blockstore,
+    reader: R,
+    chunk_size: usize,
+) -> Result<Cid, io::Error> {
+    assert!(chunk_size > 0, "chunk size must be at least 1");
+    tokio::pin!(reader);
+
+    // The links waiting to go into a node, bottom level (the chunks) first.
+    let mut levels: Vec<Vec<Link>> = vec![Vec::new()];
+    let mut first = true;
+    loop {
+        let chunk = read_chunk(&mut reader, chunk_size).await?;
+        // An empty file still gets a (empty) chunk.
+        if chunk.is_empty() && !first {
+            break;
+        }
+        first = false;
+        let full = chunk.len() == chunk_size;
+
+        let leaf = new_block(chunk, Codec::Raw);
+        store.put_block(&leaf).await?;
+        levels[0].push(Link::to_leaf(&leaf));
+
+        // Parents get built as soon as they're full, so the tree fills up from the left.
+        let mut level = 0;
+        while levels[level].len() == MAX_LINKS {
+            let parent = build_node(store, std::mem::take(&mut levels[level])).await?;
+            if level + 1 == levels.len() {
+                levels.push(Vec::new());
+            }
+            levels[level + 1].push(parent);
+            level += 1;
+        }
+
+        if !full {
+            break;
+        }
+    }
+
+    // Whatever's left at each level goes into a node one level up, until there's a single root.
+    for level in 0..levels.len() {
+        let links = std::mem::take(&mut levels[level]);
+        if level + 1 == levels.len() && links.len() == 1 {
+            return Ok(links[0].cid);
+        }
+        if links.is_empty() {
+            continue;
+        }
+
+        let parent = build_node(store, links).await?;
+        if level + 1 == levels.len() {
+            return Ok(parent.cid);
+        }
+        levels[level + 1].push(parent);
+    }
+    unreachable!("the top level always ends up with a root")
+}
+
+// Fills a chunk from `reader`, which comes up short only at the end of the file.
+async fn read_chunk<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    chunk_size: usize,
+) -> Result<Vec<u8>, io::Error> {
+    let mut chunk = Vec::with_capacity(chunk_size);
+    while chunk.len() < chunk_size {
+        let read = (&mut *reader)
+            .take((chunk_size - chunk.len()) as u64)
+            .read_to_end(&mut chunk)
+            .await?;
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(chunk)
+}
+
+// A link from a node to one of its children.
+struct Link {
+    cid: Cid,
+    // The size of the file data under the child.
+    file_size: u64,
+    // The size of all the blocks making up the child's subtree, which dag-pb links record.
+    tree_size: u64,
+}
+
+impl Link {
+    fn to_leaf(leaf: &Block) -> Self {
+        Link {
+            cid: leaf.cid,
+            file_size: leaf.data.len() as u64,
+            tree_size: leaf.data.len() as u64,
+        }
+    }
+}
+
+// Stores a dag-pb file node linking to `links`, returning a link to it.
+async fn build_node(store: &impl Blockstore, links: Vec<Link>) -> Result<Link, io::Error> {
+    let file_size = links.iter().map(|link| link.file_size).sum();
+
+    // The UnixFS Data message: Type, filesize, then blocksizes.
+    let mut unixfs = Vec::new();
+    write_field(1, FILE, &mut unixfs);
+    write_field(3, file_size, &mut unixfs);
+    for link in &links {
+        write_field(4, link.file_size, &mut unixfs);
+    }
+
+    // The PBNode: Links first, then Data, as dag-pb requires.
+    let mut node = Vec::new();
+    for link in &links {
+        let hash = link.cid.to_bytes();
+        let mut encoded = Vec::new();
+        write_bytes(1, &hash, &mut encoded);
+        write_bytes(2, b"", &mut encoded);
+        write_field(3, link.tree_size, &mut encoded);
+        write_bytes(2, &encoded, &mut node);
+    }
+    write_bytes(1, &unixfs, &mut node);
+
+    let tree_size = node.len() as u64 + links.iter().map(|link| link.tree_size).sum::<u64>();
+    let node = new_block(node, Codec::DagPb);
+    store.put_block(&node).await?;
+
+    Ok(Link {
+        cid: node.cid,
+        file_size,
+        tree_size,
+    })
+}
+
+// Writes a varint protobuf field.
+fn write_field(number: u64, value: u64, out: &mut Vec<u8>) {
+    write_varint(number << 3, out);
+    write_varint(value, out);
+}
+
+// Writes a length-delimited protobuf field.
+fn write_bytes(number: u64, value: &[u8], out: &mut Vec<u8>) {
+    write_varint(number << 3 | 2, out);
+    write_varint(value.len() as u64, out);
+    out.extend_from_slice(value);
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn new_block(data: Vec<u8>, codec: Codec) -> Block {
+    // Can't fail: SHA2-256 digests always fit in a multihash.
+    Block::new_with_codec(data, codec, Hasher::Sha2_256).unwrap()
+}
+
+/// Writes the file whose UnixFS DAG is rooted at `root` to `writer`, returning its size. Blocks
+/// are fetched from `store` as the file gets written, up to `read_ahead` of them ahead of the
+/// one being written. Both raw leaves and dag-pb ones are understood, so this works for files
+/// imported by Kubo with or without `--raw-leaves`, as well as by [`import_file`].
+pub async fn export_file<W: AsyncWrite>(
+    store: &impl Blockstore,
+    root: Cid,
+    writer: W,
+    read_ahead: usize,
+) -> Result<u64, io::Error> {
+    let writer = BufWriter::new(writer);
+    tokio::pin!(writer);
+
+    // The blocks still to be written out in file order, as far as we know it: a node's children
+    // only take its place once it's been fetched.
+    let mut pending = VecDeque::from([Fetch::Queued(root)]);
+    let mut written = 0;
+    while !pending.is_empty() {
+        for fetch in pending.iter_mut().take(read_ahead.max(1)) {
+            if let Fetch::Queued(cid) = *fetch {
+                *fetch = Fetch::Running(Box::pin(fetch_block(store, cid)));
+            }
+        }
+
+        let block = next_fetched(&mut pending).await?;
+        let (data, children) = file_contents(&block)?;
+        writer.write_all(&data).await?;
+        written += data.len() as u64;
+        for child in children.into_iter().rev() {
+            pending.push_front(Fetch::Queued(child));
+        }
+    }
+
+    writer.flush().await?;
+    Ok(written)
+}
+
+async fn fetch_block(store: &impl Blockstore, cid: Cid) -> Result<Block, io::Error> {
+    store
+        .get_block(&cid)
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("block {} not found", cid)))
+}
+
+enum Fetch<F: Future> {
+    Queued(Cid),
+    Running(Pin<Box<F>>),
+    Done(F::Output),
+}
+
+// Drives all the running fetches until the first pending block is in, and pops it.
+async fn next_fetched<F>(pending: &mut VecDeque<Fetch<F>>) -> Result<Block, io::Error>
+where
+    F: Future<Output = Result<Block, io::Error>>,
+{
+    future::poll_fn(|cx| {
+        for fetch in pending.iter_mut() {
+            if let Fetch::Running(future) = fetch
+                && let Poll::Ready(result) = future.as_mut().poll(cx)
+            {
+                *fetch = Fetch::Done(result);
+            }
+        }
+        match pending.front() {
+            Some(Fetch::Done(_)) => match pending.pop_front() {
+                Some(Fetch::Done(result)) => Poll::Ready(result),
+                _ => unreachable!(),
+            },
+            _ => Poll::Pending,
+        }
+    })
+    .await
+}
+
+// Returns the file data held by a UnixFS block itself, and the children holding the rest.
+fn file_contents(block: &Block) -> Result<(Cow<'_, [u8]>, Vec<Cid>), io::Error> {
+    if block.cid.codec() == Codec::Raw.code() {
+        return Ok((Cow::Borrowed(&block.data), Vec::new()));
+    }
+    if block.cid.codec() != Codec::DagPb.code() {
+        return Err(invalid_data(format!(
+            "block {} is not a UnixFS node",
+            block.cid
+        )));
+    }
+
+    let node: Ipld = block.decode()?;
+    let Some(Ipld::Bytes(unixfs)) = node.get("Data") else {
+        return Err(invalid_data(format!(
+            "block {} has no UnixFS data",
+            block.cid
+        )));
+    };
+    let mut kind = None;
+    let mut data: &[u8] = &[];
+    let mut reader = PbReader { buf: unixfs };
+    while let Some(field) = reader.field()? {
+        match field {
+            (1, PbValue::Varint(value)) => kind = Some(value),
+            (2, PbValue::Bytes(value)) => data = value,
+            // Sizes, and metadata we don't care about.
+            _ => {}
+        }
+    }
+    match kind {
+        Some(RAW | FILE) => {}
+        Some(other) => {
+            return Err(invalid_data(format!(
+                "block {} is a UnixFS node of type {}, not a file",
+                block.cid, other
+            )));
+        }
+        None => {
+            return Err(invalid_data(format!(
+                "block {} has no UnixFS type",
+                block.cid
+            )));
+        }
+    }
+
+    let Some(Ipld::List(links)) = node.get("Links") else {
+        unreachable!("dag-pb nodes always decode with links");
+    };
+    let children = links
+        .iter()
+        .filter_map(|link| match link.get("Hash") {
+            Some(Ipld::Link(cid)) => Some(*cid),
+            _ => None,
+        })
+        .collect();
+    Ok((Cow::Owned(data.to_vec()), children))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::walk;
+    use crate::ipld::Ipld;
+    use crate::memstore::MemStore;
+    use rand::RngCore;
+
+    fn random_file(size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        rand::rng().fill_bytes(&mut data);
+        data
+    }
+
+    // Concatenates the leaves under `root`, which a breadth-first walk visits in file order
+    // since they're all at the same depth.
+    async fn leaf_data(store: &MemStore, root: Cid) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut blocks = walk(store, root);
+        while let Some(block) = blocks.next().await {
+            let block = block.unwrap();
+            if block.cid.codec() == Codec::Raw.code() {
+                data.extend_from_slice(&block.data);
+            }
+        }
+        data
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_import_small_file_as_single_raw_block() {
+        let store = MemStore::new();
+
+        let root = import_file(&store, &b"hello"[..], DEFAULT_CHUNK_SIZE)
+            .await
+            .unwrap();
+
+        // The same CID `ipfs add --cid-version=1 --raw-leaves` gives.
+        assert_eq!(
+            root.to_string(),
+            "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq"
+        );
+        assert_eq!(store.len(), 1);
+
+        let empty = import_file(&store, &b""[..], DEFAULT_CHUNK_SIZE)
+            .await
+            .unwrap();
+        assert!(store.get_block(&empty).await.unwrap().unwrap().data.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_build_file_node_over_chunks() {
+        let store = MemStore::new();
+        let file = random_file(1_000);
+
+        let root = import_file(&store, file.as_slice(), 300).await.unwrap();
+
+        let node: Ipld = store
+            .get_block(&root)
+            .await
+            .unwrap()
+            .unwrap()
+            .decode()
+            .unwrap();
+        let Some(Ipld::List(links)) = node.get("Links") else {
+            panic!("no links in {:?}", node);
+        };
+        let sizes: Vec<_> = links
+            .iter()
+            .map(|link| link.get("Tsize").unwrap())
+            .collect();
+        let expected = [300, 300, 300, 100].map(Ipld::Integer);
+        assert_eq!(sizes, expected.iter().collect::<Vec<_>>());
+        // Type file, filesize 1000, blocksizes 300, 300, 300 and 100.
+        let data = [
+            0x08, 0x02, 0x18, 0xe8, 0x07, 0x20, 0xac, 0x02, 0x20, 0xac, 0x02, 0x20, 0xac, 0x02,
+            0x20, 0x64,
+        ];
+        assert_eq!(node.get("Data"), Some(&Ipld::Bytes(data.to_vec())));
+
+        assert_eq!(leaf_data(&store, root).await, file);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_grow_balanced_tree() {
+        let store = MemStore::new();
+        // Enough chunks for a full subtree, and then some.
+        let file = random_file(MAX_LINKS * 10 + 25);
+
+        let root = import_file(&store, file.as_slice(), 10).await.unwrap();
+
+        let node: Ipld = store
+            .get_block(&root)
+            .await
+            .unwrap()
+            .unwrap()
+            .decode()
+            .unwrap();
+        let Some(Ipld::List(links)) = node.get("Links") else {
+            panic!("no links in {:?}", node);
+        };
+        assert_eq!(links.len(), 2);
+        // The chunks, the two nodes above them, and the root.
+        assert_eq!(store.len(), MAX_LINKS + 3 + 3);
+        assert_eq!(leaf_data(&store, root).await, file);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_export_imported_file() {
+        let store = MemStore::new();
+        for size in [0, 5, 1_000, MAX_LINKS * 10 + 25] {
+            let file = random_file(size);
+            let root = import_file(&store, file.as_slice(), 10).await.unwrap();
+
+            let mut exported = Vec::new();
+            let written = export_file(&store, root, &mut exported, 4).await.unwrap();
+            assert_eq!(written, size as u64);
+            assert_eq!(exported, file);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_export_file_with_dag_pb_leaves() {
+        let store = MemStore::new();
+        // A UnixFS file holding "hello", with " world" in a child, as Kubo builds without
+        // --raw-leaves.
+        let mut child_data = Vec::new();
+        write_field(1, FILE, &mut child_data);
+        write_bytes(2, b" world", &mut child_data);
+        let mut child = Vec::new();
+        write_bytes(1, &child_data, &mut child);
+        let child = new_block(child, Codec::DagPb);
+
+        let mut root_data = Vec::new();
+        write_field(1, FILE, &mut root_data);
+        write_bytes(2, b"hello", &mut root_data);
+        let mut link = Vec::new();
+        write_bytes(1, &child.cid.to_bytes(), &mut link);
+        let mut root = Vec::new();
+        write_bytes(2, &link, &mut root);
+        write_bytes(1, &root_data, &mut root);
+        let root = new_block(root, Codec::DagPb);
+        store.put_many(&[child, root.clone()]).await.unwrap();
+
+        let mut exported = Vec::new();
+        export_file(&store, root.cid, &mut exported, DEFAULT_READ_AHEAD)
+            .await
+            .unwrap();
+        assert_eq!(exported, b"hello world");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_fail_export_of_missing_chunk() {
+        let store = MemStore::new();
+        let root = import_file(&store, random_file(100).as_slice(), 10)
+            .await
+            .unwrap();
+        let mut cids = store.blocks();
+        while let Some(cid) = cids.recv().await {
+            let cid = cid.unwrap();
+            if cid != root {
+                store.del_block(&cid).await.unwrap();
+                break;
+            }
+        }
+
+        let err = export_file(&store, root, tokio::io::sink(), DEFAULT_READ_AHEAD)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,270 @@
+//! Write-back caching, for taking bursts of writes faster than the backing store can.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use cid::Cid;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot};
+
+use crate::block::Block;
+use crate::blockstore::{Blockstore, BlockstoreError, CidStream, Put, StoreStats};
+use crate::mirrored::merge_listings;
+
+/// How many bytes of blocks can be waiting to be flushed by default.
+pub const DEFAULT_DIRTY_LIMIT: usize = 64 << 20;
+
+/// Acknowledges puts as soon as the blocks are buffered in memory, and writes them to the
+/// backing store from a background task. Reads see buffered blocks right away. Once the buffer
+/// holds the dirty limit's worth of blocks, puts wait for flushing to make room.
+///
+/// Blocks that fail to flush are dropped, and the error comes back from the next
+/// [`WriteBackStore::flush`]. Whatever is still buffered gets flushed after the store is
+/// dropped, but a crash loses it: call [`WriteBackStore::flush`] for writes that have to last.
+pub struct WriteBackStore<S> {
+    store: Arc<S>,
+    queue: mpsc::UnboundedSender<Flush>,
+    state: Arc<Mutex<State>>,
+    // One permit per byte of buffer that's free.
+    room: Arc<Semaphore>,
+    dirty_limit: usize,
+    // Held by the flusher while it writes a block, so that deletes never race a write of the
+    // block they're deleting.
+    writing: Arc<tokio::sync::Mutex<()>>,
+}
+
+enum Flush {
+    // The permit gives the block's room back once it's written.
+    Block(Cid, OwnedSemaphorePermit),
+    Barrier(oneshot::Sender<()>),
+}
+
+#[derive(Default)]
+struct State {
+    // Blocks acknowledged but not yet written.
+    dirty: HashMap<Cid, Block>,
+    // The first flush failure since the last `flush`.
+    error: Option<BlockstoreError>,
+}
+
+impl<S: Blockstore + 'static> WriteBackStore<S> {
+    /// Buffers writes to `store`, spawning the task that flushes them.
+    pub fn new(store: S) -> Self {
+        let (queue, receiver) = mpsc::unbounded_channel();
+        let store = Arc::new(store);
+        let state = Arc::new(Mutex::new(State::default()));
+        let writing = Arc::new(tokio::sync::Mutex::new(()));
+        tokio::spawn(flush_blocks(
+            store.clone(),
+            receiver,
+            state.clone(),
+            writing.clone(),
+        ));
+
+        WriteBackStore {
+            store,
+            queue,
+            state,
+            room: Arc::new(Semaphore::new(DEFAULT_DIRTY_LIMIT)),
+            dirty_limit: DEFAULT_DIRTY_LIMIT,
+            writing,
+        }
+    }
+
+    /// Lets up to `dirty_limit` bytes of blocks wait to be flushed. A block bigger than that
+    /// waits for the buffer to empty, and then has it to itself.
+    pub fn with_dirty_limit(mut self, dirty_limit: usize) -> Self {
+        let dirty_limit = dirty_limit.min(u32::MAX as usize);
+        self.room = Arc::new(Semaphore::new(dirty_limit));
+        self.dirty_limit = dirty_limit;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// How many bytes of blocks are waiting to be flushed.
+    pub fn dirty_bytes(&self) -> usize {
+        self.dirty_limit - self.room.available_permits()
+    }
+
+    /// Waits for every block buffered so far to be written to the backing store. Fails with the
+    /// first error flushing ran into since the last call, if any: the block that caused it is
+    /// lost.
+    pub async fn flush(&self) -> Result<(), BlockstoreError> {
+        self.barrier().await?;
+        match self.state.lock().unwrap().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    async fn barrier(&self) -> Result<(), BlockstoreError> {
+        let (done, flushed) = oneshot::channel();
+        self.queue
+            .send(Flush::Barrier(done))
+            .map_err(|_| stopped())?;
+        flushed.await.map_err(|_| stopped())
+    }
+
+    fn dirty(&self, cid: &Cid) -> Option<Block> {
+        self.state.lock().unwrap().dirty.get(cid).cloned()
+    }
+}
+
+async fn flush_blocks<S: Blockstore>(
+    store: Arc<S>,
+    mut receiver: mpsc::UnboundedReceiver<Flush>,
+    state: Arc<Mutex<State>>,
+    writing: Arc<tokio::sync::Mutex<()>>,
+) {
+    while let Some(flush) = receiver.recv().await {
+        let (cid, _permit) = match flush {
+            Flush::Block(cid, permit) => (cid, permit),
+            Flush::Barrier(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+
+        let _writing = writing.lock().await;
+        // Deleted in the meantime, or already written for an earlier put of the same block.
+        let Some(block) = state.lock().unwrap().dirty.get(&cid).cloned() else {
+            continue;
+        };
+        let result = store.put_block(&block).await;
+
+        let mut state = state.lock().unwrap();
+        state.dirty.remove(&cid);
+        if let Err(e) = result {
+            state.error.get_or_insert(e);
+        }
+    }
+}
+
+fn stopped() -> BlockstoreError {
+    BlockstoreError::Backend(std::io::Error::other("flush task stopped"))
+}
+
+impl<S: Blockstore + 'static> Blockstore for WriteBackStore<S> {
+    async fn put_block(&self, block: &Block) -> Result<Put, BlockstoreError> {
+        if self.dirty(&block.cid).is_some() || self.store.has_block(&block.cid).await {
+            return Ok(Put::Existing);
+        }
+
+        let size = block.data.len().min(self.dirty_limit) as u32;
+        let permit = self
+            .room
+            .clone()
+            .acquire_many_owned(size)
+            .await
+            .expect("never closed");
+        self.state
+            .lock()
+            .unwrap()
+            .dirty
+            .insert(block.cid, block.clone());
+        self.queue
+            .send(Flush::Block(block.cid, permit))
+            .map_err(|_| stopped())?;
+        // Not written yet, but it will be, unless the flush fails.
+        Ok(Put::Written {
+            bytes: block.data.len() as u64,
+        })
+    }
+
+    async fn has_block(&self, cid: &Cid) -> bool {
+        self.dirty(cid).is_some() || self.store.has_block(cid).await
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Block>, BlockstoreError> {
+        match self.dirty(cid) {
+            Some(block) => Ok(Some(block)),
+            None => self.store.get_block(cid).await,
+        }
+    }
+
+    fn prefetch(&self, cids: &[Cid]) {
+        self.store.prefetch(cids);
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreError> {
+        match self.dirty(cid) {
+            Some(block) => Ok(Some(block.data.len() as u64)),
+            None => self.store.block_size(cid).await,
+        }
+    }
+
+    /// Deletes the block whether or not it's been flushed yet.
+    async fn del_block(&self, cid: &Cid) -> Result<(), BlockstoreError> {
+        let _writing = self.writing.lock().await;
+        let dirty = self.state.lock().unwrap().dirty.remove(cid);
+        match self.store.del_block(cid).await {
+            Err(BlockstoreError::NotFound(_)) if dirty.is_some() => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Lists the blocks in the buffer as well as the backing store.
+    fn blocks(&self) -> CidStream {
+        let dirty: Vec<Cid> = self.state.lock().unwrap().dirty.keys().copied().collect();
+        let (sender, buffered) = mpsc::channel(dirty.len().max(1));
+        for cid in dirty {
+            let _ = sender.try_send(Ok(cid));
+        }
+        merge_listings(vec![buffered, self.store.blocks()])
+    }
+
+    /// The backing store's stats, once everything buffered so far has been flushed.
+    async fn stats(&self) -> Result<StoreStats, BlockstoreError> {
+        self.barrier().await?;
+        self.store.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::make_random_block;
+    use crate::memstore::MemStore;
+    use crate::readonly::ReadOnlyStore;
+
+    async fn make_write_back_store() -> (WriteBackStore<MemStore>, ()) {
+        (WriteBackStore::new(MemStore::new()), ())
+    }
+
+    crate::conformance::conformance_tests!(make_write_back_store);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_flush_buffered_blocks() {
+        let store = WriteBackStore::new(MemStore::new()).with_dirty_limit(1_000);
+        let blocks: Vec<Block> = (0..10).map(|_| make_random_block(300)).collect();
+
+        for block in &blocks {
+            store.put_block(block).await.unwrap();
+            assert!(store.dirty_bytes() <= 1_000);
+            assert_eq!(store.get_block(&block.cid).await.unwrap().unwrap(), *block);
+        }
+        store.del_block(&blocks[0].cid).await.unwrap();
+        store.flush().await.unwrap();
+
+        assert_eq!(store.dirty_bytes(), 0);
+        assert!(!store.store().has_block(&blocks[0].cid).await);
+        for block in &blocks[1..] {
+            assert!(store.store().has_block(&block.cid).await);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn should_report_flush_failures() {
+        let store = WriteBackStore::new(ReadOnlyStore::new(MemStore::new()));
+        let block = make_random_block(100);
+
+        // The put itself succeeds, since the buffer took it.
+        assert_eq!(store.put_block(&block).await.unwrap(), Put::Written { bytes: 100 });
+        let result = store.flush().await;
+        assert!(matches!(result, Err(BlockstoreError::ReadOnly)));
+        store.flush().await.unwrap();
+        assert!(!store.has_block(&block.cid).await);
+    }
+}
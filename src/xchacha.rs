@@ -0,0 +1,337 @@
+//! XChaCha20-Poly1305 (draft-irtf-cfrg-xchacha), built from the ChaCha20 and Poly1305 of
+//! RFC 8439. Only what [`crate::encrypted`] needs: one-shot sealing and opening of buffers.
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// Encrypts `data` in place, returning the tag that authenticates it along with `aad`.
+pub fn seal(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut [u8],
+) -> [u8; TAG_LEN] {
+    let (subkey, nonce) = derive(key, nonce);
+    seal_ietf(&subkey, &nonce, aad, data)
+}
+
+/// Checks `tag` and decrypts `data` in place. Returns false, leaving `data` untouched, if the
+/// tag doesn't match.
+pub fn open(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut [u8],
+    tag: &[u8; TAG_LEN],
+) -> bool {
+    let (subkey, nonce) = derive(key, nonce);
+    open_ietf(&subkey, &nonce, aad, data, tag)
+}
+
+// XChaCha20 is ChaCha20 under a subkey derived from the first 16 bytes of the nonce, with the
+// last 8 bytes as the (zero-padded) IETF nonce.
+fn derive(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN]) -> ([u8; KEY_LEN], [u8; 12]) {
+    let subkey = hchacha20(key, nonce[..16].try_into().unwrap());
+    let mut ietf_nonce = [0u8; 12];
+    ietf_nonce[4..].copy_from_slice(&nonce[16..]);
+    (subkey, ietf_nonce)
+}
+
+fn seal_ietf(key: &[u8; KEY_LEN], nonce: &[u8; 12], aad: &[u8], data: &mut [u8]) -> [u8; TAG_LEN] {
+    let poly_key = poly_key(key, nonce);
+    chacha20_xor(key, nonce, 1, data);
+    poly1305(&poly_key, &mac_data(aad, data))
+}
+
+fn open_ietf(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; 12],
+    aad: &[u8],
+    data: &mut [u8],
+    tag: &[u8; TAG_LEN],
+) -> bool {
+    let poly_key = poly_key(key, nonce);
+    let expected = poly1305(&poly_key, &mac_data(aad, data));
+    // Compare without branching on the contents, so timing doesn't give away how much of a
+    // forged tag was right.
+    let difference = expected
+        .iter()
+        .zip(tag)
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    if difference != 0 {
+        return false;
+    }
+
+    chacha20_xor(key, nonce, 1, data);
+    true
+}
+
+fn poly_key(key: &[u8; KEY_LEN], nonce: &[u8; 12]) -> [u8; 32] {
+    chacha20_block(key, 0, nonce)[..32].try_into().unwrap()
+}
+
+// The message Poly1305 authenticates: both inputs zero-padded to 16 bytes, then their lengths.
+fn mac_data(aad: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(aad.len() + ciphertext.len() + 48);
+    for part in [aad, ciphertext] {
+        data.extend_from_slice(part);
+        data.resize(data.len().next_multiple_of(16), 0);
+    }
+    data.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    data
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn rounds(state: &mut [u32; 16]) {
+    for _ in 0..10 {
+        quarter_round(state, 0, 4, 8, 12);
+        quarter_round(state, 1, 5, 9, 13);
+        quarter_round(state, 2, 6, 10, 14);
+        quarter_round(state, 3, 7, 11, 15);
+        quarter_round(state, 0, 5, 10, 15);
+        quarter_round(state, 1, 6, 11, 12);
+        quarter_round(state, 2, 7, 8, 13);
+        quarter_round(state, 3, 4, 9, 14);
+    }
+}
+
+fn initial_state(key: &[u8; KEY_LEN], input: &[u8; 16]) -> [u32; 16] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&CONSTANTS);
+    for (word, bytes) in state[4..]
+        .iter_mut()
+        .zip(key.chunks(4).chain(input.chunks(4)))
+    {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    state
+}
+
+fn chacha20_block(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let mut input = [0u8; 16];
+    input[..4].copy_from_slice(&counter.to_le_bytes());
+    input[4..].copy_from_slice(nonce);
+
+    let initial = initial_state(key, &input);
+    let mut state = initial;
+    rounds(&mut state);
+
+    let mut block = [0u8; 64];
+    for (i, bytes) in block.chunks_mut(4).enumerate() {
+        bytes.copy_from_slice(&state[i].wrapping_add(initial[i]).to_le_bytes());
+    }
+    block
+}
+
+fn chacha20_xor(key: &[u8; KEY_LEN], nonce: &[u8; 12], counter: u32, data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let keystream = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (byte, key) in chunk.iter_mut().zip(keystream) {
+            *byte ^= key;
+        }
+    }
+}
+
+fn hchacha20(key: &[u8; KEY_LEN], nonce: &[u8; 16]) -> [u8; KEY_LEN] {
+    let mut state = initial_state(key, nonce);
+    rounds(&mut state);
+
+    let mut subkey = [0u8; KEY_LEN];
+    let words = state[..4].iter().chain(&state[12..]);
+    for (bytes, word) in subkey.chunks_mut(4).zip(words) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    subkey
+}
+
+// Poly1305 over 26-bit limbs, after poly1305-donna.
+fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; TAG_LEN] {
+    const MASK: u64 = 0x3ff_ffff;
+    let word = |bytes: &[u8], at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+
+    // Clamping r is folded into the masks.
+    let r0 = u64::from(word(key, 0)) & 0x3ff_ffff;
+    let r1 = u64::from(word(key, 3) >> 2) & 0x3ff_ff03;
+    let r2 = u64::from(word(key, 6) >> 4) & 0x3ff_c0ff;
+    let r3 = u64::from(word(key, 9) >> 6) & 0x3f0_3fff;
+    let r4 = u64::from(word(key, 12) >> 8) & 0x00f_ffff;
+    let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+
+    let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0u64, 0u64, 0u64, 0u64, 0u64);
+    for chunk in message.chunks(16) {
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+        block[chunk.len()] = 1;
+        let high = if chunk.len() == 16 { 1 << 24 } else { 0 };
+
+        h0 += u64::from(word(&block, 0)) & MASK;
+        h1 += u64::from(word(&block, 3) >> 2) & MASK;
+        h2 += u64::from(word(&block, 6) >> 4) & MASK;
+        h3 += u64::from(word(&block, 9) >> 6) & MASK;
+        h4 += u64::from(word(&block, 12) >> 8) | high;
+
+        let d0 = h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1;
+        let mut d1 = h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2;
+        let mut d2 = h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3;
+        let mut d3 = h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4;
+        let mut d4 = h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0;
+
+        d1 += d0 >> 26;
+        h0 = d0 & MASK;
+        d2 += d1 >> 26;
+        h1 = d1 & MASK;
+        d3 += d2 >> 26;
+        h2 = d2 & MASK;
+        d4 += d3 >> 26;
+        h3 = d3 & MASK;
+        h0 += (d4 >> 26) * 5;
+        h4 = d4 & MASK;
+        h1 += h0 >> 26;
+        h0 &= MASK;
+    }
+
+    // Fully carry h, then subtract p = 2^130 - 5 if h >= p.
+    h2 += h1 >> 26;
+    h1 &= MASK;
+    h3 += h2 >> 26;
+    h2 &= MASK;
+    h4 += h3 >> 26;
+    h3 &= MASK;
+    h0 += (h4 >> 26) * 5;
+    h4 &= MASK;
+    h1 += h0 >> 26;
+    h0 &= MASK;
+
+    let mut g0 = h0 + 5;
+    let mut g1 = h1 + (g0 >> 26);
+    g0 &= MASK;
+    let mut g2 = h2 + (g1 >> 26);
+    g1 &= MASK;
+    let mut g3 = h3 + (g2 >> 26);
+    g2 &= MASK;
+    let g4 = (h4 + (g3 >> 26)).wrapping_sub(1 << 26);
+    g3 &= MASK;
+
+    // All ones if g didn't underflow, i.e. h >= p.
+    let select = (g4 >> 63).wrapping_sub(1);
+    let h0 = (h0 & !select) | (g0 & select);
+    let h1 = (h1 & !select) | (g1 & select);
+    let h2 = (h2 & !select) | (g2 & select);
+    let h3 = (h3 & !select) | (g3 & select);
+    let h4 = (h4 & !select) | (g4 & select);
+
+    let words = [
+        (h0 | (h1 << 26)) & 0xffff_ffff,
+        ((h1 >> 6) | (h2 << 20)) & 0xffff_ffff,
+        ((h2 >> 12) | (h3 << 14)) & 0xffff_ffff,
+        ((h3 >> 18) | (h4 << 8)) & 0xffff_ffff,
+    ];
+
+    let mut tag = [0u8; TAG_LEN];
+    let mut carry = 0u64;
+    for (i, h) in words.into_iter().enumerate() {
+        let sum = h + u64::from(word(key, 16 + 4 * i)) + carry;
+        tag[4 * i..4 * i + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+        carry = sum >> 32;
+    }
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn should_match_rfc_8439_poly1305_vector() {
+        let key: [u8; 32] =
+            unhex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b")
+                .try_into()
+                .unwrap();
+        let tag = poly1305(&key, b"Cryptographic Forum Research Group");
+        assert_eq!(tag.to_vec(), unhex("a8061dc1305136c6c22b8baf0c0127a9"));
+    }
+
+    #[test]
+    fn should_match_rfc_8439_aead_vector() {
+        let key: [u8; 32] = core::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; 12] = unhex("070000004041424344454647").try_into().unwrap();
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+        let mut data = plaintext.to_vec();
+        let tag = seal_ietf(&key, &nonce, &aad, &mut data);
+        assert_eq!(&data[..16], unhex("d31a8d34648e60db7b86afbc53ef7ec2"));
+        assert_eq!(tag.to_vec(), unhex("1ae10b594f09e26a7e902ecbd0600691"));
+
+        assert!(open_ietf(&key, &nonce, &aad, &mut data, &tag));
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn should_match_hchacha20_vector() {
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let nonce: [u8; 16] = unhex("000000090000004a0000000031415927")
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            hchacha20(&key, &nonce).to_vec(),
+            unhex("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc")
+        );
+    }
+
+    #[test]
+    fn should_seal_with_extended_nonce() {
+        let key: [u8; 32] = core::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; 24] = core::array::from_fn(|i| 0x40 + i as u8);
+        let plaintext = b"hello, encrypted world".repeat(3);
+
+        let mut data = plaintext.clone();
+        let tag = seal(&key, &nonce, b"cid", &mut data);
+        data.extend_from_slice(&tag);
+        assert_eq!(
+            data,
+            unhex(
+                "99691f9834dcd43f957105aa86daf83112301d1c6920f489a6d69a20623063ab\
+                 724560094e09bf0a5ea18d2d06f63236ae3d8a71c258d609de90f2335d11fb4f\
+                 2d8436caaa35c54fb0719a1adda48d5b8c45"
+            )
+        );
+    }
+
+    #[test]
+    fn should_reject_tampered_data() {
+        let key = [7u8; KEY_LEN];
+        let nonce = [9u8; NONCE_LEN];
+        let mut data = b"attack at dawn".to_vec();
+        let tag = seal(&key, &nonce, b"aad", &mut data);
+
+        let mut tampered = data.clone();
+        tampered[0] ^= 1;
+        assert!(!open(&key, &nonce, b"aad", &mut tampered, &tag));
+        assert!(!open(&key, &nonce, b"other", &mut data.clone(), &tag));
+
+        assert!(open(&key, &nonce, b"aad", &mut data, &tag));
+        assert_eq!(data, b"attack at dawn");
+    }
+}